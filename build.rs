@@ -0,0 +1,53 @@
+//! Records the commit and time of the build, which `/version` reports, and
+//! generates the gRPC service with the `grpc` feature.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    // `GIT_SHA` covers builds without the repository, such as Docker's.
+    let sha = std::env::var("GIT_SHA").ok().filter(|sha| !sha.is_empty()).or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "HEAD"]).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha.unwrap_or_default());
+
+    // `SOURCE_DATE_EPOCH` keeps reproducible builds reproducible.
+    let time = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|time| time.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()));
+    println!("cargo:rustc-env=BUILD_UNIX_TIME={}", time);
+
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=src");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generates the server of `proto/transform.proto`. Its messages are written
+/// out in `src/grpc.rs`, so that building needs no `protoc`.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let service = Service::builder()
+        .name("Transformer")
+        .package("image_transformer.v1")
+        .method(
+            Method::builder()
+                .name("transform")
+                .route_name("Transform")
+                .input_type("super::TransformRequest")
+                .output_type("super::TransformResponse")
+                .codec_path("tonic_prost::ProstCodec")
+                .build(),
+        )
+        .build();
+    Builder::new().build_client(false).build_transport(false).compile(&[service]);
+}
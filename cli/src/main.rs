@@ -0,0 +1,234 @@
+//! Runs the /transform pipeline on local files, so that its output can be
+//! reproduced without the server:
+//!
+//! ```sh
+//! transform --size 800x600 --format avif ./photos/*.jpg -o out/
+//! ```
+
+use image_transformer_core::{process_image, ClientHints, Error, Fields, ProcessOptions, Settings, Variant};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const USAGE: &str = "\
+Usage: transform [OPTIONS] <INPUT>... -o <DIR>
+
+Transforms images like POST /transform of the image transformer API, writing
+the outputs to DIR. Directories are read recursively, and their layout is
+kept in DIR.
+
+Options:
+  -o, --output <DIR>     Directory the outputs are written to
+      --watermark <FILE> Watermark image, instead of WATERMARK_PATH
+      --accept <VALUE>   Accept header that `--format auto` picks from
+      --<field> <VALUE>  Any /transform field, e.g. `--size 800x600`,
+                         `--format avif` or `--no-enlarge true`
+  -h, --help             Prints this help
+
+WATERMARK_PATH, FACE_MODEL_PATH and PDFIUM_LIBRARY_PATH are read like the
+server reads them.";
+
+fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(message) => {
+            eprintln!("transform: {message}\n\nRun `transform --help` for the options.");
+            return ExitCode::from(2);
+        }
+    };
+
+    image_transformer_core::install(Settings {
+        watermark_path: var("WATERMARK_PATH"),
+        face_model_path: var("FACE_MODEL_PATH"),
+        pdfium_library_path: var("PDFIUM_LIBRARY_PATH"),
+        ..Settings::default()
+    });
+
+    let jobs = match collect_jobs(&args.inputs, &args.output) {
+        Ok(jobs) => jobs,
+        Err(message) => {
+            eprintln!("transform: {message}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let failed = run(&jobs, &args.options);
+    if failed > 0 {
+        eprintln!("transform: {failed} of {} inputs failed", jobs.len());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// The command line, with the fields resolved like the server resolves a
+/// request without Client Hints.
+struct Args {
+    options: ProcessOptions,
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+}
+
+impl Args {
+    /// Returns `None` when help was asked for.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut fields = Fields::default();
+        let (mut inputs, mut output, mut accept) = (Vec::new(), None, None);
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-').filter(|short| !short.is_empty())) else {
+                inputs.push(PathBuf::from(arg));
+                continue;
+            };
+            if matches!(option, "h" | "help") {
+                return Ok(None);
+            }
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name.replace('-', "_"), value.to_string()),
+                None => (option.replace('-', "_"), args.next().ok_or_else(|| format!("`{arg}` needs a value"))?),
+            };
+            match name.as_str() {
+                "o" | "output" => output = Some(PathBuf::from(value)),
+                "accept" => accept = Some(value),
+                "watermark" => {
+                    let watermark = std::fs::read(&value).map_err(|err| format!("cannot read {value}: {err}"))?;
+                    fields.options.watermark = Some(watermark.into());
+                }
+                _ => match fields.set(&name, &value) {
+                    Ok(true) => {}
+                    Ok(false) => return Err(format!("unknown option `{arg}`")),
+                    Err(err) => return Err(describe(&err)),
+                },
+            }
+        }
+
+        let output = output.ok_or("no output directory given with `-o`")?;
+        if inputs.is_empty() {
+            return Err("no inputs given".into());
+        }
+        let hints = ClientHints { accept: accept.as_deref(), ..ClientHints::default() };
+        let (options, _) = fields.resolve(&hints).map_err(|err| describe(&err))?;
+        Ok(Some(Self { options, inputs, output }))
+    }
+}
+
+/// One input file and where its outputs go, without the extension.
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+    /// Found in a directory rather than named.
+    found: bool,
+}
+
+/// Lists the files to transform. The files of a directory keep their path
+/// below it, and named files go straight into `output`.
+fn collect_jobs(inputs: &[PathBuf], output: &Path) -> Result<Vec<Job>, String> {
+    let mut jobs = Vec::new();
+    for input in inputs {
+        if input.is_dir() {
+            walk(input, input, output, &mut jobs).map_err(|err| format!("cannot read {}: {err}", input.display()))?;
+        } else {
+            let stem = input.file_stem().ok_or_else(|| format!("{} is not a file", input.display()))?;
+            jobs.push(Job { input: input.clone(), output: output.join(stem), found: false });
+        }
+    }
+    Ok(jobs)
+}
+
+fn walk(root: &Path, dir: &Path, output: &Path, jobs: &mut Vec<Job>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            walk(root, &path, output, jobs)?;
+        } else {
+            let relative = path.strip_prefix(root).expect("walked below the root").with_extension("");
+            jobs.push(Job { input: path, output: output.join(relative), found: true });
+        }
+    }
+    Ok(())
+}
+
+/// Transforms the inputs on every core. Returns how many failed.
+fn run(jobs: &[Job], options: &ProcessOptions) -> usize {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    // Keeps the lines of different inputs from interleaving.
+    let report = Mutex::new(());
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get()).min(jobs.len());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = transform(job, options.clone());
+                    let _guard = report.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    match result {
+                        Ok(written) => {
+                            for (path, placeholder) in written {
+                                match placeholder {
+                                    Some(placeholder) => println!("{} -> {} {placeholder}", job.input.display(), path.display()),
+                                    None => println!("{} -> {}", job.input.display(), path.display()),
+                                }
+                            }
+                        }
+                        // Directories may hold files other than images.
+                        Err(Failure::Pipeline(err)) if job.found && err.code() == "unsupported_format" => {}
+                        Err(failure) => {
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            let message = match failure {
+                                Failure::Pipeline(err) => describe(&err),
+                                Failure::Io(err) => err.to_string(),
+                            };
+                            eprintln!("{}: {message}", job.input.display());
+                        }
+                    }
+                }
+            });
+        }
+    });
+    failed.into_inner()
+}
+
+enum Failure {
+    Pipeline(Error),
+    Io(std::io::Error),
+}
+
+/// Writes `<name>.<ext>`, or `<name>-<width>x<height>.<ext>` for each size
+/// of a `variants` request. Returns each path with its placeholder.
+fn transform(job: &Job, options: ProcessOptions) -> Result<Vec<(PathBuf, Option<String>)>, Failure> {
+    let has_variants = !options.variants.is_empty();
+    let input = std::fs::read(&job.input).map_err(Failure::Io)?;
+    let outputs = process_image(input.into(), options).map_err(Failure::Pipeline)?;
+    if let Some(dir) = job.output.parent() {
+        std::fs::create_dir_all(dir).map_err(Failure::Io)?;
+    }
+    let mut written = Vec::with_capacity(outputs.len());
+    for Variant { bytes, format, width, height, placeholder } in outputs {
+        let mut name = job.output.file_name().unwrap_or_default().to_os_string();
+        if has_variants {
+            name.push(format!("-{width}x{height}"));
+        }
+        name.push(format!(".{}", format.extension()));
+        let path = job.output.with_file_name(name);
+        std::fs::write(&path, bytes).map_err(Failure::Io)?;
+        written.push((path, placeholder));
+    }
+    Ok(written)
+}
+
+/// The message of an error with its code, as in the API's problem documents.
+fn describe(err: &Error) -> String {
+    format!("{} ({})", err.message(), err.code())
+}
+
+/// Reads an environment variable, treating an empty value as unset.
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
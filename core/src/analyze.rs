@@ -0,0 +1,136 @@
+//! Statistics of an image's pixels, such as whether text drawn over it
+//! should be light or dark.
+
+use image::DynamicImage;
+
+/// Median luma below which an image counts as dark.
+const DARK_BELOW: u8 = 128;
+
+/// Counts of each of the 256 levels of a channel.
+pub type Histogram = [u64; 256];
+
+/// The histograms and luminance of an image, as read by `analyze`.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub red: Histogram,
+    pub green: Histogram,
+    pub blue: Histogram,
+    /// Over every pixel, transparent ones included. `None` for images
+    /// without an alpha channel.
+    pub alpha: Option<Histogram>,
+    /// Luma with the Rec. 709 weights, as `grayscale` computes it.
+    pub luma: Histogram,
+    /// Pixels counted in the color and luma histograms.
+    pub pixels: u64,
+    pub mean_luminance: f64,
+    pub median_luminance: u8,
+    pub tone: Tone,
+}
+
+/// Whether an image is mostly dark or mostly light, so that text over it
+/// is best drawn light or dark respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tone {
+    Dark,
+    Light,
+}
+
+impl Tone {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tone::Dark => "dark",
+            Tone::Light => "light",
+        }
+    }
+}
+
+/// Counts the levels of each channel in 8 bits. Fully transparent pixels
+/// show nothing, so they are left out of all but the alpha histogram; an
+/// image without any visible pixel is taken to be light, like the page
+/// behind it usually is.
+pub fn analyze(img: &DynamicImage) -> Analysis {
+    let has_alpha = img.color().has_alpha();
+    let mut analysis = Analysis {
+        red: [0; 256],
+        green: [0; 256],
+        blue: [0; 256],
+        alpha: has_alpha.then_some([0; 256]),
+        luma: [0; 256],
+        pixels: 0,
+        mean_luminance: 0.0,
+        median_luminance: 255,
+        tone: Tone::Light,
+    };
+
+    let mut luma_sum = 0u64;
+    for pixel in img.to_rgba8().pixels() {
+        let [red, green, blue, alpha] = pixel.0;
+        if let Some(histogram) = &mut analysis.alpha {
+            histogram[usize::from(alpha)] += 1;
+        }
+        if alpha == 0 {
+            continue;
+        }
+        analysis.red[usize::from(red)] += 1;
+        analysis.green[usize::from(green)] += 1;
+        analysis.blue[usize::from(blue)] += 1;
+        let luma = luma(red, green, blue);
+        analysis.luma[usize::from(luma)] += 1;
+        luma_sum += u64::from(luma);
+        analysis.pixels += 1;
+    }
+
+    if analysis.pixels > 0 {
+        analysis.mean_luminance = luma_sum as f64 / analysis.pixels as f64;
+        analysis.median_luminance = median(&analysis.luma, analysis.pixels);
+        analysis.tone = if analysis.median_luminance < DARK_BELOW { Tone::Dark } else { Tone::Light };
+    }
+    analysis
+}
+
+/// The same weights as `image`'s conversion to grayscale.
+fn luma(red: u8, green: u8, blue: u8) -> u8 {
+    ((2126 * u32::from(red) + 7152 * u32::from(green) + 722 * u32::from(blue)) / 10000) as u8
+}
+
+/// The lowest level that at least half of the counted pixels are at or below.
+fn median(histogram: &Histogram, pixels: u64) -> u8 {
+    let mut seen = 0;
+    for (level, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen * 2 >= pixels {
+            return level as u8;
+        }
+    }
+    255
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    #[test]
+    fn classifies_by_median_luma() {
+        // Three quarters dark blue, one quarter white.
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(4, 4, |x, _| if x == 0 { Rgb([255; 3]) } else { Rgb([10, 20, 90]) }));
+        let analysis = analyze(&img);
+
+        assert_eq!((analysis.pixels, analysis.alpha), (16, None));
+        assert_eq!((analysis.blue[90], analysis.blue[255]), (12, 4));
+        assert_eq!(analysis.median_luminance, 22);
+        assert_eq!(analysis.tone, Tone::Dark);
+        assert!((analysis.mean_luminance - (3.0 * 22.0 + 255.0) / 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn leaves_out_transparent_pixels() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 2, |x, _| if x == 0 { Rgba([0, 0, 0, 0]) } else { Rgba([250, 250, 250, 255]) }));
+        let analysis = analyze(&img);
+
+        assert_eq!(analysis.pixels, 2);
+        assert_eq!(analysis.alpha.unwrap()[0], 2);
+        assert_eq!(analysis.luma[0], 0);
+        assert_eq!(analysis.tone, Tone::Light);
+    }
+}
@@ -0,0 +1,128 @@
+use flate2::{write::ZlibEncoder, Compression};
+use image::{DynamicImage, RgbImage};
+use lcms2::{ColorSpaceSignature, InfoType, Intent, Locale, PixelFormat, Pod, Profile, Transform};
+use std::io::Write;
+
+use crate::container;
+use crate::encode::OutputFormat;
+use crate::error::Error;
+
+/// Converts an image from its embedded ICC profile to sRGB, keeping 16-bit
+/// images at full precision.
+///
+/// Profiles that cannot be parsed or that do not describe an RGB color space
+/// are ignored and the image is returned unchanged.
+pub fn convert_to_srgb(img: DynamicImage, icc_profile: &[u8]) -> DynamicImage {
+    let input = match Profile::new_icc(icc_profile) {
+        Ok(profile) if profile.color_space() == ColorSpaceSignature::RgbData => profile,
+        Ok(_) => {
+            tracing::debug!("Ignoring ICC profile for a non-RGB color space");
+            return img;
+        }
+        Err(e) => {
+            tracing::debug!("Ignoring unparseable ICC profile: {}", e);
+            return img;
+        }
+    };
+
+    match img {
+        DynamicImage::ImageRgb16(mut pixels) => {
+            transform_to_srgb::<u16, 3>(&input, PixelFormat::RGB_16, &mut pixels);
+            DynamicImage::ImageRgb16(pixels)
+        }
+        DynamicImage::ImageRgba16(mut pixels) => {
+            transform_to_srgb::<u16, 4>(&input, PixelFormat::RGBA_16, &mut pixels);
+            DynamicImage::ImageRgba16(pixels)
+        }
+        img if img.color().has_alpha() => {
+            let mut pixels = img.to_rgba8();
+            transform_to_srgb::<u8, 4>(&input, PixelFormat::RGBA_8, &mut pixels);
+            DynamicImage::ImageRgba8(pixels)
+        }
+        img => {
+            let mut pixels = img.to_rgb8();
+            transform_to_srgb::<u8, 3>(&input, PixelFormat::RGB_8, &mut pixels);
+            DynamicImage::ImageRgb8(pixels)
+        }
+    }
+}
+
+/// Converts CMYK samples (0 = no ink) to 8-bit sRGB, through the image's
+/// CMYK profile when one is embedded and a naive conversion otherwise.
+pub fn cmyk_to_srgb(cmyk: &[u8], width: u32, height: u32, icc_profile: Option<&[u8]>) -> Option<RgbImage> {
+    let transform = icc_profile
+        .and_then(|profile| Profile::new_icc(profile).ok())
+        .filter(|profile| profile.color_space() == ColorSpaceSignature::CmykData)
+        .and_then(|profile| {
+            Transform::<[u8; 4], [u8; 3]>::new(&profile, PixelFormat::CMYK_8, &Profile::new_srgb(), PixelFormat::RGB_8, Intent::Perceptual).ok()
+        });
+
+    let (cmyk, _) = cmyk.as_chunks::<4>();
+    let rgb: Vec<u8> = match transform {
+        Some(transform) => {
+            let mut rgb = vec![[0u8; 3]; cmyk.len()];
+            transform.transform_pixels(cmyk, &mut rgb);
+            rgb.into_flattened()
+        }
+        None => cmyk
+            .iter()
+            .flat_map(|&[c, m, y, k]| {
+                let white = 255 - u16::from(k);
+                [c, m, y].map(|ink| ((255 - u16::from(ink)) * white / 255) as u8)
+            })
+            .collect(),
+    };
+
+    RgbImage::from_raw(width, height, rgb)
+}
+
+/// The description of an ICC profile, such as `Display P3`.
+pub fn profile_description(icc_profile: &[u8]) -> Option<String> {
+    let description = Profile::new_icc(icc_profile).ok()?.info(InfoType::Description, Locale::new("en_US"))?;
+    let description = description.trim();
+    (!description.is_empty()).then(|| description.to_string())
+}
+
+fn transform_to_srgb<T: Pod, const N: usize>(input: &Profile, format: PixelFormat, samples: &mut [T]) {
+    match Transform::<[T; N], [T; N]>::new(input, format, &Profile::new_srgb(), format, Intent::Perceptual) {
+        Ok(transform) => transform.transform_in_place(samples.as_chunks_mut::<N>().0),
+        Err(e) => tracing::debug!("Ignoring ICC profile without a usable transform: {}", e),
+    }
+}
+
+/// Writes an ICC profile into already encoded output. Formats without
+/// profile support are returned unchanged.
+pub fn embed_icc_profile(encoded: Vec<u8>, format: OutputFormat, icc_profile: &[u8]) -> Result<Vec<u8>, Error> {
+    match format {
+        OutputFormat::Jpeg => Ok(embed_jpeg(&encoded, icc_profile)),
+        OutputFormat::Png => embed_png(&encoded, icc_profile),
+        OutputFormat::WebP => container::set_webp_chunks(&encoded, &[(c"ICCP", icc_profile)]),
+        OutputFormat::Avif | OutputFormat::Jxl | OutputFormat::Ico => Ok(encoded),
+    }
+}
+
+/// Splits the profile across `ICC_PROFILE` APP2 segments.
+fn embed_jpeg(encoded: &[u8], icc_profile: &[u8]) -> Vec<u8> {
+    const SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+    const MAX_CHUNK: usize = 65535 - 2 - SIGNATURE.len() - 2;
+
+    let chunks: Vec<_> = icc_profile.chunks(MAX_CHUNK).collect();
+    let payloads: Vec<Vec<u8>> = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| [SIGNATURE, &[index as u8 + 1, chunks.len() as u8], chunk].concat())
+        .collect();
+    let segments: Vec<_> = payloads.iter().map(|payload| (0xE2, payload.as_slice())).collect();
+    container::insert_jpeg_segments(encoded, &segments)
+}
+
+/// Writes a compressed `iCCP` chunk, dropping any `sRGB` chunk since the two
+/// must not appear together.
+fn embed_png(encoded: &[u8], icc_profile: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut compressed = ZlibEncoder::new(Vec::new(), Compression::default());
+    compressed.write_all(icc_profile)?;
+    let mut data = b"ICC Profile\0\0".to_vec();
+    data.extend(compressed.finish()?);
+
+    Ok(container::insert_png_chunks(encoded, &[(b"iCCP", &data)], &[b"iCCP", b"sRGB"]))
+}
@@ -0,0 +1,197 @@
+//! Objective metrics of how much an image differs from a reference, such as
+//! an encode from its original.
+
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage, RgbaImage};
+
+use crate::error::Error;
+
+/// Side of the Gaussian window that SSIM compares, as in Wang et al.
+const WINDOW_SIZE: usize = 11;
+
+/// Standard deviation of the SSIM window, in pixels.
+const WINDOW_SIGMA: f64 = 1.5;
+
+/// Images are averaged down to about this shorter side before SSIM is
+/// computed, as its reference implementation does to match viewing
+/// distance; this also bounds its memory.
+const SSIM_SIZE: u32 = 256;
+
+/// Difference in levels at which the heatmap is fully saturated; encoders
+/// rarely stray further, and smaller differences would not show otherwise.
+const HEATMAP_RANGE: f64 = 64.0;
+
+/// How closely an image matches its reference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    /// Structural similarity of the luma, from -1 to 1 where 1 is identical.
+    pub ssim: f64,
+    /// Peak signal-to-noise ratio over the RGB channels, in dB. `None` for
+    /// identical images, whose ratio is infinite.
+    pub psnr: Option<f64>,
+}
+
+/// Compares an image to its reference, with both flattened onto white.
+pub fn quality(reference: &DynamicImage, image: &DynamicImage) -> Result<Quality, Error> {
+    let (reference, image) = flatten_pair(reference, image)?;
+    Ok(Quality { ssim: ssim(&reference, &image), psnr: psnr(&reference, &image) })
+}
+
+/// Draws where an image differs from its reference: a dimmed grayscale of
+/// the reference, turning red and then yellow as the difference grows.
+pub fn diff_heatmap(reference: &DynamicImage, image: &DynamicImage) -> Result<DynamicImage, Error> {
+    let (reference, image) = flatten_pair(reference, image)?;
+    let heatmap = RgbImage::from_fn(reference.width(), reference.height(), |x, y| {
+        let (a, b) = (reference.get_pixel(x, y), image.get_pixel(x, y));
+        let difference = (0..3).map(|c| a[c].abs_diff(b[c])).max().unwrap_or(0);
+        let t = (f64::from(difference) / HEATMAP_RANGE).min(1.0);
+        let gray = luma(a) / 4.0;
+        let mix = |heat: f64| (gray * (1.0 - t) + heat * t).round() as u8;
+        Rgb([mix(255.0), mix(255.0 * t), mix(0.0)])
+    });
+    Ok(DynamicImage::ImageRgb8(heatmap))
+}
+
+fn flatten_pair(reference: &DynamicImage, image: &DynamicImage) -> Result<(RgbImage, RgbImage), Error> {
+    if reference.dimensions() != image.dimensions() {
+        return Err(Error::invalid(format!(
+            "Images differ in size: the reference is {}x{}, the image {}x{}",
+            reference.width(), reference.height(), image.width(), image.height(),
+        )).with_code("dimensions_mismatch"));
+    }
+    Ok((flatten(&reference.to_rgba8()), flatten(&image.to_rgba8())))
+}
+
+/// Composites onto white, so that the colors of transparent pixels, which
+/// encoders are free to change, do not count.
+fn flatten(img: &RgbaImage) -> RgbImage {
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let pixel = img.get_pixel(x, y);
+        let alpha = u16::from(pixel[3]);
+        let channel = |c: usize| ((u16::from(pixel[c]) * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+        Rgb([channel(0), channel(1), channel(2)])
+    })
+}
+
+fn luma(pixel: &Rgb<u8>) -> f64 {
+    0.299 * f64::from(pixel[0]) + 0.587 * f64::from(pixel[1]) + 0.114 * f64::from(pixel[2])
+}
+
+fn psnr(reference: &RgbImage, image: &RgbImage) -> Option<f64> {
+    let squared_error: f64 = reference.as_raw().iter().zip(image.as_raw())
+        .map(|(&a, &b)| (f64::from(a) - f64::from(b)).powi(2))
+        .sum();
+    let mse = squared_error / reference.as_raw().len() as f64;
+    (mse > 0.0).then(|| 10.0 * (255.0 * 255.0 / mse).log10())
+}
+
+/// A plane of luma values, averaged down by a whole factor.
+struct Plane {
+    width: usize,
+    height: usize,
+    values: Vec<f64>,
+}
+
+impl Plane {
+    fn luma(img: &RgbImage, factor: u32) -> Self {
+        let (width, height) = ((img.width() / factor) as usize, (img.height() / factor) as usize);
+        let mut values = vec![0.0; width * height];
+        for (y, row) in values.chunks_mut(width).enumerate() {
+            for (x, value) in row.iter_mut().enumerate() {
+                let (left, top) = (x as u32 * factor, y as u32 * factor);
+                let sum: f64 = (top..top + factor)
+                    .flat_map(|y| (left..left + factor).map(move |x| (x, y)))
+                    .map(|(x, y)| luma(img.get_pixel(x, y)))
+                    .sum();
+                *value = sum / f64::from(factor * factor);
+            }
+        }
+        Self { width, height, values }
+    }
+
+    fn map(&self, other: &Plane, f: impl Fn(f64, f64) -> f64) -> Plane {
+        let values = self.values.iter().zip(&other.values).map(|(&a, &b)| f(a, b)).collect();
+        Plane { width: self.width, height: self.height, values }
+    }
+
+    /// Weighted means over every position of the window that fits within
+    /// the plane.
+    fn filter(&self, kernel: &[f64]) -> Plane {
+        let size = kernel.len();
+        let width = self.width - size + 1;
+        let rows: Vec<f64> = (0..self.height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| kernel.iter().enumerate().map(|(i, k)| k * self.values[y * self.width + x + i]).sum())
+            .collect();
+        let height = self.height - size + 1;
+        let values = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| kernel.iter().enumerate().map(|(i, k)| k * rows[(y + i) * width + x]).sum())
+            .collect();
+        Plane { width, height, values }
+    }
+}
+
+/// Mean SSIM over Gaussian windows, narrowed for images smaller than one.
+fn ssim(reference: &RgbImage, image: &RgbImage) -> f64 {
+    let factor = (reference.width().min(reference.height()) / SSIM_SIZE).max(1);
+    let (a, b) = (Plane::luma(reference, factor), Plane::luma(image, factor));
+    let size = WINDOW_SIZE.min(a.width).min(a.height);
+    let kernel = gaussian(size);
+
+    let (c1, c2) = ((0.01 * 255.0f64).powi(2), (0.03 * 255.0f64).powi(2));
+    let (mean_a, mean_b) = (a.filter(&kernel), b.filter(&kernel));
+    let square_a = a.map(&a, |a, _| a * a).filter(&kernel);
+    let square_b = b.map(&b, |b, _| b * b).filter(&kernel);
+    let product = a.map(&b, |a, b| a * b).filter(&kernel);
+
+    let count = mean_a.values.len();
+    let total: f64 = (0..count)
+        .map(|i| {
+            let (mu_a, mu_b) = (mean_a.values[i], mean_b.values[i]);
+            let variance_a = square_a.values[i] - mu_a * mu_a;
+            let variance_b = square_b.values[i] - mu_b * mu_b;
+            let covariance = product.values[i] - mu_a * mu_b;
+            (2.0 * mu_a * mu_b + c1) * (2.0 * covariance + c2)
+                / ((mu_a * mu_a + mu_b * mu_b + c1) * (variance_a + variance_b + c2))
+        })
+        .sum();
+    total / count as f64
+}
+
+/// A normalized one-dimensional Gaussian kernel, applied to rows and then
+/// columns.
+fn gaussian(size: usize) -> Vec<f64> {
+    let center = (size as f64 - 1.0) / 2.0;
+    let weights: Vec<f64> = (0..size).map(|i| (-(i as f64 - center).powi(2) / (2.0 * WINDOW_SIGMA * WINDOW_SIGMA)).exp()).collect();
+    let sum: f64 = weights.iter().sum();
+    weights.into_iter().map(|weight| weight / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(noise: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            let offset = if (x + y) % 2 == 0 { noise } else { 0 };
+            Rgb([((x * 4) as u8).saturating_add(offset), (y * 5) as u8, 128])
+        }))
+    }
+
+    #[test]
+    fn scores_identical_images_as_perfect() {
+        let quality = quality(&gradient(0), &gradient(0)).unwrap();
+        assert!((quality.ssim - 1.0).abs() < 1e-9);
+        assert_eq!(quality.psnr, None);
+    }
+
+    #[test]
+    fn scores_differences_lower() {
+        let (slight, strong) = (quality(&gradient(0), &gradient(4)).unwrap(), quality(&gradient(0), &gradient(40)).unwrap());
+        assert!(slight.ssim > strong.ssim && strong.ssim < 1.0);
+        assert!(slight.psnr.unwrap() > strong.psnr.unwrap());
+
+        let error = quality(&gradient(0), &DynamicImage::new_rgb8(32, 48)).unwrap_err();
+        assert_eq!(error.code(), "dimensions_mismatch");
+    }
+}
@@ -0,0 +1,132 @@
+use flate2::Crc;
+use std::ffi::CStr;
+
+use crate::error::Error;
+
+/// Iterates over the marker segments of a JPEG header as `(marker, payload)`
+/// pairs, stopping at the first scan.
+pub fn jpeg_segments(encoded: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut offset = 2;
+    std::iter::from_fn(move || {
+        let header = encoded.get(offset..offset + 4)?;
+        let (marker, length) = (header[1], u16::from_be_bytes([header[2], header[3]]) as usize);
+        if header[0] != 0xFF || marker == 0xDA || length < 2 {
+            return None;
+        }
+        let payload = encoded.get(offset + 4..offset + 2 + length)?;
+        offset += 2 + length;
+        Some((marker, payload))
+    })
+}
+
+/// Iterates over the chunks of a PNG file as `(type, data)` pairs.
+pub fn png_chunks(encoded: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = encoded.get(8..).unwrap_or_default();
+    std::iter::from_fn(move || {
+        let length = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let kind = rest.get(4..8)?;
+        let data = rest.get(8..8 + length)?;
+        rest = rest.get(12 + length..).unwrap_or_default();
+        Some((kind, data))
+    })
+}
+
+/// Iterates over the top-level chunks of a WebP (RIFF) file as
+/// `(fourcc, data)` pairs.
+pub fn webp_chunks(encoded: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = encoded.get(12..).unwrap_or_default();
+    std::iter::from_fn(move || {
+        let fourcc = rest.get(..4)?;
+        let length = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?) as usize;
+        let data = rest.get(8..8 + length)?;
+        rest = rest.get(8 + length + length % 2..).unwrap_or_default();
+        Some((fourcc, data))
+    })
+}
+
+/// Inserts marker segments after SOI and any leading APP0 (JFIF) segment,
+/// which decoders expect to come first. Each segment is a marker byte and
+/// its payload; payloads must be shorter than 64 KiB.
+pub fn insert_jpeg_segments(encoded: &[u8], segments: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut insert_at = 2;
+    if encoded.get(2..4) == Some(&[0xFF, 0xE0])
+        && let Some(len) = encoded.get(4..6).map(|l| u16::from_be_bytes([l[0], l[1]]) as usize)
+    {
+        insert_at += 2 + len;
+    }
+
+    let extra: usize = segments.iter().map(|(_, payload)| payload.len() + 4).sum();
+    let mut output = Vec::with_capacity(encoded.len() + extra);
+    output.extend_from_slice(&encoded[..insert_at]);
+    for (marker, payload) in segments {
+        output.extend_from_slice(&[0xFF, *marker]);
+        output.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        output.extend_from_slice(payload);
+    }
+    output.extend_from_slice(&encoded[insert_at..]);
+    output
+}
+
+/// Inserts chunks directly after `IHDR`, dropping existing chunks of the
+/// types listed in `replace`.
+pub fn insert_png_chunks(encoded: &[u8], chunks: &[(&[u8; 4], &[u8])], replace: &[&[u8; 4]]) -> Vec<u8> {
+    const SIGNATURE_LEN: usize = 8;
+    const IHDR_LEN: usize = 4 + 4 + 13 + 4;
+
+    let extra: usize = chunks.iter().map(|(_, data)| data.len() + 12).sum();
+    let mut output = Vec::with_capacity(encoded.len() + extra);
+    output.extend_from_slice(&encoded[..SIGNATURE_LEN + IHDR_LEN]);
+    for (kind, data) in chunks {
+        let mut crc = Crc::new();
+        crc.update(*kind);
+        crc.update(data);
+        output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        output.extend_from_slice(*kind);
+        output.extend_from_slice(data);
+        output.extend_from_slice(&crc.sum().to_be_bytes());
+    }
+
+    let mut rest = &encoded[SIGNATURE_LEN + IHDR_LEN..];
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let (chunk, tail) = rest.split_at((12 + length).min(rest.len()));
+        if !replace.iter().any(|kind| &chunk[4..8] == *kind) {
+            output.extend_from_slice(chunk);
+        }
+        rest = tail;
+    }
+    output
+}
+
+/// Sets chunks through libwebp's mux API, which also takes care of upgrading
+/// simple files to the extended (`VP8X`) container.
+pub fn set_webp_chunks(encoded: &[u8], chunks: &[(&CStr, &[u8])]) -> Result<Vec<u8>, Error> {
+    let error = || Error::internal("Failed to write metadata to WebP output");
+
+    // SAFETY: the mux copies its input and chunk data, is deleted before
+    // returning, and the assembled buffer is copied out before being freed.
+    unsafe {
+        use libwebp_sys::*;
+
+        let input = WebPData { bytes: encoded.as_ptr(), size: encoded.len() };
+        let mux = WebPMuxCreateInternal(&input, 1, WEBP_MUX_ABI_VERSION as i32);
+        if mux.is_null() {
+            return Err(error());
+        }
+
+        let mut ok = chunks.iter().all(|(fourcc, data)| {
+            let chunk = WebPData { bytes: data.as_ptr(), size: data.len() };
+            WebPMuxSetChunk(mux, fourcc.as_ptr(), &chunk, 1) == WebPMuxError::WEBP_MUX_OK
+        });
+        let mut assembled = WebPData::default();
+        ok = ok && WebPMuxAssemble(mux, &mut assembled) == WebPMuxError::WEBP_MUX_OK;
+        WebPMuxDelete(mux);
+
+        if !ok {
+            return Err(error());
+        }
+        let output = std::slice::from_raw_parts(assembled.bytes, assembled.size).to_vec();
+        WebPDataClear(&mut assembled);
+        Ok(output)
+    }
+}
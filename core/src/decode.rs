@@ -0,0 +1,593 @@
+use image::codecs::{gif::GifDecoder, jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageError, ImageFormat};
+use resvg::{tiny_skia, usvg};
+use std::io::Cursor;
+use std::sync::{Arc, LazyLock};
+
+use crate::{color, container, settings};
+use crate::error::Error;
+
+const SUPPORTED_FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+    ImageFormat::Tiff,
+    ImageFormat::Bmp,
+];
+
+/// ISOBMFF major brands used by HEIC/HEIF still images and sequences.
+const HEIF_BRANDS: [&[u8; 4]; 8] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"hevm", b"hevs"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// A format decoded by the `image` crate.
+    Raster(ImageFormat),
+    /// HEIC/HEIF, decoded through libheif when the `heif` feature is enabled.
+    Heif,
+    /// SVG, rasterized with resvg at the requested output size.
+    Svg,
+    /// PDF, with a single page rasterized through pdfium when the `pdf` feature is enabled.
+    Pdf,
+}
+
+impl InputFormat {
+    /// Short name of the format, such as `jpeg` or `svg`.
+    pub fn name(self) -> &'static str {
+        match self {
+            InputFormat::Raster(ImageFormat::Jpeg) => "jpeg",
+            InputFormat::Raster(format) => format.extensions_str()[0],
+            InputFormat::Heif => "heif",
+            InputFormat::Svg => "svg",
+            InputFormat::Pdf => "pdf",
+        }
+    }
+
+    pub fn media_type(self) -> &'static str {
+        match self {
+            InputFormat::Raster(format) => format.to_mime_type(),
+            InputFormat::Heif => "image/heic",
+            InputFormat::Svg => "image/svg+xml",
+            InputFormat::Pdf => "application/pdf",
+        }
+    }
+}
+
+/// Hints for formats that are rendered rather than decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeOptions {
+    /// Target size that vector and document inputs are rendered to fit within.
+    pub size: Option<(u32, u32)>,
+    /// 1-based page number for multi-page documents.
+    pub page: u32,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self { size: None, page: 1 }
+    }
+}
+
+/// Largest input accepted, checked against the image header before anything
+/// is decoded, so that small files that decode to huge images are refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputLimit {
+    pub width: u32,
+    pub height: u32,
+    /// Width times height.
+    pub pixels: u64,
+}
+
+impl InputLimit {
+    /// Rejects images beyond the limit with `Error::TooLarge`.
+    pub fn check(self, (width, height): (u32, u32)) -> Result<(), Error> {
+        let too_large = |message: String| Err(Error::too_large(message).with_code("input_too_large"));
+        if width > self.width {
+            return too_large(format!("Image width of {} pixels exceeds the server limit of {}", width, self.width));
+        }
+        if height > self.height {
+            return too_large(format!("Image height of {} pixels exceeds the server limit of {}", height, self.height));
+        }
+        if u64::from(width) * u64::from(height) > self.pixels {
+            return too_large(format!("Image of {}x{} pixels exceeds the server limit of {} pixels", width, height, self.pixels));
+        }
+        Ok(())
+    }
+}
+
+/// Checks an input that is about to be decoded, and charges it.
+fn check_dimensions(dimensions: (u32, u32)) -> Result<(), Error> {
+    settings::get().input_limit.check(dimensions)?;
+    charge(dimensions);
+    Ok(())
+}
+
+/// Reports a decoded input to `Settings::on_decode`. Each input must be
+/// charged once, by whichever path ends up decoding it.
+fn charge(dimensions: (u32, u32)) {
+    (settings::get().on_decode)(dimensions);
+    #[cfg(test)]
+    tests::CHARGED.with(|charged| charged.set(charged.get() + u64::from(dimensions.0) * u64::from(dimensions.1)));
+}
+
+/// System fonts used for SVG `<text>` elements, loaded once on first use.
+static SVG_FONTS: LazyLock<Arc<usvg::fontdb::Database>> = LazyLock::new(|| {
+    let mut database = usvg::fontdb::Database::new();
+    database.load_system_fonts();
+    Arc::new(database)
+});
+
+/// A single decoded frame of an animated image.
+pub struct Frame {
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+/// Media types of the inputs that this build can decode.
+pub fn input_formats() -> Vec<&'static str> {
+    let mut formats: Vec<_> = SUPPORTED_FORMATS.iter().map(ImageFormat::to_mime_type).collect();
+    if cfg!(feature = "heif") {
+        formats.push("image/heic");
+    }
+    formats.push("image/svg+xml");
+    if cfg!(feature = "pdf") {
+        formats.push("application/pdf");
+    }
+    formats
+}
+
+pub fn detect_format(image_bytes: &[u8]) -> Result<InputFormat, Error> {
+    if is_heif(image_bytes) {
+        return Ok(InputFormat::Heif);
+    }
+    if is_svg(image_bytes) {
+        return Ok(InputFormat::Svg);
+    }
+    if image_bytes.starts_with(b"%PDF-") {
+        return Ok(InputFormat::Pdf);
+    }
+
+    let image_format = image::guess_format(image_bytes)
+        .map_err(|_| Error::invalid("Could not determine image format").with_code("unsupported_format"))?;
+
+    if !SUPPORTED_FORMATS.contains(&image_format) {
+        return Err(Error::invalid("Input image must be PNG, JPG, WebP, GIF, TIFF, BMP, HEIC, SVG, or PDF").with_code("unsupported_format"));
+    }
+
+    Ok(InputFormat::Raster(image_format))
+}
+
+fn is_heif(image_bytes: &[u8]) -> bool {
+    image_bytes.len() >= 12
+        && &image_bytes[4..8] == b"ftyp"
+        && HEIF_BRANDS.iter().any(|brand| &image_bytes[8..12] == *brand)
+}
+
+fn is_svg(image_bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&image_bytes[..image_bytes.len().min(4096)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    ["<?xml", "<svg", "<!--", "<!DOCTYPE"].iter().any(|prefix| head.starts_with(prefix)) && head.contains("<svg")
+}
+
+/// Decodes a still image. Animated inputs are decoded to their first frame.
+/// Vector and document inputs are rendered to fit within the requested size.
+#[tracing::instrument(name = "decode", skip_all, fields(format = ?input_format))]
+pub fn decode_image(image_bytes: &[u8], input_format: InputFormat, options: &DecodeOptions) -> Result<DynamicImage, Error> {
+    match input_format {
+        InputFormat::Raster(ImageFormat::Jpeg) => decode_jpeg(image_bytes),
+        InputFormat::Raster(_) => load_raster(image_bytes, decode_error),
+        InputFormat::Heif => decode_heif(image_bytes),
+        InputFormat::Svg => rasterize_svg(image_bytes, options.size),
+        InputFormat::Pdf => rasterize_pdf(image_bytes, options.size, options.page),
+    }
+}
+
+/// Decodes an image with the `image` crate, within `Settings::input_limit`.
+/// Other decoding errors are turned into errors by `error`.
+pub fn load_raster(image_bytes: &[u8], error: impl Fn(ImageError) -> Error) -> Result<DynamicImage, Error> {
+    let reader = || image::io::Reader::new(Cursor::new(image_bytes)).with_guessed_format().map_err(|e| error(e.into()));
+    check_dimensions(reader()?.into_dimensions().map_err(&error)?)?;
+
+    // Also enforced by the decoder, for formats whose header can understate
+    // what they decode to.
+    let limit = settings::get().input_limit;
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(limit.width);
+    limits.max_image_height = Some(limit.height);
+    limits.max_alloc = limits.max_alloc.max(Some(limit.pixels.saturating_mul(8)));
+    let mut reader = reader()?;
+    reader.limits(limits);
+    reader.decode().map_err(|e| match e {
+        ImageError::Limits(e) => Error::too_large(format!("Image exceeds the server limits: {}", e)).with_code("input_too_large"),
+        e => error(e),
+    })
+}
+
+/// Decodes all frames of an animated GIF or WebP. Returns `None` for still
+/// images (including single-frame animations) so callers can take the regular
+/// path, which charges them instead.
+#[tracing::instrument(name = "decode_animation", skip_all, fields(format = ?input_format))]
+pub fn decode_animation(image_bytes: &[u8], input_format: InputFormat) -> Result<Option<Vec<Frame>>, Error> {
+    let limit = settings::get().input_limit;
+    let (dimensions, frames) = match input_format {
+        InputFormat::Raster(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(Cursor::new(image_bytes)).map_err(decode_error)?;
+            let dimensions = decoder.dimensions();
+            limit.check(dimensions)?;
+            (dimensions, collect_frames(decoder.into_frames(), limit)?)
+        }
+        InputFormat::Raster(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(Cursor::new(image_bytes)).map_err(decode_error)?;
+            let dimensions = decoder.dimensions();
+            limit.check(dimensions)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            (dimensions, collect_frames(decoder.into_frames(), limit)?)
+        }
+        _ => return Ok(None),
+    };
+
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    charge(dimensions);
+    Ok(Some(frames))
+}
+
+/// Decodes frames one at a time, refusing animations whose frames together
+/// hold more pixels than `limit` allows a single image, since a small file
+/// can repeat a full-size frame thousands of times.
+fn collect_frames(frames: image::Frames<'_>, limit: InputLimit) -> Result<Vec<Frame>, Error> {
+    let mut decoded = Vec::new();
+    let mut pixels = 0u64;
+    for frame in frames {
+        let frame = frame.map_err(decode_error)?;
+        let (width, height) = frame.buffer().dimensions();
+        pixels += u64::from(width) * u64::from(height);
+        if pixels > limit.pixels {
+            return Err(Error::too_large(format!(
+                "Animation of more than {} frames of {}x{} pixels exceeds the server limit of {} pixels",
+                decoded.len(), width, height, limit.pixels,
+            )).with_code("input_too_large"));
+        }
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        decoded.push(Frame {
+            delay_ms: numer / denom.max(1),
+            image: DynamicImage::ImageRgba8(frame.into_buffer()),
+        });
+    }
+    Ok(decoded)
+}
+
+/// Extracts the embedded ICC profile, if the input carries one. Only RGB
+/// profiles are returned; CMYK profiles are applied while decoding.
+pub fn icc_profile(image_bytes: &[u8], input_format: InputFormat) -> Option<Vec<u8>> {
+    embedded_icc_profile(image_bytes, input_format).filter(|profile| profile.get(16..20) == Some(b"RGB "))
+}
+
+/// Extracts the embedded ICC profile of any color space.
+pub fn embedded_icc_profile(image_bytes: &[u8], input_format: InputFormat) -> Option<Vec<u8>> {
+    let cursor = Cursor::new(image_bytes);
+    match input_format {
+        InputFormat::Raster(ImageFormat::Png) => PngDecoder::new(cursor).ok()?.icc_profile(),
+        InputFormat::Raster(ImageFormat::Jpeg) => JpegDecoder::new(cursor).ok()?.icc_profile(),
+        InputFormat::Raster(ImageFormat::WebP) => WebPDecoder::new(cursor).ok()?.icc_profile(),
+        InputFormat::Raster(ImageFormat::Tiff) => TiffDecoder::new(cursor).ok()?.icc_profile(),
+        InputFormat::Heif => heif_icc_profile(image_bytes),
+        _ => None,
+    }
+}
+
+/// Decodes a JPEG. CMYK files without an Adobe APP14 segment store plain
+/// (non-inverted) samples, which the generic decoder renders inverted, and
+/// CMYK profiles are only honored here.
+fn decode_jpeg(image_bytes: &[u8]) -> Result<DynamicImage, Error> {
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(image_bytes));
+    decoder.read_info().map_err(decode_error)?;
+    let info = decoder.info().ok_or_else(|| decode_error("missing JPEG header"))?;
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return load_raster(image_bytes, decode_error);
+    }
+    check_dimensions((info.width.into(), info.height.into()))?;
+
+    // jpeg-decoder assumes Adobe's inverted convention; undo that for plain
+    // files so every sample is ink coverage (0 = no ink).
+    let mut cmyk = decoder.decode().map_err(decode_error)?;
+    if !has_adobe_segment(image_bytes) {
+        cmyk.iter_mut().for_each(|sample| *sample = 255 - *sample);
+    }
+
+    let icc_profile = decoder.icc_profile();
+    color::cmyk_to_srgb(&cmyk, info.width.into(), info.height.into(), icc_profile.as_deref())
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| decode_error("CMYK buffer size mismatch"))
+}
+
+/// Whether the JPEG carries an Adobe APP14 segment.
+fn has_adobe_segment(image_bytes: &[u8]) -> bool {
+    container::jpeg_segments(image_bytes).any(|(marker, payload)| marker == 0xEE && payload.starts_with(b"Adobe"))
+}
+
+#[cfg(feature = "heif")]
+fn heif_icc_profile(image_bytes: &[u8]) -> Option<Vec<u8>> {
+    let context = libheif_rs::HeifContext::read_from_bytes(image_bytes).ok()?;
+    let handle = context.primary_image_handle().ok()?;
+    handle.color_profile_raw().map(|profile| profile.data)
+}
+
+#[cfg(not(feature = "heif"))]
+fn heif_icc_profile(_image_bytes: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(image_bytes: &[u8]) -> Result<DynamicImage, Error> {
+    use libheif_rs::{ColorSpace, HeifContext, HeifError, LibHeif, RgbChroma};
+
+    let heif_error = |e: HeifError| Error::invalid(format!("Failed to decode HEIF image: {}", e)).with_code("decode_failed");
+
+    let context = HeifContext::read_from_bytes(image_bytes).map_err(heif_error)?;
+    let handle = context.primary_image_handle().map_err(heif_error)?;
+    check_dimensions((handle.width(), handle.height()))?;
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(heif_error)?;
+
+    let plane = image.planes().interleaved
+        .ok_or_else(|| Error::internal("Failed to decode HEIF image: missing pixel data").with_code("decode_failed"))?;
+
+    // Rows may be padded, so copy only the visible pixels of each stride.
+    let row_bytes = plane.width as usize * 4;
+    let pixels: Vec<u8> = plane.data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_bytes])
+        .copied()
+        .collect();
+
+    image::RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| Error::internal("Failed to decode HEIF image: invalid pixel data").with_code("decode_failed"))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(_image_bytes: &[u8]) -> Result<DynamicImage, Error> {
+    Err(Error::invalid("HEIC/HEIF input is not supported by this build (enable the 'heif' feature)").with_code("unsupported_format"))
+}
+
+fn parse_svg(image_bytes: &[u8]) -> Result<usvg::Tree, Error> {
+    // Images other than `data:` URIs would be read from the server's own
+    // files, so they are left out.
+    let image_href_resolver = usvg::ImageHrefResolver {
+        resolve_data: usvg::ImageHrefResolver::default_data_resolver(),
+        resolve_string: Box::new(|_, _| None),
+    };
+    let options = usvg::Options { fontdb: SVG_FONTS.clone(), image_href_resolver, ..Default::default() };
+    usvg::Tree::from_data(image_bytes, &options)
+        .map_err(|e| Error::invalid(format!("Failed to parse SVG: {}", e)).with_code("decode_failed"))
+}
+
+/// The size an SVG input is rasterized at when no size is requested.
+pub fn svg_size(image_bytes: &[u8]) -> Result<(u32, u32), Error> {
+    let size = parse_svg(image_bytes)?.size();
+    Ok((size.width().round().max(1.0) as u32, size.height().round().max(1.0) as u32))
+}
+
+fn rasterize_svg(image_bytes: &[u8], size: Option<(u32, u32)>) -> Result<DynamicImage, Error> {
+    let tree = parse_svg(image_bytes)?;
+
+    // Scale the vector data directly to the target size so the result stays sharp.
+    let svg_size = tree.size();
+    let scale = match size {
+        Some((width, height)) => (width as f32 / svg_size.width()).min(height as f32 / svg_size.height()),
+        None => 1.0,
+    };
+    let width = (svg_size.width() * scale).round().max(1.0) as u32;
+    let height = (svg_size.height() * scale).round().max(1.0) as u32;
+    check_dimensions((width, height))?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| Error::invalid("Invalid SVG output dimensions").with_code("decode_failed"))?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    let pixels: Vec<u8> = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+
+    image::RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| Error::internal("Failed to rasterize SVG").with_code("decode_failed"))
+}
+
+/// Pdfium bindings are process-global, so bind once.
+/// `Settings::pdfium_library_path` may point at the directory containing
+/// the library; otherwise the system library is used.
+#[cfg(feature = "pdf")]
+static PDFIUM: LazyLock<Result<pdfium_render::prelude::Pdfium, String>> = LazyLock::new(|| {
+    use pdfium_render::prelude::Pdfium;
+
+    let bindings = match &settings::get().pdfium_library_path {
+        Some(path) => Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(path)),
+        None => Pdfium::bind_to_system_library(),
+    };
+    bindings.map(Pdfium::new).map_err(|e| e.to_string())
+});
+
+/// Resolution used when no target size is requested.
+#[cfg(feature = "pdf")]
+const DEFAULT_DPI: f32 = 150.0;
+
+#[cfg(feature = "pdf")]
+fn open_pdf(image_bytes: &[u8]) -> Result<pdfium_render::prelude::PdfDocument<'_>, Error> {
+    let pdfium = PDFIUM.as_ref()
+        .map_err(|e| Error::internal(format!("Failed to load pdfium: {}", e)))?;
+    pdfium.load_pdf_from_byte_slice(image_bytes, None)
+        .map_err(|e| Error::invalid(format!("Failed to open PDF: {}", e)).with_code("decode_failed"))
+}
+
+/// The page count of a PDF input, and the size its first page is
+/// rasterized at when no size is requested.
+#[cfg(feature = "pdf")]
+pub fn pdf_pages(image_bytes: &[u8]) -> Result<(u32, (u32, u32)), Error> {
+    let document = open_pdf(image_bytes)?;
+    let page = document.pages().first()
+        .map_err(|e| Error::invalid(format!("Failed to load PDF page: {}", e)).with_code("decode_failed"))?;
+    let size = |points: f32| (points.max(1.0) * DEFAULT_DPI / 72.0).round() as u32;
+    Ok((document.pages().len() as u32, (size(page.width().value), size(page.height().value))))
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn pdf_pages(_image_bytes: &[u8]) -> Result<(u32, (u32, u32)), Error> {
+    Err(Error::invalid("PDF input is not supported by this build (enable the 'pdf' feature)").with_code("unsupported_format"))
+}
+
+#[cfg(feature = "pdf")]
+fn rasterize_pdf(image_bytes: &[u8], size: Option<(u32, u32)>, page_number: u32) -> Result<DynamicImage, Error> {
+    use pdfium_render::prelude::PdfRenderConfig;
+
+    let document = open_pdf(image_bytes)?;
+
+    let page_count = document.pages().len();
+    let page_index = i32::try_from(page_number).unwrap_or(i32::MAX) - 1;
+    if page_index >= page_count {
+        return Err(Error::invalid(format!("Page {} does not exist, the document has {} page(s)", page_number, page_count)).with_code("invalid_page"));
+    }
+    let page = document.pages().get(page_index)
+        .map_err(|e| Error::internal(format!("Failed to load PDF page: {}", e)).with_code("decode_failed"))?;
+
+    // Pdfium fits the page to the target width, then to the maximum height.
+    let (page_width, page_height) = (page.width().value.max(1.0), page.height().value.max(1.0));
+    let scale = match size {
+        Some((width, height)) => (width as f32 / page_width).min(height as f32 / page_height),
+        None => DEFAULT_DPI / 72.0,
+    };
+    check_dimensions(((page_width * scale).round() as u32, (page_height * scale).round() as u32))?;
+
+    let config = match size {
+        Some((width, height)) => PdfRenderConfig::new()
+            .set_target_width(width as i32)
+            .set_maximum_height(height as i32),
+        None => PdfRenderConfig::new().scale_page_by_factor(DEFAULT_DPI / 72.0),
+    };
+
+    page.render_with_config(&config)
+        .and_then(|bitmap| bitmap.as_image())
+        .map_err(|e| Error::internal(format!("Failed to render PDF page: {}", e)).with_code("decode_failed"))
+}
+
+#[cfg(not(feature = "pdf"))]
+fn rasterize_pdf(_image_bytes: &[u8], _size: Option<(u32, u32)>, _page_number: u32) -> Result<DynamicImage, Error> {
+    Err(Error::invalid("PDF input is not supported by this build (enable the 'pdf' feature)").with_code("unsupported_format"))
+}
+
+fn decode_error(e: impl std::fmt::Display) -> Error {
+    Error::invalid(format!("Failed to decode image: {}", e)).with_code("decode_failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Pixels charged by the current test.
+        pub(super) static CHARGED: Cell<u64> = const { Cell::new(0) };
+    }
+
+    /// Decodes like `pipeline` does, returning the pixels charged.
+    fn charged_pixels(image_bytes: &[u8]) -> u64 {
+        CHARGED.with(|charged| charged.set(0));
+        let input_format = detect_format(image_bytes).unwrap();
+        if decode_animation(image_bytes, input_format).unwrap().is_none() {
+            decode_image(image_bytes, input_format, &DecodeOptions::default()).unwrap();
+        }
+        CHARGED.with(Cell::get)
+    }
+
+    #[test]
+    fn detects_formats_from_their_signature() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(1, 1).write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+        assert_eq!(detect_format(png.get_ref()).unwrap(), InputFormat::Raster(ImageFormat::Png));
+        assert_eq!(detect_format(b"<?xml version=\"1.0\"?><svg></svg>").unwrap(), InputFormat::Svg);
+        assert_eq!(detect_format(b"%PDF-1.7").unwrap(), InputFormat::Pdf);
+        assert_eq!(detect_format(b"\0\0\0\x18ftypheic").unwrap(), InputFormat::Heif);
+        assert_eq!(detect_format(b"plain text").unwrap_err().code(), "unsupported_format");
+    }
+
+    #[test]
+    fn limits_inputs_by_side_and_pixels() {
+        let limit = InputLimit { width: 1000, height: 800, pixels: 500_000 };
+        assert!(limit.check((1000, 500)).is_ok());
+        for dimensions in [(1001, 1), (1, 801), (1000, 501)] {
+            let error = limit.check(dimensions).unwrap_err();
+            assert!(matches!(error, Error::TooLarge(_)));
+            assert_eq!(error.code(), "input_too_large");
+        }
+    }
+
+    #[test]
+    fn limits_animations_by_the_pixels_of_all_frames() {
+        let mut gif = Vec::new();
+        let frames = (0..5).map(|i| image::Frame::new(image::RgbaImage::from_pixel(10, 10, image::Rgba([i * 50, 0, 0, 255]))));
+        image::codecs::gif::GifEncoder::new(&mut gif).encode_frames(frames).unwrap();
+        let frames = || GifDecoder::new(Cursor::new(&gif)).unwrap().into_frames();
+
+        let limit = InputLimit { width: 100, height: 100, pixels: 500 };
+        assert_eq!(collect_frames(frames(), limit).unwrap().len(), 5);
+        // Each frame is within the limit, but all five are not.
+        let error = collect_frames(frames(), InputLimit { pixels: 499, ..limit }).err().unwrap();
+        assert!(matches!(error, Error::TooLarge(_)));
+        assert_eq!(error.code(), "input_too_large");
+    }
+
+    #[test]
+    fn charges_each_input_once() {
+        let gif = |count: u8| {
+            let mut gif = Vec::new();
+            let frames = (0..count).map(|i| image::Frame::new(image::RgbaImage::from_pixel(12, 10, image::Rgba([i * 50, 0, 0, 255]))));
+            image::codecs::gif::GifEncoder::new(&mut gif).encode_frames(frames).unwrap();
+            gif
+        };
+        let webp = webp::Encoder::from_rgba(&[0; 12 * 10 * 4], 12, 10).encode_lossless().to_vec();
+
+        assert_eq!(charged_pixels(&gif(1)), 120);
+        assert_eq!(charged_pixels(&webp), 120);
+        assert_eq!(charged_pixels(&gif(3)), 120);
+    }
+
+    #[test]
+    fn rasterizes_svg_at_the_requested_size() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="5"><rect width="10" height="5" fill="red"/></svg>"#;
+        let options = DecodeOptions { size: Some((100, 100)), ..Default::default() };
+        let img = decode_image(svg, InputFormat::Svg, &options).unwrap();
+        assert_eq!((img.width(), img.height()), (100, 50));
+    }
+
+    #[test]
+    fn leaves_out_svg_images_of_local_files() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255])))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("svg-href-{}.png", std::process::id()));
+        std::fs::write(&path, png.get_ref()).unwrap();
+        let svg = |href: &str| format!(r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4"><image href="{}" width="4" height="4"/></svg>"#, href);
+        let render = |svg: String| decode_image(svg.as_bytes(), InputFormat::Svg, &DecodeOptions::default()).unwrap().to_rgba8();
+
+        let local = render(svg(&path.display().to_string()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(local.pixels().all(|pixel| pixel[3] == 0));
+        // The same image as a data: URI is still drawn.
+        let data = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, png.get_ref());
+        let inline = render(svg(&format!("data:image/png;base64,{}", data)));
+        assert_eq!(inline.get_pixel(2, 2).0, [255, 0, 0, 255]);
+    }
+}
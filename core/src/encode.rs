@@ -0,0 +1,641 @@
+#[cfg(not(feature = "mozjpeg"))]
+use image::codecs::jpeg::JpegEncoder;
+use image::{codecs::ico::{IcoEncoder, IcoFrame}, ColorType, DynamicImage, ImageOutputFormat, RgbaImage};
+use std::io::Cursor;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::color;
+use crate::decode::Frame;
+use crate::error::Error;
+use crate::metadata::{self, Metadata};
+use crate::{settings, telemetry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    WebP,
+    Jpeg,
+    Avif,
+    Png,
+    Jxl,
+    Ico,
+}
+
+impl OutputFormat {
+    /// The formats that this build can encode.
+    pub fn available() -> Vec<Self> {
+        let mut formats = vec![OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Avif, OutputFormat::Png];
+        if cfg!(feature = "jxl") {
+            formats.push(OutputFormat::Jxl);
+        }
+        formats.push(OutputFormat::Ico);
+        formats
+    }
+
+    /// Whether an ICC profile can be embedded in this format.
+    pub fn supports_icc_profile(self) -> bool {
+        matches!(self, OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::WebP)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jxl => "image/jxl",
+            OutputFormat::Ico => "image/x-icon",
+        }
+    }
+
+    /// File extension for output of this format, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "webp",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Png => "png",
+            OutputFormat::Jxl => "jxl",
+            OutputFormat::Ico => "ico",
+        }
+    }
+
+    /// Whether `Settings::enabled_formats` allows this format.
+    pub fn is_enabled(self) -> bool {
+        settings::get().enabled_formats.as_ref().is_none_or(|formats| formats.contains(&self))
+    }
+
+    /// Returns the format, or an error if the server has it disabled.
+    pub fn check_enabled(self) -> Result<Self, Error> {
+        if !self.is_enabled() {
+            return Err(Error::invalid(format!("Output format '{}' is disabled on this server", self.extension())).with_code("format_disabled"));
+        }
+        Ok(self)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "webp" => Ok(OutputFormat::WebP),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "avif" => Ok(OutputFormat::Avif),
+            "png" => Ok(OutputFormat::Png),
+            "jxl" => Ok(OutputFormat::Jxl),
+            "ico" => Ok(OutputFormat::Ico),
+            _ => Err(Error::invalid("Unsupported output format. Use 'webp', 'jpeg', 'avif', 'png', 'jxl', 'ico', 'auto' or 'auto-content'").with_code("invalid_format")),
+        }
+    }
+}
+
+/// Resolutions embedded in ICO output.
+pub const ICO_SIZES: [u32; 4] = [16, 32, 48, 64];
+
+/// Output format as requested by the client, before content negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatSelection {
+    Fixed(OutputFormat),
+    /// Pick AVIF, WebP, or JPEG based on the request's `Accept` header.
+    Auto,
+    /// Pick a format suited to the decoded image: lossless for graphics,
+    /// lossy for photographic content.
+    AutoContent,
+}
+
+impl FromStr for FormatSelection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(FormatSelection::Auto),
+            "auto-content" => Ok(FormatSelection::AutoContent),
+            _ => s.parse().map(FormatSelection::Fixed),
+        }
+    }
+}
+
+/// JPEG chroma subsampling mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().replace(':', "").as_str() {
+            "420" => Ok(ChromaSubsampling::Yuv420),
+            "422" => Ok(ChromaSubsampling::Yuv422),
+            "444" => Ok(ChromaSubsampling::Yuv444),
+            _ => Err(Error::invalid("Invalid chroma subsampling. Use '420', '422' or '444'").with_code("invalid_subsampling")),
+        }
+    }
+}
+
+/// Encoder settings shared by all output formats. Options that do not apply
+/// to the selected format are ignored.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// Lossy compression quality (0.0 to 100.0).
+    pub quality: f32,
+    /// AVIF encoder speed (1 = slowest/smallest, 10 = fastest).
+    pub speed: u8,
+    /// Palette size for indexed PNG output (2 to 256). `None` keeps full color.
+    pub colors: Option<u16>,
+    /// Use lossless WebP or JPEG XL encoding instead of lossy compression.
+    pub lossless: bool,
+    /// WebP compression method (0 = fastest, 6 = slowest/smallest).
+    pub webp_method: u8,
+    /// WebP alpha channel quality (0 to 100), independent of `quality`.
+    pub alpha_quality: u8,
+    /// WebP near-lossless preprocessing level (0 = most loss, 100 = off).
+    /// Setting it switches WebP output to the lossless encoder.
+    pub near_lossless: Option<u8>,
+    /// JPEG XL encoder effort (1 = fastest, 10 = slowest/smallest).
+    pub effort: u8,
+    /// Write progressive JPEG scans (mozjpeg only).
+    pub progressive: bool,
+    /// JPEG chroma subsampling (mozjpeg only).
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Run PNG output through oxipng with zopfli compression.
+    pub optimize: bool,
+    /// Upper bound on the encoded size. Lossy formats lower the quality
+    /// (down to `MIN_BUDGET_QUALITY`) until the output fits.
+    pub max_bytes: Option<usize>,
+    /// ICC profile to embed in JPEG, PNG and WebP output.
+    pub icc_profile: Option<Vec<u8>>,
+    /// EXIF, XMP and IPTC data to embed in JPEG, PNG and WebP output.
+    pub metadata: Metadata,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            quality: 100.0,
+            speed: 6,
+            colors: None,
+            lossless: false,
+            webp_method: 4,
+            alpha_quality: 100,
+            near_lossless: None,
+            effort: 7,
+            progressive: true,
+            chroma_subsampling: ChromaSubsampling::Yuv420,
+            optimize: false,
+            max_bytes: None,
+            icc_profile: None,
+            metadata: Metadata::default(),
+        }
+    }
+}
+
+/// Lowest quality the `max_bytes` search will go to; below this the output
+/// is rarely usable, so the request fails instead.
+pub const MIN_BUDGET_QUALITY: f32 = 10.0;
+
+/// Number of encodes spent narrowing the quality once the bounds are known.
+const BUDGET_SEARCH_STEPS: usize = 6;
+
+#[tracing::instrument(name = "encode", skip_all, fields(format = format.extension()))]
+pub fn encode_image(img: DynamicImage, format: OutputFormat, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    let started = Instant::now();
+    let encoded = encode_within_budget(img, format, options);
+    telemetry::record_encode(format, started);
+    encoded
+}
+
+fn encode_within_budget(img: DynamicImage, format: OutputFormat, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    let Some(max_bytes) = options.max_bytes else {
+        return encode_single(img, format, options);
+    };
+
+    let quality_applies = match format {
+        OutputFormat::WebP => !options.lossless && options.near_lossless.is_none(),
+        OutputFormat::Jxl => !options.lossless,
+        OutputFormat::Jpeg | OutputFormat::Avif => true,
+        OutputFormat::Png | OutputFormat::Ico => false,
+    };
+    fit_to_budget(max_bytes, options.quality, quality_applies, |quality| {
+        encode_single(img.clone(), format, &EncodeOptions { quality, ..options.clone() })
+    })
+}
+
+/// Encodes at the requested quality and, if the output exceeds `max_bytes`,
+/// binary-searches for the highest quality that fits.
+fn fit_to_budget(
+    max_bytes: usize,
+    quality: f32,
+    quality_applies: bool,
+    encode: impl Fn(f32) -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let encoded = encode(quality)?;
+    if encoded.len() <= max_bytes {
+        return Ok(encoded);
+    }
+    if !quality_applies {
+        return Err(over_budget(max_bytes, encoded.len()));
+    }
+
+    let mut low = MIN_BUDGET_QUALITY.min(quality);
+    let mut best = encode(low)?;
+    if best.len() > max_bytes {
+        return Err(over_budget(max_bytes, best.len()));
+    }
+
+    let mut high = quality;
+    for _ in 0..BUDGET_SEARCH_STEPS {
+        if high - low < 1.0 {
+            break;
+        }
+        let mid = (low + high) / 2.0;
+        let encoded = encode(mid)?;
+        if encoded.len() <= max_bytes {
+            low = mid;
+            best = encoded;
+        } else {
+            high = mid;
+        }
+    }
+
+    Ok(best)
+}
+
+fn over_budget(max_bytes: usize, smallest: usize) -> Error {
+    Error::invalid(format!("Output does not fit within max_bytes ({} bytes); the smallest encoding is {} bytes", max_bytes, smallest),
+    ).with_code("output_too_large")
+}
+
+fn encode_single(img: DynamicImage, format: OutputFormat, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    let encoded = encode_pixels(img, format, options)?;
+    embed_extras(encoded, format, options)
+}
+
+/// Adds the ICC profile and metadata from `options` to encoded output.
+fn embed_extras(mut encoded: Vec<u8>, format: OutputFormat, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    if let Some(icc_profile) = &options.icc_profile {
+        encoded = color::embed_icc_profile(encoded, format, icc_profile)?;
+    }
+    if !options.metadata.is_empty() {
+        encoded = metadata::embed_metadata(encoded, format, &options.metadata)?;
+    }
+    Ok(encoded)
+}
+
+fn encode_pixels(img: DynamicImage, format: OutputFormat, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    match format {
+        OutputFormat::WebP => encode_to_webp(img, options),
+        OutputFormat::Jpeg => encode_jpeg(img, options),
+        OutputFormat::Avif => encode_avif(img, options.quality, options.speed),
+        OutputFormat::Png => {
+            let encoded = match options.colors {
+                Some(colors) => encode_indexed_png(img, colors)?,
+                None => encode_png(img)?,
+            };
+            if options.optimize { optimize_png(&encoded) } else { Ok(encoded) }
+        }
+        OutputFormat::Jxl => encode_jxl(img, options),
+        OutputFormat::Ico => encode_ico(&img, &ICO_SIZES),
+    }
+}
+
+/// Builds the libwebp configuration shared by still and animated output.
+/// Near-lossless encoding is a preprocessing step of the lossless encoder, so
+/// requesting it implies lossless mode.
+fn webp_config(options: &EncodeOptions) -> Result<webp::WebPConfig, Error> {
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| Error::internal("Failed to initialize WebP encoder").with_code("encode_failed"))?;
+    let lossless = options.lossless || options.near_lossless.is_some();
+    config.lossless = lossless as i32;
+    config.quality = if lossless { 75.0 } else { options.quality };
+    config.method = options.webp_method as i32;
+    config.alpha_quality = options.alpha_quality as i32;
+    if let Some(near_lossless) = options.near_lossless {
+        config.near_lossless = near_lossless as i32;
+    }
+    Ok(config)
+}
+
+fn encode_to_webp(img: DynamicImage, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    let config = webp_config(options)?;
+    let (width, height) = (img.width(), img.height());
+
+    // Opaque images skip the alpha channel so libwebp does not have to strip it.
+    let (pixels, layout) = if img.color().has_alpha() {
+        (img.into_rgba8().into_raw(), webp::PixelLayout::Rgba)
+    } else {
+        (img.into_rgb8().into_raw(), webp::PixelLayout::Rgb)
+    };
+
+    let encoder = webp::Encoder::new(&pixels, layout, width, height);
+    let encoded = encoder.encode_advanced(&config)
+        .map_err(|e| Error::internal(format!("Failed to encode image to WebP format: {:?}", e)).with_code("encode_failed"))?;
+
+    Ok(encoded.to_vec())
+}
+
+/// Encodes a sequence of frames as an animated WebP, applying the WebP
+/// encoder settings to every frame.
+#[tracing::instrument(name = "encode_animation", skip_all, fields(format = "webp", frames = frames.len()))]
+pub fn encode_animated_webp(frames: &[Frame], options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    let started = Instant::now();
+    let encoded = match options.max_bytes {
+        Some(max_bytes) => fit_to_budget(max_bytes, options.quality, !options.lossless && options.near_lossless.is_none(), |quality| {
+            encode_animation(frames, &EncodeOptions { quality, ..options.clone() })
+        }),
+        None => encode_animation(frames, options),
+    };
+    telemetry::record_encode(OutputFormat::WebP, started);
+    encoded
+}
+
+fn encode_animation(frames: &[Frame], options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    let config = webp_config(options)?;
+
+    let buffers: Vec<_> = frames.iter().map(|frame| frame.image.to_rgba8()).collect();
+    let (width, height) = buffers.first().map(|b| b.dimensions()).ok_or_else(animation_error)?;
+
+    // SAFETY: the encoder is created and deleted within this block, every
+    // picture is freed after being added, and all buffers outlive the calls.
+    unsafe {
+        use libwebp_sys::*;
+
+        let mut anim_options = std::mem::MaybeUninit::<WebPAnimEncoderOptions>::uninit();
+        if WebPAnimEncoderOptionsInitInternal(anim_options.as_mut_ptr(), WEBP_MUX_ABI_VERSION as i32) == 0 {
+            return Err(animation_error());
+        }
+        let encoder = WebPAnimEncoderNewInternal(width as i32, height as i32, anim_options.as_ptr(), WEBP_MUX_ABI_VERSION as i32);
+        if encoder.is_null() {
+            return Err(animation_error());
+        }
+
+        let mut timestamp = 0;
+        let mut ok = true;
+        for (buffer, frame) in buffers.iter().zip(frames) {
+            let Ok(mut picture) = WebPPicture::new() else {
+                ok = false;
+                break;
+            };
+            picture.use_argb = 1;
+            picture.width = width as i32;
+            picture.height = height as i32;
+            ok = WebPPictureImportRGBA(&mut picture, buffer.as_ptr(), width as i32 * 4) != 0
+                && WebPAnimEncoderAdd(encoder, &mut picture, timestamp, &config) != 0;
+            WebPPictureFree(&mut picture);
+            if !ok {
+                break;
+            }
+            timestamp += frame.delay_ms as i32;
+        }
+
+        // The final timestamp marks the end of the last frame, preserving its duration.
+        let mut data = WebPData::default();
+        ok = ok
+            && WebPAnimEncoderAdd(encoder, std::ptr::null_mut(), timestamp, std::ptr::null()) != 0
+            && WebPAnimEncoderAssemble(encoder, &mut data) != 0;
+        WebPAnimEncoderDelete(encoder);
+
+        if !ok {
+            return Err(animation_error());
+        }
+        let encoded = std::slice::from_raw_parts(data.bytes, data.size).to_vec();
+        WebPDataClear(&mut data);
+
+        embed_extras(encoded, OutputFormat::WebP, options)
+    }
+}
+
+fn animation_error() -> Error {
+    Error::internal("Failed to encode animated WebP").with_code("encode_failed")
+}
+
+#[cfg(feature = "mozjpeg")]
+fn encode_jpeg(img: DynamicImage, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    // JPEG has no alpha channel, so flatten to RGB before encoding.
+    let img = img.to_rgb8();
+    let (width, height) = img.dimensions();
+    let quality = options.quality.round().clamp(1.0, 100.0);
+    let progressive = options.progressive;
+    let pixel_sizes = match options.chroma_subsampling {
+        ChromaSubsampling::Yuv420 => (2, 2),
+        ChromaSubsampling::Yuv422 => (2, 1),
+        ChromaSubsampling::Yuv444 => (1, 1),
+    };
+
+    // libjpeg reports errors by unwinding, so contain them here.
+    std::panic::catch_unwind(move || -> std::io::Result<Vec<u8>> {
+        let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        compress.set_size(width as usize, height as usize);
+        compress.set_quality(quality);
+        compress.set_chroma_sampling_pixel_sizes(pixel_sizes, pixel_sizes);
+        if progressive {
+            compress.set_progressive_mode();
+        } else {
+            compress.set_optimize_scans(false);
+        }
+
+        let mut started = compress.start_compress(Vec::new())?;
+        started.write_scanlines(img.as_raw())?;
+        started.finish()
+    })
+    .map_err(|_| Error::internal("Failed to encode image to JPEG format").with_code("encode_failed"))?
+    .map_err(|e| Error::internal(format!("Failed to encode image to JPEG format: {}", e)).with_code("encode_failed"))
+}
+
+#[cfg(not(feature = "mozjpeg"))]
+fn encode_jpeg(img: DynamicImage, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    // JPEG has no alpha channel, so flatten to RGB before encoding.
+    let img = img.to_rgb8();
+    let mut buffer = Vec::new();
+
+    JpegEncoder::new_with_quality(&mut buffer, options.quality.round().clamp(1.0, 100.0) as u8)
+        .encode_image(&img)
+        .map_err(|e| Error::internal(format!("Failed to encode image to JPEG format: {}", e)).with_code("encode_failed"))?;
+
+    Ok(buffer)
+}
+
+fn encode_avif(img: DynamicImage, quality: f32, speed: u8) -> Result<Vec<u8>, Error> {
+    let img = img.to_rgba8();
+    let (width, height) = img.dimensions();
+    let pixels: Vec<ravif::RGBA8> = img
+        .pixels()
+        .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+        .collect();
+
+    let encoded = ravif::Encoder::new()
+        .with_quality(quality.clamp(1.0, 100.0))
+        .with_speed(speed)
+        .encode_rgba(ravif::Img::new(pixels.as_slice(), width as usize, height as usize))
+        .map_err(|e| Error::internal(format!("Failed to encode image to AVIF format: {}", e)).with_code("encode_failed"))?;
+
+    Ok(encoded.avif_file)
+}
+
+fn encode_png(img: DynamicImage) -> Result<Vec<u8>, Error> {
+    let mut buffer = Vec::new();
+
+    img.write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Png)
+        .map_err(|e| Error::internal(format!("Failed to encode image to PNG format: {}", e)).with_code("encode_failed"))?;
+
+    Ok(buffer)
+}
+
+/// Quantizes the image to a palette of at most `colors` entries and writes it
+/// as an indexed PNG, using the smallest bit depth that fits the palette.
+fn encode_indexed_png(img: DynamicImage, colors: u16) -> Result<Vec<u8>, Error> {
+    let img = img.to_rgba8();
+    let (width, height) = img.dimensions();
+
+    let quantizer = color_quant::NeuQuant::new(10, colors as usize, img.as_raw());
+    let palette_rgba = quantizer.color_map_rgba();
+    let palette: Vec<u8> = palette_rgba.chunks_exact(4).flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let trns: Vec<u8> = palette_rgba.chunks_exact(4).map(|c| c[3]).collect();
+
+    let (bit_depth, bits) = match palette_rgba.len() / 4 {
+        0..=2 => (png::BitDepth::One, 1),
+        3..=4 => (png::BitDepth::Two, 2),
+        5..=16 => (png::BitDepth::Four, 4),
+        _ => (png::BitDepth::Eight, 8),
+    };
+
+    let pixels_per_byte = 8 / bits;
+    let row_bytes = (width as usize).div_ceil(pixels_per_byte);
+    let mut data = vec![0u8; row_bytes * height as usize];
+    for (y, row) in img.rows().enumerate() {
+        for (x, pixel) in row.enumerate() {
+            let index = quantizer.index_of(&pixel.0) as u8;
+            let shift = 8 - bits * (x % pixels_per_byte + 1);
+            data[y * row_bytes + x / pixels_per_byte] |= index << shift;
+        }
+    }
+
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(bit_depth);
+    encoder.set_palette(palette);
+    if trns.iter().any(|&a| a != 255) {
+        encoder.set_trns(trns);
+    }
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(|e| Error::internal(format!("Failed to encode image to PNG format: {}", e)).with_code("encode_failed"))?;
+
+    Ok(buffer)
+}
+
+/// Writes a multi-resolution ICO with one PNG-compressed entry per size.
+pub fn encode_ico(img: &DynamicImage, sizes: &[u32]) -> Result<Vec<u8>, Error> {
+    let ico_error = |e: image::ImageError| Error::internal(format!("Failed to encode image to ICO format: {}", e)).with_code("encode_failed");
+
+    let frames = sizes
+        .iter()
+        .map(|&size| {
+            let icon = square_icon(img, size);
+            IcoFrame::as_png(icon.as_raw(), size, size, ColorType::Rgba8)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ico_error)?;
+
+    let mut buffer = Vec::new();
+    IcoEncoder::new(&mut buffer).encode_images(&frames).map_err(ico_error)?;
+
+    Ok(buffer)
+}
+
+/// Scales the image to fit a `size`x`size` square and centers it on a
+/// transparent background, so non-square sources are not distorted.
+pub fn square_icon(img: &DynamicImage, size: u32) -> RgbaImage {
+    let scaled = img.resize(size, size, image::imageops::FilterType::Lanczos3).to_rgba8();
+    let mut canvas = RgbaImage::new(size, size);
+    let x = (size - scaled.width()) / 2;
+    let y = (size - scaled.height()) / 2;
+    image::imageops::overlay(&mut canvas, &scaled, x as i64, y as i64);
+    canvas
+}
+
+/// Losslessly recompresses an encoded PNG, trying alternative filters and
+/// color reductions and deflating with zopfli.
+fn optimize_png(png_bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut options = oxipng::Options::from_preset(2);
+    options.deflater = oxipng::Deflater::Zopfli(oxipng::ZopfliOptions::default());
+    options.optimize_alpha = true;
+
+    oxipng::optimize_from_memory(png_bytes, &options)
+        .map_err(|e| Error::internal(format!("Failed to optimize PNG: {}", e)).with_code("encode_failed"))
+}
+
+#[cfg(feature = "jxl")]
+fn encode_jxl(img: DynamicImage, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    use jpegxl_rs::encode::{EncoderFrame, EncoderSpeed};
+
+    let jxl_error = |e: jpegxl_rs::EncodeError| Error::internal(format!("Failed to encode image to JPEG XL format: {}", e)).with_code("encode_failed");
+
+    let (width, height) = (img.width(), img.height());
+
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .jpeg_quality(options.quality.clamp(0.0, 100.0))
+        .build()
+        .map_err(jxl_error)?;
+    encoder.has_alpha = true;
+    encoder.lossless = Some(options.lossless);
+    encoder.speed = match options.effort {
+        1 => EncoderSpeed::Lightning,
+        2 => EncoderSpeed::Thunder,
+        3 => EncoderSpeed::Falcon,
+        4 => EncoderSpeed::Cheetah,
+        5 => EncoderSpeed::Hare,
+        6 => EncoderSpeed::Wombat,
+        7 => EncoderSpeed::Squirrel,
+        8 => EncoderSpeed::Kitten,
+        9 => EncoderSpeed::Tortoise,
+        _ => EncoderSpeed::Glacier,
+    };
+
+    // JPEG XL stores high bit depth natively, so 16-bit sources keep their precision.
+    let encoded = if crate::transform::is_high_bit_depth(&img) {
+        let pixels = img.into_rgba16();
+        encoder.encode_frame(&EncoderFrame::new(pixels.as_raw()).num_channels(4), width, height)
+    } else {
+        let pixels = img.into_rgba8();
+        encoder.encode_frame(&EncoderFrame::new(pixels.as_raw()).num_channels(4), width, height)
+    };
+    encoded.map_err(jxl_error)
+}
+
+#[cfg(not(feature = "jxl"))]
+fn encode_jxl(_img: DynamicImage, _options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    Err(Error::invalid("JPEG XL output is not supported by this build (enable the 'jxl' feature)").with_code("unsupported_format"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    #[test]
+    fn encodes_every_available_format() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 24, |x, y| image::Rgb([x as u8 * 8, y as u8 * 10, 128])));
+        for format in OutputFormat::available() {
+            let bytes = encode_image(img.clone(), format, &EncodeOptions::default()).unwrap();
+            assert!(!bytes.is_empty(), "{:?}", format);
+            if matches!(format, OutputFormat::Png | OutputFormat::Jpeg | OutputFormat::WebP) {
+                assert_eq!(image::load_from_memory(&bytes).unwrap().dimensions(), (32, 24), "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_format_selections() {
+        assert_eq!("JPG".parse::<FormatSelection>().unwrap(), FormatSelection::Fixed(OutputFormat::Jpeg));
+        assert_eq!("auto-content".parse::<FormatSelection>().unwrap(), FormatSelection::AutoContent);
+        assert_eq!("gif".parse::<FormatSelection>().unwrap_err().code(), "invalid_format");
+    }
+}
@@ -0,0 +1,90 @@
+use std::borrow::Cow;
+use std::fmt;
+
+/// Why an image could not be processed. The variant tells mistakes of the
+/// caller apart from faults of the pipeline itself.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// A parameter or the image is not usable.
+    Invalid(Problem),
+    /// An input is over a limit of the [`Settings`](crate::Settings).
+    TooLarge(Problem),
+    /// A fault of the pipeline, such as a library that failed to load.
+    Internal(Problem),
+}
+
+/// What an error reports, with a stable `code` to branch on.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    code: Cow<'static, str>,
+    message: String,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>) -> Self {
+        Self { code: Cow::Borrowed(""), message: message.into() }
+    }
+}
+
+impl Error {
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::Invalid(Problem::new(message))
+    }
+
+    pub fn too_large(message: impl Into<String>) -> Self {
+        Self::TooLarge(Problem::new(message))
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::Internal(Problem::new(message))
+    }
+
+    /// Replaces the generic code of the variant with a more specific one,
+    /// such as `invalid_size`.
+    pub fn with_code(mut self, code: impl Into<Cow<'static, str>>) -> Self {
+        self.problem_mut().code = code.into();
+        self
+    }
+
+    /// The specific code, or `invalid_request`, `too_large` or
+    /// `internal_error` by the variant.
+    pub fn code(&self) -> &str {
+        match (&*self.problem().code, self) {
+            ("", Self::Invalid(_)) => "invalid_request",
+            ("", Self::TooLarge(_)) => "too_large",
+            ("", Self::Internal(_)) => "internal_error",
+            (code, _) => code,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.problem().message
+    }
+
+    fn problem(&self) -> &Problem {
+        match self {
+            Self::Invalid(problem) | Self::TooLarge(problem) | Self::Internal(problem) => problem,
+        }
+    }
+
+    fn problem_mut(&mut self) -> &mut Problem {
+        match self {
+            Self::Invalid(problem) | Self::TooLarge(problem) | Self::Internal(problem) => problem,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Faults of the pipeline itself, such as an encoder failing to write.
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::internal(err.to_string())
+    }
+}
@@ -0,0 +1,54 @@
+use image::DynamicImage;
+
+use crate::error::Error;
+
+/// Finds faces in the image and returns the center of the region covering
+/// them, as fractions of the width and height. Returns `None` when no face
+/// is found.
+#[cfg(feature = "face")]
+pub fn focal_point(img: &DynamicImage) -> Result<Option<(f64, f64)>, Error> {
+    use rustface::{ImageData, Model};
+    use crate::settings;
+    use std::sync::LazyLock;
+
+    /// The SeetaFace model is loaded once from `Settings::face_model_path`.
+    static MODEL: LazyLock<Result<Model, String>> = LazyLock::new(|| {
+        let path = settings::get().face_model_path.as_ref().ok_or_else(|| "No face detection model is configured".to_string())?;
+        rustface::load_model(path).map_err(|e| format!("{}: {}", path, e))
+    });
+
+    /// Longest side of the copy that detection runs on.
+    const DETECTION_SIZE: u32 = 1024;
+    /// Smallest face, in pixels of the detection copy, that is looked for.
+    const MIN_FACE_SIZE: u32 = 20;
+
+    let model = MODEL.as_ref()
+        .map_err(|e| Error::internal(format!("Failed to load face detection model: {}", e)))?;
+
+    let sample = img.thumbnail(DETECTION_SIZE, DETECTION_SIZE).to_luma8();
+    let mut detector = rustface::create_detector_with_model(model.clone());
+    detector.set_min_face_size(MIN_FACE_SIZE);
+    detector.set_score_thresh(2.0);
+    detector.set_pyramid_scale_factor(0.8);
+    detector.set_slide_window_step(4, 4);
+    let faces = detector.detect(&ImageData::new(sample.as_raw(), sample.width(), sample.height()));
+
+    let bounds = faces.iter().map(|face| face.bbox()).fold(None, |bounds: Option<(i32, i32, i32, i32)>, bbox| {
+        let (left, top) = (bbox.x(), bbox.y());
+        let (right, bottom) = (left + bbox.width() as i32, top + bbox.height() as i32);
+        Some(match bounds {
+            Some((l, t, r, b)) => (l.min(left), t.min(top), r.max(right), b.max(bottom)),
+            None => (left, top, right, bottom),
+        })
+    });
+
+    Ok(bounds.map(|(left, top, right, bottom)| {
+        let center = |low: i32, high: i32, size: u32| ((low + high) as f64 / 2.0 / size as f64).clamp(0.0, 1.0);
+        (center(left, right, sample.width()), center(top, bottom, sample.height()))
+    }))
+}
+
+#[cfg(not(feature = "face"))]
+pub fn focal_point(_img: &DynamicImage) -> Result<Option<(f64, f64)>, Error> {
+    Err(Error::invalid("Face detection is not supported by this build (enable the 'face' feature)").with_code("unsupported_gravity"))
+}
@@ -0,0 +1,369 @@
+use image::Rgba;
+
+use crate::encode::{FormatSelection, OutputFormat};
+use crate::error::Error;
+use crate::filter::{self, Filter, RedactMode};
+use crate::pipeline::{ProcessOptions, Size};
+use crate::transform::{self, Fit, Flip, Frame, Gravity, Radius, Region};
+use crate::{negotiate, ops, text};
+
+/// Accepted range for the `dpr` field and `DPR` Client Hint.
+pub const MIN_DPR: f32 = 1.0;
+pub const MAX_DPR: f32 = 3.0;
+
+/// Most sizes accepted in one `variants` list.
+pub const MAX_VARIANTS: usize = 16;
+
+/// The settings of a /transform request, built up one form field at a time
+/// like the server reads them. Fields that others decide the meaning of are
+/// kept as given until `resolve`.
+#[derive(Debug, Clone, Default)]
+pub struct Fields {
+    pub options: ProcessOptions,
+    /// WebP when `None`, or the pick of `format=auto` on servers that have
+    /// WebP disabled.
+    pub format: Option<FormatSelection>,
+    /// `None` crops to an aspect ratio, and fits inside the size otherwise.
+    pub fit: Option<Fit>,
+    /// Multiplies the sizes; the `DPR` hint stands in when `None`.
+    pub dpr: Option<f32>,
+}
+
+/// Request headers that stand in for fields a request leaves out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientHints<'a> {
+    /// `Accept`, which `format=auto` picks a format from.
+    pub accept: Option<&'a str>,
+    /// `DPR`, for requests with a size but no `dpr`.
+    pub dpr: Option<f32>,
+    /// `Width`, already in physical pixels, for requests without a size.
+    pub width: Option<f32>,
+}
+
+impl Fields {
+    /// Applies a form field by name, returning `false` for names that are
+    /// not /transform fields.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<bool, Error> {
+        match name {
+            "text" => {
+                self.options.transform.text.text = Some(value.to_string());
+            }
+            "text_size" => {
+                self.options.transform.text.size = value.trim().parse::<f32>()
+                    .ok()
+                    .filter(|size| *size >= 1.0 && *size <= text::MAX_TEXT_SIZE)
+                    .ok_or_else(|| Error::invalid(format!("Text size must be between 1 and {} pixels", text::MAX_TEXT_SIZE)).with_code("invalid_text"))?;
+            }
+            "text_color" => {
+                self.options.transform.text.color = transform::parse_color(value)?;
+            }
+            "text_position" => {
+                self.options.transform.text.position = transform::parse_anchor(value, "text_position")?;
+            }
+            "watermark_position" => {
+                self.options.transform.watermark.position = transform::parse_anchor(value, "watermark_position")?;
+            }
+            "watermark_opacity" => {
+                self.options.transform.watermark.opacity = value.trim().parse::<f32>()
+                    .ok()
+                    .filter(|o| (0.0..=1.0).contains(o))
+                    .ok_or_else(|| Error::invalid("Watermark opacity must be between 0.0 and 1.0").with_code("invalid_watermark"))?;
+            }
+            "watermark_scale" => {
+                self.options.transform.watermark.scale = Some(value.trim().parse::<f32>()
+                    .ok()
+                    .filter(|s| *s > 0.0 && *s <= 1.0)
+                    .ok_or_else(|| Error::invalid("Watermark scale must be greater than 0.0 and at most 1.0").with_code("invalid_watermark"))?);
+            }
+            "watermark_tile" => {
+                self.options.transform.watermark.tile = parse_bool(value, "watermark_tile")?;
+            }
+            "watermark_spacing" => {
+                self.options.transform.watermark.tile_spacing = value.trim().parse::<u32>()
+                    .map_err(|_| Error::invalid("Watermark spacing must be a non-negative integer").with_code("invalid_watermark"))?;
+            }
+            "watermark_angle" => {
+                self.options.transform.watermark.tile_angle = transform::parse_rotation(value)?;
+            }
+            "size" => {
+                (self.options.transform.width, self.options.transform.height) = transform::parse_size(value)?;
+            }
+            "variants" => {
+                self.options.variants = parse_variants(value)?;
+            }
+            "ar" => {
+                self.options.transform.aspect_ratio = Some(transform::parse_aspect_ratio(value)?);
+            }
+            "dpr" => {
+                self.dpr = Some(value.trim().parse::<f32>()
+                    .ok()
+                    .filter(|d| (MIN_DPR..=MAX_DPR).contains(d))
+                    .ok_or_else(|| Error::invalid(format!("DPR must be between {} and {}", MIN_DPR, MAX_DPR)).with_code("invalid_dpr"))?);
+            }
+            "fit" => {
+                self.fit = Some(value.parse::<Fit>()?);
+            }
+            "ops" => {
+                self.options.transform.ops = ops::parse_operations(value)?;
+            }
+            "no_enlarge" => {
+                self.options.transform.no_enlarge = parse_bool(value, "no_enlarge")?;
+            }
+            "resize_filter" => {
+                self.options.transform.resize_filter = transform::parse_resize_filter(value)?;
+            }
+            "gravity" => {
+                self.options.transform.gravity = value.parse::<Gravity>()?;
+            }
+            "background" => {
+                self.options.transform.background = transform::parse_color(value)?;
+            }
+            "rotate" => {
+                self.options.transform.rotate = transform::parse_rotation(value)?;
+            }
+            "flip" => {
+                self.options.transform.flip = Some(value.parse::<Flip>()?);
+            }
+            "blur" => {
+                self.options.transform.blur = Some(filter::parse_blur(value)?);
+            }
+            "sharpen" => {
+                self.options.transform.sharpen = Some(filter::parse_sharpen(value)?);
+            }
+            "brightness" => {
+                self.options.transform.adjustments.brightness = filter::parse_factor(value, "brightness", 0.0)?;
+            }
+            "contrast" => {
+                self.options.transform.adjustments.contrast = filter::parse_factor(value, "contrast", 0.0)?;
+            }
+            "saturation" => {
+                self.options.transform.adjustments.saturation = filter::parse_factor(value, "saturation", 0.0)?;
+            }
+            "gamma" => {
+                self.options.transform.adjustments.gamma = filter::parse_factor(value, "gamma", 0.1)?;
+            }
+            "filter" => {
+                self.options.transform.filter = Some(value.parse::<Filter>()?);
+            }
+            "redact" => {
+                self.options.transform.redact = filter::parse_regions(value)?;
+            }
+            "redact_mode" => {
+                self.options.transform.redact_mode = value.parse::<RedactMode>()?;
+            }
+            "pad" => {
+                self.options.transform.pad = Some(Frame::parse(value, "pad", Rgba([0, 0, 0, 0]))?);
+            }
+            "border" => {
+                self.options.transform.border = Some(Frame::parse(value, "border", Rgba([0, 0, 0, 255]))?);
+            }
+            "radius" => {
+                self.options.transform.radius = Some(value.parse::<Radius>()?);
+            }
+            "trim" => {
+                self.options.transform.trim = parse_bool(value, "trim")?;
+            }
+            "trim_tolerance" => {
+                self.options.transform.trim_tolerance = value.trim().parse::<u8>()
+                    .map_err(|_| Error::invalid("Trim tolerance must be an integer between 0 and 255").with_code("invalid_trim"))?;
+            }
+            "crop" => {
+                self.options.transform.crop = Some(value.parse::<Region>()?);
+            }
+            "crop_after_resize" => {
+                self.options.transform.crop_after_resize = parse_bool(value, "crop_after_resize")?;
+            }
+            "page" => {
+                self.options.page = value.trim().parse::<u32>()
+                    .ok()
+                    .filter(|p| *p >= 1)
+                    .ok_or_else(|| Error::invalid("Page must be a positive integer").with_code("invalid_page"))?;
+            }
+            "quality" => {
+                if let Ok(q) = value.parse::<f32>() {
+                    if !(0.0..=100.0).contains(&q) {
+                        return Err(Error::invalid("Quality must be between 0.0 and 100.0").with_code("invalid_quality"));
+                    }
+                    self.options.encode.quality = q;
+                }
+            }
+            "speed" => {
+                self.options.encode.speed = value.trim().parse::<u8>()
+                    .ok()
+                    .filter(|s| (1..=10).contains(s))
+                    .ok_or_else(|| Error::invalid("Speed must be an integer between 1 and 10").with_code("invalid_speed"))?;
+            }
+            "effort" => {
+                self.options.encode.effort = value.trim().parse::<u8>()
+                    .ok()
+                    .filter(|e| (1..=10).contains(e))
+                    .ok_or_else(|| Error::invalid("Effort must be an integer between 1 and 10").with_code("invalid_effort"))?;
+            }
+            "progressive" => {
+                self.options.encode.progressive = parse_bool(value, "progressive")?;
+            }
+            "subsampling" => {
+                self.options.encode.chroma_subsampling = value.parse()?;
+            }
+            "colors" => {
+                self.options.encode.colors = Some(value.trim().parse::<u16>()
+                    .ok()
+                    .filter(|c| (2..=256).contains(c))
+                    .ok_or_else(|| Error::invalid("Colors must be an integer between 2 and 256").with_code("invalid_colors"))?);
+            }
+            "optimize" => {
+                self.options.encode.optimize = parse_bool(value, "optimize")?;
+            }
+            "max_bytes" => {
+                let max_bytes = value.trim().parse::<usize>()
+                    .ok()
+                    .filter(|b| *b > 0)
+                    .ok_or_else(|| Error::invalid("max_bytes must be a positive integer").with_code("invalid_max_bytes"))?;
+                self.options.encode.max_bytes = Some(max_bytes);
+            }
+            "method" => {
+                self.options.encode.webp_method = value.trim().parse::<u8>()
+                    .ok()
+                    .filter(|m| *m <= 6)
+                    .ok_or_else(|| Error::invalid("Method must be an integer between 0 and 6").with_code("invalid_method"))?;
+            }
+            "alpha_quality" => {
+                self.options.encode.alpha_quality = value.trim().parse::<u8>()
+                    .ok()
+                    .filter(|q| *q <= 100)
+                    .ok_or_else(|| Error::invalid("Alpha quality must be an integer between 0 and 100").with_code("invalid_alpha_quality"))?;
+            }
+            "near_lossless" => {
+                let level = value.trim().parse::<u8>()
+                    .ok()
+                    .filter(|l| *l <= 100)
+                    .ok_or_else(|| Error::invalid("Near-lossless must be an integer between 0 and 100").with_code("invalid_near_lossless"))?;
+                self.options.encode.near_lossless = Some(level);
+            }
+            "auto_orient" => {
+                self.options.auto_orient = parse_bool(value, "auto_orient")?;
+            }
+            "metadata" => {
+                self.options.metadata = value.parse()?;
+            }
+            "placeholder" => {
+                self.options.placeholder = Some(value.parse()?);
+            }
+            "keep_profile" => {
+                self.options.keep_profile = parse_bool(value, "keep_profile")?;
+            }
+            "lossless" => {
+                self.options.encode.lossless = parse_bool(value, "lossless")?;
+            }
+            "format" => {
+                self.format = Some(value.parse()?);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Resolves the defaults that depend on other fields or on the client
+    /// hints. Also returns the names of the hints consulted, which a
+    /// response must vary on.
+    pub fn resolve(self, hints: &ClientHints) -> Result<(ProcessOptions, Vec<&'static str>), Error> {
+        let Fields { mut options, format, fit, dpr } = self;
+        // An aspect ratio asks for exactly that shape, so crop unless told otherwise.
+        options.transform.fit = fit.unwrap_or(if options.transform.aspect_ratio.is_some() { Fit::Cover } else { Fit::Inside });
+
+        let mut vary = Vec::new();
+        let has_size = options.transform.width.is_some() || options.transform.height.is_some();
+        if has_size && !options.variants.is_empty() {
+            return Err(Error::invalid("Use either 'size' or 'variants', not both").with_code("invalid_size"));
+        }
+        if !has_size && options.variants.is_empty() {
+            vary.push("width");
+            // The Width hint is already in physical pixels and is not scaled again.
+            options.transform.width = hints.width.filter(|w| *w >= 1.0).map(|w| w.round() as u32);
+        } else {
+            let dpr = dpr.unwrap_or_else(|| {
+                vary.push("dpr");
+                hints.dpr.map_or(1.0, |d| d.clamp(MIN_DPR, MAX_DPR))
+            });
+            let scale = |side: Option<u32>| side.map(|side| (side as f32 * dpr).round() as u32);
+            options.transform.width = scale(options.transform.width);
+            options.transform.height = scale(options.transform.height);
+            for (width, height) in &mut options.variants {
+                (*width, *height) = (scale(*width), scale(*height));
+            }
+        }
+
+        // Servers without WebP pick from what the client accepts instead.
+        let default = || if OutputFormat::WebP.is_enabled() { FormatSelection::Fixed(OutputFormat::WebP) } else { FormatSelection::Auto };
+        options.format = match format.unwrap_or_else(default) {
+            FormatSelection::Fixed(format) => Some(format.check_enabled()?),
+            FormatSelection::Auto => {
+                vary.push("accept");
+                Some(negotiate::negotiate_format(hints.accept))
+            }
+            FormatSelection::AutoContent => None,
+        };
+        Ok((options, vary))
+    }
+}
+
+/// Parses a comma-separated list of sizes like `1600x,800x,400x` in the
+/// `size` format.
+pub fn parse_variants(value: &str) -> Result<Vec<Size>, Error> {
+    let sizes: Vec<&str> = value.split(',').map(str::trim).filter(|size| !size.is_empty()).collect();
+    if sizes.is_empty() {
+        return Err(Error::invalid("Variants must list at least one size, e.g. '800x,400x'").with_code("invalid_variants"));
+    }
+    if sizes.len() > MAX_VARIANTS {
+        return Err(Error::invalid(format!("Variants accept at most {} sizes", MAX_VARIANTS)).with_code("invalid_variants"));
+    }
+    sizes.into_iter().map(transform::parse_size).collect()
+}
+
+pub fn parse_bool(value: &str, field: &str) -> Result<bool, Error> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" | "" => Ok(false),
+        _ => Err(Error::invalid(format!("Invalid boolean value for '{}'", field)).with_code("invalid_boolean")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_sizes_by_the_dpr_hint() {
+        let mut fields = Fields::default();
+        fields.set("size", "400x300").unwrap();
+        let hints = ClientHints { dpr: Some(2.0), ..Default::default() };
+        let (options, vary) = fields.resolve(&hints).unwrap();
+
+        assert_eq!((options.transform.width, options.transform.height), (Some(800), Some(600)));
+        assert_eq!(vary, ["dpr"]);
+    }
+
+    #[test]
+    fn rejects_a_size_with_variants() {
+        let mut fields = Fields::default();
+        fields.set("size", "400x").unwrap();
+        fields.set("variants", "100x,200x").unwrap();
+        assert_eq!(fields.resolve(&ClientHints::default()).err().unwrap().code(), "invalid_size");
+    }
+
+    #[test]
+    fn reports_invalid_encoder_settings_by_code() {
+        let invalid = [
+            ("quality", "101"), ("speed", "0"), ("effort", "11"), ("colors", "1"), ("max_bytes", "0"),
+            ("method", "7"), ("alpha_quality", "101"), ("near_lossless", "-1"),
+        ];
+        for (name, value) in invalid {
+            assert_eq!(Fields::default().set(name, value).unwrap_err().code(), format!("invalid_{}", name), "{}={}", name, value);
+        }
+    }
+
+    #[test]
+    fn reports_unknown_fields() {
+        assert!(!Fields::default().set("bundle", "zip").unwrap());
+    }
+}
@@ -0,0 +1,232 @@
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::transform::{self, Region};
+
+/// Color filters applied to the finished image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Grayscale,
+    Sepia,
+    Invert,
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "grayscale" | "greyscale" => Ok(Filter::Grayscale),
+            "sepia" => Ok(Filter::Sepia),
+            "invert" => Ok(Filter::Invert),
+            _ => Err(Error::invalid("Filter must be 'grayscale', 'sepia' or 'invert'").with_code("invalid_filter")),
+        }
+    }
+}
+
+impl Filter {
+    /// Applies the filter, keeping the alpha channel and bit depth.
+    pub fn apply(self, mut img: DynamicImage) -> DynamicImage {
+        match self {
+            Filter::Grayscale => img.grayscale(),
+            Filter::Sepia => sepia(img),
+            Filter::Invert => {
+                img.invert();
+                img
+            }
+        }
+    }
+}
+
+/// How redacted regions are obscured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactMode {
+    /// Replace the region with large blocks of its average colors.
+    Pixelate,
+    /// Fill the region with opaque black.
+    Black,
+}
+
+impl FromStr for RedactMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pixelate" => Ok(RedactMode::Pixelate),
+            "black" => Ok(RedactMode::Black),
+            _ => Err(Error::invalid("Redact mode must be 'pixelate' or 'black'").with_code("invalid_redact_mode")),
+        }
+    }
+}
+
+/// Parses a `;`-separated list of `x,y,width,height` regions.
+pub fn parse_regions(value: &str) -> Result<Vec<Region>, Error> {
+    value.split(';').filter(|region| !region.trim().is_empty()).map(str::parse).collect()
+}
+
+/// Smallest pixelation block, in pixels.
+const MIN_BLOCK_SIZE: u32 = 8;
+/// Blocks across the longer side of a pixelated region. Few enough that
+/// text such as license plates is no longer legible.
+const BLOCKS_PER_REGION: u32 = 8;
+
+/// Obscures each region, which must lie within the image, keeping the bit
+/// depth.
+pub fn redact(img: DynamicImage, regions: &[Region], mode: RedactMode) -> DynamicImage {
+    let high_bit_depth = transform::is_high_bit_depth(&img);
+    let alpha = img.color().has_alpha();
+    let mut pixels = img.into_rgba32f();
+
+    for region in regions {
+        let block = match mode {
+            RedactMode::Pixelate => (region.width.max(region.height) / BLOCKS_PER_REGION).max(MIN_BLOCK_SIZE),
+            RedactMode::Black => region.width.max(region.height),
+        };
+        for block_y in (region.y..region.y + region.height).step_by(block as usize) {
+            for block_x in (region.x..region.x + region.width).step_by(block as usize) {
+                let (width, height) = (block.min(region.x + region.width - block_x), block.min(region.y + region.height - block_y));
+                let mut cell = image::imageops::crop(&mut pixels, block_x, block_y, width, height);
+                let fill = match mode {
+                    RedactMode::Pixelate => {
+                        let mut sum = [0.0; 4];
+                        for (_, _, pixel) in cell.pixels() {
+                            sum.iter_mut().zip(pixel.0).for_each(|(total, value)| *total += value);
+                        }
+                        Rgba(sum.map(|total| total / (width * height) as f32))
+                    }
+                    RedactMode::Black => Rgba([0.0, 0.0, 0.0, 1.0]),
+                };
+                for y in 0..height {
+                    for x in 0..width {
+                        cell.put_pixel(x, y, fill);
+                    }
+                }
+            }
+        }
+    }
+    transform::from_rgba32f(pixels, high_bit_depth, alpha)
+}
+
+/// Tonal adjustments as factors, where 1.0 leaves the image unchanged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustments {
+    /// Multiplies every color channel.
+    pub brightness: f32,
+    /// Scales the distance of each channel from mid-gray.
+    pub contrast: f32,
+    /// Scales the distance of each pixel from its gray level; 0 is grayscale.
+    pub saturation: f32,
+    /// Gamma correction; values above 1 brighten the midtones.
+    pub gamma: f32,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Self { brightness: 1.0, contrast: 1.0, saturation: 1.0, gamma: 1.0 }
+    }
+}
+
+impl Adjustments {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Applies all adjustments in one pass, keeping the alpha channel and
+    /// bit depth.
+    pub fn apply(&self, img: DynamicImage) -> DynamicImage {
+        let (high_bit_depth, alpha) = (transform::is_high_bit_depth(&img), img.color().has_alpha());
+        let mut pixels = img.into_rgba32f();
+        for pixel in pixels.pixels_mut() {
+            let Rgba([r, g, b, a]) = *pixel;
+            let rgb = [r, g, b].map(|c| ((c * self.brightness - 0.5) * self.contrast + 0.5).clamp(0.0, 1.0));
+            let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+            let [r, g, b] = rgb.map(|c| (luma + (c - luma) * self.saturation).clamp(0.0, 1.0).powf(1.0 / self.gamma));
+            *pixel = Rgba([r, g, b, a]);
+        }
+        transform::from_rgba32f(pixels, high_bit_depth, alpha)
+    }
+}
+
+/// Largest accepted brightness, contrast, saturation or gamma factor.
+pub const MAX_ADJUSTMENT_FACTOR: f32 = 10.0;
+
+/// Parses an adjustment factor of at least `min`. `field` names the
+/// parameter in the error.
+pub fn parse_factor(value: &str, field: &str, min: f32) -> Result<f32, Error> {
+    check_factor(value.trim().parse::<f32>().unwrap_or(f32::NAN), field, min)
+}
+
+/// Checks that an adjustment factor lies between `min` and
+/// `MAX_ADJUSTMENT_FACTOR`.
+pub fn check_factor(factor: f32, field: &str, min: f32) -> Result<f32, Error> {
+    Some(factor)
+        .filter(|factor| (min..=MAX_ADJUSTMENT_FACTOR).contains(factor))
+        .ok_or_else(|| Error::invalid(format!("'{}' must be a factor between {} and {}", field, min, MAX_ADJUSTMENT_FACTOR)).with_code("invalid_adjustment"))
+}
+
+/// Largest accepted blur sigma; the cost grows with the kernel size.
+pub const MAX_BLUR_SIGMA: f32 = 50.0;
+/// Largest accepted sharpening amount.
+pub const MAX_SHARPEN_AMOUNT: f32 = 10.0;
+/// Radius of the unsharp mask used by `sharpen`.
+const SHARPEN_SIGMA: f32 = 1.0;
+
+/// Parses a blur sigma greater than 0 and at most `MAX_BLUR_SIGMA`.
+pub fn parse_blur(value: &str) -> Result<f32, Error> {
+    check_blur(value.trim().parse::<f32>().unwrap_or(f32::NAN))
+}
+
+pub fn check_blur(sigma: f32) -> Result<f32, Error> {
+    Some(sigma)
+        .filter(|sigma| *sigma > 0.0 && *sigma <= MAX_BLUR_SIGMA)
+        .ok_or_else(|| Error::invalid(format!("Blur must be a sigma greater than 0 and at most {}", MAX_BLUR_SIGMA)).with_code("invalid_blur"))
+}
+
+/// Parses a sharpening amount greater than 0 and at most `MAX_SHARPEN_AMOUNT`.
+pub fn parse_sharpen(value: &str) -> Result<f32, Error> {
+    check_sharpen(value.trim().parse::<f32>().unwrap_or(f32::NAN))
+}
+
+pub fn check_sharpen(amount: f32) -> Result<f32, Error> {
+    Some(amount)
+        .filter(|amount| *amount > 0.0 && *amount <= MAX_SHARPEN_AMOUNT)
+        .ok_or_else(|| Error::invalid(format!("Sharpen must be an amount greater than 0 and at most {}", MAX_SHARPEN_AMOUNT)).with_code("invalid_sharpen"))
+}
+
+/// Gaussian blur with the given standard deviation in pixels.
+pub fn blur(img: DynamicImage, sigma: f32) -> DynamicImage {
+    img.blur(sigma)
+}
+
+/// Unsharp mask: adds `amount` times the difference between the image and a
+/// blurred copy, keeping the alpha channel and bit depth.
+pub fn sharpen(img: DynamicImage, amount: f32) -> DynamicImage {
+    let (high_bit_depth, alpha) = (transform::is_high_bit_depth(&img), img.color().has_alpha());
+    let mut pixels = img.into_rgba32f();
+    let blurred = image::imageops::blur(&pixels, SHARPEN_SIGMA);
+    for (pixel, soft) in pixels.pixels_mut().zip(blurred.pixels()) {
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] + amount * (pixel[channel] - soft[channel])).clamp(0.0, 1.0);
+        }
+    }
+    transform::from_rgba32f(pixels, high_bit_depth, alpha)
+}
+
+/// The classic sepia tone matrix.
+const SEPIA: [[f32; 3]; 3] = [
+    [0.393, 0.769, 0.189],
+    [0.349, 0.686, 0.168],
+    [0.272, 0.534, 0.131],
+];
+
+fn sepia(img: DynamicImage) -> DynamicImage {
+    let (high_bit_depth, alpha) = (transform::is_high_bit_depth(&img), img.color().has_alpha());
+    let mut pixels = img.into_rgba32f();
+    for pixel in pixels.pixels_mut() {
+        let Rgba([r, g, b, a]) = *pixel;
+        let [red, green, blue] = SEPIA.map(|[wr, wg, wb]| (r * wr + g * wg + b * wb).min(1.0));
+        *pixel = Rgba([red, green, blue, a]);
+    }
+    transform::from_rgba32f(pixels, high_bit_depth, alpha)
+}
@@ -0,0 +1,251 @@
+//! Describes an input from its headers, without decoding its pixels.
+
+use image::codecs::{bmp::BmpDecoder, gif::GifDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+use image::{ColorType, ImageDecoder, ImageFormat};
+use std::io::Cursor;
+
+use crate::decode::{self, InputFormat};
+use crate::error::Error;
+use crate::metadata::{self, ExifSummary};
+use crate::{color, container, settings};
+
+/// What an input holds, as read by `inspect`.
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub format: InputFormat,
+    /// As stored, before the EXIF orientation is applied. Vector and
+    /// document inputs report the size they render at without a requested
+    /// size.
+    pub width: u32,
+    pub height: u32,
+    /// The channels of the pixels as decoded: `l` (grayscale), `la`, `rgb`,
+    /// `rgba` or `cmyk`.
+    pub color_type: &'static str,
+    /// Bits per channel.
+    pub bit_depth: u8,
+    /// Frames of an animation or pages of a document, 1 for still images.
+    pub frames: u32,
+    /// Description of the embedded ICC profile.
+    pub icc_profile: Option<String>,
+    pub exif: Option<ExifSummary>,
+    /// Memory that the decoded pixels take, with every frame of an animation
+    /// that is decoded as one.
+    pub decoded_bytes: u64,
+    /// Whether `Settings::input_limit` allows decoding it, with every frame of
+    /// an animation counted towards its pixels.
+    pub within_limit: bool,
+}
+
+impl ImageInfo {
+    pub fn has_alpha(&self) -> bool {
+        self.color_type.ends_with('a')
+    }
+}
+
+/// The header of an input: its size, channels, bits per channel and
+/// frames, and the bytes per pixel it decodes to.
+struct Header {
+    dimensions: (u32, u32),
+    color_type: &'static str,
+    bit_depth: u8,
+    frames: u32,
+    decoded_pixel_bytes: u64,
+}
+
+impl Header {
+    fn still(dimensions: (u32, u32), color: ColorType) -> Self {
+        let color_type = match (color.has_color(), color.has_alpha()) {
+            (false, false) => "l",
+            (false, true) => "la",
+            (true, false) => "rgb",
+            (true, true) => "rgba",
+        };
+        Self {
+            dimensions,
+            color_type,
+            bit_depth: (color.bits_per_pixel() / u16::from(color.channel_count())) as u8,
+            frames: 1,
+            decoded_pixel_bytes: color.bytes_per_pixel().into(),
+        }
+    }
+
+    /// Rendered or converted to 8-bit RGBA whatever it holds.
+    fn rgba(dimensions: (u32, u32)) -> Self {
+        Self { dimensions, color_type: "rgba", bit_depth: 8, frames: 1, decoded_pixel_bytes: 4 }
+    }
+}
+
+/// Reads the format, size, color and metadata of an input. Only headers are
+/// read, so inputs beyond `Settings::input_limit` are described rather than
+/// refused.
+pub fn inspect(image_bytes: &[u8]) -> Result<ImageInfo, Error> {
+    let format = decode::detect_format(image_bytes)?;
+    let header = match format {
+        InputFormat::Raster(format) => raster_header(image_bytes, format)?,
+        InputFormat::Heif => heif_header(image_bytes)?,
+        InputFormat::Svg => Header::rgba(decode::svg_size(image_bytes)?),
+        InputFormat::Pdf => {
+            // Only a single page is rendered.
+            let (pages, dimensions) = decode::pdf_pages(image_bytes)?;
+            Header { frames: pages, ..Header::rgba(dimensions) }
+        }
+    };
+
+    let (width, height) = header.dimensions;
+    // Animations are decoded whole, to RGBA; other frames are not decoded.
+    let frames = match format {
+        InputFormat::Raster(ImageFormat::Gif | ImageFormat::WebP) if header.frames > 1 => header.frames,
+        _ => 1,
+    };
+    let pixel_bytes = if frames > 1 { 4 } else { header.decoded_pixel_bytes };
+    let limit = settings::get().input_limit;
+    Ok(ImageInfo {
+        format,
+        width,
+        height,
+        color_type: header.color_type,
+        bit_depth: header.bit_depth,
+        frames: header.frames,
+        icc_profile: decode::embedded_icc_profile(image_bytes, format).and_then(|profile| color::profile_description(&profile)),
+        exif: metadata::exif_summary(image_bytes, format),
+        decoded_bytes: u64::from(width) * u64::from(height) * pixel_bytes * u64::from(frames),
+        within_limit: limit.check(header.dimensions).is_ok() && u64::from(width) * u64::from(height) * u64::from(frames) <= limit.pixels,
+    })
+}
+
+fn raster_header(image_bytes: &[u8], format: ImageFormat) -> Result<Header, Error> {
+    let cursor = || Cursor::new(image_bytes);
+    let header = match format {
+        ImageFormat::Jpeg => return jpeg_header(image_bytes),
+        ImageFormat::Png => {
+            let header = still(PngDecoder::new(cursor()).map_err(decode_error)?);
+            Header { frames: png_frames(image_bytes), ..header }
+        }
+        ImageFormat::WebP => {
+            let header = still(WebPDecoder::new(cursor()).map_err(decode_error)?);
+            let frames = container::webp_chunks(image_bytes).filter(|(fourcc, _)| *fourcc == b"ANMF").count();
+            Header { frames: (frames as u32).max(1), ..header }
+        }
+        ImageFormat::Gif => {
+            let header = still(GifDecoder::new(cursor()).map_err(decode_error)?);
+            Header { frames: gif_frames(image_bytes).max(1), ..header }
+        }
+        ImageFormat::Tiff => still(TiffDecoder::new(cursor()).map_err(decode_error)?),
+        ImageFormat::Bmp => still(BmpDecoder::new(cursor()).map_err(decode_error)?),
+        _ => unreachable!("detect_format only returns supported formats"),
+    };
+    Ok(header)
+}
+
+fn still<'a>(decoder: impl ImageDecoder<'a>) -> Header {
+    Header::still(decoder.dimensions(), decoder.color_type())
+}
+
+/// Reads JPEGs with jpeg-decoder, which unlike the `image` crate tells CMYK
+/// files apart. Those are decoded to RGB.
+fn jpeg_header(image_bytes: &[u8]) -> Result<Header, Error> {
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(image_bytes));
+    decoder.read_info().map_err(decode_error)?;
+    let info = decoder.info().ok_or_else(|| decode_error("missing JPEG header"))?;
+    let dimensions = (info.width.into(), info.height.into());
+    Ok(match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => Header::still(dimensions, ColorType::L8),
+        jpeg_decoder::PixelFormat::L16 => Header::still(dimensions, ColorType::L16),
+        jpeg_decoder::PixelFormat::RGB24 => Header::still(dimensions, ColorType::Rgb8),
+        jpeg_decoder::PixelFormat::CMYK32 => Header { color_type: "cmyk", ..Header::still(dimensions, ColorType::Rgb8) },
+    })
+}
+
+/// The frames of an APNG, from its `acTL` chunk.
+fn png_frames(image_bytes: &[u8]) -> u32 {
+    container::png_chunks(image_bytes)
+        .find_map(|(kind, data)| (kind == b"acTL").then(|| data.get(..4)?.try_into().ok().map(u32::from_be_bytes)).flatten())
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Counts the image descriptors of a GIF, skipping over the data that
+/// follows each block.
+fn gif_frames(image_bytes: &[u8]) -> u32 {
+    // The length of the color table that the low bits of `flags` give, when
+    // its high bit is set.
+    let color_table = |flags: u8| if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+    // Skips data sub-blocks up to and including the empty one.
+    let sub_blocks = |mut offset: usize| {
+        while let Some(&length) = image_bytes.get(offset) {
+            offset += 1 + usize::from(length);
+            if length == 0 {
+                break;
+            }
+        }
+        offset
+    };
+
+    let Some(&flags) = image_bytes.get(10) else { return 0 };
+    let mut offset = 13 + color_table(flags);
+    let mut frames = 0;
+    while let Some(&block) = image_bytes.get(offset) {
+        match block {
+            // Extension: label, then sub-blocks.
+            0x21 => offset = sub_blocks(offset + 2),
+            // Image descriptor: position, size and flags, an optional local
+            // color table, the LZW code size and the image data.
+            0x2C => {
+                let Some(&flags) = image_bytes.get(offset + 9) else { break };
+                frames += 1;
+                offset = sub_blocks(offset + 10 + color_table(flags) + 1);
+            }
+            _ => break,
+        }
+    }
+    frames
+}
+
+#[cfg(feature = "heif")]
+fn heif_header(image_bytes: &[u8]) -> Result<Header, Error> {
+    let heif_error = |e: libheif_rs::HeifError| Error::invalid(format!("Failed to read HEIF image: {}", e)).with_code("decode_failed");
+    let context = libheif_rs::HeifContext::read_from_bytes(image_bytes).map_err(heif_error)?;
+    let handle = context.primary_image_handle().map_err(heif_error)?;
+    let color_type = if handle.has_alpha_channel() { "rgba" } else { "rgb" };
+    Ok(Header { color_type, bit_depth: handle.luma_bits_per_pixel(), ..Header::rgba((handle.width(), handle.height())) })
+}
+
+#[cfg(not(feature = "heif"))]
+fn heif_header(_image_bytes: &[u8]) -> Result<Header, Error> {
+    Err(Error::invalid("HEIC/HEIF input is not supported by this build (enable the 'heif' feature)").with_code("unsupported_format"))
+}
+
+fn decode_error(e: impl std::fmt::Display) -> Error {
+    Error::invalid(format!("Failed to read image: {}", e)).with_code("decode_failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{DynamicImage, Frame, RgbaImage};
+
+    #[test]
+    fn describes_a_png_without_decoding_it() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgba8(40, 30).write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+        let info = inspect(png.get_ref()).unwrap();
+
+        assert_eq!((info.format.name(), info.width, info.height), ("png", 40, 30));
+        assert_eq!((info.color_type, info.bit_depth, info.frames), ("rgba", 8, 1));
+        assert!(info.has_alpha() && info.within_limit);
+        assert_eq!(info.decoded_bytes, 40 * 30 * 4);
+        assert!(info.icc_profile.is_none() && info.exif.is_none());
+    }
+
+    #[test]
+    fn counts_the_frames_of_an_animation() {
+        let mut gif = Vec::new();
+        let frames = (0..3).map(|i| Frame::new(RgbaImage::from_pixel(8, 6, image::Rgba([i * 80, 0, 0, 255]))));
+        GifEncoder::new(&mut gif).encode_frames(frames).unwrap();
+        let info = inspect(&gif).unwrap();
+
+        assert_eq!((info.format.name(), info.width, info.height, info.frames), ("gif", 8, 6, 3));
+        assert_eq!(info.decoded_bytes, 8 * 6 * 4 * 3);
+    }
+}
@@ -0,0 +1,44 @@
+//! The image pipeline of the image transformer: decoding, the operations of
+//! a /transform request, and encoding, without the HTTP server around it.
+//!
+//! ```no_run
+//! use image_transformer_core::{process_image, OutputFormat, ProcessOptions};
+//!
+//! let mut options = ProcessOptions::default();
+//! options.transform.width = Some(800);
+//! options.format = Some(OutputFormat::Avif);
+//! let input = std::fs::read("photo.jpg").unwrap();
+//! let outputs = process_image(input.into(), options).unwrap();
+//! std::fs::write(outputs[0].file_name(), &outputs[0].bytes).unwrap();
+//! ```
+
+pub mod analyze;
+pub mod color;
+pub mod compare;
+pub mod container;
+pub mod decode;
+pub mod encode;
+mod error;
+pub mod face;
+pub mod fields;
+pub mod filter;
+pub mod inspect;
+pub mod metadata;
+pub mod negotiate;
+pub mod ops;
+mod pipeline;
+pub mod placeholder;
+mod settings;
+pub mod smartcrop;
+mod telemetry;
+pub mod text;
+pub mod transform;
+pub mod watermark;
+
+pub use encode::{EncodeOptions, OutputFormat};
+pub use error::{Error, Problem};
+pub use fields::{ClientHints, Fields};
+pub use inspect::{inspect, ImageInfo};
+pub use pipeline::{process_image, ProcessOptions, Size, Variant};
+pub use settings::{get as settings, install, Settings};
+pub use transform::TransformOptions;
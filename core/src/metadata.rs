@@ -0,0 +1,335 @@
+use exif::{Field, In, Tag};
+use image::{DynamicImage, ImageFormat};
+use std::io::Cursor;
+use std::str::FromStr;
+
+use crate::container;
+use crate::decode::InputFormat;
+use crate::encode::OutputFormat;
+use crate::error::Error;
+
+/// Which input metadata is copied into the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMode {
+    /// Drop EXIF, XMP and IPTC data.
+    Strip,
+    /// Copy EXIF, XMP and IPTC data.
+    Keep,
+    /// Copy only authorship and copyright fields from EXIF and IPTC.
+    KeepCopyright,
+    /// Copy EXIF and IPTC data without GPS location and serial numbers.
+    Privacy,
+}
+
+impl FromStr for MetadataMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "strip" => Ok(MetadataMode::Strip),
+            "keep" => Ok(MetadataMode::Keep),
+            "keep-copyright" => Ok(MetadataMode::KeepCopyright),
+            "privacy" => Ok(MetadataMode::Privacy),
+            _ => Err(Error::invalid("Unsupported metadata mode. Use 'strip', 'keep', 'keep-copyright' or 'privacy'").with_code("invalid_metadata")),
+        }
+    }
+}
+
+/// Metadata copied from the input, in its raw encoded form.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// TIFF-structured EXIF data, without the JPEG `Exif\0\0` prefix.
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet.
+    pub xmp: Option<Vec<u8>>,
+    /// IPTC-IIM datasets. Only JPEG inputs and outputs carry these.
+    pub iptc: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.iptc.is_none()
+    }
+}
+
+/// EXIF tags copied by `keep-copyright`.
+const COPYRIGHT_EXIF_TAGS: [Tag; 2] = [Tag::Artist, Tag::Copyright];
+
+/// IPTC record 2 datasets copied by `keep-copyright`: by-line, by-line
+/// title, credit, source and copyright notice.
+const COPYRIGHT_IPTC_DATASETS: [u8; 5] = [80, 85, 110, 115, 116];
+
+/// EXIF tags removed by `privacy` besides the GPS IFD. Maker notes are
+/// vendor-specific and commonly embed the camera serial number.
+const PRIVATE_EXIF_TAGS: [Tag; 4] = [
+    Tag::BodySerialNumber,
+    Tag::LensSerialNumber,
+    Tag::MakerNote,
+    // CameraSerialNumber, defined by DNG.
+    Tag(exif::Context::Tiff, 0xC62F),
+];
+
+/// EXIF tags that describe the input pixels and would be wrong after
+/// resizing or re-encoding.
+const STALE_EXIF_TAGS: [Tag; 4] = [Tag::ImageWidth, Tag::ImageLength, Tag::PixelXDimension, Tag::PixelYDimension];
+
+const JPEG_EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const JPEG_XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const JPEG_PHOTOSHOP_PREFIX: &[u8] = b"Photoshop 3.0\0";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+/// Photoshop image resource holding IPTC-IIM data.
+const IPTC_RESOURCE_ID: u16 = 0x0404;
+
+/// Collects the metadata to copy into the output. `oriented` drops the EXIF
+/// orientation because it has already been applied to the pixels. XMP is
+/// only copied in `keep` mode since it cannot be filtered field by field
+/// and may repeat the GPS position.
+pub fn extract(image_bytes: &[u8], input_format: InputFormat, mode: MetadataMode, oriented: bool) -> Metadata {
+    if mode == MetadataMode::Strip || !matches!(input_format, InputFormat::Raster(_) | InputFormat::Heif) {
+        return Metadata::default();
+    }
+
+    let exif = read_exif(image_bytes).and_then(|exif| {
+        rewrite_exif(&exif, |field| {
+            let wanted = match mode {
+                MetadataMode::KeepCopyright => COPYRIGHT_EXIF_TAGS.contains(&field.tag),
+                MetadataMode::Privacy => field.tag.context() != exif::Context::Gps && !PRIVATE_EXIF_TAGS.contains(&field.tag),
+                _ => true,
+            };
+            wanted && !(oriented && field.tag == Tag::Orientation)
+        })
+    });
+
+    let (xmp, iptc) = match input_format {
+        InputFormat::Raster(ImageFormat::Jpeg) => (jpeg_xmp(image_bytes), jpeg_iptc(image_bytes)),
+        InputFormat::Raster(ImageFormat::Png) => (png_xmp(image_bytes), None),
+        InputFormat::Raster(ImageFormat::WebP) => (webp_xmp(image_bytes), None),
+        _ => (None, None),
+    };
+
+    match mode {
+        MetadataMode::KeepCopyright => Metadata { exif, xmp: None, iptc: iptc.and_then(|iptc| filter_iptc(&iptc, &COPYRIGHT_IPTC_DATASETS)) },
+        MetadataMode::Privacy => Metadata { exif, xmp: None, iptc },
+        _ => Metadata { exif, xmp, iptc },
+    }
+}
+
+fn read_exif(image_bytes: &[u8]) -> Option<exif::Exif> {
+    exif::Reader::new().read_from_container(&mut Cursor::new(image_bytes)).ok()
+}
+
+/// Re-serializes the primary image's EXIF fields accepted by `keep`, leaving
+/// out the thumbnail and tags that no longer match the output pixels.
+fn rewrite_exif(exif: &exif::Exif, keep: impl Fn(&Field) -> bool) -> Option<Vec<u8>> {
+    let fields: Vec<&Field> = exif
+        .fields()
+        .filter(|field| field.ifd_num == In::PRIMARY && !STALE_EXIF_TAGS.contains(&field.tag) && keep(field))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut output = Cursor::new(Vec::new());
+    writer.write(&mut output, exif.little_endian()).ok()?;
+    Some(output.into_inner())
+}
+
+fn jpeg_xmp(image_bytes: &[u8]) -> Option<Vec<u8>> {
+    container::jpeg_segments(image_bytes)
+        .find_map(|(marker, payload)| (marker == 0xE1).then(|| payload.strip_prefix(JPEG_XMP_PREFIX)).flatten())
+        .map(<[u8]>::to_vec)
+}
+
+/// Reads the IPTC-IIM datasets from the Photoshop APP13 segment.
+fn jpeg_iptc(image_bytes: &[u8]) -> Option<Vec<u8>> {
+    let resources = container::jpeg_segments(image_bytes)
+        .find_map(|(marker, payload)| (marker == 0xED).then(|| payload.strip_prefix(JPEG_PHOTOSHOP_PREFIX)).flatten())?;
+
+    // Image resources: "8BIM", id, padded Pascal name, size, padded data.
+    let mut rest = resources;
+    while let Some(tail) = rest.strip_prefix(b"8BIM") {
+        let id = u16::from_be_bytes(tail.get(..2)?.try_into().ok()?);
+        let name_len = *tail.get(2)? as usize;
+        let name_total = (1 + name_len + 1) & !1;
+        let size_at = 2 + name_total;
+        let size = u32::from_be_bytes(tail.get(size_at..size_at + 4)?.try_into().ok()?) as usize;
+        let data = tail.get(size_at + 4..size_at + 4 + size)?;
+        if id == IPTC_RESOURCE_ID {
+            return Some(data.to_vec());
+        }
+        rest = tail.get(size_at + 4 + ((size + 1) & !1)..)?;
+    }
+    None
+}
+
+fn png_xmp(image_bytes: &[u8]) -> Option<Vec<u8>> {
+    // iTXt: keyword, compression flag and method, language tag, translated keyword, text.
+    let data = container::png_chunks(image_bytes)
+        .find_map(|(kind, data)| (kind == b"iTXt").then(|| data.strip_prefix(PNG_XMP_KEYWORD)).flatten())?;
+    let (&[0, _], rest) = data.split_at_checked(2)? else {
+        return None;
+    };
+    let mut fields = rest.splitn(3, |&b| b == 0);
+    let text = fields.nth(2)?;
+    Some(text.to_vec())
+}
+
+fn webp_xmp(image_bytes: &[u8]) -> Option<Vec<u8>> {
+    container::webp_chunks(image_bytes)
+        .find_map(|(fourcc, data)| (fourcc == b"XMP ").then(|| data.to_vec()))
+}
+
+/// Keeps only the record 2 datasets listed in `datasets`, plus the character
+/// set declaration from record 1.
+fn filter_iptc(iptc: &[u8], datasets: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut kept_any = false;
+    let mut rest = iptc;
+    while let [0x1C, record, dataset, high, low, tail @ ..] = rest {
+        let length = u16::from_be_bytes([*high, *low]) as usize;
+        if tail.len() < length {
+            break;
+        }
+        let wanted = *record == 2 && datasets.contains(dataset);
+        if wanted || (*record == 1 && *dataset == 90) {
+            output.extend_from_slice(&rest[..5 + length]);
+        }
+        kept_any |= wanted;
+        rest = &tail[length..];
+    }
+    kept_any.then_some(output)
+}
+
+/// Writes metadata into already encoded output. AVIF, JPEG XL and ICO
+/// output is returned unchanged.
+pub fn embed_metadata(encoded: Vec<u8>, format: OutputFormat, metadata: &Metadata) -> Result<Vec<u8>, Error> {
+    match format {
+        OutputFormat::Jpeg => {
+            let exif = metadata.exif.as_ref().map(|exif| [JPEG_EXIF_PREFIX, exif].concat());
+            let xmp = metadata.xmp.as_ref().map(|xmp| [JPEG_XMP_PREFIX, xmp].concat());
+            let iptc = metadata.iptc.as_ref().map(|iptc| photoshop_iptc_segment(iptc));
+            let segments: Vec<(u8, &[u8])> = [(0xE1, &exif), (0xE1, &xmp), (0xED, &iptc)]
+                .into_iter()
+                .filter_map(|(marker, payload)| payload.as_deref().map(|payload| (marker, payload)))
+                .filter(|(_, payload)| payload.len() <= u16::MAX as usize - 2)
+                .collect();
+            Ok(container::insert_jpeg_segments(&encoded, &segments))
+        }
+        OutputFormat::Png => {
+            let xmp = metadata.xmp.as_ref().map(|xmp| [PNG_XMP_KEYWORD, &[0, 0, 0, 0], xmp].concat());
+            let chunks: Vec<(&[u8; 4], &[u8])> = [(b"eXIf", metadata.exif.as_deref()), (b"iTXt", xmp.as_deref())]
+                .into_iter()
+                .filter_map(|(kind, data)| data.map(|data| (kind, data)))
+                .collect();
+            Ok(container::insert_png_chunks(&encoded, &chunks, &[b"eXIf"]))
+        }
+        OutputFormat::WebP => {
+            let chunks: Vec<_> = [(c"EXIF", metadata.exif.as_deref()), (c"XMP ", metadata.xmp.as_deref())]
+                .into_iter()
+                .filter_map(|(fourcc, data)| data.map(|data| (fourcc, data)))
+                .collect();
+            if chunks.is_empty() { Ok(encoded) } else { container::set_webp_chunks(&encoded, &chunks) }
+        }
+        OutputFormat::Avif | OutputFormat::Jxl | OutputFormat::Ico => Ok(encoded),
+    }
+}
+
+/// Wraps IPTC-IIM data in a Photoshop APP13 payload.
+fn photoshop_iptc_segment(iptc: &[u8]) -> Vec<u8> {
+    let mut payload = JPEG_PHOTOSHOP_PREFIX.to_vec();
+    payload.extend_from_slice(b"8BIM");
+    payload.extend_from_slice(&IPTC_RESOURCE_ID.to_be_bytes());
+    payload.extend_from_slice(&[0, 0]);
+    payload.extend_from_slice(&(iptc.len() as u32).to_be_bytes());
+    payload.extend_from_slice(iptc);
+    if iptc.len() % 2 == 1 {
+        payload.push(0);
+    }
+    payload
+}
+
+/// The EXIF fields that describe how and with what a photo was taken.
+#[derive(Debug, Clone, Default)]
+pub struct ExifSummary {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub software: Option<String>,
+    /// As written by the camera, such as `2024:05:17 14:02:33`.
+    pub taken: Option<String>,
+    /// 1 to 8, see `apply_orientation`.
+    pub orientation: Option<u32>,
+    /// Such as `1/250 s`.
+    pub exposure_time: Option<String>,
+    /// Such as `f/2.8`.
+    pub f_number: Option<String>,
+    pub iso: Option<u32>,
+    /// Such as `35 mm`.
+    pub focal_length: Option<String>,
+    /// Whether a GPS position is included.
+    pub gps: bool,
+}
+
+/// Summarizes the EXIF data of a raster or HEIF input, if it has any.
+pub fn exif_summary(image_bytes: &[u8], input_format: InputFormat) -> Option<ExifSummary> {
+    if !matches!(input_format, InputFormat::Raster(_) | InputFormat::Heif) {
+        return None;
+    }
+
+    let exif = read_exif(image_bytes)?;
+    let field = |tag| exif.get_field(tag, In::PRIMARY);
+    let text = |tag| match &field(tag)?.value {
+        exif::Value::Ascii(values) => {
+            let text = String::from_utf8_lossy(values.first()?).trim_end_matches('\0').trim().to_string();
+            (!text.is_empty()).then_some(text)
+        }
+        _ => None,
+    };
+    let with_unit = |tag| field(tag).map(|field| field.display_value().with_unit(&exif).to_string());
+    Some(ExifSummary {
+        make: text(Tag::Make),
+        model: text(Tag::Model),
+        lens: text(Tag::LensModel),
+        software: text(Tag::Software),
+        taken: text(Tag::DateTimeOriginal).or_else(|| text(Tag::DateTime)),
+        orientation: field(Tag::Orientation).and_then(|field| field.value.get_uint(0)).filter(|orientation| (1..=8).contains(orientation)),
+        exposure_time: with_unit(Tag::ExposureTime),
+        f_number: with_unit(Tag::FNumber),
+        iso: field(Tag::PhotographicSensitivity).and_then(|field| field.value.get_uint(0)),
+        focal_length: with_unit(Tag::FocalLength),
+        gps: exif.fields().any(|field| field.tag.context() == exif::Context::Gps),
+    })
+}
+
+/// Reads the EXIF orientation (1 to 8) of a raster input. HEIF inputs are
+/// skipped because libheif already applies their rotation while decoding.
+pub fn orientation(image_bytes: &[u8], input_format: InputFormat) -> Option<u32> {
+    if !matches!(input_format, InputFormat::Raster(_)) {
+        return None;
+    }
+
+    read_exif(image_bytes)?
+        .get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Rotates and flips an image so that it displays upright for the given
+/// EXIF orientation.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
@@ -0,0 +1,152 @@
+use image::DynamicImage;
+use std::collections::HashSet;
+
+use crate::encode::OutputFormat;
+
+/// Formats considered for `format=auto`, in order of preference.
+const NEGOTIABLE_FORMATS: [(OutputFormat, &str); 2] = [
+    (OutputFormat::Avif, "image/avif"),
+    (OutputFormat::WebP, "image/webp"),
+];
+
+/// Formats that any client can display, in order of preference, for when
+/// the server has disabled the one picked.
+const FALLBACK_FORMATS: [OutputFormat; 3] = [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::WebP];
+
+/// Picks the best output format advertised in an `Accept` header.
+///
+/// Only explicitly listed modern formats are honored; wildcards such as
+/// `image/*` are sent by clients that cannot necessarily decode AVIF or WebP,
+/// so anything else falls back to JPEG. Formats disabled by
+/// `Settings::enabled_formats` are never picked.
+pub fn negotiate_format(accept: Option<&str>) -> OutputFormat {
+    negotiate_among(accept, OutputFormat::is_enabled)
+}
+
+fn negotiate_among(accept: Option<&str>, enabled: impl Fn(OutputFormat) -> bool) -> OutputFormat {
+    let accepted: Vec<(&str, f32)> = accept
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let media_type = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((media_type, quality))
+        })
+        .collect();
+
+    NEGOTIABLE_FORMATS
+        .iter()
+        .find(|(format, media_type)| {
+            enabled(*format)
+                && accepted.iter().any(|(accepted_type, quality)| accepted_type.eq_ignore_ascii_case(media_type) && *quality > 0.0)
+        })
+        .map(|(format, _)| *format)
+        .unwrap_or_else(|| fallback(enabled))
+}
+
+/// The first enabled format of `FALLBACK_FORMATS`, or of the others on
+/// servers left with none of them, such as with only AVIF enabled.
+fn fallback(enabled: impl Fn(OutputFormat) -> bool) -> OutputFormat {
+    FALLBACK_FORMATS.into_iter()
+        .chain(NEGOTIABLE_FORMATS.map(|(format, _)| format))
+        .chain([OutputFormat::Jxl, OutputFormat::Ico])
+        .find(|format| enabled(*format))
+        .unwrap_or(OutputFormat::Jpeg)
+}
+
+/// Longest side of the thumbnail analyzed by `select_for_content`.
+const ANALYSIS_SIZE: u32 = 256;
+
+/// Images with at most this many distinct colors are treated as palette graphics.
+const PALETTE_COLORS: usize = 256;
+
+/// Share of horizontally adjacent pixel pairs that must be identical for an
+/// image to count as flat graphics rather than a photo.
+const FLAT_RATIO: f32 = 0.5;
+
+/// Output chosen by `format=auto-content`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentChoice {
+    pub format: OutputFormat,
+    pub lossless: bool,
+}
+
+/// Picks an output format from the image's content.
+///
+/// Graphics with a small palette become PNG and other flat graphics
+/// (screenshots, diagrams) lossless WebP. Photographic content is encoded
+/// lossy: AVIF when opaque, WebP when it carries transparency. Disabled
+/// formats give way to the fallback of `format=auto`.
+pub fn select_for_content(img: &DynamicImage) -> ContentChoice {
+    let choice = classify(img);
+    if choice.format.is_enabled() {
+        return choice;
+    }
+    ContentChoice { format: fallback(OutputFormat::is_enabled), ..choice }
+}
+
+fn classify(img: &DynamicImage) -> ContentChoice {
+    let sample = img.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_rgba8();
+
+    let mut colors = HashSet::new();
+    let mut has_alpha = false;
+    for pixel in sample.pixels() {
+        has_alpha |= pixel[3] < u8::MAX;
+        if colors.len() <= PALETTE_COLORS {
+            colors.insert(pixel.0);
+        }
+    }
+
+    if colors.len() <= PALETTE_COLORS {
+        return ContentChoice { format: OutputFormat::Png, lossless: true };
+    }
+
+    let (width, height) = sample.dimensions();
+    let pairs = (width.saturating_sub(1) * height) as usize;
+    let identical = sample
+        .rows()
+        .map(|row| {
+            let row: Vec<_> = row.collect();
+            row.windows(2).filter(|pair| pair[0] == pair[1]).count()
+        })
+        .sum::<usize>();
+
+    if pairs > 0 && identical as f32 / pairs as f32 >= FLAT_RATIO {
+        ContentChoice { format: OutputFormat::WebP, lossless: true }
+    } else if has_alpha {
+        ContentChoice { format: OutputFormat::WebP, lossless: false }
+    } else {
+        ContentChoice { format: OutputFormat::Avif, lossless: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_avif_then_webp() {
+        assert_eq!(negotiate_format(Some("image/avif,image/webp,*/*")), OutputFormat::Avif);
+        assert_eq!(negotiate_format(Some("image/webp;q=0.9, image/avif;q=0")), OutputFormat::WebP);
+    }
+
+    #[test]
+    fn falls_back_to_jpeg_for_wildcards() {
+        assert_eq!(negotiate_format(Some("image/*")), OutputFormat::Jpeg);
+        assert_eq!(negotiate_format(None), OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn skips_disabled_formats() {
+        let without = |disabled: &'static [OutputFormat]| move |format| !disabled.contains(&format);
+        let accept = Some("image/avif,image/webp,*/*");
+        assert_eq!(negotiate_among(accept, without(&[OutputFormat::Avif])), OutputFormat::WebP);
+        assert_eq!(negotiate_among(accept, without(&[OutputFormat::Avif, OutputFormat::WebP])), OutputFormat::Jpeg);
+        assert_eq!(negotiate_among(None, without(&[OutputFormat::Jpeg])), OutputFormat::Png);
+        assert_eq!(negotiate_among(None, |format| format == OutputFormat::Avif), OutputFormat::Avif);
+    }
+}
@@ -0,0 +1,153 @@
+use image::{DynamicImage, Rgba};
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::filter::{self, Adjustments, Filter};
+use crate::telemetry;
+use crate::transform::{self, Fit, Flip, Frame, Gravity, Radius, Region, TransformOptions};
+
+/// Most steps accepted in one `ops` pipeline.
+pub const MAX_OPERATIONS: usize = 32;
+
+/// A single step of an `ops` pipeline, parsed from `name` or `name:args`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Crop(Region),
+    /// Resizes into the box, using the request's `fit` and `gravity` unless
+    /// the step sets its own.
+    Resize { width: Option<u32>, height: Option<u32>, fit: Option<Fit>, gravity: Option<Gravity> },
+    Rotate(f64),
+    Flip(Flip),
+    /// Trims uniform borders within the given tolerance.
+    Trim(u8),
+    Blur(f32),
+    Sharpen(f32),
+    Adjust(Adjustments),
+    Filter(Filter),
+    Pad(Frame),
+    Border(Frame),
+    Radius(Radius),
+}
+
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::Crop(_) => "crop",
+            Operation::Resize { .. } => "resize",
+            Operation::Rotate(_) => "rotate",
+            Operation::Flip(_) => "flip",
+            Operation::Trim(_) => "trim",
+            Operation::Blur(_) => "blur",
+            Operation::Sharpen(_) => "sharpen",
+            Operation::Adjust(_) => "adjust",
+            Operation::Filter(_) => "filter",
+            Operation::Pad(_) => "pad",
+            Operation::Border(_) => "border",
+            Operation::Radius(_) => "radius",
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, args) = s.split_once(':').unwrap_or((s, ""));
+        let name = name.trim().to_ascii_lowercase();
+        let adjust = |adjustments: Adjustments| Ok(Operation::Adjust(adjustments));
+        match (name.as_str(), args) {
+            ("crop", args) => Ok(Operation::Crop(args.parse()?)),
+            ("resize", args) => {
+                let (width, height) = transform::parse_size(args.trim())?;
+                Ok(Operation::Resize { width, height, fit: None, gravity: None })
+            }
+            ("rotate", args) => Ok(Operation::Rotate(transform::parse_rotation(args)?)),
+            ("flip", args) => Ok(Operation::Flip(args.parse()?)),
+            ("trim", "") => Ok(Operation::Trim(TransformOptions::default().trim_tolerance)),
+            ("trim", args) => args.trim().parse::<u8>()
+                .map(Operation::Trim)
+                .map_err(|_| Error::invalid("Trim tolerance must be an integer between 0 and 255").with_code("invalid_ops")),
+            ("blur", args) => Ok(Operation::Blur(filter::parse_blur(args)?)),
+            ("sharpen", args) => Ok(Operation::Sharpen(filter::parse_sharpen(args)?)),
+            ("brightness", args) => adjust(Adjustments { brightness: filter::parse_factor(args, "brightness", 0.0)?, ..Default::default() }),
+            ("contrast", args) => adjust(Adjustments { contrast: filter::parse_factor(args, "contrast", 0.0)?, ..Default::default() }),
+            ("saturation", args) => adjust(Adjustments { saturation: filter::parse_factor(args, "saturation", 0.0)?, ..Default::default() }),
+            ("gamma", args) => adjust(Adjustments { gamma: filter::parse_factor(args, "gamma", 0.1)?, ..Default::default() }),
+            (name @ ("grayscale" | "greyscale" | "sepia" | "invert"), "") => Ok(Operation::Filter(name.parse()?)),
+            ("pad", args) => Ok(Operation::Pad(Frame::parse(args, "pad", Rgba([0, 0, 0, 0]))?)),
+            ("border", args) => Ok(Operation::Border(Frame::parse(args, "border", Rgba([0, 0, 0, 255]))?)),
+            ("radius", args) => Ok(Operation::Radius(args.parse()?)),
+            _ => Err(Error::invalid(format!("Unknown operation '{}' in 'ops'", s.trim())).with_code("invalid_ops")),
+        }
+    }
+}
+
+/// Parses a pipeline of operations separated by `|`, e.g.
+/// `crop:0,0,800,800|resize:400x400|grayscale`.
+pub fn parse_operations(value: &str) -> Result<Vec<Operation>, Error> {
+    let steps: Vec<&str> = value.split('|').filter(|step| !step.trim().is_empty()).collect();
+    check_count(steps.len())?;
+    steps.into_iter().map(str::parse).collect()
+}
+
+/// Checks a pipeline length against `MAX_OPERATIONS`.
+pub fn check_count(count: usize) -> Result<(), Error> {
+    if count > MAX_OPERATIONS {
+        return Err(Error::invalid(format!("A pipeline accepts at most {} operations", MAX_OPERATIONS)).with_code("invalid_ops"));
+    }
+    Ok(())
+}
+
+/// Runs the operations in order. `options` supplies the settings steps do
+/// not carry themselves, such as the fit for resizing and the background
+/// for rotation.
+pub fn apply(mut img: DynamicImage, operations: &[Operation], options: &TransformOptions) -> Result<DynamicImage, Error> {
+    for operation in operations {
+        let _span = telemetry::op_span(operation.name()).entered();
+        img = match *operation {
+            Operation::Crop(region) => transform::crop_image(img, region)?,
+            Operation::Resize { width, height, fit, gravity } => {
+                let step = TransformOptions {
+                    width,
+                    height,
+                    aspect_ratio: None,
+                    fit: fit.unwrap_or(options.fit),
+                    gravity: gravity.unwrap_or(options.gravity),
+                    ..options.clone()
+                };
+                transform::resize_to_target(img, &step)?
+            }
+            Operation::Rotate(0.0) => img,
+            Operation::Rotate(degrees) => transform::rotate(img, degrees, options.background),
+            Operation::Flip(Flip::Horizontal) => img.fliph(),
+            Operation::Flip(Flip::Vertical) => img.flipv(),
+            Operation::Trim(tolerance) => transform::trim(img, tolerance),
+            Operation::Blur(sigma) => filter::blur(img, sigma),
+            Operation::Sharpen(amount) => filter::sharpen(img, amount),
+            Operation::Adjust(adjustments) => adjustments.apply(img),
+            Operation::Filter(filter) => filter.apply(img),
+            Operation::Pad(frame) | Operation::Border(frame) => frame.apply(img),
+            Operation::Radius(radius) => transform::round_corners(img, radius),
+        };
+    }
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_pipeline_in_order() {
+        let operations = parse_operations("crop:0,0,800,800|resize:400x400| grayscale |blur:2").unwrap();
+        let names: Vec<_> = operations.iter().map(Operation::name).collect();
+        assert_eq!(names, ["crop", "resize", "filter", "blur"]);
+    }
+
+    #[test]
+    fn rejects_unknown_and_too_many_operations() {
+        assert_eq!(parse_operations("resize:400x|explode").unwrap_err().code(), "invalid_ops");
+        let steps = vec!["grayscale"; MAX_OPERATIONS + 1].join("|");
+        assert_eq!(parse_operations(&steps).unwrap_err().code(), "invalid_ops");
+    }
+}
@@ -0,0 +1,209 @@
+use bytes::Bytes;
+use image::{DynamicImage, GenericImageView};
+
+use crate::encode::{EncodeOptions, OutputFormat};
+use crate::error::Error;
+use crate::metadata::MetadataMode;
+use crate::placeholder::{self, Placeholder};
+use crate::transform::TransformOptions;
+use crate::{color, decode, encode, metadata, negotiate, transform, watermark};
+
+/// A requested width and height, either of which may be left to the aspect
+/// ratio.
+pub type Size = (Option<u32>, Option<u32>);
+
+/// Everything an image is processed with.
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    pub transform: TransformOptions,
+    /// Sizes rendered instead of the one in `transform`, each from the same
+    /// decoded image.
+    pub variants: Vec<Size>,
+    /// Encoded watermark, replacing the one of `Settings::watermark_path`.
+    pub watermark: Option<Bytes>,
+    /// 1-based page for document inputs.
+    pub page: u32,
+    /// Output format; `None` picks one from the image content.
+    pub format: Option<OutputFormat>,
+    /// Embed the input's ICC profile instead of converting to sRGB, when the
+    /// output format can carry it.
+    pub keep_profile: bool,
+    /// Apply the EXIF orientation before resizing.
+    pub auto_orient: bool,
+    /// Which EXIF/XMP/IPTC metadata is copied into the output.
+    pub metadata: MetadataMode,
+    pub encode: EncodeOptions,
+    /// A placeholder computed for each output.
+    pub placeholder: Option<Placeholder>,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            transform: TransformOptions::default(),
+            variants: Vec::new(),
+            watermark: None,
+            page: 1,
+            format: Some(OutputFormat::WebP),
+            keep_profile: false,
+            auto_orient: true,
+            metadata: MetadataMode::Strip,
+            encode: EncodeOptions::default(),
+            placeholder: None,
+        }
+    }
+}
+
+/// One encoded size.
+#[derive(Clone)]
+pub struct Variant {
+    pub bytes: Vec<u8>,
+    pub format: OutputFormat,
+    pub width: u32,
+    pub height: u32,
+    /// As requested with `ProcessOptions::placeholder`.
+    pub placeholder: Option<String>,
+}
+
+impl Variant {
+    /// Named after the output size, e.g. `800x600.webp`.
+    pub fn file_name(&self) -> String {
+        format!("{}x{}.{}", self.width, self.height, self.format.extension())
+    }
+}
+
+/// Decodes, transforms and encodes an image, once per requested size.
+/// Returns the encoded outputs in the order of `variants`.
+pub fn process_image(image_bytes: Bytes, options: ProcessOptions) -> Result<Vec<Variant>, Error> {
+    let ProcessOptions { transform: mut transform_options, variants: sizes, watermark, page, format, keep_profile, auto_orient, metadata: metadata_mode, encode: mut encode_options, placeholder: placeholder_kind } = options;
+    let image_format = decode::detect_format(&image_bytes)?;
+    let icc_profile = decode::icc_profile(&image_bytes, image_format);
+    transform_options.watermark.image = watermark::load(watermark.as_deref())?;
+    // Only a single output can be decoded straight at its target size.
+    let size_hint = if sizes.is_empty() { transform_options.decode_size_hint() } else { None };
+    let sizes = if sizes.is_empty() { vec![(transform_options.width, transform_options.height)] } else { sizes };
+    let sized = |(width, height)| TransformOptions { width, height, ..transform_options.clone() };
+
+    if format.map_or(OutputFormat::WebP.is_enabled(), |format| format == OutputFormat::WebP)
+        && let Some(frames) = decode::decode_animation(&image_bytes, image_format)?
+    {
+        let icc_profile = if keep_profile { encode_options.icc_profile = icc_profile; None } else { icc_profile };
+        encode_options.metadata = metadata::extract(&image_bytes, image_format, metadata_mode, false);
+        let frames: Vec<decode::Frame> = frames
+            .into_iter()
+            .map(|frame| match &icc_profile {
+                Some(profile) => decode::Frame { image: color::convert_to_srgb(frame.image, profile), ..frame },
+                None => frame,
+            })
+            .collect();
+        return sizes
+            .into_iter()
+            .map(|size| {
+                let options = sized(size);
+                let frames = frames
+                    .iter()
+                    .map(|frame| Ok(decode::Frame { image: transform::transform_image(frame.image.clone(), &options)?, delay_ms: frame.delay_ms }))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let (width, height) = frames.first().map_or((0, 0), |frame| frame.image.dimensions());
+                let placeholder = placeholder_kind.zip(frames.first()).map(|(kind, frame)| placeholder::encode(&frame.image, kind));
+                Ok(Variant { bytes: encode::encode_animated_webp(&frames, &encode_options)?, format: OutputFormat::WebP, width, height, placeholder })
+            })
+            .collect();
+    }
+
+    let mut img = decode::decode_image(&image_bytes, image_format, &decode::DecodeOptions { size: size_hint, page })?;
+    let orientation = auto_orient.then(|| metadata::orientation(&image_bytes, image_format)).flatten();
+    if let Some(orientation) = orientation {
+        img = metadata::apply_orientation(img, orientation);
+    }
+    encode_options.metadata = metadata::extract(&image_bytes, image_format, metadata_mode, orientation.is_some());
+
+    let format = match format {
+        Some(format) => format,
+        None => {
+            let choice = negotiate::select_for_content(&img);
+            encode_options.lossless = choice.lossless;
+            choice.format
+        }
+    };
+
+    let img = match icc_profile {
+        Some(profile) if keep_profile && format.supports_icc_profile() => {
+            encode_options.icc_profile = Some(profile);
+            img
+        }
+        Some(profile) => color::convert_to_srgb(img, &profile),
+        None => img,
+    };
+
+    let render = |img: DynamicImage, size| -> Result<Variant, Error> {
+        let img = transform::transform_image(img, &sized(size))?;
+        let (width, height) = img.dimensions();
+        let placeholder = placeholder_kind.map(|kind| placeholder::encode(&img, kind));
+        Ok(Variant { bytes: encode::encode_image(img, format, &encode_options)?, format, width, height, placeholder })
+    };
+    // The last size takes the decoded image instead of a copy.
+    let (&last, rest) = sizes.split_last().expect("at least one size");
+    let mut outputs = rest.iter().map(|&size| render(img.clone(), size)).collect::<Result<Vec<_>, Error>>()?;
+    outputs.push(render(img, last)?);
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    /// A PNG whose colors vary across the image, so that it reads as a photo.
+    fn photo(width: u32, height: u32) -> Bytes {
+        let img = RgbImage::from_fn(width, height, |x, y| Rgb([(x * 7 % 256) as u8, (y * 13 % 256) as u8, ((x * y) % 256) as u8]));
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img).write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+        bytes.into_inner().into()
+    }
+
+    #[test]
+    fn resizes_to_the_requested_width() {
+        let mut options = ProcessOptions::default();
+        options.transform.width = Some(200);
+        let outputs = process_image(photo(400, 300), options).unwrap();
+
+        assert_eq!(outputs.len(), 1);
+        let output = &outputs[0];
+        assert_eq!((output.format, output.width, output.height), (OutputFormat::WebP, 200, 150));
+        assert_eq!(image::load_from_memory(&output.bytes).unwrap().dimensions(), (200, 150));
+        assert_eq!(output.file_name(), "200x150.webp");
+    }
+
+    #[test]
+    fn renders_variants_in_the_requested_order() {
+        let options = ProcessOptions {
+            variants: vec![(Some(100), None), (Some(300), None), (None, Some(30))],
+            format: Some(OutputFormat::Png),
+            ..Default::default()
+        };
+        let outputs = process_image(photo(400, 300), options).unwrap();
+
+        let sizes: Vec<_> = outputs.iter().map(|output| (output.width, output.height)).collect();
+        assert_eq!(sizes, [(100, 75), (300, 225), (40, 30)]);
+    }
+
+    #[test]
+    fn picks_png_for_graphics_without_a_format() {
+        let img = RgbImage::from_fn(64, 64, |x, _| if x < 32 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) });
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img).write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+
+        let options = ProcessOptions { format: None, ..Default::default() };
+        let outputs = process_image(bytes.into_inner().into(), options).unwrap();
+        assert_eq!(outputs[0].format, OutputFormat::Png);
+    }
+
+    #[test]
+    fn rejects_unknown_input() {
+        let error = process_image(Bytes::from_static(b"not an image"), ProcessOptions::default()).err().unwrap();
+        assert!(matches!(error, Error::Invalid(_)));
+        assert_eq!(error.code(), "unsupported_format");
+    }
+}
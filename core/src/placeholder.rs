@@ -0,0 +1,69 @@
+//! Short strings that clients decode into a blurred preview while the image
+//! loads.
+
+use base64::Engine;
+use image::DynamicImage;
+use std::str::FromStr;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Placeholder {
+    /// A BlurHash of 4x3 components, or 3x4 for portrait images.
+    BlurHash,
+    /// A ThumbHash, base64-encoded. Unlike a BlurHash it keeps transparency
+    /// and the aspect ratio.
+    ThumbHash,
+}
+
+impl FromStr for Placeholder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "blurhash" => Ok(Placeholder::BlurHash),
+            "thumbhash" => Ok(Placeholder::ThumbHash),
+            _ => Err(Error::invalid("Unsupported placeholder. Use 'blurhash' or 'thumbhash'").with_code("invalid_placeholder")),
+        }
+    }
+}
+
+/// Largest side of the thumbnail that placeholders are computed from;
+/// ThumbHash takes at most 100 pixels, and neither gains from more detail.
+const THUMBNAIL_SIZE: u32 = 100;
+
+/// Computes a placeholder for an image as it is output.
+pub fn encode(img: &DynamicImage, placeholder: Placeholder) -> String {
+    let thumbnail = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgba8();
+    let (width, height) = thumbnail.dimensions();
+    match placeholder {
+        Placeholder::BlurHash => {
+            let (x, y) = if width >= height { (4, 3) } else { (3, 4) };
+            blurhash::encode(x, y, width, height, thumbnail.as_raw()).expect("component counts are within 1 to 9")
+        }
+        Placeholder::ThumbHash => {
+            let hash = thumbhash::rgba_to_thumb_hash(width as usize, height as usize, thumbnail.as_raw());
+            base64::engine::general_purpose::STANDARD.encode(hash)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn encodes_both_kinds() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(300, 200, |x, _| Rgba([(x % 256) as u8, 90, 160, 255])));
+
+        let blurhash = encode(&img, Placeholder::BlurHash);
+        // Size flag, maximum AC, DC and 11 AC components of 2 characters.
+        assert_eq!(blurhash.len(), 1 + 1 + 4 + 11 * 2);
+        let thumbhash = encode(&img, Placeholder::ThumbHash);
+        let decoded = base64::engine::general_purpose::STANDARD.decode(&thumbhash).unwrap();
+        // The ratio is kept only roughly, as 7/5 for 3/2.
+        let ratio = thumbhash::thumb_hash_to_approximate_aspect_ratio(&decoded).unwrap();
+        assert!((1.3..=1.6).contains(&ratio), "{}", ratio);
+    }
+}
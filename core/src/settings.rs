@@ -0,0 +1,51 @@
+use std::sync::OnceLock;
+
+use crate::decode::InputLimit;
+use crate::encode::OutputFormat;
+
+/// Process-wide settings of the pipeline, installed once before the first
+/// image is processed. Images processed without them get the defaults.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Largest input decoded.
+    pub input_limit: InputLimit,
+    /// Watermark applied when a request does not bring its own.
+    pub watermark_path: Option<String>,
+    /// SeetaFace model for `Gravity::Face`, with the `face` feature.
+    pub face_model_path: Option<String>,
+    /// Directory holding libpdfium, with the `pdf` feature; the system
+    /// library is used when `None`.
+    pub pdfium_library_path: Option<String>,
+    /// Output formats that may be produced; all of them when `None`.
+    pub enabled_formats: Option<Vec<OutputFormat>>,
+    /// Called with the dimensions of every input about to be decoded, such
+    /// as to charge them to a quota.
+    pub on_decode: fn((u32, u32)),
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            input_limit: InputLimit { width: u32::MAX, height: u32::MAX, pixels: 100_000_000 },
+            watermark_path: None,
+            face_model_path: None,
+            pdfium_library_path: None,
+            enabled_formats: None,
+            on_decode: |_| {},
+        }
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Installs the settings. Only the first call takes effect, and none after
+/// an image was processed with the defaults.
+pub fn install(settings: Settings) {
+    if SETTINGS.set(settings).is_err() {
+        tracing::warn!("Ignoring pipeline settings installed a second time");
+    }
+}
+
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
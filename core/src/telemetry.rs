@@ -0,0 +1,16 @@
+use metrics::histogram;
+use std::time::Instant;
+use tracing::Span;
+
+use crate::encode::OutputFormat;
+
+/// A span for one step of the pipeline, named after it in exported traces.
+pub fn op_span(op: &'static str) -> Span {
+    tracing::info_span!("transform", op, otel.name = op)
+}
+
+/// Records how long an output took to encode, for whichever `metrics`
+/// recorder the application installed.
+pub fn record_encode(format: OutputFormat, started: Instant) {
+    histogram!("image_encode_duration_seconds", "format" => format.extension()).record(started.elapsed().as_secs_f64());
+}
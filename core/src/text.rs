@@ -0,0 +1,130 @@
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{DynamicImage, Rgba};
+use std::sync::LazyLock;
+
+use crate::telemetry;
+use crate::transform::{self, Gravity};
+
+/// Font used for captions, embedded so the service has no system font
+/// dependencies.
+static FONT: LazyLock<FontRef<'static>> = LazyLock::new(|| {
+    FontRef::try_from_slice(include_bytes!("../assets/fonts/DejaVuSans.ttf")).expect("embedded font is valid")
+});
+
+/// A caption drawn onto the output.
+#[derive(Debug, Clone)]
+pub struct TextOptions {
+    /// Caption text; lines are separated by `\n` and wrapped to the image
+    /// width. Nothing is drawn when `None`.
+    pub text: Option<String>,
+    /// Font size in pixels.
+    pub size: f32,
+    pub color: Rgba<u8>,
+    /// Corner, edge or center of the image the caption is anchored to.
+    pub position: Gravity,
+}
+
+impl Default for TextOptions {
+    fn default() -> Self {
+        Self { text: None, size: 48.0, color: Rgba([255, 255, 255, 255]), position: Gravity::South }
+    }
+}
+
+/// Largest accepted font size, in pixels.
+pub const MAX_TEXT_SIZE: f32 = 1000.0;
+
+/// Draws the caption, if any, onto the image, keeping its bit depth.
+pub fn apply(img: DynamicImage, options: &TextOptions) -> DynamicImage {
+    let Some(text) = options.text.as_deref().filter(|text| !text.trim().is_empty()) else {
+        return img;
+    };
+    let _span = telemetry::op_span("text").entered();
+
+    let font = FONT.as_scaled(PxScale::from(options.size));
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    let margin = options.size / 2.0;
+    let lines = wrap(text, &font, (width - 2.0 * margin).max(options.size));
+
+    let line_height = font.height() + font.line_gap();
+    let block_height = line_height * lines.len() as f32 - font.line_gap();
+    let (fx, fy) = options.position.focal_point();
+    let top = margin + (height - 2.0 * margin - block_height).max(0.0) * fy as f32;
+
+    let high_bit_depth = transform::is_high_bit_depth(&img);
+    let alpha = img.color().has_alpha();
+    let mut pixels = img.into_rgba32f();
+    let color = options.color.0.map(|c| f32::from(c) / 255.0);
+
+    for (index, line) in lines.iter().enumerate() {
+        let line_width = line_width(line, &font);
+        let left = margin + (width - 2.0 * margin - line_width).max(0.0) * fx as f32;
+        let baseline = top + index as f32 * line_height + font.ascent();
+
+        let mut caret = left;
+        let mut previous = None;
+        for c in line.chars() {
+            let id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += font.kern(previous, id);
+            }
+            previous = Some(id);
+
+            let glyph = id.with_scale_and_position(font.scale(), point(caret, baseline));
+            caret += font.h_advance(id);
+            let Some(outline) = FONT.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|x, y, coverage| {
+                let (x, y) = (bounds.min.x as i64 + i64::from(x), bounds.min.y as i64 + i64::from(y));
+                let (Ok(x), Ok(y)) = (u32::try_from(x), u32::try_from(y)) else {
+                    return;
+                };
+                if x >= pixels.width() || y >= pixels.height() {
+                    return;
+                }
+                let pixel = pixels.get_pixel_mut(x, y);
+                let strength = coverage * color[3];
+                for channel in 0..3 {
+                    pixel[channel] += (color[channel] - pixel[channel]) * strength;
+                }
+                pixel[3] += (1.0 - pixel[3]) * strength;
+            });
+        }
+    }
+
+    transform::from_rgba32f(pixels, high_bit_depth, alpha)
+}
+
+/// Splits the text into lines at `\n` and wherever a word would overflow
+/// `max_width`. Words wider than a whole line are left to overflow.
+fn wrap(text: &str, font: &impl ScaleFont<&'static FontRef<'static>>, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let candidate = if line.is_empty() { word.to_string() } else { format!("{} {}", line, word) };
+            if !line.is_empty() && line_width(&candidate, font) > max_width {
+                lines.push(std::mem::replace(&mut line, word.to_string()));
+            } else {
+                line = candidate;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn line_width<F: Font>(line: &str, font: &impl ScaleFont<F>) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in line.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, id);
+        }
+        width += font.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
@@ -0,0 +1,862 @@
+use image::{imageops::{self, FilterType}, DynamicImage, ImageBuffer, Pixel, Rgba, Rgba32FImage};
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::filter::{self, Adjustments, Filter, RedactMode};
+use crate::text::{self, TextOptions};
+use crate::watermark::{self, WatermarkOptions};
+use crate::ops::{self, Operation};
+use crate::{face, smartcrop, telemetry};
+
+/// A rectangular region in pixels, parsed from `x,y,width,height`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::invalid("Invalid region format. Use 'X,Y,WIDTH,HEIGHT'").with_code("invalid_region");
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Region { x, y, width, height }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Region {
+    /// Clamps the region to a `width`x`height` image. `usage` names the
+    /// parameter in the error for regions that start outside the image.
+    pub fn clamp_to(self, width: u32, height: u32, usage: &str) -> Result<Region, Error> {
+        if self.x >= width || self.y >= height {
+            return Err(Error::invalid(format!("{} region starts outside the {}x{} image", usage, width, height),
+            ).with_code("invalid_region"));
+        }
+        Ok(Region { width: self.width.min(width - self.x), height: self.height.min(height - self.y), ..self })
+    }
+}
+
+/// How an image is fitted into the requested size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Scale to cover the box, cropping the overflow from the center.
+    Cover,
+    /// Scale to fit within the box and pad the rest with the background color.
+    Contain,
+    /// Stretch to the exact size, ignoring the aspect ratio.
+    Fill,
+    /// Scale to fit within the box, preserving the aspect ratio.
+    #[default]
+    Inside,
+    /// Scale to cover the box, preserving the aspect ratio without cropping.
+    Outside,
+}
+
+impl FromStr for Fit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cover" => Ok(Fit::Cover),
+            "contain" => Ok(Fit::Contain),
+            "fill" => Ok(Fit::Fill),
+            "inside" => Ok(Fit::Inside),
+            "outside" => Ok(Fit::Outside),
+            _ => Err(Error::invalid("Fit must be 'cover', 'contain', 'fill', 'inside' or 'outside'",
+            ).with_code("invalid_fit")),
+        }
+    }
+}
+
+/// Which part of the image is kept when `Fit::Cover` crops it, and where
+/// `Fit::Contain` places it on the padded canvas.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+    /// A point given as fractions of the width and height.
+    Focal(f64, f64),
+    /// The region with the most detail, found by analyzing the image.
+    Smart,
+    /// The detected faces, falling back to `Smart` when there are none.
+    Face,
+}
+
+impl Gravity {
+    /// The point, as fractions of the width and height, that the visible
+    /// window is centered on as far as the image bounds allow. Content-aware
+    /// gravities fall back to the center where no image is analyzed.
+    pub fn focal_point(self) -> (f64, f64) {
+        match self {
+            Gravity::Center => (0.5, 0.5),
+            Gravity::North => (0.5, 0.0),
+            Gravity::NorthEast => (1.0, 0.0),
+            Gravity::East => (1.0, 0.5),
+            Gravity::SouthEast => (1.0, 1.0),
+            Gravity::South => (0.5, 1.0),
+            Gravity::SouthWest => (0.0, 1.0),
+            Gravity::West => (0.0, 0.5),
+            Gravity::NorthWest => (0.0, 0.0),
+            Gravity::Focal(x, y) => (x, y),
+            Gravity::Smart | Gravity::Face => (0.5, 0.5),
+        }
+    }
+}
+
+impl FromStr for Gravity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_ascii_lowercase();
+        if let Some(point) = value.strip_prefix("focal:") {
+            let invalid = || Error::invalid("Invalid focal point. Use 'focal:X,Y' with percentages between 0 and 100").with_code("invalid_gravity");
+            let (x, y) = point.split_once(',').ok_or_else(invalid)?;
+            let parse = |v: &str| v.trim().parse::<f64>().ok().filter(|p| (0.0..=100.0).contains(p)).ok_or_else(invalid);
+            return Ok(Gravity::Focal(parse(x)? / 100.0, parse(y)? / 100.0));
+        }
+
+        match value.as_str() {
+            "center" | "centre" => Ok(Gravity::Center),
+            "north" => Ok(Gravity::North),
+            "northeast" => Ok(Gravity::NorthEast),
+            "east" => Ok(Gravity::East),
+            "southeast" => Ok(Gravity::SouthEast),
+            "south" => Ok(Gravity::South),
+            "southwest" => Ok(Gravity::SouthWest),
+            "west" => Ok(Gravity::West),
+            "northwest" => Ok(Gravity::NorthWest),
+            "smart" => Ok(Gravity::Smart),
+            "face" => Ok(Gravity::Face),
+            _ => Err(Error::invalid("Gravity must be 'center', a compass direction like 'north' or 'southwest', 'smart', 'face', or 'focal:X,Y'",
+            ).with_code("invalid_gravity")),
+        }
+    }
+}
+
+/// Corner radius for rounded corners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Radius {
+    Pixels(u32),
+    /// Half the shorter side, which turns square images into circles.
+    Max,
+}
+
+impl FromStr for Radius {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "max" => Ok(Radius::Max),
+            value => value.parse::<u32>()
+                .map(Radius::Pixels)
+                .map_err(|_| Error::invalid("Radius must be a number of pixels or 'max'").with_code("invalid_radius")),
+        }
+    }
+}
+
+/// A uniform band of color added around the image, parsed from
+/// `width,color` with the color optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub color: Rgba<u8>,
+}
+
+/// Widest accepted padding or border, in pixels.
+pub const MAX_FRAME_WIDTH: u32 = 1000;
+
+impl Frame {
+    /// Parses `width[,color]`, using `default_color` when no color is given.
+    /// `field` names the parameter in errors.
+    pub fn parse(value: &str, field: &str, default_color: Rgba<u8>) -> Result<Frame, Error> {
+        let (width, color) = match value.split_once(',') {
+            Some((width, color)) => (width, parse_color(color)?),
+            None => (value, default_color),
+        };
+        let width = width.trim().parse::<u32>()
+            .ok()
+            .filter(|width| *width <= MAX_FRAME_WIDTH)
+            .ok_or_else(|| Error::invalid(format!("'{}' must be a width in pixels up to {}, optionally followed by ',COLOR'", field, MAX_FRAME_WIDTH),
+            ).with_code("invalid_frame"))?;
+        Ok(Frame { width, color })
+    }
+
+    /// Builds a frame from already separated parts, with the same limits as
+    /// `parse`.
+    pub fn new(width: u32, color: Option<&str>, field: &str, default_color: Rgba<u8>) -> Result<Frame, Error> {
+        if width > MAX_FRAME_WIDTH {
+            return Err(Error::invalid(format!("'{}' width must be at most {} pixels", field, MAX_FRAME_WIDTH)).with_code("invalid_frame"));
+        }
+        let color = color.map_or(Ok(default_color), parse_color)?;
+        Ok(Frame { width, color })
+    }
+
+    pub fn apply(self, img: DynamicImage) -> DynamicImage {
+        let (width, height) = (img.width() + 2 * self.width, img.height() + 2 * self.width);
+        pad(img, width, height, self.color, (0.5, 0.5))
+    }
+}
+
+/// Parses an aspect ratio as `W:H` or a decimal number.
+pub fn parse_aspect_ratio(value: &str) -> Result<f64, Error> {
+    let parse = |v: &str| v.trim().parse::<f64>().ok().filter(|v| v.is_finite() && *v > 0.0);
+    let ratio = match value.split_once(':') {
+        Some((width, height)) => parse(width).zip(parse(height)).map(|(width, height)| width / height),
+        None => parse(value),
+    };
+    ratio.ok_or_else(|| Error::invalid("Invalid aspect ratio. Use 'W:H' like '16:9' or a number like '1.5'").with_code("invalid_aspect_ratio"))
+}
+
+/// Parses `WIDTHxHEIGHT`, where either side may be left out (`800x`, `x600`,
+/// or just `800`) to be derived from the aspect ratio. Sides of 0 are
+/// rejected, since nothing can be resized to them.
+pub fn parse_size(size_str: &str) -> Result<(Option<u32>, Option<u32>), Error> {
+    let parts: Vec<&str> = size_str.split('x').collect();
+    let (width, height) = match parts[..] {
+        [width] => (width, ""),
+        [width, height] => (width, height),
+        _ => return Err(Error::invalid("Invalid size format. Use 'WIDTHxHEIGHT'").with_code("invalid_size")),
+    };
+    let side = |value: &str, error: &str| match value {
+        "" => Ok(None),
+        value => value.parse::<u32>().ok().filter(|side| *side > 0).map(Some)
+            .ok_or_else(|| Error::invalid(error).with_code("invalid_size")),
+    };
+    let width = side(width, "Width must be a whole number of at least 1 pixel")?;
+    let height = side(height, "Height must be a whole number of at least 1 pixel")?;
+    if width.is_none() && height.is_none() {
+        return Err(Error::invalid("Invalid size format. Use 'WIDTHxHEIGHT'").with_code("invalid_size"));
+    }
+    Ok((width, height))
+}
+
+/// Parses an anchor for overlays: `center` or a compass direction. `field`
+/// names the parameter in the error.
+pub fn parse_anchor(value: &str, field: &str) -> Result<Gravity, Error> {
+    match value.parse::<Gravity>() {
+        Ok(Gravity::Smart | Gravity::Face | Gravity::Focal(..)) | Err(_) => Err(Error::invalid(format!("'{}' must be 'center' or a compass direction like 'north' or 'southeast'", field),
+        ).with_code("invalid_gravity")),
+        Ok(anchor) => Ok(anchor),
+    }
+}
+
+/// Mirrors the image along one axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+impl FromStr for Flip {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "h" | "horizontal" => Ok(Flip::Horizontal),
+            "v" | "vertical" => Ok(Flip::Vertical),
+            _ => Err(Error::invalid("Flip must be 'h' or 'v'").with_code("invalid_flip")),
+        }
+    }
+}
+
+/// Largest box a request may resize to. Larger boxes are either scaled down
+/// to fit, keeping their shape, or rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimit {
+    pub width: u32,
+    pub height: u32,
+    pub clamp: bool,
+}
+
+impl SizeLimit {
+    fn apply(self, (width, height): (u32, u32)) -> Result<(u32, u32), Error> {
+        if width <= self.width && height <= self.height {
+            return Ok((width, height));
+        }
+        if !self.clamp {
+            let (side, requested, limit) = if width > self.width { ("width", width, self.width) } else { ("height", height, self.height) };
+            return Err(Error::invalid(format!("Requested {} of {} pixels exceeds the server limit of {}", side, requested, limit),
+            ).with_code("size_limit_exceeded"));
+        }
+        let scale = (self.width as f64 / width as f64).min(self.height as f64 / height as f64);
+        let side = |length: u32| (length as f64 * scale).floor().max(1.0) as u32;
+        Ok((side(width), side(height)))
+    }
+}
+
+/// Parses a resampling filter name.
+pub fn parse_resize_filter(value: &str) -> Result<FilterType, Error> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "lanczos3" => Ok(FilterType::Lanczos3),
+        "catmullrom" => Ok(FilterType::CatmullRom),
+        "triangle" => Ok(FilterType::Triangle),
+        "nearest" => Ok(FilterType::Nearest),
+        _ => Err(Error::invalid("Resize filter must be 'lanczos3', 'catmullrom', 'triangle' or 'nearest'",
+        ).with_code("invalid_resize_filter")),
+    }
+}
+
+/// Parses a rotation in degrees clockwise, normalized to `[0, 360)`.
+pub fn parse_rotation(value: &str) -> Result<f64, Error> {
+    value.trim().parse::<f64>()
+        .ok()
+        .filter(|degrees| degrees.is_finite())
+        .map(|degrees| degrees.rem_euclid(360.0))
+        .ok_or_else(|| Error::invalid("Rotate must be an angle in degrees").with_code("invalid_rotation"))
+}
+
+/// Parses a hex color as `RGB`, `RRGGBB` or `RRGGBBAA`, with an optional
+/// leading `#`.
+pub fn parse_color(value: &str) -> Result<Rgba<u8>, Error> {
+    let invalid = || Error::invalid("Invalid color. Use a hex value like '#RRGGBB' or '#RRGGBBAA'").with_code("invalid_color");
+    let hex = value.trim().trim_start_matches('#');
+    if !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let expanded: String = match hex.len() {
+        3 => hex.chars().flat_map(|c| [c, c]).chain("ff".chars()).collect(),
+        6 => format!("{}ff", hex),
+        8 => hex.to_string(),
+        _ => return Err(invalid()),
+    };
+
+    let mut channels = [0u8; 4];
+    for (channel, digits) in channels.iter_mut().zip(expanded.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *channel = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    Ok(Rgba(channels))
+}
+
+/// Geometry changes, filters and overlays applied to every image or
+/// animation frame.
+#[derive(Debug, Clone)]
+pub struct TransformOptions {
+    /// Box the image is resized into according to `fit`. A missing side
+    /// follows from `aspect_ratio`, or else from the image itself.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Width divided by height of the box.
+    pub aspect_ratio: Option<f64>,
+    pub fit: Fit,
+    /// Shrink the box rather than scale the image up beyond its own size.
+    pub no_enlarge: bool,
+    /// Server limit on the resolved box.
+    pub size_limit: Option<SizeLimit>,
+    /// Resampling filter used whenever the image is scaled.
+    pub resize_filter: FilterType,
+    pub gravity: Gravity,
+    /// Padding color for `Fit::Contain` and the corners uncovered by
+    /// rotating by arbitrary angles.
+    pub background: Rgba<u8>,
+    /// Degrees clockwise, applied before cropping and resizing.
+    pub rotate: f64,
+    pub flip: Option<Flip>,
+    /// Removes uniform borders before cropping and resizing.
+    pub trim: bool,
+    /// Per-channel difference (0-255) still counted as border color.
+    pub trim_tolerance: u8,
+    /// Gaussian blur sigma, applied after all geometry changes.
+    pub blur: Option<f32>,
+    /// Unsharp mask amount, applied after blurring.
+    pub sharpen: Option<f32>,
+    /// Brightness, contrast, saturation and gamma, applied after sharpening.
+    pub adjustments: Adjustments,
+    /// Color filter applied after the adjustments.
+    pub filter: Option<Filter>,
+    /// Caption drawn after the filter.
+    pub text: TextOptions,
+    /// Watermark composited onto the finished image.
+    pub watermark: WatermarkOptions,
+    /// Padding added around the finished image, inside the border.
+    pub pad: Option<Frame>,
+    pub border: Option<Frame>,
+    /// Rounds the corners of the finished image, making them transparent.
+    pub radius: Option<Radius>,
+    /// Steps from `ops`, run in order after redaction and before the
+    /// individual options below.
+    pub ops: Vec<Operation>,
+    /// Regions to pixelate or black out before any other change.
+    pub redact: Vec<Region>,
+    pub redact_mode: RedactMode,
+    pub crop: Option<Region>,
+    /// Crop the resized image instead of the original.
+    pub crop_after_resize: bool,
+}
+
+impl Default for TransformOptions {
+    fn default() -> Self {
+        Self {
+            width: None,
+            height: None,
+            aspect_ratio: None,
+            fit: Fit::Inside,
+            no_enlarge: false,
+            size_limit: None,
+            resize_filter: FilterType::Lanczos3,
+            gravity: Gravity::Center,
+            background: Rgba([0, 0, 0, 0]),
+            rotate: 0.0,
+            flip: None,
+            trim: false,
+            trim_tolerance: 10,
+            blur: None,
+            sharpen: None,
+            adjustments: Adjustments::default(),
+            filter: None,
+            text: TextOptions::default(),
+            watermark: WatermarkOptions::default(),
+            pad: None,
+            border: None,
+            radius: None,
+            ops: Vec::new(),
+            redact: Vec::new(),
+            redact_mode: RedactMode::Pixelate,
+            crop: None,
+            crop_after_resize: false,
+        }
+    }
+}
+
+impl TransformOptions {
+    /// Size that vector and document inputs can be rendered at directly, when
+    /// the result would only be scaled down to fit within it anyway.
+    pub fn decode_size_hint(&self) -> Option<(u32, u32)> {
+        let crops_original = self.crop.is_some() && !self.crop_after_resize;
+        let fits_inside = matches!(self.fit, Fit::Inside | Fit::Contain);
+        let size = self.width.zip(self.height);
+        size.filter(|_| fits_inside && !crops_original && self.rotate == 0.0 && !self.trim && self.ops.is_empty())
+            .and_then(|size| self.size_limit.map_or(Some(size), |limit| limit.apply(size).ok()))
+    }
+
+    /// Resolves the box for an image of the given size. With only an aspect
+    /// ratio, the box is the largest one of that ratio within the image, or
+    /// the smallest one around it for `Fit::Contain`. With `no_enlarge`, a
+    /// box that would scale the image up is shrunk, keeping its shape.
+    pub fn target_size(&self, (image_width, image_height): (u32, u32)) -> Option<(u32, u32)> {
+        let ratio = self.aspect_ratio.unwrap_or(image_width as f64 / image_height as f64);
+        let side = |length: f64| length.round().max(1.0) as u32;
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, side(width as f64 / ratio)),
+            (None, Some(height)) => (side(height as f64 * ratio), height),
+            (None, None) => {
+                let ratio = self.aspect_ratio?;
+                let wider = image_width as f64 / image_height as f64 > ratio;
+                // Padding grows the box around the image, everything else shrinks it.
+                if wider == (self.fit != Fit::Contain) {
+                    (side(image_height as f64 * ratio), image_height)
+                } else {
+                    (image_width, side(image_width as f64 / ratio))
+                }
+            }
+        };
+
+        let (scale_x, scale_y) = (width as f64 / image_width as f64, height as f64 / image_height as f64);
+        let scale = match self.fit {
+            Fit::Inside | Fit::Contain => scale_x.min(scale_y),
+            Fit::Cover | Fit::Outside | Fit::Fill => scale_x.max(scale_y),
+        };
+        if self.no_enlarge && scale > 1.0 {
+            return Some((side(width as f64 / scale), side(height as f64 / scale)));
+        }
+        Some((width, height))
+    }
+}
+
+/// Applies the requested transformations to a single image or animation frame.
+pub fn transform_image(mut img: DynamicImage, options: &TransformOptions) -> Result<DynamicImage, Error> {
+    if !options.redact.is_empty() {
+        let _span = telemetry::op_span("redact").entered();
+        let regions = options.redact
+            .iter()
+            .map(|region| region.clamp_to(img.width(), img.height(), "Redact"))
+            .collect::<Result<Vec<_>, _>>()?;
+        img = filter::redact(img, &regions, options.redact_mode);
+    }
+    if options.rotate != 0.0 {
+        let _span = telemetry::op_span("rotate").entered();
+        img = rotate(img, options.rotate, options.background);
+    }
+    img = match options.flip {
+        Some(Flip::Horizontal) => telemetry::op_span("flip").in_scope(|| img.fliph()),
+        Some(Flip::Vertical) => telemetry::op_span("flip").in_scope(|| img.flipv()),
+        None => img,
+    };
+    if options.trim {
+        let _span = telemetry::op_span("trim").entered();
+        img = trim(img, options.trim_tolerance);
+    }
+    if !options.ops.is_empty() {
+        img = ops::apply(img, &options.ops, options)?;
+    }
+    if let Some(crop) = options.crop.filter(|_| !options.crop_after_resize) {
+        let _span = telemetry::op_span("crop").entered();
+        img = crop_image(img, crop)?;
+    }
+    img = telemetry::op_span("resize").in_scope(|| resize_to_target(img, options))?;
+    if let Some(crop) = options.crop.filter(|_| options.crop_after_resize) {
+        let _span = telemetry::op_span("crop").entered();
+        img = crop_image(img, crop)?;
+    }
+    if let Some(sigma) = options.blur {
+        let _span = telemetry::op_span("blur").entered();
+        img = filter::blur(img, sigma);
+    }
+    if let Some(amount) = options.sharpen {
+        let _span = telemetry::op_span("sharpen").entered();
+        img = filter::sharpen(img, amount);
+    }
+    if !options.adjustments.is_identity() {
+        let _span = telemetry::op_span("adjust").entered();
+        img = options.adjustments.apply(img);
+    }
+    if let Some(filter) = options.filter {
+        let _span = telemetry::op_span("filter").entered();
+        img = filter.apply(img);
+    }
+    img = text::apply(img, &options.text);
+    img = watermark::apply(img, &options.watermark);
+    if let Some(pad) = options.pad {
+        let _span = telemetry::op_span("pad").entered();
+        img = pad.apply(img);
+    }
+    if let Some(border) = options.border {
+        let _span = telemetry::op_span("border").entered();
+        img = border.apply(img);
+    }
+    if let Some(radius) = options.radius {
+        let _span = telemetry::op_span("radius").entered();
+        img = round_corners(img, radius);
+    }
+
+    Ok(img)
+}
+
+/// Crops away rows and columns at the edges that match the top-left pixel
+/// within `tolerance` on every channel. Uniform images are left unchanged.
+pub fn trim(img: DynamicImage, tolerance: u8) -> DynamicImage {
+    let pixels = img.to_rgba8();
+    let reference = *pixels.get_pixel(0, 0);
+    let matches = |x: u32, y: u32| {
+        pixels.get_pixel(x, y).0.iter().zip(reference.0).all(|(&channel, reference)| channel.abs_diff(reference) <= tolerance)
+    };
+    let (width, height) = pixels.dimensions();
+    let row_matches = |y: u32| (0..width).all(|x| matches(x, y));
+    let column_matches = |x: u32, top: u32, bottom: u32| (top..bottom).all(|y| matches(x, y));
+
+    let Some(top) = (0..height).find(|&y| !row_matches(y)) else {
+        return img;
+    };
+    let bottom = (top..height).rev().find(|&y| !row_matches(y)).map_or(height, |y| y + 1);
+    let left = (0..width).find(|&x| !column_matches(x, top, bottom)).unwrap_or(0);
+    let right = (left..width).rev().find(|&x| !column_matches(x, top, bottom)).map_or(width, |x| x + 1);
+
+    img.crop_imm(left, top, right - left, bottom - top)
+}
+
+/// Crops to `crop`, clamped to the image bounds.
+pub fn crop_image(img: DynamicImage, crop: Region) -> Result<DynamicImage, Error> {
+    let crop = crop.clamp_to(img.width(), img.height(), "Crop")?;
+    Ok(img.crop_imm(crop.x, crop.y, crop.width, crop.height))
+}
+
+/// Resizes into the box requested by `options`, if any, within its size
+/// limit.
+pub fn resize_to_target(img: DynamicImage, options: &TransformOptions) -> Result<DynamicImage, Error> {
+    let Some(size) = options.target_size((img.width(), img.height())) else {
+        return Ok(img);
+    };
+    let (width, height) = options.size_limit.map_or(Ok(size), |limit| limit.apply(size))?;
+    if (width, height) == (img.width(), img.height()) {
+        return Ok(img);
+    }
+    resize(img, width, height, options)
+}
+
+fn resize(img: DynamicImage, width: u32, height: u32, options: &TransformOptions) -> Result<DynamicImage, Error> {
+    let focal_point = options.gravity.focal_point();
+    let filter = options.resize_filter;
+    Ok(match options.fit {
+        Fit::Cover => {
+            // Detect on the original, where small faces are still large enough to find.
+            let face = match options.gravity {
+                Gravity::Face => face::focal_point(&img)?,
+                _ => None,
+            };
+            let img = resize_to_cover(img, width, height, filter);
+            let (x, y) = match (options.gravity, face) {
+                (_, Some(face)) => window_offset((img.width(), img.height()), (width, height), face),
+                (Gravity::Smart | Gravity::Face, None) => smartcrop::best_window(&img, width, height),
+                _ => window_offset((img.width(), img.height()), (width, height), focal_point),
+            };
+            img.crop_imm(x, y, width.min(img.width()), height.min(img.height()))
+        }
+        Fit::Contain => pad(img.resize(width, height, filter), width, height, options.background, focal_point),
+        Fit::Fill => img.resize_exact(width, height, filter),
+        Fit::Inside => img.resize(width, height, filter),
+        Fit::Outside => resize_to_cover(img, width, height, filter),
+    })
+}
+
+/// Rotates clockwise by `degrees`. Right angles are exact; other angles
+/// enlarge the canvas to fit the rotated image and fill the uncovered
+/// corners with `background`.
+pub fn rotate(img: DynamicImage, degrees: f64, background: Rgba<u8>) -> DynamicImage {
+    match degrees {
+        90.0 => return img.rotate90(),
+        180.0 => return img.rotate180(),
+        270.0 => return img.rotate270(),
+        _ => {}
+    }
+
+    let high_bit_depth = is_high_bit_depth(&img);
+    let needs_alpha = img.color().has_alpha() || background[3] < u8::MAX;
+    let source = premultiply(img.into_rgba32f());
+    let (source_width, source_height) = (source.width() as f64, source.height() as f64);
+
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let width = (source_width * cos.abs() + source_height * sin.abs()).round().max(1.0);
+    let height = (source_width * sin.abs() + source_height * cos.abs()).round().max(1.0);
+    let fill = premultiply_pixel(Rgba(background.0.map(|c| f32::from(c) / 255.0)));
+
+    let sample = |x: i64, y: i64| match (u32::try_from(x), u32::try_from(y)) {
+        (Ok(x), Ok(y)) if x < source.width() && y < source.height() => *source.get_pixel(x, y),
+        _ => fill,
+    };
+    let rotated = Rgba32FImage::from_fn(width as u32, height as u32, |x, y| {
+        // Map the output pixel center back into the source image.
+        let (dx, dy) = (x as f64 + 0.5 - width / 2.0, y as f64 + 0.5 - height / 2.0);
+        let sx = dx * cos + dy * sin + source_width / 2.0 - 0.5;
+        let sy = -dx * sin + dy * cos + source_height / 2.0 - 0.5;
+        let (x0, y0) = (sx.floor() as i64, sy.floor() as i64);
+        let (fx, fy) = ((sx - sx.floor()) as f32, (sy - sy.floor()) as f32);
+
+        let mut pixel = [0.0; 4];
+        for (corner, weight) in [((0, 0), (1.0 - fx) * (1.0 - fy)), ((1, 0), fx * (1.0 - fy)), ((0, 1), (1.0 - fx) * fy), ((1, 1), fx * fy)] {
+            let value = sample(x0 + corner.0, y0 + corner.1);
+            for (channel, v) in pixel.iter_mut().zip(value.0) {
+                *channel += v * weight;
+            }
+        }
+        Rgba(pixel)
+    });
+
+    from_rgba32f(unpremultiply(rotated), high_bit_depth, needs_alpha)
+}
+
+/// Converts a working copy back to 8 or 16 bits per channel, dropping the
+/// alpha channel when it is not needed.
+pub fn from_rgba32f(img: Rgba32FImage, high_bit_depth: bool, alpha: bool) -> DynamicImage {
+    let img = DynamicImage::ImageRgba32F(img);
+    match (high_bit_depth, alpha) {
+        (true, true) => DynamicImage::ImageRgba16(img.to_rgba16()),
+        (true, false) => DynamicImage::ImageRgb16(img.to_rgb16()),
+        (false, true) => DynamicImage::ImageRgba8(img.to_rgba8()),
+        (false, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+    }
+}
+
+/// Interpolating premultiplied colors keeps transparent neighbours from
+/// darkening the edges.
+fn premultiply(mut img: Rgba32FImage) -> Rgba32FImage {
+    img.pixels_mut().for_each(|pixel| *pixel = premultiply_pixel(*pixel));
+    img
+}
+
+fn premultiply_pixel(Rgba([r, g, b, a]): Rgba<f32>) -> Rgba<f32> {
+    Rgba([r * a, g * a, b * a, a])
+}
+
+fn unpremultiply(mut img: Rgba32FImage) -> Rgba32FImage {
+    for Rgba([r, g, b, a]) in img.pixels_mut() {
+        if *a > 0.0 {
+            *r /= *a;
+            *g /= *a;
+            *b /= *a;
+        }
+    }
+    img
+}
+
+/// Makes everything outside the rounded rectangle transparent, with
+/// anti-aliased edges, keeping 16-bit images at full precision.
+pub fn round_corners(img: DynamicImage, radius: Radius) -> DynamicImage {
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    let radius = match radius {
+        Radius::Pixels(pixels) => (pixels as f32).min(width.min(height) / 2.0),
+        Radius::Max => width.min(height) / 2.0,
+    };
+    if radius <= 0.0 {
+        return img;
+    }
+
+    // Coverage of the pixel centered at (x, y): distance from the nearest
+    // corner circle, smoothed over one pixel.
+    let coverage = |x: u32, y: u32| {
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let cx = px.clamp(radius, width - radius);
+        let cy = py.clamp(radius, height - radius);
+        let distance = ((px - cx).powi(2) + (py - cy).powi(2)).sqrt();
+        (radius - distance + 0.5).clamp(0.0, 1.0)
+    };
+
+    if is_high_bit_depth(&img) {
+        let mut pixels = img.into_rgba16();
+        for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+            pixel[3] = (f32::from(pixel[3]) * coverage(x, y)).round() as u16;
+        }
+        DynamicImage::ImageRgba16(pixels)
+    } else {
+        let mut pixels = img.into_rgba8();
+        for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+            pixel[3] = (f32::from(pixel[3]) * coverage(x, y)).round() as u8;
+        }
+        DynamicImage::ImageRgba8(pixels)
+    }
+}
+
+/// Scales the image, preserving its aspect ratio, so that it covers the box.
+fn resize_to_cover(img: DynamicImage, width: u32, height: u32, filter: FilterType) -> DynamicImage {
+    let scale = (width as f64 / img.width() as f64).max(height as f64 / img.height() as f64);
+    let outer_width = (img.width() as f64 * scale).round().max(1.0) as u32;
+    let outer_height = (img.height() as f64 * scale).round().max(1.0) as u32;
+    img.resize_exact(outer_width, outer_height, filter)
+}
+
+/// Offset of a `window` inside `outer` centered on `focal_point`, clamped to
+/// stay within bounds.
+fn window_offset(outer: (u32, u32), window: (u32, u32), focal_point: (f64, f64)) -> (u32, u32) {
+    let axis = |outer: u32, window: u32, focus: f64| {
+        let max = outer.saturating_sub(window) as f64;
+        (focus * outer as f64 - window as f64 / 2.0).clamp(0.0, max).round() as u32
+    };
+    (axis(outer.0, window.0, focal_point.0), axis(outer.1, window.1, focal_point.1))
+}
+
+/// Places the image on a `width`x`height` canvas filled with `background`,
+/// as close to `focal_point` as the canvas allows, keeping 16-bit images at
+/// full precision.
+fn pad(img: DynamicImage, width: u32, height: u32, background: Rgba<u8>, focal_point: (f64, f64)) -> DynamicImage {
+    let x = (width.saturating_sub(img.width()) as f64 * focal_point.0).round() as i64;
+    let y = (height.saturating_sub(img.height()) as f64 * focal_point.1).round() as i64;
+    let needs_alpha = img.color().has_alpha() || background[3] < u8::MAX;
+    let high_bit_depth = is_high_bit_depth(&img);
+
+    match (high_bit_depth, needs_alpha) {
+        (true, true) => DynamicImage::ImageRgba16(overlay(img.to_rgba16(), width, height, background.0.map(widen), x, y)),
+        (true, false) => DynamicImage::ImageRgb16(overlay(img.to_rgb16(), width, height, background.to_rgb().0.map(widen), x, y)),
+        (false, true) => DynamicImage::ImageRgba8(overlay(img.to_rgba8(), width, height, background.0, x, y)),
+        (false, false) => DynamicImage::ImageRgb8(overlay(img.to_rgb8(), width, height, background.to_rgb().0, x, y)),
+    }
+}
+
+fn overlay<P: Pixel>(top: ImageBuffer<P, Vec<P::Subpixel>>, width: u32, height: u32, fill: impl Into<P>, x: i64, y: i64) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let mut canvas = ImageBuffer::from_pixel(width, height, fill.into());
+    imageops::replace(&mut canvas, &top, x, y);
+    canvas
+}
+
+pub fn is_high_bit_depth(img: &DynamicImage) -> bool {
+    matches!(img, DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_) | DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_))
+}
+
+fn widen(channel: u8) -> u16 {
+    u16::from(channel) * 257
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    #[test]
+    fn parses_sizes_with_either_side_left_out() {
+        assert_eq!(parse_size("800x600").unwrap(), (Some(800), Some(600)));
+        assert_eq!(parse_size("800x").unwrap(), (Some(800), None));
+        assert_eq!(parse_size("x600").unwrap(), (None, Some(600)));
+        assert_eq!(parse_size("800").unwrap(), (Some(800), None));
+        for invalid in ["x", "", "800x600x1", "-1x5", "widex", "0x100", "100x0"] {
+            assert_eq!(parse_size(invalid).unwrap_err().code(), "invalid_size", "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn keeps_every_side_above_zero() {
+        for zero in ["0x", "x0", "0"] {
+            assert_eq!(parse_size(zero).unwrap_err().code(), "invalid_size", "{:?}", zero);
+        }
+        // Sides derived from an extreme aspect ratio round to at least 1.
+        let options = |width, height, fit| TransformOptions { width, height, fit, aspect_ratio: Some(1000.0), ..Default::default() };
+        assert_eq!(options(Some(1), None, Fit::Cover).target_size((400, 200)), Some((1, 1)));
+        assert_eq!(options(None, None, Fit::Inside).target_size((10, 10)), Some((10, 1)));
+        let img = transform_image(DynamicImage::new_rgb8(400, 200), &options(Some(1), None, Fit::Cover)).unwrap();
+        assert_eq!(img.dimensions(), (1, 1));
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(parse_color("#f00").unwrap(), Rgba([255, 0, 0, 255]));
+        assert_eq!(parse_color("336699").unwrap(), Rgba([0x33, 0x66, 0x99, 255]));
+        assert_eq!(parse_color("#33669980").unwrap(), Rgba([0x33, 0x66, 0x99, 0x80]));
+        assert_eq!(parse_color("#ggg").unwrap_err().code(), "invalid_color");
+        assert_eq!(parse_color("#ab").unwrap_err().code(), "invalid_color");
+    }
+
+    #[test]
+    fn parses_aspect_ratios() {
+        assert_eq!(parse_aspect_ratio("16:9").unwrap(), 16.0 / 9.0);
+        assert_eq!(parse_aspect_ratio("1.5").unwrap(), 1.5);
+        assert!(parse_aspect_ratio("0:9").is_err());
+        assert!(parse_aspect_ratio("wide").is_err());
+    }
+
+    #[test]
+    fn clamps_regions_to_the_image() {
+        let region: Region = "50,50,100,100".parse().unwrap();
+        assert_eq!(region.clamp_to(120, 80, "Crop").unwrap(), Region { x: 50, y: 50, width: 70, height: 30 });
+        assert_eq!(region.clamp_to(50, 80, "Crop").unwrap_err().code(), "invalid_region");
+        assert!("0,0,0,10".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn scales_boxes_down_to_the_size_limit() {
+        let limit = SizeLimit { width: 1000, height: 1000, clamp: true };
+        assert_eq!(limit.apply((800, 600)).unwrap(), (800, 600));
+        assert_eq!(limit.apply((4000, 2000)).unwrap(), (1000, 500));
+
+        let limit = SizeLimit { clamp: false, ..limit };
+        assert_eq!(limit.apply((4000, 2000)).unwrap_err().code(), "size_limit_exceeded");
+    }
+
+    #[test]
+    fn fits_images_into_the_box() {
+        let img = DynamicImage::new_rgb8(400, 200);
+        let transform = |fit| {
+            let options = TransformOptions { width: Some(100), height: Some(100), fit, ..Default::default() };
+            transform_image(img.clone(), &options).unwrap().dimensions()
+        };
+        assert_eq!(transform(Fit::Inside), (100, 50));
+        assert_eq!(transform(Fit::Cover), (100, 100));
+        assert_eq!(transform(Fit::Fill), (100, 100));
+        assert_eq!(transform(Fit::Outside), (200, 100));
+    }
+}
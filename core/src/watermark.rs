@@ -0,0 +1,129 @@
+use image::{imageops::{self, FilterType}, DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba};
+use std::sync::{Arc, LazyLock};
+
+use crate::decode;
+use crate::error::Error;
+use crate::{settings, telemetry};
+use crate::transform::{self, Gravity};
+
+/// The watermark and how it is placed on the output.
+#[derive(Debug, Clone)]
+pub struct WatermarkOptions {
+    /// Decoded watermark; nothing is composited when `None`.
+    pub image: Option<Arc<DynamicImage>>,
+    /// Corner, edge or center of the image the watermark is anchored to.
+    pub position: Gravity,
+    /// Multiplies the watermark's own alpha channel.
+    pub opacity: f32,
+    /// Watermark width as a fraction of the image width; `None` keeps its
+    /// own size. Watermarks are always shrunk to fit within the image.
+    pub scale: Option<f32>,
+    /// Repeat the watermark across the whole image instead of placing it
+    /// once at `position`.
+    pub tile: bool,
+    /// Gap between tiles, in pixels.
+    pub tile_spacing: u32,
+    /// Degrees clockwise each tile is rotated by.
+    pub tile_angle: f64,
+}
+
+impl Default for WatermarkOptions {
+    fn default() -> Self {
+        Self {
+            image: None,
+            position: Gravity::SouthEast,
+            opacity: 1.0,
+            scale: None,
+            tile: false,
+            tile_spacing: 100,
+            tile_angle: 315.0,
+        }
+    }
+}
+
+/// Watermark loaded once from `Settings::watermark_path`, used when a
+/// request does not bring its own.
+static DEFAULT_WATERMARK: LazyLock<Option<Arc<DynamicImage>>> = LazyLock::new(|| {
+    let path = settings::get().watermark_path.as_ref()?;
+    match image::open(path) {
+        Ok(watermark) => Some(Arc::new(watermark)),
+        Err(e) => {
+            tracing::error!("Failed to load watermark from {}: {}", path, e);
+            None
+        }
+    }
+});
+
+/// Decodes an uploaded watermark, falling back to the default one.
+pub fn load(uploaded: Option<&[u8]>) -> Result<Option<Arc<DynamicImage>>, Error> {
+    match uploaded {
+        Some(bytes) => decode::load_raster(bytes, |e| Error::invalid(format!("Failed to decode watermark: {}", e)).with_code("invalid_watermark"))
+            .map(|watermark| Some(Arc::new(watermark))),
+        None => Ok(DEFAULT_WATERMARK.clone()),
+    }
+}
+
+/// Composites the watermark, if any, onto the image, keeping its bit depth.
+pub fn apply(img: DynamicImage, options: &WatermarkOptions) -> DynamicImage {
+    let Some(watermark) = &options.image else {
+        return img;
+    };
+    let _span = telemetry::op_span("watermark").entered();
+    let (width, height) = img.dimensions();
+    let target_width = options.scale.map_or(watermark.width(), |scale| (width as f32 * scale).round().max(1.0) as u32);
+    let mark = if target_width != watermark.width() || watermark.width() > width || watermark.height() > height {
+        let target_height = (watermark.height() as f64 * target_width as f64 / watermark.width() as f64).round().max(1.0) as u32;
+        watermark.resize(target_width.min(width), target_height.min(height), FilterType::Lanczos3)
+    } else {
+        watermark.as_ref().clone()
+    };
+
+    let mut mark = mark.into_rgba32f();
+    if options.opacity < 1.0 {
+        mark.pixels_mut().for_each(|pixel| pixel[3] *= options.opacity);
+    }
+    let mut mark = DynamicImage::ImageRgba32F(mark);
+    if options.tile && options.tile_angle != 0.0 {
+        mark = transform::rotate(mark, options.tile_angle, Rgba([0, 0, 0, 0]));
+    }
+
+    let positions = if options.tile {
+        tile_positions((width, height), mark.dimensions(), options.tile_spacing)
+    } else {
+        let (fx, fy) = options.position.focal_point();
+        let x = (width.saturating_sub(mark.width()) as f64 * fx).round() as i64;
+        let y = (height.saturating_sub(mark.height()) as f64 * fy).round() as i64;
+        vec![(x, y)]
+    };
+    let alpha = img.color().has_alpha();
+
+    if transform::is_high_bit_depth(&img) {
+        let composited = DynamicImage::ImageRgba16(overlay(img.to_rgba16(), &mark.to_rgba16(), &positions));
+        if alpha { composited } else { DynamicImage::ImageRgb16(composited.to_rgb16()) }
+    } else {
+        let composited = DynamicImage::ImageRgba8(overlay(img.to_rgba8(), &mark.to_rgba8(), &positions));
+        if alpha { composited } else { DynamicImage::ImageRgb8(composited.to_rgb8()) }
+    }
+}
+
+/// Positions covering the image with tiles, shifting every other row by half
+/// a step so the pattern runs diagonally.
+fn tile_positions(image: (u32, u32), tile: (u32, u32), spacing: u32) -> Vec<(i64, i64)> {
+    let step_x = i64::from(tile.0 + spacing);
+    let step_y = i64::from(tile.1 + spacing);
+    let mut positions = Vec::new();
+    for (row, y) in (-step_y..i64::from(image.1)).step_by(step_y as usize).enumerate() {
+        let shift = if row % 2 == 1 { step_x / 2 } else { 0 };
+        for x in (-step_x + shift..i64::from(image.0)).step_by(step_x as usize) {
+            positions.push((x, y));
+        }
+    }
+    positions
+}
+
+fn overlay<P: Pixel>(mut base: ImageBuffer<P, Vec<P::Subpixel>>, mark: &ImageBuffer<P, Vec<P::Subpixel>>, positions: &[(i64, i64)]) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    for &(x, y) in positions {
+        imageops::overlay(&mut base, mark, x, y);
+    }
+    base
+}
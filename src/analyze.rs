@@ -0,0 +1,60 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, Request},
+    http::Uri,
+    response::{IntoResponse, Response},
+    Json,
+};
+use image_transformer_core::analyze::{self, Analysis};
+use serde_json::json;
+
+use crate::error::AppError;
+use crate::{color, decode, fetch, pool, signing, upload};
+
+/// Handler for `POST /analyze`, which returns the histograms and luminance
+/// of an image. Accepts multipart/form-data with an "image" file or a "url"
+/// field, or the image itself as an `image/*` or `application/octet-stream`
+/// body.
+pub async fn analyze_handler(request: Request) -> Result<Response, AppError> {
+    let image_bytes = upload::read_image(request).await?;
+    analyze(image_bytes).await
+}
+
+/// Handler for `GET /analyze?url=...`, which downloads the image to analyze.
+/// With `URL_SIGNING_KEY` set, the query must be signed.
+pub async fn analyze_url_handler(uri: Uri, Query(fields): Query<Vec<(String, String)>>) -> Result<Response, AppError> {
+    signing::verify_query(&uri)?;
+    let url = fields.iter().find(|(name, _)| name == "url").map(|(_, value)| value)
+        .ok_or_else(|| AppError::invalid("Source URL not provided in 'url' parameter").with_code("missing_image"))?;
+    let image_bytes = fetch::fetch(url).await?;
+    analyze(image_bytes).await
+}
+
+async fn analyze(image_bytes: Bytes) -> Result<Response, AppError> {
+    let analysis = pool::run(move || -> Result<Analysis, AppError> {
+        let image_format = decode::detect_format(&image_bytes)?;
+        let mut img = decode::decode_image(&image_bytes, image_format, &decode::DecodeOptions::default())?;
+        if let Some(profile) = decode::icc_profile(&image_bytes, image_format) {
+            img = color::convert_to_srgb(img, &profile);
+        }
+        Ok(analyze::analyze(&img))
+    })
+    .await??;
+    Ok(Json(to_json(&analysis)).into_response())
+}
+
+fn to_json(analysis: &Analysis) -> serde_json::Value {
+    json!({
+        "pixels": analysis.pixels,
+        "mean_luminance": analysis.mean_luminance,
+        "median_luminance": analysis.median_luminance,
+        "tone": analysis.tone.name(),
+        "histograms": {
+            "red": analysis.red.as_slice(),
+            "green": analysis.green.as_slice(),
+            "blue": analysis.blue.as_slice(),
+            "luma": analysis.luma.as_slice(),
+            "alpha": analysis.alpha.as_ref().map(|histogram| histogram.as_slice()),
+        },
+    })
+}
@@ -0,0 +1,191 @@
+use axum::{
+    extract::{Path, Request},
+    http::{header, HeaderMap, HeaderName, Method},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::config;
+use crate::error::AppError;
+use crate::jwt::{self, JwtConfig};
+use crate::keys;
+use crate::quota::QuotaConfig;
+use crate::{signing, thumbor};
+
+/// Settings for authenticating API requests.
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    /// Whether API requests need a key, which is the case once `API_KEYS`,
+    /// `API_KEYS_FILE` or `KEY_STORE_PATH` is set. A key file that cannot be read leaves it on, so
+    /// that no request gets in.
+    pub required: bool,
+    /// Names of the accepted keys, by the SHA-256 digest of the key.
+    pub api_keys: HashMap<[u8; 32], String>,
+    /// Names of the keys for admin endpoints such as `/usage`, from
+    /// `ADMIN_API_KEYS`.
+    pub admin_keys: HashMap<[u8; 32], String>,
+    /// Bearer tokens accepted besides API keys, once `JWT_ISSUER` or
+    /// `JWT_JWKS_URL` is set.
+    pub jwt: Option<JwtConfig>,
+}
+
+/// Parses `name:key` entries into names by key digest. Keys without a name
+/// are named after the start of their digest, which identifies them in logs
+/// without revealing them.
+pub fn parse_keys<'a>(entries: impl IntoIterator<Item = &'a str>) -> HashMap<[u8; 32], String> {
+    let mut keys = HashMap::new();
+    for entry in entries.into_iter().map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (name, key) = entry.split_once(':').map_or(("", entry), |(name, key)| (name.trim(), key.trim()));
+        if key.is_empty() {
+            tracing::error!("Ignoring API key {:?} with an empty key", name);
+            continue;
+        }
+        let digest = digest(key);
+        let name = match name {
+            "" => format!("key-{}", &signing::hex(&digest)[..8]),
+            name => name.to_string(),
+        };
+        keys.insert(digest, name);
+    }
+    keys
+}
+
+static X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+/// The API key name or token subject of an authenticated request, kept in
+/// its extensions.
+#[derive(Debug, Clone)]
+pub struct Client(pub String);
+
+/// Middleware requiring a known key in the `X-API-Key` header, or a valid
+/// bearer token, once either is configured. The limits of keys created
+/// through `/admin/keys` are kept in the request's extensions for
+/// `quota::meter`. The name of the key or the
+/// subject of the token is recorded as `client` on the request span, so that
+/// the logs of partners can be told apart.
+pub async fn authenticate(mut request: Request, next: Next) -> Result<Response, AppError> {
+    if is_signed_url(&request) {
+        return Ok(next.run(request).await);
+    }
+    let Some((client, quota)) = identify(request.headers()).await? else {
+        return Ok(next.run(request).await);
+    };
+    tracing::Span::current().record("client", client.as_str());
+    request.extensions_mut().insert(Client(client));
+    if let Some(quota) = quota {
+        request.extensions_mut().insert(quota);
+    }
+    Ok(next.run(request).await)
+}
+
+/// `authenticate` for Thumbor-style URLs. Signed ones are checked by their
+/// handler, like signed GET `/transform` URLs, while `/unsafe/` ones need
+/// credentials like the rest of the API. The signature segment is compared
+/// percent-decoded, as the handler reads it, so that `/%75nsafe/` is no way
+/// around the check.
+pub async fn authenticate_thumbor(Path((signature, _)): Path<(String, String)>, request: Request, next: Next) -> Result<Response, AppError> {
+    if thumbor::is_unsigned(&signature) {
+        authenticate(request, next).await
+    } else {
+        Ok(next.run(request).await)
+    }
+}
+
+/// Checks the credentials in `headers`, returning the client's name and the
+/// limits of a key created through `/admin/keys`. `None` while no
+/// credentials are configured.
+pub async fn identify(headers: &HeaderMap) -> Result<Option<(String, Option<QuotaConfig>)>, AppError> {
+    let auth = &config::get().auth;
+    if !auth.required && auth.jwt.is_none() {
+        return Ok(None);
+    }
+    let bearer = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer ").or_else(|| value.strip_prefix("bearer ")));
+    let identity = match (bearer, &auth.jwt, headers.get(&X_API_KEY)) {
+        (Some(token), Some(jwt), _) => (jwt::verify(jwt, token.trim()).await?.subject, None),
+        (_, _, Some(key)) if auth.required => {
+            let digest = key.to_str().map(|key| digest(key.trim())).unwrap_or_default();
+            match auth.api_keys.get(&digest) {
+                Some(name) => (name.clone(), None),
+                None => keys::find(&digest)
+                    .map(|(name, quota)| (name, Some(quota)))
+                    .ok_or_else(|| AppError::unauthorized("Invalid API key").with_code("invalid_api_key"))?,
+            }
+        }
+        _ => {
+            let expected = match (auth.required, auth.jwt.is_some()) {
+                (true, true) => "an API key in the X-API-Key header or a bearer token",
+                (true, false) => "an API key in the X-API-Key header",
+                _ => "a bearer token in the Authorization header",
+            };
+            return Err(AppError::unauthorized(format!("Missing credentials, expected {}", expected)).with_code("missing_credentials"));
+        }
+    };
+    Ok(Some(identity))
+}
+
+/// Middleware for admin endpoints, requiring a key of `ADMIN_API_KEYS` in
+/// the `X-API-Key` header. They are refused while no admin key is set.
+pub async fn require_admin(request: Request, next: Next) -> Result<Response, AppError> {
+    let auth = &config::get().auth;
+    if auth.admin_keys.is_empty() {
+        return Err(AppError::forbidden("The admin API is not configured on this server").with_code("admin_not_configured"));
+    }
+    let key = request.headers().get(&X_API_KEY)
+        .ok_or_else(|| AppError::unauthorized("Missing admin key in the X-API-Key header").with_code("missing_credentials"))?;
+    let digest = key.to_str().map(|key| digest(key.trim())).unwrap_or_default();
+    let Some(name) = auth.admin_keys.get(&digest) else {
+        if auth.api_keys.contains_key(&digest) || keys::find(&digest).is_some() {
+            return Err(AppError::forbidden("This API key has no admin access").with_code("not_admin"));
+        }
+        return Err(AppError::unauthorized("Invalid API key").with_code("invalid_api_key"));
+    };
+    tracing::Span::current().record("client", name.as_str());
+    Ok(next.run(request).await)
+}
+
+/// Signed GET `/transform` URLs are checked by their handler instead, since
+/// they are made for clients such as browsers that cannot send a key.
+fn is_signed_url(request: &Request) -> bool {
+    config::get().signing_key.is_some()
+        && request.method() == Method::GET
+        && request.uri().path() == "/transform"
+        && request.uri().query().is_some_and(|query| query.split('&').any(|param| param.starts_with("signature=")))
+}
+
+pub fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn requires_credentials_for_unsafe_thumbor_urls_however_encoded() {
+        // Read once, on the first use of the configuration.
+        unsafe { std::env::set_var("API_KEYS", "test:secret") };
+        let app = Router::new()
+            .route("/:signature/*path", get(|| async { "handled" }))
+            .route_layer(axum::middleware::from_fn(authenticate_thumbor));
+        let status = |path: &str, key: Option<&str>| {
+            let mut request = axum::http::Request::get(path);
+            if let Some(key) = key {
+                request = request.header(&X_API_KEY, key);
+            }
+            let response = app.clone().oneshot(request.body(Body::empty()).unwrap());
+            async { response.await.unwrap().status().as_u16() }
+        };
+
+        assert_eq!(status("/unsafe/300x200/example.com/a.jpg", None).await, 401);
+        assert_eq!(status("/%75nsafe/300x200/example.com/a.jpg", None).await, 401);
+        assert_eq!(status("/%75nsafe/300x200/example.com/a.jpg", Some("secret")).await, 200);
+        // Signatures are checked by the handler instead.
+        assert_eq!(status("/c2lnbmF0dXJl/300x200/example.com/a.jpg", None).await, 200);
+    }
+}
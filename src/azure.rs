@@ -0,0 +1,301 @@
+use base64::Engine;
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::{Client, RequestBuilder};
+use sha2::Sha256;
+use std::{
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use crate::config;
+use crate::error::AppError;
+use crate::storage::{self, uri_encode, Location, StorageBackend, UtcTime};
+
+/// Settings for Azure Blob Storage.
+#[derive(Debug, Clone)]
+pub struct AzureConfig {
+    pub account: Option<String>,
+    /// Base URL of the Blob service; `https://{account}.blob.core.windows.net`
+    /// when unset.
+    pub endpoint: Option<String>,
+    /// The decoded account key. Without one, requests are authorized with
+    /// the host's managed identity.
+    pub account_key: Option<Vec<u8>>,
+    /// Picks a user-assigned managed identity.
+    pub client_id: Option<String>,
+    /// Containers that may be written to; the first is the default. Empty
+    /// disables Azure output.
+    pub containers: Vec<String>,
+    /// Lifetime of SAS download URLs.
+    pub url_expiry: Duration,
+}
+
+/// The parts of a storage account connection string that locate and
+/// authorize the Blob service.
+#[derive(Debug, Default)]
+pub struct ConnectionString {
+    pub account: Option<String>,
+    pub account_key: Option<String>,
+    pub endpoint: Option<String>,
+}
+
+/// Account and key of the local storage emulator, Azurite.
+const DEVELOPMENT_ACCOUNT: &str = "devstoreaccount1";
+const DEVELOPMENT_KEY: &str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+impl ConnectionString {
+    /// Parses `Key=Value;...` pairs, including `UseDevelopmentStorage=true`
+    /// for Azurite.
+    pub fn parse(value: &str) -> Self {
+        let mut parts = std::collections::HashMap::new();
+        for pair in value.split(';') {
+            if let Some((key, value)) = pair.split_once('=') {
+                parts.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+        if parts.get("usedevelopmentstorage").is_some_and(|value| value.eq_ignore_ascii_case("true")) {
+            return ConnectionString {
+                account: Some(DEVELOPMENT_ACCOUNT.to_string()),
+                account_key: Some(DEVELOPMENT_KEY.to_string()),
+                endpoint: Some(format!("http://127.0.0.1:10000/{}", DEVELOPMENT_ACCOUNT)),
+            };
+        }
+        let account = parts.remove("accountname");
+        let endpoint = parts.remove("blobendpoint").or_else(|| {
+            let protocol = parts.get("defaultendpointsprotocol").map_or("https", String::as_str);
+            let suffix = parts.get("endpointsuffix").map_or("core.windows.net", String::as_str);
+            account.as_ref().map(|account| format!("{}://{}.blob.{}", protocol, account, suffix))
+        });
+        ConnectionString { account, account_key: parts.remove("accountkey"), endpoint }
+    }
+}
+
+/// Authorizes requests with the account key (Shared Key), or with a managed
+/// identity token when there is none.
+pub struct Azure;
+
+const API_VERSION: &str = "2021-08-06";
+const STORAGE_RESOURCE: &str = "https://storage.azure.com/";
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()
+        .expect("failed to build the HTTP client")
+});
+
+/// The managed identity token and the Unix time it expires at.
+static TOKEN: Mutex<Option<(String, u64)>> = Mutex::new(None);
+
+impl Azure {
+    /// Resolves the container of an `output` target, defaulting to the first
+    /// configured one.
+    pub fn container(container: Option<&str>) -> Result<String, AppError> {
+        let azure = &config::get().azure;
+        if azure.containers.is_empty() || azure.account.is_none() {
+            return Err(not_configured());
+        }
+        match container {
+            None => Ok(azure.containers[0].clone()),
+            Some(container) if azure.containers.iter().any(|allowed| allowed == container) => Ok(container.to_string()),
+            Some(container) => Err(AppError::forbidden(format!("Container '{}' is not allowed", container)).with_code("bucket_not_allowed")),
+        }
+    }
+}
+
+impl StorageBackend for Azure {
+    async fn put(&self, container: &str, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<Location, AppError> {
+        let azure = &config::get().azure;
+        let account = azure.account.as_deref().ok_or_else(not_configured)?;
+        let endpoint = endpoint()?;
+        let endpoint = endpoint.as_str();
+        let url = format!("{}/{}/{}", endpoint, uri_encode(container, true), uri_encode(key, false));
+        let now = UtcTime::now();
+
+        let request = CLIENT
+            .put(&url)
+            .header("content-type", content_type)
+            .header("x-ms-blob-type", "BlockBlob")
+            .header("x-ms-date", now.rfc1123())
+            .header("x-ms-version", API_VERSION);
+        let request = match &azure.account_key {
+            Some(account_key) => {
+                // Shared Key: the standard headers in a fixed order, then the
+                // `x-ms-*` headers sorted, then `/account/path`.
+                let path = reqwest::Url::parse(&url).map(|url| url.path().to_string()).map_err(invalid_endpoint)?;
+                let string_to_sign = format!(
+                    "PUT\n\n\n{}\n\n{}\n\n\n\n\n\n\nx-ms-blob-type:BlockBlob\nx-ms-date:{}\nx-ms-version:{}\n/{}{}",
+                    if bytes.is_empty() { String::new() } else { bytes.len().to_string() },
+                    content_type,
+                    now.rfc1123(),
+                    API_VERSION,
+                    account,
+                    path
+                );
+                let signature = base64::engine::general_purpose::STANDARD.encode(hmac(account_key, &string_to_sign));
+                request.header("authorization", format!("SharedKey {}:{}", account, signature))
+            }
+            None => request.bearer_auth(token().await?),
+        };
+        send(request.body(bytes)).await?;
+
+        let expires = azure.url_expiry.as_secs().clamp(1, 7 * 24 * 3600);
+        let expiry = now.plus(expires).iso8601();
+        let resource = format!("/blob/{}/{}/{}", account, container, key);
+        let sas = match &azure.account_key {
+            // Service SAS, for read access to this blob only.
+            Some(account_key) => {
+                let string_to_sign = format!("r\n\n{}\n{}\n\n\n\n{}\nb\n\n\n\n\n\n\n", expiry, resource, API_VERSION);
+                let signature = base64::engine::general_purpose::STANDARD.encode(hmac(account_key, &string_to_sign));
+                format!("sv={}&sr=b&sp=r&se={}&sig={}", API_VERSION, uri_encode(&expiry, true), uri_encode(&signature, true))
+            }
+            // User delegation SAS, signed with a key issued to the identity.
+            None => user_delegation_sas(endpoint, now, &expiry, &resource).await?,
+        };
+
+        Ok(Location { url: format!("{}?{}", url, sas), expires_in: Some(expires) })
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        storage::reachable(&endpoint().map_err(|e| e.message().to_string())?).await
+    }
+}
+
+/// Fetches a user delegation key valid until `expiry` (at most 7 days out)
+/// and signs a read-only SAS for `resource` with it.
+async fn user_delegation_sas(endpoint: &str, now: UtcTime, expiry: &str, resource: &str) -> Result<String, AppError> {
+    // Backdated a little, in case this host's clock is ahead of Azure's.
+    let start = UtcTime::from_unix(now.unix.saturating_sub(300)).iso8601();
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><KeyInfo><Start>{}</Start><Expiry>{}</Expiry></KeyInfo>",
+        start, expiry
+    );
+    let request = CLIENT
+        .post(format!("{}/?restype=service&comp=userdelegationkey", endpoint))
+        .header("content-type", "application/xml")
+        .header("x-ms-date", now.rfc1123())
+        .header("x-ms-version", API_VERSION)
+        .bearer_auth(token().await?)
+        .body(body);
+    let key = send(request).await?;
+
+    let field = |name: &str| {
+        xml_element(&key, name)
+            .ok_or_else(|| AppError::upstream(format!("Azure user delegation key has no {}", name)).with_code("storage_error"))
+    };
+    let (oid, tid, key_start, key_expiry, service, version, value) = (
+        field("SignedOid")?,
+        field("SignedTid")?,
+        field("SignedStart")?,
+        field("SignedExpiry")?,
+        field("SignedService")?,
+        field("SignedVersion")?,
+        field("Value")?,
+    );
+    let value = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|e| AppError::upstream(format!("Invalid Azure user delegation key: {}", e)).with_code("storage_error"))?;
+
+    let string_to_sign = format!(
+        "r\n\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n\n\n\n\n\n{}\nb\n\n\n\n\n\n\n",
+        expiry, resource, oid, tid, key_start, key_expiry, service, version, API_VERSION
+    );
+    let signature = base64::engine::general_purpose::STANDARD.encode(hmac(&value, &string_to_sign));
+    let query = [
+        ("sv", API_VERSION),
+        ("sr", "b"),
+        ("sp", "r"),
+        ("se", expiry),
+        ("skoid", oid),
+        ("sktid", tid),
+        ("skt", key_start),
+        ("ske", key_expiry),
+        ("sks", service),
+        ("skv", version),
+        ("sig", &signature),
+    ];
+    Ok(query.iter().map(|(name, value)| format!("{}={}", name, uri_encode(value, true))).collect::<Vec<_>>().join("&"))
+}
+
+/// Gets an access token for Azure Storage from the managed identity
+/// endpoint: App Service's when `IDENTITY_ENDPOINT` is set, the instance
+/// metadata service's otherwise. Tokens are reused until shortly before
+/// they expire.
+async fn token() -> Result<String, AppError> {
+    let now = UtcTime::now().unix;
+    if let Some((token, expires_on)) = TOKEN.lock().unwrap().as_ref() && now + 300 < *expires_on {
+        return Ok(token.clone());
+    }
+
+    let mut query = vec![("resource", STORAGE_RESOURCE.to_string())];
+    if let Some(client_id) = &config::get().azure.client_id {
+        query.push(("client_id", client_id.clone()));
+    }
+    let request = match (std::env::var("IDENTITY_ENDPOINT"), std::env::var("IDENTITY_HEADER")) {
+        (Ok(endpoint), Ok(secret)) => {
+            query.push(("api-version", "2019-08-01".to_string()));
+            CLIENT.get(endpoint).header("X-IDENTITY-HEADER", secret)
+        }
+        _ => {
+            query.push(("api-version", "2018-02-01".to_string()));
+            CLIENT.get("http://169.254.169.254/metadata/identity/oauth2/token").header("Metadata", "true")
+        }
+    };
+    let response: serde_json::Value = serde_json::from_str(&send(request.query(&query)).await?)
+        .map_err(|e| AppError::upstream(format!("Invalid managed identity token response: {}", e)).with_code("storage_error"))?;
+    let token = response["access_token"].as_str()
+        .ok_or_else(|| AppError::upstream("Managed identity token response has no access_token").with_code("storage_error"))?
+        .to_string();
+    // A string of Unix seconds, though some endpoints send a number.
+    let expires_on = match &response["expires_on"] {
+        serde_json::Value::String(seconds) => seconds.parse().ok(),
+        value => value.as_u64(),
+    };
+    *TOKEN.lock().unwrap() = Some((token.clone(), expires_on.unwrap_or(now + 300)));
+    Ok(token)
+}
+
+/// Sends a request and returns the response body, turning failures into
+/// `502 Bad Gateway`.
+async fn send(request: RequestBuilder) -> Result<String, AppError> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| AppError::upstream(format!("Failed to reach Azure: {}", e)).with_code("storage_error"))?;
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(AppError::upstream(format!("Azure returned {}: {}", status, body.trim())).with_code("storage_error"));
+    }
+    Ok(body)
+}
+
+/// The Blob endpoint of the connection string, or the account's.
+fn endpoint() -> Result<String, AppError> {
+    let azure = &config::get().azure;
+    let account = azure.account.as_deref().ok_or_else(not_configured)?;
+    let endpoint = azure.endpoint.clone().unwrap_or_else(|| format!("https://{}.blob.core.windows.net", account));
+    Ok(endpoint.trim_end_matches('/').to_string())
+}
+
+fn not_configured() -> AppError {
+    AppError::forbidden("Azure output is not configured on this server").with_code("storage_not_configured")
+}
+
+fn invalid_endpoint(e: impl std::fmt::Display) -> AppError {
+    AppError::internal(format!("Invalid Azure Blob endpoint: {}", e))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The text of the first `<name>` element; enough for the flat responses
+/// of the Blob service.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
@@ -0,0 +1,192 @@
+use axum::{
+    body::Bytes,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Cursor, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::encode::OutputFormat;
+use crate::error::AppError;
+use crate::fetch;
+
+/// Most images accepted in one `/transform/batch` request.
+pub const MAX_BATCH_ITEMS: usize = 100;
+
+/// How the results of a batch are packaged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Bundle {
+    /// A ZIP archive with a `manifest.json`.
+    #[default]
+    Zip,
+    /// A `multipart/mixed` body with one part per image, then the manifest.
+    Multipart,
+}
+
+impl FromStr for Bundle {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "zip" => Ok(Bundle::Zip),
+            "multipart" | "mixed" => Ok(Bundle::Multipart),
+            _ => Err(AppError::invalid("Invalid bundle. Use 'zip' or 'multipart'").with_code("invalid_bundle")),
+        }
+    }
+}
+
+/// A batch sent as JSON, e.g.
+/// `{"urls": ["https://cdn.example.com/a.jpg"], "options": {"size": "400x", "format": "webp"}}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchSpec {
+    pub urls: Vec<String>,
+    /// Form fields applied to every image.
+    #[serde(default)]
+    pub options: BTreeMap<String, Value>,
+    pub bundle: Option<String>,
+}
+
+impl BatchSpec {
+    /// The options as form fields; strings, numbers and booleans are accepted.
+    pub fn fields(&self) -> Result<Vec<(String, String)>, AppError> {
+        self.options
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    Value::Number(_) | Value::Bool(_) => value.to_string(),
+                    _ => return Err(AppError::invalid(format!("Option '{}' must be a string, number or boolean", name))),
+                };
+                Ok((name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// Where one image of a batch comes from.
+pub enum Source {
+    Upload { file_name: Option<String>, data: Bytes },
+    Url(String),
+}
+
+impl Source {
+    /// A file name stem for the output, from the upload or the URL path.
+    pub fn name(&self, index: usize) -> String {
+        let name = match self {
+            Source::Upload { file_name, .. } => file_name.as_deref().unwrap_or_default(),
+            Source::Url(url) => url.split(['?', '#']).next().unwrap_or_default().rsplit('/').next().unwrap_or_default(),
+        };
+        let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+        let stem: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+        if stem.is_empty() { format!("image-{}", index + 1) } else { stem }
+    }
+
+    /// Returns the uploaded bytes, or downloads the URL.
+    pub async fn load(self) -> Result<Bytes, AppError> {
+        match self {
+            Source::Upload { data, .. } => Ok(data),
+            Source::Url(url) => fetch::fetch(&url).await,
+        }
+    }
+}
+
+/// The outcome of one image, in request order.
+pub struct Item {
+    pub name: String,
+    pub result: Result<(Vec<u8>, OutputFormat, Option<String>), AppError>,
+}
+
+/// Packages the results. Failed images do not fail the batch; they are
+/// listed in the manifest with their status and error message instead.
+pub fn respond(items: Vec<Item>, bundle: Bundle) -> Result<Response, AppError> {
+    let mut used = HashSet::new();
+    let mut manifest = Vec::with_capacity(items.len());
+    let mut files = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        match item.result {
+            Ok((bytes, format, placeholder)) => {
+                let mut file = format!("{}.{}", item.name, format.extension());
+                if !used.insert(file.clone()) {
+                    file = format!("{}-{}.{}", item.name, index + 1, format.extension());
+                    used.insert(file.clone());
+                }
+                let mut entry = json!({
+                    "index": index,
+                    "name": item.name,
+                    "status": StatusCode::OK.as_u16(),
+                    "file": file,
+                    "content_type": format.content_type(),
+                    "bytes": bytes.len(),
+                });
+                if let Some(placeholder) = placeholder {
+                    entry["placeholder"] = json!(placeholder);
+                }
+                manifest.push(entry);
+                files.push((file, format.content_type(), bytes));
+            }
+            Err(error) => manifest.push(json!({
+                "index": index,
+                "name": item.name,
+                "status": error.status_code().as_u16(),
+                "code": error.code(),
+                "error": error.message(),
+            })),
+        }
+    }
+    let manifest = serde_json::to_vec_pretty(&json!({ "items": manifest }))?;
+
+    match bundle {
+        Bundle::Zip => {
+            // Images are already compressed, so store them without deflating again.
+            let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            for (file, _, bytes) in &files {
+                archive.start_file(file.as_str(), options)?;
+                archive.write_all(bytes)?;
+            }
+            archive.start_file("manifest.json", options)?;
+            archive.write_all(&manifest)?;
+            let archive = archive.finish()?.into_inner();
+
+            Ok((
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, "application/zip".to_string()),
+                    (header::CONTENT_DISPOSITION, "attachment; filename=\"batch.zip\"".to_string()),
+                ],
+                archive,
+            ).into_response())
+        }
+        Bundle::Multipart => {
+            files.push(("manifest.json".to_string(), "application/json", manifest));
+            let boundary = boundary(&files);
+            let mut body = Vec::new();
+            for (file, content_type, bytes) in &files {
+                write!(body, "--{}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\n\r\n", boundary, content_type, file)?;
+                body.extend_from_slice(bytes);
+                body.extend_from_slice(b"\r\n");
+            }
+            write!(body, "--{}--\r\n", boundary)?;
+
+            Ok((
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, format!("multipart/mixed; boundary={}", boundary))],
+                body,
+            ).into_response())
+        }
+    }
+}
+
+/// Picks a multipart boundary that does not occur in any of the parts.
+fn boundary(files: &[(String, &str, Vec<u8>)]) -> String {
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_nanos());
+    (seed..)
+        .map(|value| format!("batch-{:x}", value))
+        .find(|boundary| !files.iter().any(|(_, _, bytes)| bytes.windows(boundary.len()).any(|window| window == boundary.as_bytes())))
+        .expect("some boundary does not occur in the parts")
+}
@@ -0,0 +1,428 @@
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::OnceCell;
+
+use crate::config;
+use crate::encode::OutputFormat;
+use crate::error::AppError;
+use crate::signing;
+use crate::telemetry;
+use crate::variants::Variant;
+
+/// Limits for the transform result cache.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Most bytes of encoded output kept in memory. `None` disables the
+    /// in-memory cache.
+    pub max_bytes: Option<usize>,
+    /// Directory for results kept across restarts. `None` disables the disk
+    /// cache.
+    pub dir: Option<PathBuf>,
+    /// Most bytes kept in `dir`.
+    pub dir_max_bytes: u64,
+    /// Redis server shared by all replicas (`redis` feature only).
+    pub redis_url: Option<String>,
+    /// How long results are kept in Redis.
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub redis_ttl: std::time::Duration,
+}
+
+/// SHA-256 over the input image and the canonical form of everything that
+/// affects the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Hashes each part with its length, so that parts cannot run into each
+    /// other.
+    pub fn new(parts: &[&[u8]]) -> Self {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        Key(hasher.finalize().into())
+    }
+
+    pub fn hex(&self) -> String {
+        signing::hex(&self.0)
+    }
+}
+
+struct Cache {
+    entries: LruCache<Key, Vec<Variant>>,
+    bytes: usize,
+}
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(|| Mutex::new(Cache { entries: LruCache::unbounded(), bytes: 0 }));
+
+pub fn enabled() -> bool {
+    let cache = &config::get().cache;
+    cache.max_bytes.is_some() || cache.dir.is_some() || (cfg!(feature = "redis") && cache.redis_url.is_some())
+}
+
+/// Checks that Redis answers, for `/readyz`.
+pub async fn check_redis() -> Result<(), String> {
+    shared::ping().await
+}
+
+/// Looks up the outputs of an earlier identical request, in memory first,
+/// then on disk and then in Redis. Hits are copied to the faster layers.
+pub async fn get(key: &Key) -> Option<Vec<Variant>> {
+    let hit = CACHE.lock().unwrap().entries.get(key).cloned();
+    if hit.is_some() {
+        tracing::debug!("result cache hit");
+        telemetry::record_cache_lookup(Some("memory"));
+        return hit;
+    }
+    let key = *key;
+    if config::get().cache.dir.is_some()
+        && let Some(outputs) = tokio::task::spawn_blocking(move || disk::get(&key)).await.ok().flatten()
+    {
+        tracing::debug!("disk result cache hit");
+        telemetry::record_cache_lookup(Some("disk"));
+        remember(key, &outputs);
+        return Some(outputs);
+    }
+    let Some(outputs) = shared::get(&key).await else {
+        telemetry::record_cache_lookup(None);
+        return None;
+    };
+    tracing::debug!("shared result cache hit");
+    telemetry::record_cache_lookup(Some("redis"));
+    remember(key, &outputs);
+    store_on_disk(key, &outputs).await;
+    Some(outputs)
+}
+
+/// Stores outputs in memory, on disk and in Redis.
+pub async fn put(key: Key, outputs: &[Variant]) {
+    remember(key, outputs);
+    store_on_disk(key, outputs).await;
+    shared::put(&key, outputs).await;
+}
+
+async fn store_on_disk(key: Key, outputs: &[Variant]) {
+    if config::get().cache.dir.is_some() {
+        let value = encode(outputs);
+        let _ = tokio::task::spawn_blocking(move || disk::put(&key, value)).await;
+    }
+}
+
+/// The outcome of a transform, shared by every request waiting for it.
+type Shared = Arc<OnceCell<Result<Vec<Variant>, AppError>>>;
+
+static IN_FLIGHT: LazyLock<Mutex<HashMap<Key, Shared>>> = LazyLock::new(Default::default);
+
+/// Runs `transform` unless an identical one is already in progress, in which
+/// case its result is awaited instead. Should the request running it go away,
+/// one of the waiting ones takes over.
+pub async fn coalesce(key: Key, transform: impl Future<Output = Result<Vec<Variant>, AppError>>) -> Result<Vec<Variant>, AppError> {
+    let shared = IN_FLIGHT.lock().unwrap().entry(key).or_default().clone();
+    let mut ran = false;
+    let result = shared
+        .get_or_init(|| {
+            ran = true;
+            transform
+        })
+        .await;
+    if !ran {
+        tracing::debug!("joined an identical transform in progress");
+    }
+    {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if in_flight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &shared)) {
+            in_flight.remove(&key);
+        }
+    }
+    result.clone()
+}
+
+/// Stores outputs in memory, evicting the least recently used ones to stay
+/// within `CACHE_MAX_BYTES`. Outputs larger than the whole cache are not
+/// kept.
+fn remember(key: Key, outputs: &[Variant]) {
+    let Some(max_bytes) = config::get().cache.max_bytes else { return };
+    let size = outputs.iter().map(|output| output.bytes.len()).sum::<usize>();
+    if size > max_bytes {
+        return;
+    }
+    let mut cache = CACHE.lock().unwrap();
+    if let Some(replaced) = cache.entries.put(key, outputs.to_vec()) {
+        cache.bytes -= replaced.iter().map(|output| output.bytes.len()).sum::<usize>();
+    }
+    cache.bytes += size;
+    while cache.bytes > max_bytes {
+        let Some((_, evicted)) = cache.entries.pop_lru() else { break };
+        cache.bytes -= evicted.iter().map(|output| output.bytes.len()).sum::<usize>();
+    }
+}
+
+/// Per output: the format's extension, width, height and placeholder, then
+/// the encoded bytes, each length-prefixed.
+fn encode(outputs: &[Variant]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(outputs.iter().map(|output| output.bytes.len() + 24).sum());
+    for output in outputs {
+        let extension = output.format.extension().as_bytes();
+        value.push(extension.len() as u8);
+        value.extend_from_slice(extension);
+        value.extend_from_slice(&output.width.to_be_bytes());
+        value.extend_from_slice(&output.height.to_be_bytes());
+        // Placeholders are a few dozen characters; none is told apart from
+        // an empty one.
+        let placeholder = output.placeholder.as_deref().unwrap_or_default().as_bytes();
+        value.push(placeholder.len() as u8);
+        value.extend_from_slice(placeholder);
+        value.extend_from_slice(&(output.bytes.len() as u64).to_be_bytes());
+        value.extend_from_slice(&output.bytes);
+    }
+    value
+}
+
+fn decode(mut value: &[u8]) -> Option<Vec<Variant>> {
+    fn take<'a>(value: &mut &'a [u8], count: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = value.split_at_checked(count)?;
+        *value = rest;
+        Some(taken)
+    }
+    let mut outputs = Vec::new();
+    while !value.is_empty() {
+        let extension_length = take(&mut value, 1)?[0] as usize;
+        let format = OutputFormat::from_str(std::str::from_utf8(take(&mut value, extension_length)?).ok()?).ok()?;
+        let width = u32::from_be_bytes(take(&mut value, 4)?.try_into().ok()?);
+        let height = u32::from_be_bytes(take(&mut value, 4)?.try_into().ok()?);
+        let placeholder_length = take(&mut value, 1)?[0] as usize;
+        let placeholder = std::str::from_utf8(take(&mut value, placeholder_length)?).ok()?;
+        let placeholder = (!placeholder.is_empty()).then(|| placeholder.to_string());
+        let length = u64::from_be_bytes(take(&mut value, 8)?.try_into().ok()?) as usize;
+        outputs.push(Variant { bytes: take(&mut value, length)?.to_vec(), format, width, height, placeholder });
+    }
+    Some(outputs)
+}
+
+/// Results stored under `CACHE_DIR`, as `xx/<key>` files holding the
+/// [`encode`]d outputs. The index of sizes is rebuilt from the directory on
+/// first use, ordered by modification time, which hits refresh.
+mod disk {
+    use lru::LruCache;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{LazyLock, Mutex};
+    use std::time::SystemTime;
+
+    use super::{decode, Key};
+    use crate::config;
+    use crate::variants::Variant;
+
+    struct Index {
+        dir: PathBuf,
+        /// File names and their sizes.
+        entries: LruCache<String, u64>,
+        bytes: u64,
+    }
+
+    static INDEX: LazyLock<Option<Mutex<Index>>> = LazyLock::new(|| {
+        let dir = config::get().cache.dir.as_deref()?;
+        match scan(dir) {
+            Ok(index) => {
+                tracing::info!(entries = index.entries.len(), bytes = index.bytes, "loaded the disk result cache from {}", dir.display());
+                Some(Mutex::new(index))
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "disabling the disk result cache in {}", dir.display());
+                None
+            }
+        }
+    });
+
+    fn scan(dir: &Path) -> std::io::Result<Index> {
+        fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        for shard in fs::read_dir(dir)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(shard.path())? {
+                let file = file?;
+                let name = file.file_name().to_string_lossy().into_owned();
+                if name.ends_with(".partial") {
+                    // Left over from a store that was interrupted.
+                    let _ = fs::remove_file(file.path());
+                    continue;
+                }
+                let metadata = file.metadata()?;
+                if metadata.is_file() {
+                    files.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), name, metadata.len()));
+                }
+            }
+        }
+        files.sort();
+        let mut index = Index { dir: dir.to_path_buf(), entries: LruCache::unbounded(), bytes: 0 };
+        for (_, name, size) in files {
+            index.bytes += size;
+            index.entries.put(name, size);
+        }
+        evict(&mut index);
+        Ok(index)
+    }
+
+    impl Index {
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.join(&name[..2]).join(name)
+        }
+    }
+
+    /// Deletes the least recently used files until the index fits within
+    /// `CACHE_DIR_MAX_BYTES`.
+    fn evict(index: &mut Index) {
+        while index.bytes > config::get().cache.dir_max_bytes {
+            let Some((name, size)) = index.entries.pop_lru() else { break };
+            index.bytes -= size;
+            if let Err(e) = fs::remove_file(index.path(&name)) {
+                tracing::warn!(error = %e, "failed to evict {} from the disk result cache", name);
+            }
+        }
+    }
+
+    pub fn get(key: &Key) -> Option<Vec<Variant>> {
+        let index = INDEX.as_ref()?;
+        let name = key.hex();
+        let (size, path) = {
+            let mut index = index.lock().unwrap();
+            let size = *index.entries.get(&name)?;
+            (size, index.path(&name))
+        };
+        let outputs = fs::read(&path).ok().and_then(|value| decode(&value));
+        if outputs.is_none() {
+            tracing::warn!("dropping unreadable {} from the disk result cache", path.display());
+            let mut index = index.lock().unwrap();
+            if index.entries.pop(&name).is_some() {
+                index.bytes -= size;
+            }
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        // Keeps the file's place in the eviction order across restarts.
+        let _ = fs::File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+        outputs
+    }
+
+    pub fn put(key: &Key, value: Vec<u8>) {
+        let Some(index) = INDEX.as_ref() else { return };
+        let size = value.len() as u64;
+        if size > config::get().cache.dir_max_bytes {
+            return;
+        }
+        let name = key.hex();
+        let path = index.lock().unwrap().path(&name);
+        // Written under a temporary name first, so a crash never leaves a
+        // truncated entry behind.
+        let partial = path.with_extension("partial");
+        let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&partial, &value))
+            .and_then(|_| fs::rename(&partial, &path));
+        if let Err(e) = written {
+            tracing::warn!(error = %e, "failed to store {} in the disk result cache", path.display());
+            let _ = fs::remove_file(&partial);
+            return;
+        }
+        let mut index = index.lock().unwrap();
+        if let Some(replaced) = index.entries.put(name, size) {
+            index.bytes -= replaced;
+        }
+        index.bytes += size;
+        evict(&mut index);
+    }
+}
+
+/// The Redis cache. Errors are logged and treated as misses, so the service
+/// keeps working while Redis is down.
+#[cfg(feature = "redis")]
+mod shared {
+    use redis::{
+        aio::{ConnectionManager, ConnectionManagerConfig},
+        AsyncCommands,
+    };
+    use std::time::Duration;
+
+    use super::{decode, encode, Key};
+    use crate::config;
+    use crate::variants::Variant;
+
+    /// A slow cache is no better than none.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Connected on first use; a failed attempt is retried by the next
+    /// request.
+    static CONNECTION: tokio::sync::Mutex<Option<ConnectionManager>> = tokio::sync::Mutex::const_new(None);
+
+    async fn connection() -> Option<ConnectionManager> {
+        let url = config::get().cache.redis_url.as_deref()?;
+        let mut connection = CONNECTION.lock().await;
+        if connection.is_none() {
+            match redis::Client::open(url) {
+                Ok(client) => {
+                    let options = ConnectionManagerConfig::new()
+                        .set_connection_timeout(Some(TIMEOUT))
+                        .set_response_timeout(Some(TIMEOUT))
+                        .set_number_of_retries(1);
+                    match ConnectionManager::new_with_config(client, options).await {
+                        Ok(manager) => *connection = Some(manager),
+                        Err(e) => tracing::warn!(error = %e, "failed to connect to the Redis cache"),
+                    }
+                }
+                Err(e) => tracing::error!(error = %e, "invalid CACHE_REDIS_URL"),
+            }
+        }
+        connection.clone()
+    }
+
+    fn redis_key(key: &Key) -> String {
+        format!("image-transformer:{}", key.hex())
+    }
+
+    pub async fn ping() -> Result<(), String> {
+        let mut connection = connection().await.ok_or("failed to connect")?;
+        redis::cmd("PING").query_async::<()>(&mut connection).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn get(key: &Key) -> Option<Vec<Variant>> {
+        let mut connection = connection().await?;
+        match connection.get::<_, Option<Vec<u8>>>(redis_key(key)).await {
+            Ok(value) => value.and_then(|value| decode(&value)),
+            Err(e) => {
+                tracing::warn!(error = %e, "Redis cache lookup failed");
+                None
+            }
+        }
+    }
+
+    pub async fn put(key: &Key, outputs: &[Variant]) {
+        let Some(mut connection) = connection().await else { return };
+        let ttl = config::get().cache.redis_ttl.as_secs().max(1);
+        if let Err(e) = connection.set_ex::<_, _, ()>(redis_key(key), encode(outputs), ttl).await {
+            tracing::warn!(error = %e, "Redis cache store failed");
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+mod shared {
+    use super::Key;
+    use crate::variants::Variant;
+
+    pub async fn ping() -> Result<(), String> {
+        Err("Redis needs the `redis` feature".to_string())
+    }
+
+    pub async fn get(_key: &Key) -> Option<Vec<Variant>> {
+        None
+    }
+
+    pub async fn put(_key: &Key, _outputs: &[Variant]) {}
+}
@@ -0,0 +1,84 @@
+use axum::{
+    body::Bytes,
+    extract::Multipart,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::Engine;
+use image::DynamicImage;
+use image_transformer_core::{compare, fields};
+use serde_json::json;
+
+use crate::encode::{self, EncodeOptions, OutputFormat};
+use crate::error::AppError;
+use crate::{color, decode, metadata, pool, upload};
+
+/// Handler for the /compare/quality endpoint, which scores how closely an
+/// image matches a reference of the same size.
+/// Accepts multipart/form-data with the following fields:
+/// - "reference": The original image.
+/// - "image": The image to score, such as an encode of the reference.
+/// - "diff": An optional boolean, also returns a heatmap of where the images differ.
+/// - "format": An optional format of the heatmap ("png" by default, "webp", "jpeg", "avif" or "jxl").
+pub async fn quality_handler(mut multipart: Multipart) -> Result<Response, AppError> {
+    let (mut reference, mut image) = (None, None);
+    let mut diff = false;
+    let mut format = OutputFormat::Png;
+
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or("").to_string();
+
+        match name.as_str() {
+            "reference" => reference = Some(upload::read_file(field).await?),
+            "image" => image = Some(upload::read_file(field).await?),
+            "diff" => diff = fields::parse_bool(&field.text().await?, "diff")?,
+            "format" => {
+                format = field.text().await?.parse::<OutputFormat>()?;
+                if format == OutputFormat::Ico {
+                    return Err(AppError::invalid("The diff cannot be an ICO file"));
+                }
+            }
+            _ => { /* Ignore other fields */ }
+        }
+    }
+
+    let reference = reference.ok_or_else(|| AppError::invalid("Reference image not provided in 'reference' field").with_code("missing_image"))?;
+    let image = image.ok_or_else(|| AppError::invalid("Image data not provided in 'image' field").with_code("missing_image"))?;
+
+    let (quality, (width, height), heatmap) = pool::run(move || -> Result<_, AppError> {
+        let (reference, image) = (load(&reference)?, load(&image)?);
+        let quality = compare::quality(&reference, &image)?;
+        let heatmap = if diff {
+            Some(encode::encode_image(compare::diff_heatmap(&reference, &image)?, format, &EncodeOptions::default())?)
+        } else {
+            None
+        };
+        Ok((quality, (reference.width(), reference.height()), heatmap))
+    })
+    .await??;
+
+    let mut body = json!({
+        "width": width,
+        "height": height,
+        "ssim": quality.ssim,
+        "psnr": quality.psnr,
+    });
+    if let Some(heatmap) = heatmap {
+        let data = base64::engine::general_purpose::STANDARD.encode(heatmap);
+        body["diff"] = json!(format!("data:{};base64,{}", format.content_type(), data));
+    }
+    Ok(Json(body).into_response())
+}
+
+/// Decodes an image upright and in sRGB, as it is displayed.
+fn load(bytes: &Bytes) -> Result<DynamicImage, AppError> {
+    let image_format = decode::detect_format(bytes)?;
+    let mut img = decode::decode_image(bytes, image_format, &decode::DecodeOptions::default())?;
+    if let Some(profile) = decode::icc_profile(bytes, image_format) {
+        img = color::convert_to_srgb(img, &profile);
+    }
+    if let Some(orientation) = metadata::orientation(bytes, image_format) {
+        img = metadata::apply_orientation(img, orientation);
+    }
+    Ok(img)
+}
@@ -0,0 +1,158 @@
+use axum::{
+    body::Bytes,
+    extract::Multipart,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::encode::{self, EncodeOptions, OutputFormat};
+use crate::error::AppError;
+use crate::transform::{self, Fit, TransformOptions};
+use crate::{color, decode, metadata, pool, upload};
+
+/// Largest accepted canvas side, in pixels.
+const MAX_CANVAS_SIZE: u32 = 8192;
+
+/// The `layout` field: a canvas and the layers drawn onto it in order.
+#[derive(Deserialize)]
+struct Layout {
+    width: u32,
+    height: u32,
+    /// Hex color of the canvas, transparent by default.
+    background: Option<String>,
+    layers: Vec<Layer>,
+}
+
+#[derive(Deserialize)]
+struct Layer {
+    /// Name of the multipart field holding the layer's image.
+    image: String,
+    #[serde(default)]
+    x: i64,
+    #[serde(default)]
+    y: i64,
+    /// Target size; with only one side given the other follows the
+    /// aspect ratio, and without either the image keeps its own size.
+    width: Option<u32>,
+    height: Option<u32>,
+    /// How the image is fitted when both sides are given, "cover" by default.
+    fit: Option<String>,
+    #[serde(default = "full_opacity")]
+    opacity: f32,
+}
+
+fn full_opacity() -> f32 {
+    1.0
+}
+
+/// Handler for the /compose endpoint.
+/// Accepts multipart/form-data with the following fields:
+/// - "layout": JSON describing the canvas ("width", "height", optional "background") and its
+///   "layers", each naming an image field with an optional "x", "y", "width", "height", "fit"
+///   and "opacity".
+/// - "format": An optional output format ("webp", "jpeg", "avif", "png" or "jxl"), defaults to WebP.
+/// - "quality": An optional float for lossy compression quality (0.0 to 100.0).
+/// - Any other field: An image referenced by name from the layout.
+pub async fn compose_handler(mut multipart: Multipart) -> Result<Response, AppError> {
+    let mut layout: Option<String> = None;
+    let mut format = OutputFormat::WebP;
+    let mut encode_options = EncodeOptions::default();
+    let mut images: HashMap<String, Bytes> = HashMap::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or("").to_string();
+
+        match name.as_str() {
+            "layout" => {
+                layout = Some(field.text().await?);
+            }
+            "format" => {
+                format = field.text().await?.parse::<OutputFormat>()?;
+                if format == OutputFormat::Ico {
+                    return Err(AppError::invalid("Use the /favicon endpoint for ICO output"));
+                }
+            }
+            "quality" => {
+                encode_options.quality = field.text().await?.trim().parse::<f32>()
+                    .ok()
+                    .filter(|q| (0.0..=100.0).contains(q))
+                    .ok_or_else(|| AppError::invalid("Quality must be between 0.0 and 100.0").with_code("invalid_quality"))?;
+            }
+            _ => {
+                images.insert(name, upload::read_file(field).await?);
+            }
+        }
+    }
+
+    let layout = layout.ok_or_else(|| AppError::invalid("Layout not provided in 'layout' field").with_code("invalid_layout"))?;
+    let layout: Layout = serde_json::from_str(&layout)
+        .map_err(|e| AppError::invalid(format!("Invalid layout: {}", e)).with_code("invalid_layout"))?;
+
+    let output_bytes = pool::run(move || -> Result<Vec<u8>, AppError> {
+        let canvas = compose(&layout, &images)?;
+        Ok(encode::encode_image(canvas, format, &encode_options)?)
+    })
+    .await??;
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, format.content_type())],
+        output_bytes,
+    ).into_response())
+}
+
+fn compose(layout: &Layout, images: &HashMap<String, Bytes>) -> Result<DynamicImage, AppError> {
+    let valid_size = 1..=MAX_CANVAS_SIZE;
+    if !valid_size.contains(&layout.width) || !valid_size.contains(&layout.height) {
+        return Err(AppError::invalid(format!("Canvas width and height must be between 1 and {}", MAX_CANVAS_SIZE),
+        ).with_code("invalid_layout"));
+    }
+
+    let background = layout.background.as_deref().map(transform::parse_color).transpose()?.unwrap_or(Rgba([0, 0, 0, 0]));
+    let mut canvas = RgbaImage::from_pixel(layout.width, layout.height, background);
+
+    for (index, layer) in layout.layers.iter().enumerate() {
+        let bytes = images.get(&layer.image).ok_or_else(|| {
+            AppError::invalid(format!("Layer {} references missing image field '{}'", index, layer.image)).with_code("invalid_layout")
+        })?;
+        if !(0.0..=1.0).contains(&layer.opacity) {
+            return Err(AppError::invalid(format!("Layer {} opacity must be between 0.0 and 1.0", index)).with_code("invalid_layout"));
+        }
+
+        let mut image = load_layer(bytes, layer)?.to_rgba8();
+        if layer.opacity < 1.0 {
+            image.pixels_mut().for_each(|pixel| pixel[3] = (f32::from(pixel[3]) * layer.opacity).round() as u8);
+        }
+        imageops::overlay(&mut canvas, &image, layer.x, layer.y);
+    }
+
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Decodes a layer upright and in sRGB, resized to the layer's size.
+fn load_layer(bytes: &[u8], layer: &Layer) -> Result<DynamicImage, AppError> {
+    let image_format = decode::detect_format(bytes)?;
+    let mut img = decode::decode_image(bytes, image_format, &decode::DecodeOptions::default())?;
+    if let Some(profile) = decode::icc_profile(bytes, image_format) {
+        img = color::convert_to_srgb(img, &profile);
+    }
+    if let Some(orientation) = metadata::orientation(bytes, image_format) {
+        img = metadata::apply_orientation(img, orientation);
+    }
+
+    let scaled = |length: u32, from: u32, to: u32| (length as f64 * to as f64 / from as f64).round().max(1.0) as u32;
+    let (size, fit) = match (layer.width, layer.height) {
+        (Some(width), Some(height)) => ((width, height), layer.fit.as_deref().map(str::parse).transpose()?.unwrap_or(Fit::Cover)),
+        (Some(width), None) => ((width, scaled(img.height(), img.width(), width)), Fit::Fill),
+        (None, Some(height)) => ((scaled(img.width(), img.height(), height), height), Fit::Fill),
+        (None, None) => return Ok(img),
+    };
+    if size.0 == 0 || size.1 == 0 || size.0 > MAX_CANVAS_SIZE || size.1 > MAX_CANVAS_SIZE {
+        return Err(AppError::invalid(format!("Layer width and height must be between 1 and {}", MAX_CANVAS_SIZE)).with_code("invalid_layout"));
+    }
+
+    Ok(transform::transform_image(img, &TransformOptions { width: Some(size.0), height: Some(size.1), fit, ..Default::default() })?)
+}
@@ -0,0 +1,682 @@
+use axum::extract::Query;
+use axum::http::{HeaderValue, Uri};
+use base64::Engine;
+use figment::{
+    providers::{Format, Toml, Yaml},
+    Figment,
+};
+use image_transformer_core::Settings;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::auth::{self, AuthConfig};
+use crate::azure::{AzureConfig, ConnectionString};
+use crate::cache::CacheConfig;
+use crate::decode::InputLimit;
+use crate::encode::OutputFormat;
+use crate::fetch::FetchConfig;
+use crate::gcs::{GcsConfig, ServiceAccount};
+use crate::jobs::JobConfig;
+use crate::jwt::JwtConfig;
+use crate::pool::PoolConfig;
+use crate::queue::QueueConfig;
+use crate::quota::{self, QuotaConfig};
+use crate::s3::S3Config;
+use crate::transform::SizeLimit;
+use crate::unix_socket::UnixSocketConfig;
+use crate::watch::WatchConfig;
+use crate::webhook::WebhookConfig;
+
+/// Server-wide defaults, read once from the environment and the file named
+/// by `CONFIG_FILE`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the server listens on, from `BIND_ADDR` and `PORT`. `None`
+    /// when it only listens on `unix_socket`.
+    pub listen_addr: Option<SocketAddr>,
+    /// Unix domain socket the server listens on, from `UNIX_SOCKET_PATH`.
+    pub unix_socket: Option<UnixSocketConfig>,
+    /// Address gRPC is served on, from `BIND_ADDR` and `GRPC_PORT`; not
+    /// served when unset or without the `grpc` feature.
+    pub grpc_addr: Option<SocketAddr>,
+    /// Origins that browsers may call the API from, from
+    /// `CORS_ALLOWED_ORIGINS`; `None` allows any origin.
+    pub cors_allowed_origins: Option<Vec<HeaderValue>>,
+    /// Default for the `no_enlarge` field, from `NO_ENLARGE`.
+    pub no_enlarge: bool,
+    /// Largest `/transform` output box, from `MAX_OUTPUT_WIDTH` and
+    /// `MAX_OUTPUT_HEIGHT`; `MAX_OUTPUT_MODE=clamp` shrinks larger requests
+    /// instead of rejecting them.
+    pub size_limit: Option<SizeLimit>,
+    /// Output formats that /transform may produce, from `ENABLED_FORMATS`;
+    /// `None` enables all of them.
+    pub enabled_formats: Option<Vec<OutputFormat>>,
+    /// Largest request body accepted, including uploads to `/jobs`, from
+    /// `MAX_UPLOAD_BYTES`.
+    pub max_upload_bytes: usize,
+    /// Uploaded files over this size are kept in a temporary file instead of
+    /// memory, from `UPLOAD_MEMORY_BYTES`.
+    pub upload_memory_bytes: usize,
+    /// Directory for those files, from `UPLOAD_TEMP_DIR`; the system's
+    /// temporary directory when unset.
+    pub upload_temp_dir: Option<PathBuf>,
+    /// Largest decoded input, from `MAX_INPUT_WIDTH`, `MAX_INPUT_HEIGHT` and
+    /// `MAX_INPUT_PIXELS`.
+    pub input_limit: InputLimit,
+    /// Limits for `url` sources, from the `FETCH_*` variables.
+    pub fetch: FetchConfig,
+    /// Accepted API keys and bearer tokens, from `API_KEYS`, `API_KEYS_FILE`
+    /// and the `JWT_*` variables.
+    pub auth: AuthConfig,
+    /// File that keys created through `/admin/keys` are kept in, from
+    /// `KEY_STORE_PATH`.
+    pub key_store_path: Option<PathBuf>,
+    /// File that presets created through `/admin/presets` are kept in, from
+    /// `PRESET_STORE_PATH`.
+    pub preset_store_path: Option<PathBuf>,
+    /// SQLite database that usage counters are kept in, from `USAGE_DB_PATH`.
+    pub usage_db_path: Option<PathBuf>,
+    /// Limits per client, from `RATE_LIMIT_PER_MINUTE` and
+    /// `QUOTA_MEGAPIXELS_PER_DAY`.
+    pub quota: QuotaConfig,
+    /// Shared secret for signed URLs, from `URL_SIGNING_KEY`.
+    pub signing_key: Option<String>,
+    /// Serve unsigned `/unsafe/...` paths, from `ALLOW_UNSAFE_URLS`; only on
+    /// by default while no signing key is set.
+    pub allow_unsafe_urls: bool,
+    /// Limits for `/jobs`, from the `JOB_*` variables. Also bounds the jobs
+    /// taken from `queue` at a time.
+    pub jobs: JobConfig,
+    /// The NATS stream that jobs are consumed from, once `NATS_URL` is set.
+    pub queue: Option<QueueConfig>,
+    /// The directory that images are transformed from, once `WATCH_DIR` is
+    /// set.
+    pub watch: Option<WatchConfig>,
+    /// Named sets of /transform fields that the `preset` field applies, from
+    /// the `PRESET_*` variables, by lower-case name.
+    pub presets: BTreeMap<String, Vec<(String, String)>>,
+    /// Limits for image processing, from the `TRANSFORM_*` variables.
+    pub pool: PoolConfig,
+    /// How long requests and jobs may take to finish once a shutdown signal
+    /// is received, from `SHUTDOWN_TIMEOUT_SECS`.
+    pub shutdown_timeout: Duration,
+    /// Job callback settings, from `JOB_CALLBACK_SECRET` and
+    /// `JOB_CALLBACK_ATTEMPTS`.
+    pub webhook: WebhookConfig,
+    /// Base URL the server is reachable at, from `PUBLIC_URL`, for absolute
+    /// links in job statuses and to stored files.
+    pub public_url: Option<String>,
+    /// S3 output settings, from the `S3_*` and `AWS_*` variables.
+    pub s3: S3Config,
+    /// Azure Blob Storage output settings, from the `AZURE_*` variables.
+    pub azure: AzureConfig,
+    /// Google Cloud Storage settings, from the `GCS_*` variables and
+    /// `GOOGLE_APPLICATION_CREDENTIALS`.
+    pub gcs: GcsConfig,
+    /// Directory for `file` output, from `FILE_STORAGE_DIR`.
+    pub file_storage_dir: Option<PathBuf>,
+    /// Result cache settings, from the `CACHE_*` variables.
+    pub cache: CacheConfig,
+    /// `Cache-Control` of transform responses, from `CACHE_CONTROL`; `none`
+    /// leaves the header out.
+    pub cache_control: Option<String>,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let signing_key = var("URL_SIGNING_KEY").filter(|key| !key.is_empty());
+        Self {
+            listen_addr: listen_addr_from_env(),
+            unix_socket: env_string("UNIX_SOCKET_PATH").map(|path| UnixSocketConfig {
+                path: PathBuf::from(path),
+                mode: env_string("UNIX_SOCKET_MODE").and_then(|mode| match u32::from_str_radix(mode.trim(), 8) {
+                    Ok(mode) if mode <= 0o777 => Some(mode),
+                    _ => {
+                        invalid(format!("Invalid UNIX_SOCKET_MODE value {:?}, expected octal permissions such as 660", mode));
+                        None
+                    }
+                }),
+            }),
+            grpc_addr: grpc_addr_from_env(),
+            cors_allowed_origins: cors_allowed_origins_from_env(),
+            no_enlarge: env_bool("NO_ENLARGE").unwrap_or(false),
+            size_limit: size_limit_from_env(),
+            enabled_formats: enabled_formats_from_env(),
+            max_upload_bytes: env_u64("MAX_UPLOAD_BYTES").map_or(100 * 1024 * 1024, |bytes| bytes as usize),
+            upload_memory_bytes: env_u64("UPLOAD_MEMORY_BYTES").map_or(8 * 1024 * 1024, |bytes| bytes as usize),
+            upload_temp_dir: env_string("UPLOAD_TEMP_DIR").map(PathBuf::from),
+            input_limit: InputLimit {
+                width: env_u32("MAX_INPUT_WIDTH").unwrap_or(u32::MAX),
+                height: env_u32("MAX_INPUT_HEIGHT").unwrap_or(u32::MAX),
+                pixels: env_u64("MAX_INPUT_PIXELS").unwrap_or(100_000_000),
+            },
+            fetch: FetchConfig {
+                allowed_hosts: var("FETCH_ALLOWED_HOSTS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|host| host.trim().to_ascii_lowercase())
+                    .filter(|host| !host.is_empty())
+                    .collect(),
+                allow_private: env_bool("FETCH_ALLOW_PRIVATE").unwrap_or(false),
+                max_bytes: env_u32("FETCH_MAX_BYTES").map_or(20 * 1024 * 1024, |bytes| bytes as usize),
+                timeout: Duration::from_secs(env_u32("FETCH_TIMEOUT_SECS").unwrap_or(10).into()),
+                max_redirects: env_u32("FETCH_MAX_REDIRECTS").map_or(3, |redirects| redirects as usize),
+            },
+            jobs: JobConfig {
+                workers: env_u32("JOB_WORKERS").map_or(2, |workers| workers as usize),
+                queue_size: env_u32("JOB_QUEUE_SIZE").map_or(100, |size| size as usize),
+                retention: Duration::from_secs(env_u32("JOB_RETENTION_SECS").unwrap_or(3600).into()),
+            },
+            queue: queue_from_env(),
+            watch: watch_from_env(),
+            presets: presets_from_env(),
+            pool: pool_from_env(),
+            // Below the 30 seconds that Kubernetes waits before killing a pod.
+            shutdown_timeout: Duration::from_secs(env_u32("SHUTDOWN_TIMEOUT_SECS").unwrap_or(25).into()),
+            webhook: WebhookConfig {
+                secret: var("JOB_CALLBACK_SECRET").filter(|secret| !secret.is_empty()),
+                attempts: env_u32("JOB_CALLBACK_ATTEMPTS").unwrap_or(5),
+            },
+            public_url: var("PUBLIC_URL")
+                .map(|url| url.trim().trim_end_matches('/').to_string())
+                .filter(|url| !url.is_empty()),
+            s3: S3Config {
+                endpoint: env_string("S3_ENDPOINT"),
+                region: env_string("S3_REGION").or_else(|| env_string("AWS_REGION")).unwrap_or_else(|| "us-east-1".to_string()),
+                access_key_id: env_string("AWS_ACCESS_KEY_ID"),
+                secret_access_key: env_string("AWS_SECRET_ACCESS_KEY"),
+                session_token: env_string("AWS_SESSION_TOKEN"),
+                buckets: env_string("S3_BUCKETS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|bucket| bucket.trim().to_string())
+                    .filter(|bucket| !bucket.is_empty())
+                    .collect(),
+                path_style: env_bool("S3_PATH_STYLE").unwrap_or(false),
+                url_expiry: Duration::from_secs(env_u32("S3_URL_EXPIRY_SECS").unwrap_or(3600).into()),
+            },
+            azure: azure_from_env(),
+            gcs: GcsConfig {
+                endpoint: env_string("GCS_ENDPOINT").map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+                credentials: gcs_credentials_from_env(),
+                buckets: env_string("GCS_BUCKETS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|bucket| bucket.trim().to_string())
+                    .filter(|bucket| !bucket.is_empty())
+                    .collect(),
+                url_expiry: Duration::from_secs(env_u32("GCS_URL_EXPIRY_SECS").unwrap_or(3600).into()),
+            },
+            file_storage_dir: env_string("FILE_STORAGE_DIR").map(PathBuf::from),
+            cache: CacheConfig {
+                max_bytes: env_u32("CACHE_MAX_BYTES").map(|bytes| bytes as usize),
+                dir: env_string("CACHE_DIR").map(PathBuf::from),
+                dir_max_bytes: env_u64("CACHE_DIR_MAX_BYTES").unwrap_or(1 << 30),
+                redis_url: env_string("CACHE_REDIS_URL"),
+                redis_ttl: Duration::from_secs(env_u32("CACHE_REDIS_TTL_SECS").unwrap_or(86400).into()),
+            },
+            cache_control: match env_string("CACHE_CONTROL") {
+                Some(value) if value.eq_ignore_ascii_case("none") => None,
+                value => Some(value.unwrap_or_else(|| "public, max-age=86400".to_string())),
+            },
+            auth: auth_from_env(),
+            key_store_path: env_string("KEY_STORE_PATH").map(PathBuf::from),
+            preset_store_path: env_string("PRESET_STORE_PATH").map(PathBuf::from),
+            usage_db_path: env_string("USAGE_DB_PATH").map(PathBuf::from),
+            quota: QuotaConfig {
+                requests_per_minute: env_u64("RATE_LIMIT_PER_MINUTE"),
+                megapixels_per_day: env_u64("QUOTA_MEGAPIXELS_PER_DAY"),
+            },
+            allow_unsafe_urls: env_bool("ALLOW_UNSAFE_URLS").unwrap_or(signing_key.is_none()),
+            signing_key,
+        }
+    }
+}
+
+static CONFIG: LazyLock<Config> = LazyLock::new(Config::from_env);
+
+/// What is wrong with the settings read into `CONFIG`, reported by `load`.
+static ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Records an invalid setting, which stops the server at startup.
+fn invalid(error: String) {
+    ERRORS.lock().unwrap_or_else(PoisonError::into_inner).push(error);
+}
+
+/// Every variable that the config file may set.
+const VARIABLES: &[&str] = &[
+    "RUST_LOG", "LOG_FORMAT", "BIND_ADDR", "PORT", "UNIX_SOCKET_PATH", "UNIX_SOCKET_MODE", "GRPC_PORT",
+    "CORS_ALLOWED_ORIGINS",
+    "NO_ENLARGE", "MAX_OUTPUT_WIDTH", "MAX_OUTPUT_HEIGHT", "MAX_OUTPUT_MODE", "ENABLED_FORMATS",
+    "MAX_UPLOAD_BYTES", "UPLOAD_MEMORY_BYTES", "UPLOAD_TEMP_DIR",
+    "MAX_INPUT_WIDTH", "MAX_INPUT_HEIGHT", "MAX_INPUT_PIXELS",
+    "FETCH_ALLOWED_HOSTS", "FETCH_ALLOW_PRIVATE", "FETCH_MAX_BYTES", "FETCH_TIMEOUT_SECS", "FETCH_MAX_REDIRECTS",
+    "API_KEYS", "API_KEYS_FILE", "ADMIN_API_KEYS", "JWT_ISSUER", "JWT_JWKS_URL", "JWT_AUDIENCE", "JWT_SCOPES",
+    "KEY_STORE_PATH", "PRESET_STORE_PATH", "RATE_LIMIT_PER_MINUTE", "QUOTA_MEGAPIXELS_PER_DAY", "USAGE_DB_PATH",
+    "URL_SIGNING_KEY", "ALLOW_UNSAFE_URLS",
+    "JOB_WORKERS", "JOB_QUEUE_SIZE", "JOB_RETENTION_SECS", "JOB_CALLBACK_SECRET", "JOB_CALLBACK_ATTEMPTS",
+    "NATS_URL", "NATS_STREAM", "NATS_SUBJECT", "NATS_CONSUMER", "NATS_RESULT_SUBJECT", "NATS_MAX_DELIVER", "NATS_ACK_WAIT_SECS",
+    "WATCH_DIR", "WATCH_OUTPUT_DIR", "WATCH_PRESET", "WATCH_DONE_DIR", "WATCH_POLL_SECS", "WATCH_SETTLE_SECS",
+    "TRANSFORM_WORKERS", "TRANSFORM_QUEUE_SIZE", "TRANSFORM_QUEUE_TIMEOUT_SECS", "SHUTDOWN_TIMEOUT_SECS",
+    "PUBLIC_URL",
+    "S3_ENDPOINT", "S3_REGION", "S3_BUCKETS", "S3_PATH_STYLE", "S3_URL_EXPIRY_SECS",
+    "AWS_REGION", "AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY", "AWS_SESSION_TOKEN",
+    "AZURE_STORAGE_CONNECTION_STRING", "AZURE_STORAGE_ACCOUNT", "AZURE_CLIENT_ID", "AZURE_STORAGE_CONTAINERS", "AZURE_URL_EXPIRY_SECS",
+    "GCS_ENDPOINT", "GCS_BUCKETS", "GCS_URL_EXPIRY_SECS", "GOOGLE_APPLICATION_CREDENTIALS",
+    "FILE_STORAGE_DIR",
+    "CACHE_MAX_BYTES", "CACHE_DIR", "CACHE_DIR_MAX_BYTES", "CACHE_CONTROL", "CACHE_REDIS_URL", "CACHE_REDIS_TTL_SECS",
+    "WATERMARK_PATH", "FACE_MODEL_PATH", "PDFIUM_LIBRARY_PATH",
+];
+
+/// Variables named with it define a preset each, such as `PRESET_THUMBNAIL`.
+const PRESET_PREFIX: &str = "PRESET_";
+
+/// The settings of the config file by variable name, or why it could not
+/// be read. Loaded without logging, since `RUST_LOG` and `LOG_FORMAT` may
+/// come from it.
+static FILE: LazyLock<Result<BTreeMap<String, String>, String>> = LazyLock::new(|| match std::env::var_os("CONFIG_FILE") {
+    Some(path) if !path.is_empty() => read_file(Path::new(&path)),
+    _ => Ok(BTreeMap::new()),
+});
+
+/// Reads the config file and the environment, returning everything that is
+/// wrong with them. Called at startup, so that mistakes stop the server
+/// instead of surfacing with the first request or falling back to defaults.
+pub fn load() -> Result<(), Vec<String>> {
+    let file = FILE.as_ref().map_err(|e| vec![e.clone()])?;
+    let mut errors: Vec<_> = file.keys()
+        .filter(|name| !VARIABLES.contains(&name.as_str()) && !name.starts_with(PRESET_PREFIX))
+        .map(|name| format!("Unknown setting {} in CONFIG_FILE", name.to_ascii_lowercase()))
+        .collect();
+    // Read by `main` before the configuration.
+    if let Some(log_format) = var("LOG_FORMAT")
+        && !matches!(log_format.trim().to_ascii_lowercase().as_str(), "" | "text" | "json")
+    {
+        errors.push(format!("Invalid LOG_FORMAT value {:?}, expected 'text' or 'json'", log_format));
+    }
+    LazyLock::force(&CONFIG);
+    errors.append(&mut ERRORS.lock().unwrap_or_else(PoisonError::into_inner));
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// Reads a setting from the environment, falling back to the config file.
+/// A variable set to an empty value clears the file's setting.
+pub fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().or_else(|| FILE.as_ref().ok()?.get(name).cloned())
+}
+
+/// Parses a TOML or YAML file. Its keys are the variable names in lower case,
+/// and may be grouped in tables: `allowed_hosts` in `[fetch]` sets
+/// `FETCH_ALLOWED_HOSTS`. Lists stand for comma-separated values.
+fn read_file(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let figment = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => Figment::from(Toml::file_exact(path)),
+        Some("yaml" | "yml") => Figment::from(Yaml::file_exact(path)),
+        _ => return Err(format!("Unsupported CONFIG_FILE {}, expected a .toml, .yaml or .yml file", path.display())),
+    };
+    let settings: serde_json::Map<String, Value> = figment.extract()
+        .map_err(|e| format!("Invalid CONFIG_FILE: {}", e))?;
+    let mut variables = BTreeMap::new();
+    flatten("", &settings, &mut variables).map_err(|e| format!("Invalid CONFIG_FILE {}: {}", path.display(), e))?;
+    Ok(variables)
+}
+
+fn flatten(prefix: &str, table: &serde_json::Map<String, Value>, variables: &mut BTreeMap<String, String>) -> Result<(), String> {
+    for (key, value) in table {
+        let name = format!("{}{}", prefix, key.to_ascii_uppercase().replace('-', "_"));
+        let value = match value {
+            Value::Object(table) => {
+                flatten(&format!("{}_", name), table, variables)?;
+                continue;
+            }
+            Value::Array(values) => values.iter().map(scalar).collect::<Option<Vec<_>>>().map(|values| values.join(",")),
+            value => scalar(value),
+        };
+        let value = value.ok_or_else(|| format!("{} must be a string, number, boolean or a list of those", name.to_ascii_lowercase()))?;
+        if variables.insert(name.clone(), value).is_some() {
+            return Err(format!("{} is set twice", name.to_ascii_lowercase()));
+        }
+    }
+    Ok(())
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns the server configuration.
+pub fn get() -> &'static Config {
+    &CONFIG
+}
+
+/// The settings of the image pipeline, which decoded inputs are charged to
+/// the client's quota by.
+pub fn pipeline_settings() -> Settings {
+    Settings {
+        input_limit: get().input_limit,
+        watermark_path: var("WATERMARK_PATH"),
+        face_model_path: var("FACE_MODEL_PATH"),
+        pdfium_library_path: var("PDFIUM_LIBRARY_PATH"),
+        enabled_formats: get().enabled_formats.clone(),
+        on_decode: quota::charge_pixels,
+    }
+}
+
+/// Prefixes a path with `PUBLIC_URL`, so that callbacks and stored results
+/// get absolute links.
+pub fn public_url(path: &str) -> String {
+    format!("{}{}", get().public_url.as_deref().unwrap_or_default(), path)
+}
+
+fn size_limit_from_env() -> Option<SizeLimit> {
+    let (width, height) = (env_u32("MAX_OUTPUT_WIDTH"), env_u32("MAX_OUTPUT_HEIGHT"));
+    if width.is_none() && height.is_none() {
+        return None;
+    }
+    let clamp = match var("MAX_OUTPUT_MODE").as_deref().map(str::trim) {
+        None | Some("reject") => false,
+        Some("clamp") => true,
+        Some(other) => {
+            invalid(format!("Invalid MAX_OUTPUT_MODE value {:?}, expected 'reject' or 'clamp'", other));
+            false
+        }
+    };
+    Some(SizeLimit { width: width.unwrap_or(u32::MAX), height: height.unwrap_or(u32::MAX), clamp })
+}
+
+/// Reads the comma-separated `ENABLED_FORMATS`, such as `webp,jpeg,png`.
+fn enabled_formats_from_env() -> Option<Vec<OutputFormat>> {
+    let value = env_string("ENABLED_FORMATS")?;
+    let mut formats = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match name.parse::<OutputFormat>() {
+            Ok(format) => formats.push(format),
+            Err(_) => invalid(format!(
+                "Invalid ENABLED_FORMATS entry {:?}, expected one of 'webp', 'jpeg', 'avif', 'png', 'jxl' or 'ico'", name,
+            )),
+        }
+    }
+    if formats.is_empty() {
+        invalid(format!("Invalid ENABLED_FORMATS value {:?}, expected at least one format", value));
+    }
+    Some(formats)
+}
+
+/// Reads the `TRANSFORM_*` limits, which default to one worker per core and
+/// a queue of eight requests per worker.
+fn pool_from_env() -> PoolConfig {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let workers = env_u32("TRANSFORM_WORKERS").map_or(cores, |workers| workers as usize);
+    PoolConfig {
+        workers,
+        queue_size: env_u32("TRANSFORM_QUEUE_SIZE").map_or(workers * 8, |size| size as usize),
+        queue_timeout: Duration::from_secs(env_u32("TRANSFORM_QUEUE_TIMEOUT_SECS").unwrap_or(30).into()),
+    }
+}
+
+/// Reads `BIND_ADDR`, an IPv4 or IPv6 address, and `PORT`, defaulting to
+/// `0.0.0.0:3000`. With `UNIX_SOCKET_PATH` set, the server only listens on
+/// TCP when either of them is set as well.
+fn listen_addr_from_env() -> Option<SocketAddr> {
+    if env_string("UNIX_SOCKET_PATH").is_some() && env_string("BIND_ADDR").is_none() && env_string("PORT").is_none() {
+        return None;
+    }
+    let ip = env_string("BIND_ADDR").and_then(|value| match value.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) => {
+            invalid(format!("Invalid BIND_ADDR value {:?}, expected an IP address", value));
+            None
+        }
+    });
+    let port = env_u32("PORT").and_then(|port| match u16::try_from(port) {
+        Ok(port) => Some(port),
+        Err(_) => {
+            invalid(format!("Invalid PORT value {}, expected at most 65535", port));
+            None
+        }
+    });
+    Some(SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port.unwrap_or(3000)))
+}
+
+/// Reads `GRPC_PORT`, which is served on the IP of `BIND_ADDR` as well.
+fn grpc_addr_from_env() -> Option<SocketAddr> {
+    let port = env_u32("GRPC_PORT")?;
+    if !cfg!(feature = "grpc") {
+        tracing::error!("Ignoring GRPC_PORT, which needs the `grpc` feature");
+        return None;
+    }
+    let Ok(port) = u16::try_from(port) else {
+        invalid(format!("Invalid GRPC_PORT value {}, expected at most 65535", port));
+        return None;
+    };
+    // An invalid `BIND_ADDR` was reported with `PORT` already.
+    let ip = env_string("BIND_ADDR").and_then(|value| value.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok());
+    Some(SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port))
+}
+
+/// Reads the `NATS_*` variables, which need the `nats` feature.
+fn queue_from_env() -> Option<QueueConfig> {
+    let url = env_string("NATS_URL")?;
+    if !cfg!(feature = "nats") {
+        tracing::error!("Ignoring NATS_URL, which needs the `nats` feature");
+        return None;
+    }
+    Some(QueueConfig {
+        url,
+        stream: env_string("NATS_STREAM").unwrap_or_else(|| "IMAGE_TRANSFORMS".to_string()),
+        subject: env_string("NATS_SUBJECT").unwrap_or_else(|| "image.transform".to_string()),
+        consumer: env_string("NATS_CONSUMER").unwrap_or_else(|| "image_transformer_api".to_string()),
+        result_subject: env_string("NATS_RESULT_SUBJECT"),
+        max_deliver: env_u32("NATS_MAX_DELIVER").unwrap_or(5).into(),
+        ack_wait: Duration::from_secs(env_u32("NATS_ACK_WAIT_SECS").unwrap_or(300).into()),
+    })
+}
+
+/// Reads the `WATCH_*` variables, which need the `watch` feature.
+fn watch_from_env() -> Option<WatchConfig> {
+    let dir = env_string("WATCH_DIR")?;
+    if !cfg!(feature = "watch") {
+        tracing::error!("Ignoring WATCH_DIR, which needs the `watch` feature");
+        return None;
+    }
+    let Some(output_dir) = env_string("WATCH_OUTPUT_DIR") else {
+        invalid("WATCH_DIR needs WATCH_OUTPUT_DIR for the results".to_string());
+        return None;
+    };
+    Some(WatchConfig {
+        dir: PathBuf::from(dir),
+        output_dir: PathBuf::from(output_dir),
+        preset: env_string("WATCH_PRESET").unwrap_or_default(),
+        done_dir: env_string("WATCH_DONE_DIR").map(PathBuf::from),
+        poll_interval: env_u32("WATCH_POLL_SECS").filter(|secs| *secs > 0).map(|secs| Duration::from_secs(secs.into())),
+        settle: Duration::from_secs(env_u32("WATCH_SETTLE_SECS").unwrap_or(2).into()),
+    })
+}
+
+/// Reads the `PRESET_*` variables, each the /transform fields of a preset as
+/// a query string such as `size=400x400&fit=cover&format=webp`.
+fn presets_from_env() -> BTreeMap<String, Vec<(String, String)>> {
+    let file = FILE.as_ref().ok().into_iter().flat_map(|file| file.keys().cloned());
+    let names: BTreeSet<String> = std::env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .chain(file)
+        .filter(|name| name.len() > PRESET_PREFIX.len() && name.starts_with(PRESET_PREFIX))
+        // Such as `PRESET_STORE_PATH`.
+        .filter(|name| !VARIABLES.contains(&name.as_str()))
+        .collect();
+    let mut presets = BTreeMap::new();
+    for name in names {
+        let Some(value) = env_string(&name) else { continue };
+        match crate::presets::parse(&value) {
+            Ok(fields) => {
+                presets.insert(crate::presets::key(&name[PRESET_PREFIX.len()..]), fields);
+            }
+            Err(e) => invalid(format!("Invalid {}: {}", name, e)),
+        }
+    }
+    presets
+}
+
+/// Parses /transform fields given as a query string, like those of a
+/// `GET /transform`.
+pub fn parse_fields(query: &str) -> Result<Vec<(String, String)>, String> {
+    let uri: Uri = format!("/?{}", query.trim().trim_start_matches('?')).parse()
+        .map_err(|_| "expected a query string such as 'size=800x600&format=avif'".to_string())?;
+    let Query(fields) = Query::<Vec<(String, String)>>::try_from_uri(&uri).map_err(|rejection| rejection.body_text())?;
+    Ok(fields)
+}
+
+/// Reads the comma-separated `CORS_ALLOWED_ORIGINS`, such as
+/// `https://app.example.com,http://localhost:5173`. Unset or `*` allows any
+/// origin.
+fn cors_allowed_origins_from_env() -> Option<Vec<HeaderValue>> {
+    let value = env_string("CORS_ALLOWED_ORIGINS")?;
+    if value == "*" {
+        return None;
+    }
+    let origins = value
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/'))
+        .filter(|origin| !origin.is_empty())
+        .filter_map(|origin| {
+            let valid = origin.split_once("://")
+                .is_some_and(|(scheme, host)| matches!(scheme, "http" | "https") && !host.is_empty() && !host.contains('/'));
+            match HeaderValue::from_str(&origin.to_ascii_lowercase()) {
+                Ok(origin) if valid => Some(origin),
+                _ => {
+                    invalid(format!("Invalid CORS_ALLOWED_ORIGINS entry {:?}, expected an origin such as https://example.com", origin));
+                    None
+                }
+            }
+        })
+        .collect();
+    Some(origins)
+}
+
+/// Reads the comma-separated `name:key` entries of `API_KEYS`, and those of
+/// `API_KEYS_FILE`, one per line with `#` starting a comment, the admin keys
+/// of `ADMIN_API_KEYS`, and the `JWT_*` settings. Keys are required as well
+/// once `KEY_STORE_PATH` is set, since it holds more of them.
+fn auth_from_env() -> AuthConfig {
+    let (issuer, jwks_url) = (env_string("JWT_ISSUER"), env_string("JWT_JWKS_URL"));
+    let mut auth = AuthConfig {
+        jwt: (issuer.is_some() || jwks_url.is_some()).then(|| JwtConfig {
+            issuer,
+            jwks_url,
+            audience: env_string("JWT_AUDIENCE"),
+            scopes: env_string("JWT_SCOPES")
+                .unwrap_or_default()
+                .split([',', ' '])
+                .filter(|scope| !scope.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }),
+        admin_keys: auth::parse_keys(env_string("ADMIN_API_KEYS").unwrap_or_default().split(',')),
+        ..AuthConfig::default()
+    };
+    if let Some(keys) = env_string("API_KEYS") {
+        auth.required = true;
+        auth.api_keys.extend(auth::parse_keys(keys.split(',')));
+    }
+    if env_string("KEY_STORE_PATH").is_some() {
+        auth.required = true;
+    }
+    if let Some(path) = env_string("API_KEYS_FILE") {
+        auth.required = true;
+        match std::fs::read_to_string(&path) {
+            Ok(keys) => auth.api_keys.extend(auth::parse_keys(keys.lines().filter(|line| !line.trim_start().starts_with('#')))),
+            Err(e) => tracing::error!("Failed to read API_KEYS_FILE {:?}, rejecting all API requests: {}", path, e),
+        }
+    }
+    auth
+}
+
+/// Reads `AZURE_STORAGE_CONNECTION_STRING`, or `AZURE_STORAGE_ACCOUNT` for
+/// managed identity.
+fn azure_from_env() -> AzureConfig {
+    let connection = env_string("AZURE_STORAGE_CONNECTION_STRING").map(|value| ConnectionString::parse(&value)).unwrap_or_default();
+    let account_key = connection.account_key.and_then(|key| match base64::engine::general_purpose::STANDARD.decode(&key) {
+        Ok(key) => Some(key),
+        Err(_) => {
+            invalid("Invalid AccountKey in AZURE_STORAGE_CONNECTION_STRING, expected base64".to_string());
+            None
+        }
+    });
+    AzureConfig {
+        account: connection.account.or_else(|| env_string("AZURE_STORAGE_ACCOUNT")),
+        endpoint: connection.endpoint,
+        account_key,
+        client_id: env_string("AZURE_CLIENT_ID"),
+        containers: env_string("AZURE_STORAGE_CONTAINERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|container| container.trim().to_string())
+            .filter(|container| !container.is_empty())
+            .collect(),
+        url_expiry: Duration::from_secs(env_u32("AZURE_URL_EXPIRY_SECS").unwrap_or(3600).into()),
+    }
+}
+
+/// Loads the service account key file named by
+/// `GOOGLE_APPLICATION_CREDENTIALS`.
+fn gcs_credentials_from_env() -> Option<ServiceAccount> {
+    let path = env_string("GOOGLE_APPLICATION_CREDENTIALS")?;
+    match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|json| ServiceAccount::parse(&json)) {
+        Ok(credentials) => Some(credentials),
+        Err(e) => {
+            invalid(format!("Invalid GOOGLE_APPLICATION_CREDENTIALS {:?}: {}", path, e));
+            None
+        }
+    }
+}
+
+/// Reads a variable, treating an empty value as unset.
+fn env_string(name: &str) -> Option<String> {
+    var(name).map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// Reads a positive integer variable, reporting other values.
+fn env_u32(name: &str) -> Option<u32> {
+    let value = var(name)?;
+    match value.trim().parse::<u32>() {
+        Ok(number) if number > 0 => Some(number),
+        _ => {
+            invalid(format!("Invalid {} value {:?}, expected a positive integer", name, value));
+            None
+        }
+    }
+}
+
+/// Like [`env_u32`], for sizes that may exceed 4 GiB.
+fn env_u64(name: &str) -> Option<u64> {
+    let value = var(name)?;
+    match value.trim().parse::<u64>() {
+        Ok(number) if number > 0 => Some(number),
+        _ => {
+            invalid(format!("Invalid {} value {:?}, expected a positive integer", name, value));
+            None
+        }
+    }
+}
+
+/// Reads a boolean variable, reporting values that are not one.
+fn env_bool(name: &str) -> Option<bool> {
+    let value = var(name)?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => {
+            invalid(format!("Invalid {} value {:?}, expected a boolean", name, value));
+            None
+        }
+    }
+}
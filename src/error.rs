@@ -0,0 +1,328 @@
+use axum::{
+    extract::multipart::MultipartError,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+
+use crate::{config, telemetry};
+
+/// An error answered as an RFC 7807 `application/problem+json` document.
+/// The variant decides the status, so that client mistakes are never
+/// reported as server faults.
+#[derive(Clone)]
+pub enum AppError {
+    /// `400 Bad Request`: the request could not be read, such as malformed
+    /// multipart or JSON, or an upload the client broke off.
+    Malformed(Problem),
+    /// `401 Unauthorized`: the request has no valid credentials.
+    Unauthorized(Problem),
+    /// `403 Forbidden`: the server does not allow what was asked.
+    Forbidden(Problem),
+    /// `404 Not Found`.
+    NotFound(Problem),
+    /// `409 Conflict`: what was asked for is not ready yet.
+    Conflict(Problem),
+    /// `413 Payload Too Large`: the body, an input or a source is over a
+    /// server limit.
+    TooLarge(Problem),
+    /// `429 Too Many Requests`: a rate limit or quota of the client is used
+    /// up.
+    TooManyRequests(Problem),
+    /// `422 Unprocessable Entity`: the request was read, but a parameter or
+    /// the image is not usable.
+    Invalid(Problem),
+    /// `500 Internal Server Error`: a fault of the server itself.
+    Internal(Problem),
+    /// `502 Bad Gateway`: a source, storage service or identity provider
+    /// failed.
+    Upstream(Problem),
+    /// `503 Service Unavailable`: the server is too busy.
+    Unavailable(Problem),
+    /// `504 Gateway Timeout`: a source did not answer in time.
+    UpstreamTimeout(Problem),
+}
+
+/// What an error reports, with a stable `code` for clients to branch on.
+#[derive(Clone)]
+pub struct Problem {
+    code: Cow<'static, str>,
+    message: String,
+    /// Seconds sent in `Retry-After`.
+    retry_after: Option<u64>,
+    /// Extra members of the problem document.
+    extensions: Map<String, Value>,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>) -> Self {
+        Self { code: Cow::Borrowed(""), message: message.into(), retry_after: None, extensions: Map::new() }
+    }
+}
+
+impl AppError {
+    pub fn malformed(message: impl Into<String>) -> Self {
+        Self::Malformed(Problem::new(message))
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::Unauthorized(Problem::new(message))
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::Forbidden(Problem::new(message))
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::NotFound(Problem::new(message))
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::Conflict(Problem::new(message))
+    }
+
+    pub fn too_large(message: impl Into<String>) -> Self {
+        Self::TooLarge(Problem::new(message))
+    }
+
+    pub fn too_many_requests(message: impl Into<String>) -> Self {
+        Self::TooManyRequests(Problem::new(message))
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::Invalid(Problem::new(message))
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::Internal(Problem::new(message))
+    }
+
+    pub fn upstream(message: impl Into<String>) -> Self {
+        Self::Upstream(Problem::new(message))
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::Unavailable(Problem::new(message))
+    }
+
+    pub fn upstream_timeout(message: impl Into<String>) -> Self {
+        Self::UpstreamTimeout(Problem::new(message))
+    }
+
+    /// Rebuilds an error from a status answered earlier, such as that of a
+    /// failed job.
+    pub fn from_status(status_code: StatusCode, message: impl Into<String>) -> Self {
+        let problem = Problem::new(message);
+        match status_code {
+            StatusCode::BAD_REQUEST => Self::Malformed(problem),
+            StatusCode::UNAUTHORIZED => Self::Unauthorized(problem),
+            StatusCode::FORBIDDEN => Self::Forbidden(problem),
+            StatusCode::NOT_FOUND => Self::NotFound(problem),
+            StatusCode::CONFLICT => Self::Conflict(problem),
+            StatusCode::PAYLOAD_TOO_LARGE => Self::TooLarge(problem),
+            StatusCode::UNPROCESSABLE_ENTITY => Self::Invalid(problem),
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequests(problem),
+            StatusCode::BAD_GATEWAY => Self::Upstream(problem),
+            StatusCode::SERVICE_UNAVAILABLE => Self::Unavailable(problem),
+            StatusCode::GATEWAY_TIMEOUT => Self::UpstreamTimeout(problem),
+            _ => Self::Internal(problem),
+        }
+    }
+
+    /// For failures to read the request body, which are mostly uploads over
+    /// `MAX_UPLOAD_BYTES` and malformed or broken off bodies.
+    pub fn body(status_code: StatusCode, message: impl Into<String>) -> Self {
+        match status_code {
+            StatusCode::PAYLOAD_TOO_LARGE => {
+                let limit = config::get().max_upload_bytes;
+                let mut error = Self::too_large(format!("The request body exceeds the limit of {} bytes", limit)).with_code("payload_too_large");
+                error.problem_mut().extensions.insert("max_upload_bytes".to_string(), limit.into());
+                error
+            }
+            status if status.is_client_error() => Self::malformed(message).with_code("invalid_body"),
+            // Failures to read the body stream, mostly clients that went away
+            // in the middle of an upload.
+            _ => Self::malformed(message).with_code("incomplete_body"),
+        }
+    }
+
+    /// Replaces the generic code of the variant with a more specific one,
+    /// such as `invalid_size`.
+    pub fn with_code(mut self, code: impl Into<Cow<'static, str>>) -> Self {
+        self.problem_mut().code = code.into();
+        self
+    }
+
+    /// Tells the client when to try again.
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.problem_mut().retry_after = Some(seconds);
+        self
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Malformed(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        match &*self.problem().code {
+            "" => default_code(self.status_code()),
+            code => code,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.problem().message
+    }
+
+    /// Logs the error as it is answered, server faults as errors and client
+    /// mistakes as warnings.
+    pub fn log(&self) {
+        let (status_code, code) = (self.status_code(), self.code());
+        if status_code.is_server_error() {
+            tracing::error!(status = %status_code, code = %code, error = %self.message());
+        } else {
+            tracing::warn!(status = %status_code, code = %code, error = %self.message());
+        }
+    }
+
+    fn problem(&self) -> &Problem {
+        match self {
+            Self::Malformed(problem)
+            | Self::Unauthorized(problem)
+            | Self::Forbidden(problem)
+            | Self::NotFound(problem)
+            | Self::Conflict(problem)
+            | Self::TooLarge(problem)
+            | Self::Invalid(problem)
+            | Self::TooManyRequests(problem)
+            | Self::Internal(problem)
+            | Self::Upstream(problem)
+            | Self::Unavailable(problem)
+            | Self::UpstreamTimeout(problem) => problem,
+        }
+    }
+
+    fn problem_mut(&mut self) -> &mut Problem {
+        match self {
+            Self::Malformed(problem)
+            | Self::Unauthorized(problem)
+            | Self::Forbidden(problem)
+            | Self::NotFound(problem)
+            | Self::Conflict(problem)
+            | Self::TooLarge(problem)
+            | Self::Invalid(problem)
+            | Self::TooManyRequests(problem)
+            | Self::Internal(problem)
+            | Self::Upstream(problem)
+            | Self::Unavailable(problem)
+            | Self::UpstreamTimeout(problem) => problem,
+        }
+    }
+}
+
+/// The code of errors that have no more specific one.
+pub fn default_code(status_code: StatusCode) -> &'static str {
+    match status_code {
+        StatusCode::BAD_REQUEST => "malformed_request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PAYLOAD_TOO_LARGE => "too_large",
+        StatusCode::UNPROCESSABLE_ENTITY => "invalid_request",
+        StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+        StatusCode::BAD_GATEWAY => "upstream_error",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        StatusCode::GATEWAY_TIMEOUT => "upstream_timeout",
+        status if status.is_client_error() => "client_error",
+        _ => "internal_error",
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        self.log();
+        let status_code = self.status_code();
+        let code = self.code().to_string();
+
+        let problem = self.problem();
+        let mut document = json!({
+            "type": "about:blank",
+            "title": status_code.canonical_reason().unwrap_or_default(),
+            "status": status_code.as_u16(),
+            "code": code,
+            "detail": problem.message,
+        });
+        // The request ID lets a failure be matched with the server's logs.
+        if let Some(request_id) = telemetry::current_request_id() {
+            document["request_id"] = request_id.into();
+        }
+        document.as_object_mut().expect("an object").extend(problem.extensions.clone());
+
+        let mut response = (status_code, [(header::CONTENT_TYPE, "application/problem+json")], document.to_string()).into_response();
+        if let Some(seconds) = problem.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
+
+// Failures that `?` turns into errors. Multipart fields carry the status of
+// what went wrong, such as an upload over the limit or a body the client
+// broke off.
+
+impl From<MultipartError> for AppError {
+    fn from(err: MultipartError) -> Self {
+        Self::body(err.status(), err.body_text())
+    }
+}
+
+/// Failures of the image pipeline, which keep their code.
+impl From<image_transformer_core::Error> for AppError {
+    fn from(err: image_transformer_core::Error) -> Self {
+        use image_transformer_core::Error as PipelineError;
+
+        let error = match &err {
+            PipelineError::Invalid(_) => Self::invalid(err.message()),
+            PipelineError::TooLarge(_) => Self::too_large(err.message()),
+            PipelineError::Internal(_) => Self::internal(err.message()),
+        };
+        error.with_code(err.code().to_string())
+    }
+}
+
+/// Faults of the server itself, such as a panicking worker or a full disk.
+macro_rules! internal_errors {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for AppError {
+                fn from(err: $error) -> Self {
+                    Self::internal(err.to_string())
+                }
+            }
+        )*
+    };
+}
+
+internal_errors!(
+    std::io::Error,
+    tokio::task::JoinError,
+    tokio::sync::AcquireError,
+    axum::http::Error,
+    serde_json::Error,
+    zip::result::ZipError,
+);
@@ -0,0 +1,102 @@
+use axum::{
+    body::Bytes,
+    extract::Multipart,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::DynamicImage;
+use std::io::{Cursor, Write};
+
+use crate::{color, decode, encode, error::AppError, metadata, pool, upload};
+
+/// Apple touch icon sizes included in the ZIP bundle, largest first.
+const APPLE_TOUCH_ICON_SIZES: [u32; 4] = [180, 167, 152, 120];
+
+/// Handler for the /favicon endpoint.
+/// Accepts multipart/form-data with the following fields:
+/// - "image": The source image, ideally square.
+/// - "bundle": An optional output type, "ico" (default) for a multi-resolution
+///   favicon.ico or "zip" for an archive that also contains apple-touch-icon PNGs.
+pub async fn favicon_handler(mut multipart: Multipart) -> Result<Response, AppError> {
+    let mut image_data: Option<Bytes> = None;
+    let mut zip_bundle = false;
+
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or("").to_string();
+
+        match name.as_str() {
+            "image" => {
+                image_data = Some(upload::read_file(field).await?);
+            }
+            "bundle" => {
+                zip_bundle = match field.text().await?.trim().to_ascii_lowercase().as_str() {
+                    "ico" => false,
+                    "zip" => true,
+                    _ => return Err(AppError::invalid("Invalid bundle. Use 'ico' or 'zip'").with_code("invalid_bundle")),
+                };
+            }
+            _ => { /* Ignore other fields */ }
+        }
+    }
+
+    let image_bytes = image_data.ok_or_else(|| {
+        AppError::invalid("Image data not provided in 'image' field").with_code("missing_image")
+    })?;
+
+    let output_bytes = pool::run(move || -> Result<Vec<u8>, AppError> {
+        let image_format = decode::detect_format(&image_bytes)?;
+        let max_size = APPLE_TOUCH_ICON_SIZES[0];
+        let decode_options = decode::DecodeOptions { size: Some((max_size, max_size)), ..Default::default() };
+        let mut img = decode::decode_image(&image_bytes, image_format, &decode_options)?;
+        if let Some(profile) = decode::icc_profile(&image_bytes, image_format) {
+            img = color::convert_to_srgb(img, &profile);
+        }
+        if let Some(orientation) = metadata::orientation(&image_bytes, image_format) {
+            img = metadata::apply_orientation(img, orientation);
+        }
+
+        if zip_bundle { build_bundle(&img) } else { Ok(encode::encode_ico(&img, &encode::ICO_SIZES)?) }
+    })
+    .await??;
+
+    let (content_type, file_name) = if zip_bundle {
+        ("application/zip", "favicons.zip")
+    } else {
+        (encode::OutputFormat::Ico.content_type(), "favicon.ico")
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)),
+        ],
+        output_bytes,
+    ).into_response())
+}
+
+fn build_bundle(img: &DynamicImage) -> Result<Vec<u8>, AppError> {
+    // Icons are already compressed, so store them without deflating again.
+    let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+
+    let mut add_file = |name: String, data: &[u8]| -> Result<(), AppError> {
+        archive.start_file(name, options)?;
+        archive.write_all(data)?;
+        Ok(())
+    };
+
+    add_file("favicon.ico".to_string(), &encode::encode_ico(img, &encode::ICO_SIZES)?)?;
+    for size in APPLE_TOUCH_ICON_SIZES {
+        let icon = DynamicImage::ImageRgba8(encode::square_icon(img, size));
+        let png = encode::encode_image(icon, encode::OutputFormat::Png, &encode::EncodeOptions::default())?;
+        let name = if size == APPLE_TOUCH_ICON_SIZES[0] {
+            "apple-touch-icon.png".to_string()
+        } else {
+            format!("apple-touch-icon-{}x{}.png", size, size)
+        };
+        add_file(name, &png)?;
+    }
+
+    Ok(archive.finish()?.into_inner())
+}
@@ -0,0 +1,167 @@
+use axum::body::Bytes;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client, Url,
+};
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use crate::config;
+use crate::error::AppError;
+use crate::storage;
+
+/// Limits for downloading source images by URL.
+#[derive(Debug, Clone)]
+pub struct FetchConfig {
+    /// Hosts that may be fetched from: exact names, `*.example.com` for any
+    /// subdomain, or `*` for any host. Empty disables fetching.
+    pub allowed_hosts: Vec<String>,
+    /// Also connect to loopback, private and link-local addresses.
+    pub allow_private: bool,
+    pub max_bytes: usize,
+    pub timeout: Duration,
+    pub max_redirects: usize,
+}
+
+impl FetchConfig {
+    /// Checks the scheme and host of a URL, including every redirect target.
+    fn check(&self, url: &Url) -> Result<(), String> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("Scheme '{}' is not allowed", url.scheme()));
+        }
+        let host = url.host_str().unwrap_or_default().trim_end_matches('.').to_ascii_lowercase();
+        let allowed = self.allowed_hosts.iter().any(|pattern| match pattern.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) => suffix.starts_with('.') && host.ends_with(suffix),
+            None => host == *pattern,
+        });
+        if !allowed {
+            return Err(format!("Host '{}' is not allowed", host));
+        }
+        // IP literals never reach the resolver, so they are checked here.
+        let literal = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>();
+        if let Ok(ip) = literal && !self.allow_private && !is_public(ip) {
+            return Err(format!("Address {} is not public", ip));
+        }
+        Ok(())
+    }
+}
+
+/// Resolves hosts, dropping addresses that are not publicly routable so
+/// allowed names cannot be pointed at internal services.
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} does not resolve to a public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (64..128).contains(&b);
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast()
+                || ip.is_documentation() || ip.is_unspecified() || a == 0 || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                let unique_local = first & 0xfe00 == 0xfc00;
+                let link_local = first & 0xffc0 == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+            }
+        },
+    }
+}
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    let fetch = config::get().fetch.clone();
+    let max_redirects = fetch.max_redirects;
+    let mut builder = Client::builder()
+        .timeout(fetch.timeout)
+        .connect_timeout(fetch.timeout)
+        // A proxy would resolve hosts itself, bypassing the address check.
+        .no_proxy()
+        .redirect(redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > max_redirects {
+                attempt.error(format!("More than {} redirects", max_redirects))
+            } else if let Err(e) = fetch.check(attempt.url()) {
+                attempt.error(e)
+            } else {
+                attempt.follow()
+            }
+        }));
+    if !config::get().fetch.allow_private {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    builder.build().expect("failed to build the HTTP client")
+});
+
+/// Downloads a source image, enforcing the server's host allowlist, size
+/// limit, timeout and redirect cap. `gs://` URLs are read from storage
+/// instead.
+pub async fn fetch(url: &str) -> Result<Bytes, AppError> {
+    if url.trim().starts_with("gs://") {
+        return storage::load(url).await;
+    }
+    let fetch = &config::get().fetch;
+    if fetch.allowed_hosts.is_empty() {
+        return Err(AppError::forbidden("Fetching images by URL is disabled on this server").with_code("fetch_disabled"));
+    }
+    let url = Url::parse(url.trim())
+        .map_err(|e| AppError::invalid(format!("Invalid source URL: {}", e)).with_code("invalid_source"))?;
+    fetch.check(&url).map_err(|e| AppError::forbidden(e).with_code("source_not_allowed"))?;
+
+    let response = CLIENT.get(url).send().await.map_err(fetch_error)?;
+    if !response.status().is_success() {
+        return Err(AppError::upstream(format!("Source URL returned {}", response.status())).with_code("fetch_failed"));
+    }
+    read_limited(response).await
+}
+
+/// Reads a source image's body, up to `FETCH_MAX_BYTES`.
+pub async fn read_limited(mut response: reqwest::Response) -> Result<Bytes, AppError> {
+    let fetch = &config::get().fetch;
+    let too_large = || AppError::too_large(format!("Source image exceeds {} bytes", fetch.max_bytes)).with_code("source_too_large");
+    if response.content_length().is_some_and(|length| length > fetch.max_bytes as u64) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+        if body.len() + chunk.len() > fetch.max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(body))
+}
+
+fn fetch_error(e: reqwest::Error) -> AppError {
+    if e.is_timeout() {
+        return AppError::upstream_timeout("Timed out fetching the source image").with_code("fetch_timeout");
+    }
+    // The top-level message is generic; the cause says what actually failed.
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    AppError::upstream(format!("Failed to fetch the source image: {}", message)).with_code("fetch_failed")
+}
@@ -0,0 +1,65 @@
+use axum::{
+    extract::Path,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::config;
+use crate::encode::OutputFormat;
+use crate::error::AppError;
+use crate::storage::{self, Location, StorageBackend};
+
+/// Writes results below `FILE_STORAGE_DIR`, to be served by `/files/...`.
+pub struct FileSystem;
+
+impl FileSystem {
+    fn root() -> Result<&'static std::path::Path, AppError> {
+        config::get().file_storage_dir.as_deref()
+            .ok_or_else(|| AppError::forbidden("File output is not configured on this server").with_code("storage_not_configured"))
+    }
+
+    /// Checks that file output is enabled before anything is rendered.
+    pub fn check() -> Result<(), AppError> {
+        Self::root().map(|_| ())
+    }
+}
+
+impl StorageBackend for FileSystem {
+    async fn put(&self, _bucket: &str, key: &str, bytes: Vec<u8>, _content_type: &str) -> Result<Location, AppError> {
+        let path = Self::root()?.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Written under a temporary name first, so `/files` never serves a
+        // partial file.
+        let partial = PathBuf::from(format!("{}.partial", path.display()));
+        tokio::fs::write(&partial, bytes).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(Location { url: config::public_url(&format!("/v1/files/{}", storage::uri_encode(key, false))), expires_in: None })
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        storage::check_dir(Self::root().map_err(|e| e.message().to_string())?).await
+    }
+}
+
+/// Handler for `GET /files/{key}`, which serves a result written by file
+/// output.
+pub async fn serve_handler(Path(key): Path<String>) -> Result<Response, AppError> {
+    let not_found = || AppError::not_found("File not found");
+    let root = FileSystem::root().map_err(|_| not_found())?;
+    if !storage::is_valid_key(&key) || key.ends_with('/') || key.ends_with(".partial") {
+        return Err(not_found());
+    }
+    let bytes = tokio::fs::read(root.join(&key)).await.map_err(|_| not_found())?;
+    // Sniffed, since an exact key's extension need not match the format.
+    let content_type = match image::guess_format(&bytes) {
+        Ok(format) => format.to_mime_type(),
+        Err(_) => key.rsplit_once('.')
+            .and_then(|(_, extension)| OutputFormat::from_str(extension).ok())
+            .map_or("application/octet-stream", OutputFormat::content_type),
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], bytes).into_response())
+}
@@ -0,0 +1,240 @@
+use axum::{body::Bytes, http::StatusCode};
+use base64::Engine;
+use reqwest::{Client, RequestBuilder, Url};
+use rsa::{
+    pkcs1v15::SigningKey,
+    pkcs8::DecodePrivateKey,
+    sha2::{Digest, Sha256},
+    signature::{SignatureEncoding, Signer},
+    RsaPrivateKey,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use crate::config;
+use crate::error::AppError;
+use crate::fetch;
+use crate::signing;
+use crate::storage::{self, uri_encode, Location, StorageBackend, UtcTime};
+
+/// Settings for Google Cloud Storage.
+#[derive(Debug, Clone)]
+pub struct GcsConfig {
+    /// Base URL of the XML API; `https://storage.googleapis.com` when unset.
+    pub endpoint: Option<String>,
+    pub credentials: Option<ServiceAccount>,
+    /// Buckets that may be read from and written to; the first is the
+    /// default output. Empty disables Cloud Storage.
+    pub buckets: Vec<String>,
+    /// Lifetime of signed download URLs.
+    pub url_expiry: Duration,
+}
+
+/// The fields of a service account key file that are needed to get tokens
+/// and sign URLs.
+#[derive(Debug, Clone)]
+pub struct ServiceAccount {
+    pub client_email: String,
+    pub private_key: RsaPrivateKey,
+    pub token_uri: String,
+}
+
+impl ServiceAccount {
+    /// Parses the JSON key file of a service account.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        #[derive(Deserialize)]
+        struct KeyFile {
+            client_email: String,
+            private_key: String,
+            token_uri: Option<String>,
+        }
+        let key: KeyFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&key.private_key).map_err(|e| format!("invalid private_key: {}", e))?;
+        Ok(ServiceAccount {
+            client_email: key.client_email,
+            private_key,
+            token_uri: key.token_uri.unwrap_or_else(|| "https://oauth2.googleapis.com/token".to_string()),
+        })
+    }
+
+    fn sign(&self, data: &str) -> Vec<u8> {
+        SigningKey::<Sha256>::new(self.private_key.clone()).sign(data.as_bytes()).to_vec()
+    }
+}
+
+/// Authorizes requests with OAuth tokens for a service account, and signs
+/// download URLs with its key.
+pub struct Gcs;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()
+        .expect("failed to build the HTTP client")
+});
+
+/// The access token and the Unix time it expires at.
+static TOKEN: Mutex<Option<(String, u64)>> = Mutex::new(None);
+
+impl Gcs {
+    /// Resolves the bucket of a `gs://` URL, defaulting to the first
+    /// configured one.
+    pub fn bucket(bucket: Option<&str>) -> Result<String, AppError> {
+        let gcs = &config::get().gcs;
+        if gcs.buckets.is_empty() || gcs.credentials.is_none() {
+            return Err(not_configured());
+        }
+        match bucket {
+            None => Ok(gcs.buckets[0].clone()),
+            Some(bucket) if gcs.buckets.iter().any(|allowed| allowed == bucket) => Ok(bucket.to_string()),
+            Some(bucket) => Err(AppError::forbidden(format!("Bucket '{}' is not allowed", bucket)).with_code("bucket_not_allowed")),
+        }
+    }
+}
+
+impl StorageBackend for Gcs {
+    async fn put(&self, bucket: &str, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<Location, AppError> {
+        let gcs = &config::get().gcs;
+        let credentials = gcs.credentials.as_ref().ok_or_else(not_configured)?;
+        let object = Object::new(gcs, bucket, key)?;
+        let request = CLIENT.put(object.url("")).header("content-type", content_type).body(bytes);
+        send(request.bearer_auth(token(credentials).await?)).await?;
+
+        // V4 signed GET, which Cloud Storage accepts for up to 7 days.
+        let now = UtcTime::now();
+        let expires = gcs.url_expiry.as_secs().clamp(1, 7 * 24 * 3600);
+        let scope = format!("{}/auto/storage/goog4_request", now.basic_date());
+        let query = [
+            ("X-Goog-Algorithm", "GOOG4-RSA-SHA256".to_string()),
+            ("X-Goog-Credential", uri_encode(&format!("{}/{}", credentials.client_email, scope), true)),
+            ("X-Goog-Date", now.basic()),
+            ("X-Goog-Expires", expires.to_string()),
+            ("X-Goog-SignedHeaders", "host".to_string()),
+        ];
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+        let canonical_request = format!("GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", object.path, query, object.host);
+        let string_to_sign = format!(
+            "GOOG4-RSA-SHA256\n{}\n{}\n{}",
+            now.basic(),
+            scope,
+            signing::hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signature = signing::hex(&credentials.sign(&string_to_sign));
+
+        Ok(Location { url: object.url(&format!("?{}&X-Goog-Signature={}", query, signature)), expires_in: Some(expires) })
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Bytes, AppError> {
+        let gcs = &config::get().gcs;
+        let credentials = gcs.credentials.as_ref().ok_or_else(not_configured)?;
+        let object = Object::new(gcs, bucket, key)?;
+        let response = CLIENT
+            .get(object.url(""))
+            .bearer_auth(token(credentials).await?)
+            .send()
+            .await
+            .map_err(request_error)?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(AppError::not_found(format!("gs://{}/{} does not exist", bucket, key)).with_code("source_not_found")),
+            status if !status.is_success() => {
+                let body = response.text().await.unwrap_or_default();
+                Err(AppError::upstream(format!("Cloud Storage returned {}: {}", status, body.trim())).with_code("storage_error"))
+            }
+            _ => fetch::read_limited(response).await,
+        }
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        storage::reachable(endpoint(&config::get().gcs)).await
+    }
+}
+
+/// The address of an object, always path-style.
+struct Object {
+    scheme: String,
+    /// Host and port, as sent in the `Host` header.
+    host: String,
+    /// The URI-encoded path.
+    path: String,
+}
+
+impl Object {
+    fn new(gcs: &GcsConfig, bucket: &str, key: &str) -> Result<Self, AppError> {
+        let endpoint = Url::parse(endpoint(gcs))
+            .map_err(|e| AppError::internal(format!("Invalid GCS_ENDPOINT: {}", e)))?;
+        let mut host = endpoint.host_str().unwrap_or_default().to_string();
+        if let Some(port) = endpoint.port() {
+            host = format!("{}:{}", host, port);
+        }
+        let path = format!("/{}/{}", uri_encode(bucket, true), uri_encode(key, false));
+        Ok(Object { scheme: endpoint.scheme().to_string(), host, path })
+    }
+
+    fn url(&self, query: &str) -> String {
+        format!("{}://{}{}{}", self.scheme, self.host, self.path, query)
+    }
+}
+
+/// Exchanges a JWT signed with the service account key for an access token,
+/// reused until shortly before it expires.
+async fn token(credentials: &ServiceAccount) -> Result<String, AppError> {
+    let now = UtcTime::now().unix;
+    if let Some((token, expires_at)) = TOKEN.lock().unwrap().as_ref() && now + 300 < *expires_at {
+        return Ok(token.clone());
+    }
+
+    let encode = |value: &serde_json::Value| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value.to_string());
+    let header = encode(&json!({ "alg": "RS256", "typ": "JWT" }));
+    let claims = encode(&json!({
+        "iss": credentials.client_email,
+        "scope": SCOPE,
+        "aud": credentials.token_uri,
+        "iat": now,
+        "exp": now + 3600,
+    }));
+    let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(credentials.sign(&format!("{}.{}", header, claims)));
+    let assertion = format!("{}.{}.{}", header, claims, signature);
+
+    let request = CLIENT
+        .post(&credentials.token_uri)
+        .form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", &assertion)]);
+    let response: serde_json::Value = serde_json::from_str(&send(request).await?)
+        .map_err(|e| AppError::upstream(format!("Invalid Google token response: {}", e)).with_code("storage_error"))?;
+    let token = response["access_token"].as_str()
+        .ok_or_else(|| AppError::upstream("Google token response has no access_token").with_code("storage_error"))?
+        .to_string();
+    let expires_in = response["expires_in"].as_u64().unwrap_or(3600);
+    *TOKEN.lock().unwrap() = Some((token.clone(), now + expires_in));
+    Ok(token)
+}
+
+/// Sends a request and returns the response body, turning failures into
+/// `502 Bad Gateway`.
+async fn send(request: RequestBuilder) -> Result<String, AppError> {
+    let response = request.send().await.map_err(request_error)?;
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(AppError::upstream(format!("Cloud Storage returned {}: {}", status, body.trim())).with_code("storage_error"));
+    }
+    Ok(body)
+}
+
+fn request_error(e: reqwest::Error) -> AppError {
+    AppError::upstream(format!("Failed to reach Cloud Storage: {}", e)).with_code("storage_error")
+}
+
+/// `GCS_ENDPOINT`, or Google's.
+fn endpoint(gcs: &GcsConfig) -> &str {
+    gcs.endpoint.as_deref().unwrap_or("https://storage.googleapis.com")
+}
+
+fn not_configured() -> AppError {
+    AppError::forbidden("Google Cloud Storage is not configured on this server").with_code("storage_not_configured")
+}
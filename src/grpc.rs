@@ -0,0 +1,296 @@
+use tokio::net::TcpListener;
+
+/// Serves the `Transform` RPC of `proto/transform.proto` on the listener of
+/// `GRPC_PORT` until shutdown is requested.
+#[cfg(feature = "grpc")]
+pub async fn serve(listener: TcpListener) {
+    server::serve(listener).await;
+}
+
+/// `GRPC_PORT` is ignored without the `grpc` feature, so there is never a
+/// listener to serve.
+#[cfg(not(feature = "grpc"))]
+pub async fn serve(_listener: TcpListener) {}
+
+#[cfg(feature = "grpc")]
+mod server {
+    use axum::{body::Bytes, http::HeaderMap};
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+    use tonic::{transport::server::TcpIncoming, Code, Request, Response, Status};
+    use tracing::{field::Empty, Instrument};
+
+    use crate::auth;
+    use crate::config;
+    use crate::error::AppError;
+    use crate::quota;
+    use crate::shutdown;
+    use crate::usage;
+
+    include!(concat!(env!("OUT_DIR"), "/image_transformer.v1.Transformer.rs"));
+
+    // The messages of `proto/transform.proto`, which must be kept in step
+    // with it.
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TransformRequest {
+        #[prost(bytes = "bytes", tag = "1")]
+        pub image: Bytes,
+        #[prost(message, optional, tag = "2")]
+        pub options: Option<TransformOptions>,
+        #[prost(bytes = "bytes", tag = "3")]
+        pub watermark: Bytes,
+    }
+
+    /// Named like the /transform form fields, which they are applied as.
+    /// Empty strings are unset.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TransformOptions {
+        #[prost(uint32, optional, tag = "1")]
+        pub width: Option<u32>,
+        #[prost(uint32, optional, tag = "2")]
+        pub height: Option<u32>,
+        #[prost(message, repeated, tag = "3")]
+        pub variants: Vec<Size>,
+        #[prost(string, tag = "4")]
+        pub format: String,
+        #[prost(float, optional, tag = "5")]
+        pub quality: Option<f32>,
+        #[prost(bool, optional, tag = "6")]
+        pub lossless: Option<bool>,
+        #[prost(uint32, optional, tag = "7")]
+        pub max_bytes: Option<u32>,
+        #[prost(string, tag = "8")]
+        pub fit: String,
+        #[prost(string, tag = "9")]
+        pub gravity: String,
+        #[prost(string, tag = "10")]
+        pub ar: String,
+        #[prost(float, optional, tag = "11")]
+        pub dpr: Option<f32>,
+        #[prost(bool, optional, tag = "12")]
+        pub no_enlarge: Option<bool>,
+        #[prost(string, tag = "13")]
+        pub resize_filter: String,
+        #[prost(string, tag = "14")]
+        pub background: String,
+        #[prost(float, optional, tag = "15")]
+        pub rotate: Option<f32>,
+        #[prost(string, tag = "16")]
+        pub flip: String,
+        #[prost(string, tag = "17")]
+        pub crop: String,
+        #[prost(bool, optional, tag = "18")]
+        pub trim: Option<bool>,
+        #[prost(float, optional, tag = "19")]
+        pub blur: Option<f32>,
+        #[prost(float, optional, tag = "20")]
+        pub sharpen: Option<f32>,
+        #[prost(float, optional, tag = "21")]
+        pub brightness: Option<f32>,
+        #[prost(float, optional, tag = "22")]
+        pub contrast: Option<f32>,
+        #[prost(float, optional, tag = "23")]
+        pub saturation: Option<f32>,
+        #[prost(float, optional, tag = "24")]
+        pub gamma: Option<f32>,
+        #[prost(string, tag = "25")]
+        pub filter: String,
+        #[prost(string, tag = "26")]
+        pub pad: String,
+        #[prost(string, tag = "27")]
+        pub border: String,
+        #[prost(string, tag = "28")]
+        pub radius: String,
+        #[prost(string, tag = "29")]
+        pub ops: String,
+        #[prost(uint32, optional, tag = "30")]
+        pub page: Option<u32>,
+        #[prost(bool, optional, tag = "31")]
+        pub auto_orient: Option<bool>,
+        #[prost(string, tag = "32")]
+        pub metadata: String,
+        #[prost(bool, optional, tag = "33")]
+        pub keep_profile: Option<bool>,
+        #[prost(bool, optional, tag = "34")]
+        pub progressive: Option<bool>,
+        #[prost(uint32, optional, tag = "35")]
+        pub effort: Option<u32>,
+        #[prost(string, tag = "36")]
+        pub preset: String,
+        #[prost(string, tag = "37")]
+        pub placeholder: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Size {
+        #[prost(uint32, optional, tag = "1")]
+        pub width: Option<u32>,
+        #[prost(uint32, optional, tag = "2")]
+        pub height: Option<u32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TransformResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub outputs: Vec<Output>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Output {
+        #[prost(bytes = "vec", tag = "1")]
+        pub data: Vec<u8>,
+        #[prost(string, tag = "2")]
+        pub content_type: String,
+        #[prost(uint32, tag = "3")]
+        pub width: u32,
+        #[prost(uint32, tag = "4")]
+        pub height: u32,
+        #[prost(string, tag = "5")]
+        pub placeholder: String,
+    }
+
+    impl TransformOptions {
+        fn into_fields(self) -> Vec<(&'static str, String)> {
+            let text = |value: String| (!value.is_empty()).then_some(value);
+            let size = |width: Option<u32>, height: Option<u32>| {
+                let side = |side: Option<u32>| side.map(|side| side.to_string()).unwrap_or_default();
+                format!("{}x{}", side(width), side(height))
+            };
+            let has_size = self.width.is_some() || self.height.is_some();
+            let variants = self.variants.iter().map(|variant| size(variant.width, variant.height)).collect::<Vec<_>>();
+            let fields = [
+                ("preset", text(self.preset)),
+                ("placeholder", text(self.placeholder)),
+                ("size", has_size.then(|| size(self.width, self.height))),
+                ("variants", (!variants.is_empty()).then(|| variants.join(","))),
+                ("format", text(self.format)),
+                ("quality", self.quality.map(|v| v.to_string())),
+                ("lossless", self.lossless.map(|v| v.to_string())),
+                ("max_bytes", self.max_bytes.map(|v| v.to_string())),
+                ("fit", text(self.fit)),
+                ("gravity", text(self.gravity)),
+                ("ar", text(self.ar)),
+                ("dpr", self.dpr.map(|v| v.to_string())),
+                ("no_enlarge", self.no_enlarge.map(|v| v.to_string())),
+                ("resize_filter", text(self.resize_filter)),
+                ("background", text(self.background)),
+                ("rotate", self.rotate.map(|v| v.to_string())),
+                ("flip", text(self.flip)),
+                ("crop", text(self.crop)),
+                ("trim", self.trim.map(|v| v.to_string())),
+                ("blur", self.blur.map(|v| v.to_string())),
+                ("sharpen", self.sharpen.map(|v| v.to_string())),
+                ("brightness", self.brightness.map(|v| v.to_string())),
+                ("contrast", self.contrast.map(|v| v.to_string())),
+                ("saturation", self.saturation.map(|v| v.to_string())),
+                ("gamma", self.gamma.map(|v| v.to_string())),
+                ("filter", text(self.filter)),
+                ("pad", text(self.pad)),
+                ("border", text(self.border)),
+                ("radius", text(self.radius)),
+                ("ops", text(self.ops)),
+                ("page", self.page.map(|v| v.to_string())),
+                ("auto_orient", self.auto_orient.map(|v| v.to_string())),
+                ("metadata", text(self.metadata)),
+                ("keep_profile", self.keep_profile.map(|v| v.to_string())),
+                ("progressive", self.progressive.map(|v| v.to_string())),
+                ("effort", self.effort.map(|v| v.to_string())),
+            ];
+            fields.into_iter().filter_map(|(name, value)| Some((name, value?))).collect()
+        }
+    }
+
+    pub async fn serve(listener: TcpListener) {
+        // Uploads are limited like those of the HTTP API.
+        let service = transformer_server::TransformerServer::new(TransformService)
+            .max_decoding_message_size(config::get().max_upload_bytes);
+        let result = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming_shutdown(TcpIncoming::from(listener), shutdown::requested())
+            .await;
+        if let Err(e) = result {
+            tracing::error!("The gRPC server failed: {}", e);
+        }
+    }
+
+    struct TransformService;
+
+    #[tonic::async_trait]
+    impl transformer_server::Transformer for TransformService {
+        async fn transform(&self, request: Request<TransformRequest>) -> Result<Response<TransformResponse>, Status> {
+            let span = tracing::info_span!("request", rpc = "Transform", client = Empty);
+            let started = Instant::now();
+            let address = request.remote_addr();
+            let headers = request.metadata().clone().into_headers();
+            let result = transform(&headers, address.map(|address| address.ip().to_string()), request.into_inner())
+                .instrument(span.clone())
+                .await;
+            let _entered = span.enter();
+            if let Err(error) = &result {
+                error.log();
+            }
+            let code = result.as_ref().map_or_else(AppError::code, |_| "ok").to_string();
+            tracing::info!(code, latency_ms = started.elapsed().as_secs_f64() * 1000.0, "finished processing request");
+            result.map(Response::new).map_err(status)
+        }
+    }
+
+    /// Authenticates and meters the call like an HTTP request, then runs it
+    /// through the same pipeline as /transform.
+    async fn transform(headers: &HeaderMap, address: Option<String>, request: TransformRequest) -> Result<TransformResponse, AppError> {
+        let (client, quota) = auth::identify(headers).await?.unzip();
+        if let Some(client) = &client {
+            tracing::Span::current().record("client", client.as_str());
+        }
+        let quota = quota.flatten().unwrap_or_else(|| config::get().quota.clone());
+        let address = || address.unwrap_or_else(|| "unknown".to_string());
+        let meter = quota::admit(client.as_deref(), address, &quota, true, request.image.len() as u64)?;
+
+        if request.image.is_empty() {
+            return Err(AppError::invalid("Image data not provided in 'image'").with_code("missing_image"));
+        }
+        let mut transform = crate::TransformRequest::new();
+        for (name, value) in request.options.unwrap_or_default().into_fields() {
+            transform.set(name, &value)?;
+        }
+        if !request.watermark.is_empty() {
+            transform.fields.options.watermark = Some(request.watermark);
+        }
+        let outputs = meter.clone().scope(transform.render(request.image, headers)).await?;
+
+        let sent = outputs.iter().map(|output| output.bytes.len() as u64).sum::<u64>();
+        usage::record(meter.client(), |usage| usage.output_bytes += sent);
+        let outputs = outputs.into_iter()
+            .map(|output| Output {
+                content_type: output.format.content_type().to_string(),
+                width: output.width,
+                height: output.height,
+                data: output.bytes,
+                placeholder: output.placeholder.unwrap_or_default(),
+            })
+            .collect();
+        Ok(TransformResponse { outputs })
+    }
+
+    /// The gRPC status closest to the HTTP one, with the error's `code` in
+    /// the `x-error-code` metadata.
+    fn status(error: AppError) -> Status {
+        let code = match error {
+            AppError::Malformed(_) | AppError::Invalid(_) => Code::InvalidArgument,
+            AppError::Unauthorized(_) => Code::Unauthenticated,
+            AppError::Forbidden(_) => Code::PermissionDenied,
+            AppError::NotFound(_) => Code::NotFound,
+            AppError::Conflict(_) => Code::FailedPrecondition,
+            AppError::TooLarge(_) | AppError::TooManyRequests(_) => Code::ResourceExhausted,
+            AppError::Internal(_) => Code::Internal,
+            AppError::Upstream(_) | AppError::Unavailable(_) => Code::Unavailable,
+            AppError::UpstreamTimeout(_) => Code::DeadlineExceeded,
+        };
+        let mut status = Status::new(code, error.message());
+        if let Ok(value) = error.code().parse() {
+            status.metadata_mut().insert("x-error-code", value);
+        }
+        status
+    }
+}
@@ -0,0 +1,90 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Map, Value};
+use tokio::task::JoinSet;
+
+use crate::azure::Azure;
+use crate::cache;
+use crate::config;
+use crate::files::FileSystem;
+use crate::gcs::Gcs;
+use crate::pool;
+use crate::queue;
+use crate::s3::S3;
+use crate::shutdown;
+use crate::storage::{self, StorageBackend};
+
+/// Handler for `GET /livez`, which answers as long as the process serves
+/// requests at all.
+pub async fn live_handler() -> &'static str {
+    "OK"
+}
+
+/// Handler for `GET /readyz`, which answers `503 Service Unavailable` while
+/// the server is shutting down, every worker is busy with a full queue
+/// behind it, or a configured cache, storage backend, NATS server or watched
+/// directory cannot be reached,
+/// so that load balancers send requests elsewhere.
+pub async fn ready_handler() -> Response {
+    let config = config::get();
+    let mut checks = Map::new();
+    let mut ready = true;
+    let mut report = |name: &str, result: Result<(), String>| {
+        ready &= result.is_ok();
+        checks.insert(name.to_string(), Value::String(result.err().unwrap_or_else(|| "ok".to_string())));
+    };
+
+    report("shutdown", if shutdown::is_draining() { Err("shutting down".to_string()) } else { Ok(()) });
+    let waiting = pool::waiting();
+    report("workers", if pool::busy() >= config.pool.workers && waiting >= config.pool.queue_size {
+        Err(format!("every worker is busy and the queue is full ({} requests waiting)", waiting))
+    } else {
+        Ok(())
+    });
+    if config.queue.is_some() {
+        report("nats", queue::check());
+    }
+
+    // Each check is bounded by the 2 seconds of `storage::reachable`, and
+    // runs at the same time as the others.
+    let mut tasks = JoinSet::new();
+    if let Some(dir) = &config.cache.dir {
+        tasks.spawn(async move { ("cache_dir", storage::check_dir(dir).await) });
+    }
+    if let Some(watch) = &config.watch {
+        tasks.spawn(async move {
+            let result = match storage::check_dir(&watch.dir).await {
+                Ok(()) => storage::check_dir(&watch.output_dir).await,
+                Err(e) => Err(e),
+            };
+            ("watch", result)
+        });
+    }
+    if cfg!(feature = "redis") && config.cache.redis_url.is_some() {
+        tasks.spawn(async { ("redis", cache::check_redis().await) });
+    }
+    if FileSystem::check().is_ok() {
+        tasks.spawn(async { ("file_storage", FileSystem.check().await) });
+    }
+    if S3::bucket(None).is_ok() {
+        tasks.spawn(async { ("s3", S3.check().await) });
+    }
+    if Azure::container(None).is_ok() {
+        tasks.spawn(async { ("azure", Azure.check().await) });
+    }
+    if Gcs::bucket(None).is_ok() {
+        tasks.spawn(async { ("gcs", Gcs.check().await) });
+    }
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok((name, result)) => report(name, result),
+            Err(e) => report("internal", Err(e.to_string())),
+        }
+    }
+
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(json!({ "status": if ready { "ready" } else { "not_ready" }, "checks": checks }))).into_response()
+}
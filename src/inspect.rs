@@ -0,0 +1,68 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, Request},
+    http::Uri,
+    response::{IntoResponse, Response},
+    Json,
+};
+use image_transformer_core::ImageInfo;
+use serde_json::json;
+
+use crate::error::AppError;
+use crate::{fetch, pool, signing, upload};
+
+/// Handler for `POST /inspect`, which describes an image without
+/// transforming it. Accepts multipart/form-data with an "image" file or a
+/// "url" field, or the image itself as an `image/*` or
+/// `application/octet-stream` body.
+pub async fn inspect_handler(request: Request) -> Result<Response, AppError> {
+    let image_bytes = upload::read_image(request).await?;
+    inspect(image_bytes).await
+}
+
+/// Handler for `GET /inspect?url=...`, which downloads the image to describe.
+/// With `URL_SIGNING_KEY` set, the query must be signed.
+pub async fn inspect_url_handler(uri: Uri, Query(fields): Query<Vec<(String, String)>>) -> Result<Response, AppError> {
+    signing::verify_query(&uri)?;
+    let url = fields.iter().find(|(name, _)| name == "url").map(|(_, value)| value)
+        .ok_or_else(|| AppError::invalid("Source URL not provided in 'url' parameter").with_code("missing_image"))?;
+    let image_bytes = fetch::fetch(url).await?;
+    inspect(image_bytes).await
+}
+
+async fn inspect(image_bytes: Bytes) -> Result<Response, AppError> {
+    let size = image_bytes.len();
+    let info = pool::run(move || image_transformer_core::inspect(&image_bytes)).await??;
+    Ok(Json(to_json(&info, size)).into_response())
+}
+
+fn to_json(info: &ImageInfo, size: usize) -> serde_json::Value {
+    let exif = info.exif.as_ref().map(|exif| json!({
+        "make": exif.make,
+        "model": exif.model,
+        "lens": exif.lens,
+        "software": exif.software,
+        "taken": exif.taken,
+        "orientation": exif.orientation,
+        "exposure_time": exif.exposure_time,
+        "f_number": exif.f_number,
+        "iso": exif.iso,
+        "focal_length": exif.focal_length,
+        "gps": exif.gps,
+    }));
+    json!({
+        "format": info.format.name(),
+        "media_type": info.format.media_type(),
+        "bytes": size,
+        "width": info.width,
+        "height": info.height,
+        "color_type": info.color_type,
+        "bit_depth": info.bit_depth,
+        "has_alpha": info.has_alpha(),
+        "frames": info.frames,
+        "icc_profile": info.icc_profile,
+        "exif": exif,
+        "decoded_bytes": info.decoded_bytes,
+        "within_limit": info.within_limit,
+    })
+}
@@ -0,0 +1,289 @@
+use axum::{
+    body::{self, Bytes},
+    extract::Path,
+    http::{header, HeaderValue, StatusCode},
+    response::{sse::{self, KeepAlive, Sse}, IntoResponse, Response},
+    Json,
+};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, watch};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::config;
+use crate::error::{self, AppError};
+use crate::quota::Meter;
+use crate::shutdown;
+use crate::webhook;
+
+/// Limits for the asynchronous job queue.
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    /// Jobs processed at the same time.
+    pub workers: usize,
+    /// Jobs that may wait for a worker before new ones are refused.
+    pub queue_size: usize,
+    /// How long finished jobs and their results are kept.
+    pub retention: Duration,
+}
+
+/// A queued request, run to completion by a worker.
+pub type Work = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+enum State {
+    Queued,
+    /// Batches report how many of their images are done.
+    Running { progress: Option<(usize, usize)> },
+    Succeeded { content_type: Option<HeaderValue>, placeholder: Option<HeaderValue>, body: Bytes },
+    Failed { status: StatusCode, code: String, message: String },
+}
+
+struct Job {
+    state: State,
+    finished: Option<Instant>,
+    /// Notified once the job has finished.
+    callback: Option<Url>,
+    /// The latest state change, for `/jobs/{id}/events` subscribers.
+    events: watch::Sender<Event>,
+}
+
+/// A state change as sent to event stream subscribers: `queued`, `started`,
+/// `progress` or `finished`, with the job's status as data.
+#[derive(Clone)]
+struct Event {
+    name: &'static str,
+    data: Value,
+}
+
+tokio::task_local! {
+    /// The job the current task is running, for progress reports.
+    static CURRENT_JOB: Uuid;
+}
+
+static JOBS: LazyLock<Mutex<HashMap<Uuid, Job>>> = LazyLock::new(Default::default);
+
+/// The queue's sending side; the first use starts the workers.
+static QUEUE: LazyLock<mpsc::Sender<(Uuid, Work)>> = LazyLock::new(|| {
+    let config = &config::get().jobs;
+    let (sender, receiver) = mpsc::channel(config.queue_size);
+    let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+    for _ in 0..config.workers {
+        let receiver = receiver.clone();
+        tokio::spawn(async move {
+            loop {
+                let next = receiver.lock().await.recv().await;
+                let Some((id, work)) = next else { break };
+                run(id, work).await;
+            }
+        });
+    }
+    sender
+});
+
+/// Queues a request and answers `202 Accepted` with the job's ID and status
+/// URL, or `503 Service Unavailable` when the queue is full or the server
+/// is shutting down. `callback` receives the final status; see
+/// [`webhook::deliver`].
+pub fn submit(work: Work, callback: Option<Url>) -> Result<Response, AppError> {
+    // They would be lost with the server's memory.
+    if shutdown::is_draining() {
+        return Err(AppError::unavailable("The server is shutting down, try again later").with_code("shutting_down"));
+    }
+    let id = Uuid::new_v4();
+    {
+        let mut jobs = JOBS.lock().unwrap();
+        let retention = config::get().jobs.retention;
+        jobs.retain(|_, job| job.finished.is_none_or(|finished| finished.elapsed() < retention));
+        let (events, _) = watch::channel(Event { name: "queued", data: describe(id, &State::Queued) });
+        jobs.insert(id, Job { state: State::Queued, finished: None, callback, events });
+    }
+    // Logged under the request that submitted it, and charged to its client.
+    let work: Work = Meter::bind(work.in_current_span());
+    if QUEUE.try_send((id, work)).is_err() {
+        JOBS.lock().unwrap().remove(&id);
+        return Err(AppError::unavailable("The job queue is full, try again later").with_code("queue_full"));
+    }
+
+    let location = config::public_url(&format!("/v1/jobs/{}", id));
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, location.clone())],
+        Json(json!({ "id": id, "status": "queued", "status_url": location })),
+    ).into_response())
+}
+
+/// Jobs waiting for a worker.
+pub fn queued() -> usize {
+    JOBS.lock().unwrap().values().filter(|job| matches!(job.state, State::Queued)).count()
+}
+
+/// Jobs that are queued or running.
+pub fn unfinished() -> usize {
+    JOBS.lock().unwrap().values().filter(|job| job.finished.is_none()).count()
+}
+
+async fn run(id: Uuid, work: Work) {
+    set_state(id, State::Running { progress: None });
+    // A panicking job fails on its own instead of taking the worker down.
+    let state = match tokio::spawn(CURRENT_JOB.scope(id, work)).await {
+        Ok(response) => {
+            let (parts, response_body) = response.into_parts();
+            match body::to_bytes(response_body, usize::MAX).await {
+                Ok(body) if parts.status.is_success() => {
+                    let placeholder = parts.headers.get(&crate::X_PLACEHOLDER).cloned();
+                    State::Succeeded { content_type: parts.headers.get(header::CONTENT_TYPE).cloned(), placeholder, body }
+                }
+                Ok(body) => failed(parts.status, &body),
+                Err(e) => internal_failure(e.to_string()),
+            }
+        }
+        Err(e) => internal_failure(format!("Job failed: {}", e)),
+    };
+    if let State::Failed { status, code, message } = &state {
+        tracing::warn!(job = %id, status = %status, code = %code, error = %message, "job failed");
+    }
+    set_state(id, state);
+
+    let callback = JOBS.lock().unwrap().get(&id).and_then(|job| Some((job.callback.clone()?, describe(id, &job.state))));
+    if let Some((url, payload)) = callback {
+        tokio::spawn(async move { webhook::deliver(url, &payload).await });
+    }
+}
+
+/// Keeps the code and detail of a failed job's problem document.
+fn failed(status: StatusCode, body: &[u8]) -> State {
+    let problem: Value = serde_json::from_slice(body).unwrap_or_default();
+    let field = |name: &str| problem[name].as_str().map(str::to_string);
+    State::Failed {
+        status,
+        code: field("code").unwrap_or_else(|| error::default_code(status).to_string()),
+        message: field("detail").unwrap_or_else(|| String::from_utf8_lossy(body).into_owned()),
+    }
+}
+
+fn internal_failure(message: String) -> State {
+    let status = StatusCode::INTERNAL_SERVER_ERROR;
+    State::Failed { status, code: error::default_code(status).to_string(), message }
+}
+
+/// Reports how many items of the current job are done. Does nothing outside
+/// of a job.
+pub fn report_progress(done: usize, total: usize) {
+    if let Ok(id) = CURRENT_JOB.try_with(|id| *id) {
+        set_state(id, State::Running { progress: Some((done, total)) });
+    }
+}
+
+fn set_state(id: Uuid, state: State) {
+    if let Some(job) = JOBS.lock().unwrap().get_mut(&id) {
+        let name = match state {
+            State::Queued => "queued",
+            State::Running { progress: None } => "started",
+            State::Running { progress: Some(_) } => "progress",
+            State::Succeeded { .. } | State::Failed { .. } => {
+                job.finished = Some(Instant::now());
+                "finished"
+            }
+        };
+        job.events.send_replace(Event { name, data: describe(id, &state) });
+        job.state = state;
+    }
+}
+
+/// The status of a job as reported to clients and callbacks.
+fn describe(id: Uuid, state: &State) -> Value {
+    match state {
+        State::Queued => json!({ "id": id, "status": "queued" }),
+        State::Running { progress: None } => json!({ "id": id, "status": "running" }),
+        State::Running { progress: Some((done, total)) } => json!({
+            "id": id,
+            "status": "running",
+            "progress": { "done": done, "total": total },
+        }),
+        State::Succeeded { content_type, placeholder, body } => {
+            let mut status = json!({
+                "id": id,
+                "status": "succeeded",
+                "result_url": config::public_url(&format!("/v1/jobs/{}/result", id)),
+                "content_type": content_type.as_ref().and_then(|value| value.to_str().ok()),
+                "bytes": body.len(),
+            });
+            if let Some(placeholder) = placeholder.as_ref().and_then(|value| value.to_str().ok()) {
+                status["placeholder"] = json!(placeholder);
+            }
+            status
+        }
+        State::Failed { status, code, message } => json!({
+            "id": id,
+            "status": "failed",
+            "error": { "status": status.as_u16(), "code": code, "message": message },
+        }),
+    }
+}
+
+fn parse_id(id: &str) -> Result<Uuid, AppError> {
+    id.parse().map_err(|_| not_found())
+}
+
+fn not_found() -> AppError {
+    AppError::not_found("Job not found").with_code("job_not_found")
+}
+
+/// Handler for `GET /jobs/{id}`, which reports the job's status; finished
+/// jobs link to their result or describe the error.
+pub async fn status_handler(Path(id): Path<String>) -> Result<Response, AppError> {
+    let id = parse_id(&id)?;
+    let jobs = JOBS.lock().unwrap();
+    let job = jobs.get(&id).ok_or_else(not_found)?;
+    Ok(Json(describe(id, &job.state)).into_response())
+}
+
+/// Handler for `GET /jobs/{id}/result`, which returns the output of a
+/// succeeded job as the synchronous endpoint would have.
+pub async fn result_handler(Path(id): Path<String>) -> Result<Response, AppError> {
+    let id = parse_id(&id)?;
+    let jobs = JOBS.lock().unwrap();
+    match &jobs.get(&id).ok_or_else(not_found)?.state {
+        State::Succeeded { content_type, placeholder, body } => {
+            let mut response = body.clone().into_response();
+            if let Some(content_type) = content_type {
+                response.headers_mut().insert(header::CONTENT_TYPE, content_type.clone());
+            }
+            if let Some(placeholder) = placeholder {
+                response.headers_mut().insert(&crate::X_PLACEHOLDER, placeholder.clone());
+            }
+            Ok(response)
+        }
+        State::Failed { status, code, message } => Err(AppError::from_status(*status, message.clone()).with_code(code.clone())),
+        State::Queued | State::Running { .. } => Err(AppError::conflict("Job has not finished yet").with_code("job_not_finished")),
+    }
+}
+
+/// Handler for `GET /jobs/{id}/events`, a Server-Sent Events stream that
+/// starts with the current state and ends after the `finished` event.
+/// Progress events may be skipped when they arrive faster than the client
+/// reads them; the latest one is always delivered.
+pub async fn events_handler(Path(id): Path<String>) -> Result<Response, AppError> {
+    let id = parse_id(&id)?;
+    let receiver = JOBS.lock().unwrap().get(&id).ok_or_else(not_found)?.events.subscribe();
+
+    let stream = futures_util::stream::unfold(Some((receiver, true)), |state| async move {
+        let (mut receiver, first) = state?;
+        if !first && receiver.changed().await.is_err() {
+            return None;
+        }
+        let Event { name, data } = receiver.borrow_and_update().clone();
+        let next = (name != "finished").then_some((receiver, false));
+        Some((Ok::<_, Infallible>(sse::Event::default().event(name).data(data.to_string())), next))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()).into_response())
+}
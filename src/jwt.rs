@@ -0,0 +1,215 @@
+use base64::Engine;
+use reqwest::Client;
+use rsa::{
+    pkcs1v15::{Signature, VerifyingKey},
+    sha2::{Sha256, Sha384, Sha512},
+    signature::Verifier,
+    BigUint, RsaPublicKey,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use crate::error::AppError;
+use crate::storage::UtcTime;
+
+/// Settings for OIDC bearer tokens.
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    /// Required `iss` claim. Its OpenID configuration names the signing keys
+    /// when `jwks_url` is unset.
+    pub issuer: Option<String>,
+    /// URL of the JSON Web Key Set that tokens are signed with.
+    pub jwks_url: Option<String>,
+    /// Value that the `aud` claim must contain.
+    pub audience: Option<String>,
+    /// Scopes that the `scope` or `scp` claim must all grant.
+    pub scopes: Vec<String>,
+}
+
+/// What a verified token says about its bearer.
+pub struct Claims {
+    /// `sub`, or the client the token was issued to when it has none.
+    pub subject: String,
+}
+
+/// Clock difference tolerated in `exp` and `nbf`.
+const LEEWAY_SECS: u64 = 60;
+/// How long fetched keys are used before they are fetched again.
+const KEYS_TTL: Duration = Duration::from_secs(3600);
+/// Earliest refetch after an unknown `kid`, which is how rotated keys show
+/// up, so that forged `kid`s cannot hammer the identity provider.
+const KEYS_MIN_REFRESH: Duration = Duration::from_secs(60);
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("failed to build the HTTP client")
+});
+
+static KEYS: Mutex<Option<SigningKeys>> = Mutex::const_new(None);
+
+struct SigningKeys {
+    /// The RSA keys of the key set, by `kid`.
+    keys: Vec<(Option<String>, RsaPublicKey)>,
+    fetched: Instant,
+    /// The last attempt to fetch them, which may have failed.
+    checked: Instant,
+}
+
+/// Verifies an RS256, RS384 or RS512 signed token and its claims.
+pub async fn verify(jwt: &JwtConfig, token: &str) -> Result<Claims, AppError> {
+    let invalid = |message: &str| AppError::unauthorized(message.to_string()).with_code("invalid_token");
+
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid("Malformed bearer token"));
+    };
+    let decode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(part).ok();
+    let json = |part: &str| decode(part).and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok()).filter(Value::is_object);
+    let (Some(header), Some(claims), Some(signature)) = (json(header), json(payload), decode(signature)) else {
+        return Err(invalid("Malformed bearer token"));
+    };
+
+    let algorithm = header["alg"].as_str().unwrap_or_default();
+    if !matches!(algorithm, "RS256" | "RS384" | "RS512") {
+        return Err(invalid(&format!("Unsupported token algorithm '{}', expected RS256, RS384 or RS512", algorithm)));
+    }
+    let (signed, _) = token.rsplit_once('.').expect("a token of three parts");
+    let keys = signing_keys(jwt, header["kid"].as_str()).await?;
+    if keys.is_empty() {
+        return Err(invalid("Unknown token signing key"));
+    }
+    if !keys.iter().any(|key| verify_signature(algorithm, key, signed.as_bytes(), &signature)) {
+        return Err(invalid("Invalid token signature"));
+    }
+
+    let now = UtcTime::now().unix;
+    match claims["exp"].as_u64() {
+        Some(exp) if now <= exp + LEEWAY_SECS => {}
+        Some(_) => return Err(invalid("Token has expired")),
+        None => return Err(invalid("Token has no 'exp' claim")),
+    }
+    if claims["nbf"].as_u64().is_some_and(|nbf| now + LEEWAY_SECS < nbf) {
+        return Err(invalid("Token is not valid yet"));
+    }
+    if let Some(issuer) = &jwt.issuer && claims["iss"].as_str() != Some(issuer.as_str()) {
+        return Err(invalid(&format!("Token was not issued by {}", issuer)));
+    }
+    if let Some(audience) = &jwt.audience && !strings(&claims["aud"]).any(|aud| aud == audience) {
+        return Err(invalid(&format!("Token is not meant for audience '{}'", audience)));
+    }
+    // `scope` is space-separated (RFC 8693), some providers send `scp` lists.
+    let granted: Vec<_> = strings(&claims["scope"]).chain(strings(&claims["scp"])).flat_map(str::split_whitespace).collect();
+    if let Some(missing) = jwt.scopes.iter().find(|scope| !granted.contains(&scope.as_str())) {
+        return Err(AppError::forbidden(format!("Token lacks the scope '{}'", missing)).with_code("insufficient_scope"));
+    }
+
+    let subject = ["sub", "azp", "client_id"].iter().find_map(|claim| claims[claim].as_str()).unwrap_or("unknown");
+    Ok(Claims { subject: subject.to_string() })
+}
+
+/// Reads a claim that may be a string or a list of them.
+fn strings(claim: &Value) -> impl Iterator<Item = &str> {
+    let values = match claim {
+        Value::Array(values) => values.as_slice(),
+        value => std::slice::from_ref(value),
+    };
+    values.iter().filter_map(Value::as_str)
+}
+
+fn verify_signature(algorithm: &str, key: &RsaPublicKey, signed: &[u8], signature: &[u8]) -> bool {
+    let Ok(signature) = Signature::try_from(signature) else {
+        return false;
+    };
+    match algorithm {
+        "RS256" => VerifyingKey::<Sha256>::new(key.clone()).verify(signed, &signature).is_ok(),
+        "RS384" => VerifyingKey::<Sha384>::new(key.clone()).verify(signed, &signature).is_ok(),
+        _ => VerifyingKey::<Sha512>::new(key.clone()).verify(signed, &signature).is_ok(),
+    }
+}
+
+/// Returns the keys that a token with this `kid` may be signed with,
+/// fetching them again when they are old or the `kid` is new.
+async fn signing_keys(jwt: &JwtConfig, kid: Option<&str>) -> Result<Vec<RsaPublicKey>, AppError> {
+    let matching = |keys: &[(Option<String>, RsaPublicKey)]| -> Vec<RsaPublicKey> {
+        keys.iter().filter(|(key_id, _)| kid.is_none() || key_id.as_deref() == kid).map(|(_, key)| key.clone()).collect()
+    };
+    let mut cached = KEYS.lock().await;
+    if let Some(cached) = cached.as_ref() {
+        let found = matching(&cached.keys);
+        let stale = found.is_empty() || cached.fetched.elapsed() >= KEYS_TTL;
+        if !stale || cached.checked.elapsed() < KEYS_MIN_REFRESH {
+            return Ok(found);
+        }
+    }
+    match fetch_keys(jwt).await {
+        Ok(keys) => {
+            let found = matching(&keys);
+            *cached = Some(SigningKeys { keys, fetched: Instant::now(), checked: Instant::now() });
+            Ok(found)
+        }
+        // The keys fetched before are kept while the identity provider is down.
+        Err(e) => match cached.as_mut() {
+            Some(cached) => {
+                tracing::warn!("Using the token keys fetched {}s ago: {}", cached.fetched.elapsed().as_secs(), e.message());
+                cached.checked = Instant::now();
+                Ok(matching(&cached.keys))
+            }
+            None => Err(e),
+        },
+    }
+}
+
+async fn fetch_keys(jwt: &JwtConfig) -> Result<Vec<(Option<String>, RsaPublicKey)>, AppError> {
+    #[derive(Deserialize)]
+    struct Discovery {
+        jwks_uri: String,
+    }
+    #[derive(Deserialize)]
+    struct Jwks {
+        keys: Vec<Jwk>,
+    }
+    #[derive(Deserialize)]
+    struct Jwk {
+        kty: String,
+        kid: Option<String>,
+        #[serde(rename = "use")]
+        usage: Option<String>,
+        n: Option<String>,
+        e: Option<String>,
+    }
+
+    let jwks_url = match (&jwt.jwks_url, &jwt.issuer) {
+        (Some(url), _) => url.clone(),
+        (None, Some(issuer)) => {
+            let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+            get_json::<Discovery>(&url).await?.jwks_uri
+        }
+        (None, None) => return Err(AppError::internal("Neither JWT_ISSUER nor JWT_JWKS_URL is set")),
+    };
+    let decode = |value: &Option<String>| value.as_deref().and_then(|value| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(value).ok());
+    let keys = get_json::<Jwks>(&jwks_url).await?.keys.into_iter()
+        .filter(|jwk| jwk.kty == "RSA" && jwk.usage.as_deref().is_none_or(|usage| usage == "sig"))
+        .filter_map(|jwk| {
+            let key = RsaPublicKey::new(BigUint::from_bytes_be(&decode(&jwk.n)?), BigUint::from_bytes_be(&decode(&jwk.e)?)).ok()?;
+            Some((jwk.kid, key))
+        })
+        .collect();
+    Ok(keys)
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(url: &str) -> Result<T, AppError> {
+    let failed = |e: &dyn std::fmt::Display| AppError::upstream(format!("Failed to get token keys from {}: {}", url, e)).with_code("identity_provider_error");
+    let response = CLIENT.get(url).send().await.map_err(|e| failed(&e))?;
+    if !response.status().is_success() {
+        return Err(failed(&response.status()));
+    }
+    let body = response.bytes().await.map_err(|e| failed(&e))?;
+    serde_json::from_slice(&body).map_err(|e| failed(&e))
+}
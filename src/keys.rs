@@ -0,0 +1,341 @@
+use axum::{
+    body::Bytes,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth;
+use crate::config;
+use crate::error::AppError;
+use crate::quota::QuotaConfig;
+use crate::signing;
+use crate::storage::UtcTime;
+
+/// An API key created through `/admin/keys`. Only its digest is kept, the
+/// key itself is shown once when it is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManagedKey {
+    pub name: String,
+    /// Hex SHA-256 digest of the key.
+    pub digest: String,
+    /// Unix time of its creation.
+    pub created: u64,
+    /// Overrides `RATE_LIMIT_PER_MINUTE` for this key.
+    pub requests_per_minute: Option<u64>,
+    /// Overrides `QUOTA_MEGAPIXELS_PER_DAY` for this key.
+    pub megapixels_per_day: Option<u64>,
+}
+
+impl ManagedKey {
+    /// The limits of requests with this key.
+    fn quota(&self) -> QuotaConfig {
+        let quota = &config::get().quota;
+        QuotaConfig {
+            requests_per_minute: self.requests_per_minute.or(quota.requests_per_minute),
+            megapixels_per_day: self.megapixels_per_day.or(quota.megapixels_per_day),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.name,
+            "created": UtcTime::from_unix(self.created).iso8601(),
+            "requests_per_minute": self.requests_per_minute,
+            "megapixels_per_day": self.megapixels_per_day,
+        })
+    }
+}
+
+/// The keys of `KEY_STORE_PATH` by digest, as last read.
+static KEYS: LazyLock<RwLock<HashMap<[u8; 32], ManagedKey>>> = LazyLock::new(Default::default);
+
+/// How often keys are read again from `KEY_STORE_PATH`, so that keys created
+/// or revoked by other instances sharing it take effect.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Reads the keys of `KEY_STORE_PATH`, and reads them again every 30 seconds.
+pub fn install() {
+    let Some(path) = config::get().key_store_path.clone() else {
+        return;
+    };
+    if let Err(e) = reload(&path) {
+        tracing::error!("Failed to read API keys from KEY_STORE_PATH {}: {}", path.display(), e);
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let path = path.clone();
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || reload(&path)).await {
+                tracing::warn!("Failed to read API keys again, keeping the ones read before: {}", e);
+            }
+        }
+    });
+}
+
+fn reload(path: &std::path::Path) -> Result<(), String> {
+    let mut keys = HashMap::new();
+    for key in store::load(path)? {
+        match signing::decode_hex(&key.digest).and_then(|digest| <[u8; 32]>::try_from(digest).ok()) {
+            Some(digest) => {
+                keys.insert(digest, key);
+            }
+            None => tracing::error!("Ignoring API key '{}' with an invalid digest in KEY_STORE_PATH", key.name),
+        }
+    }
+    *KEYS.write().unwrap() = keys;
+    Ok(())
+}
+
+/// The name and limits of a managed key, by the digest of the key.
+pub fn find(digest: &[u8; 32]) -> Option<(String, QuotaConfig)> {
+    KEYS.read().unwrap().get(digest).map(|key| (key.name.clone(), key.quota()))
+}
+
+fn store_path() -> Result<PathBuf, AppError> {
+    config::get().key_store_path.clone()
+        .ok_or_else(|| AppError::forbidden("Managing API keys is not configured on this server").with_code("key_store_not_configured"))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewKey {
+    name: Option<String>,
+    requests_per_minute: Option<u64>,
+    megapixels_per_day: Option<u64>,
+}
+
+/// Handler for `POST /admin/keys`, which creates a key and answers with it.
+pub async fn create_handler(body: Bytes) -> Result<Response, AppError> {
+    let path = store_path()?;
+    let new: NewKey = serde_json::from_slice(&body)
+        .map_err(|e| AppError::malformed(format!("Invalid JSON request: {}", e)).with_code("invalid_json"))?;
+
+    let secret = format!("itk_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let digest = auth::digest(&secret);
+    let name = new.name.map(|name| name.trim().to_string()).unwrap_or_else(|| format!("key-{}", &signing::hex(&digest)[..8]));
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(AppError::invalid("Key names must be 1 to 64 letters, digits, '-', '_' or '.'").with_code("invalid_key_name"));
+    }
+    if config::get().auth.api_keys.values().any(|key| *key == name) {
+        return Err(AppError::conflict(format!("A key named '{}' is set in API_KEYS", name)).with_code("key_exists"));
+    }
+    let key = ManagedKey {
+        name,
+        digest: signing::hex(&digest),
+        created: UtcTime::now().unix,
+        requests_per_minute: new.requests_per_minute,
+        megapixels_per_day: new.megapixels_per_day,
+    };
+
+    let stored = key.clone();
+    let created = tokio::task::spawn_blocking(move || store::insert(&path, &stored).and_then(|created| reload(&path).map(|_| created)))
+        .await
+        .map_err(|e| AppError::internal(e.to_string()))?
+        .map_err(|e| AppError::internal(format!("Failed to store the API key: {}", e)))?;
+    if !created {
+        return Err(AppError::conflict(format!("A key named '{}' exists already", key.name)).with_code("key_exists"));
+    }
+    tracing::info!("Created API key '{}'", key.name);
+
+    let mut body = key.to_json();
+    body["key"] = json!(secret);
+    Ok((StatusCode::CREATED, Json(body)).into_response())
+}
+
+/// Handler for `GET /admin/keys`, which lists the managed keys, without the
+/// keys themselves.
+pub async fn list_handler() -> Result<Response, AppError> {
+    store_path()?;
+    let mut keys: Vec<_> = KEYS.read().unwrap().values().cloned().collect();
+    keys.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(json!({ "keys": keys.iter().map(ManagedKey::to_json).collect::<Vec<_>>() })).into_response())
+}
+
+/// Handler for `DELETE /admin/keys/{name}`, which revokes a key.
+pub async fn revoke_handler(Path(name): Path<String>) -> Result<Response, AppError> {
+    let path = store_path()?;
+    let removed = {
+        let name = name.clone();
+        tokio::task::spawn_blocking(move || store::remove(&path, &name).and_then(|removed| reload(&path).map(|_| removed)))
+            .await
+            .map_err(|e| AppError::internal(e.to_string()))?
+            .map_err(|e| AppError::internal(format!("Failed to revoke the API key: {}", e)))?
+    };
+    if !removed {
+        if config::get().auth.api_keys.values().any(|key| *key == name) {
+            return Err(AppError::conflict(format!("The key '{}' is set in API_KEYS, and can only be removed there", name)).with_code("key_not_managed"));
+        }
+        return Err(AppError::not_found(format!("No key named '{}'", name)).with_code("key_not_found"));
+    }
+    tracing::info!("Revoked API key '{}'", name);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Keeps keys in a SQLite database when `KEY_STORE_PATH` ends in `.db`,
+/// `.sqlite` or `.sqlite3`, and in a JSON file otherwise.
+mod store {
+    use std::path::Path;
+
+    use super::ManagedKey;
+
+    fn is_sqlite(path: &Path) -> bool {
+        matches!(path.extension().and_then(|extension| extension.to_str()), Some("db" | "sqlite" | "sqlite3"))
+    }
+
+    pub fn load(path: &Path) -> Result<Vec<ManagedKey>, String> {
+        if is_sqlite(path) { sqlite::load(path) } else { file::load(path) }
+    }
+
+    /// Adds a key, unless its name is taken.
+    pub fn insert(path: &Path, key: &ManagedKey) -> Result<bool, String> {
+        if is_sqlite(path) { sqlite::insert(path, key) } else { file::insert(path, key) }
+    }
+
+    /// Removes the key of this name, if there is one.
+    pub fn remove(path: &Path, name: &str) -> Result<bool, String> {
+        if is_sqlite(path) { sqlite::remove(path, name) } else { file::remove(path, name) }
+    }
+
+    mod file {
+        use std::path::{Path, PathBuf};
+        use std::sync::Mutex;
+
+        use super::ManagedKey;
+
+        /// Keeps this instance's changes from overwriting each other.
+        static WRITE: Mutex<()> = Mutex::new(());
+
+        pub fn load(path: &Path) -> Result<Vec<ManagedKey>, String> {
+            match std::fs::read(path) {
+                Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| e.to_string()),
+                // Created with the first key.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+
+        fn save(path: &Path, keys: &[ManagedKey]) -> Result<(), String> {
+            let json = serde_json::to_vec_pretty(keys).map_err(|e| e.to_string())?;
+            // Written under a temporary name first, so a crash never leaves
+            // a partial file.
+            let partial = PathBuf::from(format!("{}.partial", path.display()));
+            std::fs::write(&partial, json).map_err(|e| e.to_string())?;
+            std::fs::rename(&partial, path).map_err(|e| e.to_string())
+        }
+
+        pub fn insert(path: &Path, key: &ManagedKey) -> Result<bool, String> {
+            let _write = WRITE.lock().unwrap();
+            let mut keys = load(path)?;
+            if keys.iter().any(|existing| existing.name == key.name) {
+                return Ok(false);
+            }
+            keys.push(key.clone());
+            save(path, &keys).map(|_| true)
+        }
+
+        pub fn remove(path: &Path, name: &str) -> Result<bool, String> {
+            let _write = WRITE.lock().unwrap();
+            let mut keys = load(path)?;
+            let count = keys.len();
+            keys.retain(|key| key.name != name);
+            if keys.len() == count {
+                return Ok(false);
+            }
+            save(path, &keys).map(|_| true)
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod sqlite {
+        use rusqlite::{params, Connection};
+        use std::path::Path;
+
+        use super::ManagedKey;
+
+        fn open(path: &Path) -> rusqlite::Result<Connection> {
+            let connection = Connection::open(path)?;
+            connection.busy_timeout(std::time::Duration::from_secs(5))?;
+            connection.execute_batch(
+                "CREATE TABLE IF NOT EXISTS api_keys (
+                    name TEXT PRIMARY KEY,
+                    digest TEXT NOT NULL UNIQUE,
+                    created INTEGER NOT NULL,
+                    requests_per_minute INTEGER,
+                    megapixels_per_day INTEGER
+                )",
+            )?;
+            Ok(connection)
+        }
+
+        pub fn load(path: &Path) -> Result<Vec<ManagedKey>, String> {
+            let connection = open(path).map_err(|e| e.to_string())?;
+            let mut statement = connection
+                .prepare("SELECT name, digest, created, requests_per_minute, megapixels_per_day FROM api_keys")
+                .map_err(|e| e.to_string())?;
+            let rows = statement.query_map([], |row| {
+                Ok(ManagedKey {
+                    name: row.get(0)?,
+                    digest: row.get(1)?,
+                    created: row.get::<_, i64>(2)? as u64,
+                    requests_per_minute: row.get::<_, Option<i64>>(3)?.map(|limit| limit as u64),
+                    megapixels_per_day: row.get::<_, Option<i64>>(4)?.map(|limit| limit as u64),
+                })
+            }).map_err(|e| e.to_string())?;
+            rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+        }
+
+        pub fn insert(path: &Path, key: &ManagedKey) -> Result<bool, String> {
+            let connection = open(path).map_err(|e| e.to_string())?;
+            let inserted = connection.execute(
+                "INSERT INTO api_keys (name, digest, created, requests_per_minute, megapixels_per_day) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (name) DO NOTHING",
+                params![
+                    key.name,
+                    key.digest,
+                    key.created as i64,
+                    key.requests_per_minute.map(|limit| limit as i64),
+                    key.megapixels_per_day.map(|limit| limit as i64),
+                ],
+            ).map_err(|e| e.to_string())?;
+            Ok(inserted > 0)
+        }
+
+        pub fn remove(path: &Path, name: &str) -> Result<bool, String> {
+            let connection = open(path).map_err(|e| e.to_string())?;
+            let removed = connection.execute("DELETE FROM api_keys WHERE name = ?1", [name]).map_err(|e| e.to_string())?;
+            Ok(removed > 0)
+        }
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    mod sqlite {
+        use std::path::Path;
+
+        use super::ManagedKey;
+
+        const UNSUPPORTED: &str = "a SQLite KEY_STORE_PATH needs the `sqlite` feature";
+
+        pub fn load(_path: &Path) -> Result<Vec<ManagedKey>, String> {
+            Err(UNSUPPORTED.to_string())
+        }
+
+        pub fn insert(_path: &Path, _key: &ManagedKey) -> Result<bool, String> {
+            Err(UNSUPPORTED.to_string())
+        }
+
+        pub fn remove(_path: &Path, _name: &str) -> Result<bool, String> {
+            Err(UNSUPPORTED.to_string())
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use axum::Router;
+
+/// Whether the process was started by the AWS Lambda runtime, which sets
+/// `AWS_LAMBDA_RUNTIME_API`, in a build with the `lambda` feature.
+pub fn running() -> bool {
+    cfg!(feature = "lambda") && std::env::var_os("AWS_LAMBDA_RUNTIME_API").is_some()
+}
+
+/// Answers the invocations of API Gateway or an Application Load Balancer
+/// with the app, until the runtime stops the function.
+#[cfg(feature = "lambda")]
+pub async fn serve(app: Router) {
+    if let Err(e) = lambda_http::run(app.layer(axum::middleware::from_fn(client_ip))).await {
+        tracing::error!("The Lambda runtime failed: {}", e);
+    }
+}
+
+/// Never called, since the server only runs as a Lambda function with the
+/// `lambda` feature.
+#[cfg(not(feature = "lambda"))]
+pub async fn serve(_app: Router) {}
+
+/// Passes on the client IP that API Gateway saw, which rate limits of
+/// requests without credentials are kept by.
+#[cfg(feature = "lambda")]
+async fn client_ip(mut request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    use axum::extract::ConnectInfo;
+    use lambda_http::{request::RequestContext, RequestExt};
+    use std::net::{IpAddr, SocketAddr};
+
+    let ip = match request.request_context_ref() {
+        Some(RequestContext::ApiGatewayV1(context)) => context.identity.source_ip.as_deref(),
+        Some(RequestContext::ApiGatewayV2(context)) => context.http.source_ip.as_deref(),
+        _ => None,
+    };
+    if let Some(ip) = ip.and_then(|ip| ip.parse::<IpAddr>().ok()) {
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip, 0)));
+    }
+    next.run(request).await
+}
@@ -1,186 +1,802 @@
+mod analyze;
+mod auth;
+mod azure;
+mod batch;
+mod cache;
+mod compare;
+mod compose;
+mod config;
+mod error;
+mod favicon;
+mod fetch;
+mod files;
+mod gcs;
+mod grpc;
+mod health;
+mod inspect;
+mod jobs;
+mod jwt;
+mod keys;
+mod lambda;
+mod pool;
+mod presets;
+mod qr;
+mod queue;
+mod quota;
+mod s3;
+mod shutdown;
+mod signing;
+mod spec;
+mod storage;
+mod telemetry;
+mod thumbor;
+mod unix_socket;
+mod upload;
+mod usage;
+mod variants;
+mod version;
+mod watch;
+mod webhook;
+
 use axum::{
-    body::Bytes,
-    extract::{DefaultBodyLimit, Multipart},
-    http::{header, StatusCode},
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, FromRequest, Multipart, Path, Query, Request},
+    http::{header, HeaderMap, StatusCode, Uri},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
-use image::{DynamicImage, ImageFormat};
+// The modules of the pipeline are addressed as if they were the server's own.
+use image_transformer_core::{color, decode, encode, filter, metadata, ops, transform};
+use image_transformer_core::{process_image, ClientHints, Fields, ProcessOptions};
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::TcpListener;
-use tower_http::cors::{Any, CorsLayer};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use tower_http::trace::{self, TraceLayer};
-use tracing::Level;
+use tracing::{Instrument, Level};
+
+use error::AppError;
+use variants::{Variant, VariantBundle};
 
 #[tokio::main]
 async fn main() {
+    // Read before the configuration, whose warnings need the subscriber.
+    let log_format = config::var("LOG_FORMAT").unwrap_or_default();
+    let json_logs = log_format.trim().eq_ignore_ascii_case("json");
     tracing_subscriber::registry()
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "image_transformer_api=info,tower_http=info".into()),
+            config::var("RUST_LOG").and_then(|filter| tracing_subscriber::EnvFilter::try_new(filter).ok())
+                .unwrap_or_else(|| "image_transformer_api=info,image_transformer_core=info,tower_http=info".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(json_logs.then(|| tracing_subscriber::fmt::layer().json().flatten_event(true).with_current_span(false)))
+        .with((!json_logs).then(tracing_subscriber::fmt::layer))
+        .with(telemetry::otel_layer())
         .init();
+    if let Err(errors) = config::load() {
+        for error in errors {
+            tracing::error!("{}", error);
+        }
+        std::process::exit(1);
+    }
+    image_transformer_core::install(config::pipeline_settings());
+    telemetry::install();
+    keys::install();
+    presets::install();
+    usage::install();
 
-    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any);
+    let cors = CorsLayer::new()
+        .allow_origin(match &config::get().cors_allowed_origins {
+            Some(origins) => AllowOrigin::list(origins.iter().cloned()),
+            None => AllowOrigin::any(),
+        })
+        .allow_methods(Any)
+        .expose_headers([header::HeaderName::from_static("x-request-id"), X_PLACEHOLDER.clone()]);
 
     let app = Router::new()
-        .route("/healthz", get(health_check))
-        .route("/transform", post(transform_image_handler))
-        .layer(DefaultBodyLimit::disable())
-        .layer(DefaultBodyLimit::max(100 * 1024 * 1024))
+        .route("/livez", get(health::live_handler))
+        .route("/readyz", get(health::ready_handler))
+        // From before liveness and readiness were told apart.
+        .route("/healthz", get(health::live_handler))
+        .route("/metrics", get(telemetry::metrics_handler))
+        .route("/version", get(version::version_handler))
+        .merge(admin())
+        .nest("/v1", v1())
+        // The unversioned paths predate `/v1` and stay for existing callers.
+        .merge(v1().layer(axum::middleware::map_response(deprecated)))
+        .merge(thumbor())
+        .layer(DefaultBodyLimit::max(config::get().max_upload_bytes))
+        .layer(axum::middleware::from_fn(telemetry::track))
         .layer(
             TraceLayer::new_for_http()
-                .on_response(trace::DefaultOnResponse::new().level(Level::INFO))
+                .make_span_with(telemetry::request_span)
+                .on_response(telemetry::log_response)
                 .on_failure(trace::DefaultOnFailure::new().level(Level::ERROR))
         )
+        .layer(axum::middleware::from_fn(telemetry::request_id))
         .layer(cors);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    tracing::debug!("listening on {}", addr);
-    let listener = TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // The runtime hands over requests itself, and freezes the process
+    // between them.
+    if lambda::running() {
+        lambda::serve(app).await;
+        usage::flush().await;
+        telemetry::shutdown();
+        return;
+    }
+
+    let tcp = match config::get().listen_addr {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tracing::info!("listening on {}", addr);
+                Some(listener)
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let unix = config::get().unix_socket.as_ref().map(|socket| match unix_socket::bind(socket) {
+        Ok(listener) => {
+            tracing::info!("listening on {}", socket.path.display());
+            (listener, socket)
+        }
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    });
+
+    let grpc = match config::get().grpc_addr {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tracing::info!("serving gRPC on {}", addr);
+                Some(listener)
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    tokio::spawn(shutdown::signal());
+    let servers = async {
+        let tcp = async {
+            if let Some(listener) = tcp {
+                axum::serve(listener, app.clone().into_make_service_with_connect_info::<SocketAddr>())
+                    .with_graceful_shutdown(shutdown::requested())
+                    .await
+                    .unwrap();
+            }
+        };
+        let unix = async {
+            if let Some((listener, socket)) = unix {
+                unix_socket::serve(listener, app.clone(), socket).await;
+            }
+        };
+        let grpc = async {
+            if let Some(listener) = grpc {
+                grpc::serve(listener).await;
+            }
+        };
+        let queue = async {
+            if let Some(queue) = &config::get().queue {
+                queue::consume(queue).await;
+            }
+        };
+        let watch = async {
+            if let Some(watch) = &config::get().watch {
+                watch::watch(watch).await;
+            }
+        };
+        tokio::join!(tcp, unix, grpc, queue, watch);
+    };
+    tokio::select! {
+        _ = servers => {}
+        _ = shutdown::deadline() => tracing::warn!("Closing connections still open after SHUTDOWN_TIMEOUT_SECS"),
+    }
+    let drained = shutdown::drain().await;
+    usage::flush().await;
+    telemetry::shutdown();
+    // Blocking work that is still running would otherwise keep the process
+    // alive until it is done.
+    if !drained {
+        std::process::exit(1);
+    }
+}
+
+/// The endpoints for operators, which need a key of `ADMIN_API_KEYS`.
+fn admin() -> Router {
+    Router::new()
+        .route("/usage", get(usage::usage_handler))
+        .route("/admin/keys", get(keys::list_handler).post(keys::create_handler))
+        .route("/admin/keys/:name", delete(keys::revoke_handler))
+        .route("/admin/presets", get(presets::list_handler))
+        .route("/admin/presets/:name", put(presets::put_handler).delete(presets::delete_handler))
+        .route_layer(axum::middleware::from_fn(auth::require_admin))
+}
+
+/// The endpoints of version 1 of the API. Breaking changes to them ship as a
+/// new version instead.
+fn v1() -> Router {
+    Router::new()
+        .route("/transform", post(transform_image_handler).get(transform_url_handler))
+        .route("/transform/batch", post(transform_batch_handler))
+        .route("/jobs", post(transform_job_handler))
+        .route("/jobs/batch", post(batch_job_handler))
+        .route("/jobs/:id", get(jobs::status_handler))
+        .route("/jobs/:id/result", get(jobs::result_handler))
+        .route("/jobs/:id/events", get(jobs::events_handler))
+        .route("/inspect", post(inspect::inspect_handler).get(inspect::inspect_url_handler))
+        .route("/analyze", post(analyze::analyze_handler).get(analyze::analyze_url_handler))
+        .route("/favicon", post(favicon::favicon_handler))
+        .route("/compose", post(compose::compose_handler))
+        .route("/compare/quality", post(compare::quality_handler))
+        .route("/generate/qr", get(qr::qr_handler))
+        .route("/files/*key", get(files::serve_handler))
+        .route_layer(axum::middleware::from_fn(quota::meter))
+        .route_layer(axum::middleware::from_fn(auth::authenticate))
+}
+
+/// The Thumbor-style URLs, which are not versioned.
+fn thumbor() -> Router {
+    Router::new()
+        .route("/:signature/*path", get(thumbor_handler))
+        .route_layer(axum::middleware::from_fn(quota::meter))
+        .route_layer(axum::middleware::from_fn(auth::authenticate_thumbor))
 }
 
-async fn health_check() -> &'static str {
-    "OK"
+/// Marks responses of the unversioned aliases as deprecated, and links to
+/// the `/v1` path that replaces them.
+async fn deprecated(uri: Uri, mut response: Response) -> Response {
+    let successor = format!("</v1{}>; rel=\"successor-version\"", uri.path());
+    let headers = response.headers_mut();
+    headers.insert("deprecation", header::HeaderValue::from_static("true"));
+    if let Ok(link) = header::HeaderValue::from_str(&successor) {
+        headers.append(header::LINK, link);
+    }
+    response
 }
 
+/// The `placeholder` of a single output, which has no other place in the
+/// response.
+static X_PLACEHOLDER: header::HeaderName = header::HeaderName::from_static("x-placeholder");
+
 /// Handler for the /transform endpoint.
-/// Accepts multipart/form-data with two fields:
-/// - "image": The image file (PNG or JPG).
-/// - "size": An optional string like "800x600".
+/// Accepts an `application/json` [`spec::TransformSpec`], a raw `image/*` body with the fields below (except
+/// "image" and "watermark") as query parameters, or multipart/form-data with the following fields:
+/// - "url": An optional http(s) URL to download the image from instead, limited by the `FETCH_*` settings.
+/// - "image": The image file (PNG, JPG, WebP, GIF, TIFF, BMP, HEIC, SVG or PDF). Animated inputs stay animated for WebP output.
+/// - "ops": An optional pipeline of steps separated by "|" and run in order, e.g.
+///   "crop:0,0,800,800|resize:400x400|grayscale|blur:2". Runs after "redact" and before the other fields.
+/// - "size": An optional string like "800x600"; either side may be left out ("800x", "x600").
+/// - "variants": Optional sizes separated by "," (e.g. "1600x,800x,400x") rendered from one decode instead of
+///   "size", returned as a ZIP archive or, with "bundle" set to "json", as JSON with `data:` URLs.
+/// - "ar": An optional aspect ratio like "16:9" that derives a missing side of "size", or crops ("cover", the
+///   default with "ar") or pads ("contain") the image to that shape.
+/// - "dpr": An optional device pixel ratio from 1 to 3 that multiplies "size"; falls back to the `DPR`
+///   request header. Without "size", the `Width` request header sets the width in physical pixels.
+/// - "fit": An optional resize mode: "cover", "contain", "fill", "inside" (default) or "outside".
+/// - "no_enlarge": An optional boolean, shrinks "size" instead of scaling the image up beyond its own size
+///   (default from `NO_ENLARGE`).
+/// - "resize_filter": An optional resampling filter: "lanczos3" (default), "catmullrom", "triangle" or
+///   "nearest" for pixel art.
+/// - "gravity": An optional anchor for "cover" crops and "contain" padding: "center" (default), a compass
+///   direction like "north" or "southwest", "smart" to keep the most detailed region, "face" to center on
+///   detected faces, or "focal:x,y" in percent.
+/// - "background": An optional hex color used to pad "contain" results and fill the corners of rotated
+///   images (default: transparent).
+/// - "rotate": An optional angle in degrees clockwise, applied before cropping and resizing.
+/// - "flip": An optional "h" or "v" to mirror the image horizontally or vertically.
+/// - "blur": An optional Gaussian blur sigma in pixels (up to 50), applied after resizing.
+/// - "sharpen": An optional unsharp mask amount (up to 10), e.g. 0.5 for a light sharpen after downscaling.
+/// - "brightness", "contrast", "saturation", "gamma": Optional adjustment factors up to 10, where 1 leaves
+///   the image unchanged (gamma must be at least 0.1).
+/// - "filter": An optional color filter: "grayscale", "sepia" or "invert".
+/// - "redact": Optional regions "x,y,width,height" separated by ";" to obscure before any other change.
+/// - "redact_mode": An optional "pixelate" (default) or "black" for redacted regions.
+/// - "text": An optional caption drawn onto the output; lines are wrapped to the image width.
+/// - "text_size": An optional font size in pixels, defaults to 48.
+/// - "text_color": An optional hex color, defaults to white.
+/// - "text_position": An optional "center" or compass direction, defaults to "south".
+/// - "watermark": An optional image (PNG with alpha) composited onto the output, replacing the server-wide
+///   watermark from `WATERMARK_PATH`.
+/// - "watermark_position": An optional "center" or compass direction, defaults to "southeast".
+/// - "watermark_opacity": An optional opacity from 0.0 to 1.0 (default).
+/// - "watermark_scale": An optional watermark width as a fraction of the image width; by default the watermark
+///   keeps its own size.
+/// - "watermark_tile": An optional boolean, repeats the watermark diagonally across the whole image.
+/// - "watermark_spacing": An optional gap in pixels between tiles, defaults to 100.
+/// - "watermark_angle": An optional tile rotation in degrees clockwise, defaults to -45.
+/// - "pad": An optional "width,color" of padding added around the output (color defaults to transparent).
+/// - "border": An optional "width,color" of border added around the padding (color defaults to black).
+/// - "radius": An optional corner radius in pixels, or "max" for a circle on square images; corners become
+///   transparent.
+/// - "trim": An optional boolean, removes uniform borders (matching the top-left pixel) before cropping and resizing.
+/// - "trim_tolerance": An optional per-channel tolerance from 0 to 255 for "trim", defaults to 10.
+/// - "crop": An optional region "x,y,width,height" to extract, clamped to the image bounds.
+/// - "crop_after_resize": An optional boolean, crops the resized image instead of the original.
+/// - "page": An optional 1-based page number for PDF inputs, defaults to the first page.
 /// - "quality": An optional float for lossy compression quality (0.0 to 100.0).
-async fn transform_image_handler(mut multipart: Multipart) -> Result<Response, AppError> {
-    let mut image_data: Option<Bytes> = None;
-    let mut size_str: Option<String> = None;
-    let mut quality: Option<f32> = None;
-
-    // Process multipart form data
-    while let Some(field) = multipart.next_field().await? {
-        let name = field.name().unwrap_or("").to_string();
-        
-        match name.as_str() {
-            "image" => {
-                image_data = Some(field.bytes().await?);
-            }
-            "size" => {
-                size_str = Some(field.text().await?);
-            }
-            "quality" => {
-                let quality_str = field.text().await?;
-                quality = quality_str.parse::<f32>().ok();
-                if let Some(q) = quality {
-                    if q < 0.0 || q > 100.0 {
-                        return Err(AppError::new(StatusCode::BAD_REQUEST, "Quality must be between 0.0 and 100.0"));
-                    }
+/// - "max_bytes": An optional output size budget in bytes; lossy formats lower the quality until the output fits.
+/// - "output": An optional storage target like "s3://bucket/key" (or "s3" for the default bucket); the result is
+///   uploaded there and the response is JSON with the object key and a presigned download URL.
+/// - "format": An optional output format ("webp", "jpeg", "avif", "png", "jxl", "ico", "auto" or "auto-content"), defaults to WebP.
+///   "auto" picks AVIF, WebP, or JPEG based on the request's `Accept` header.
+/// - "speed": An optional AVIF encoder speed (1 to 10).
+/// - "effort": An optional JPEG XL encoder effort (1 to 10).
+/// - "progressive": An optional boolean for progressive JPEG scans (mozjpeg builds only).
+/// - "subsampling": An optional JPEG chroma subsampling ("420", "422" or "444", mozjpeg builds only).
+/// - "colors": An optional palette size for indexed PNG output (2 to 256).
+/// - "optimize": An optional boolean enabling oxipng/zopfli optimization of PNG output.
+/// - "auto_orient": An optional boolean, rotates the image upright from its EXIF orientation (default true).
+/// - "metadata": An optional metadata mode ("strip", "keep", "keep-copyright" or "privacy"), defaults to "strip".
+/// - "keep_profile": An optional boolean embedding the input's ICC profile instead of converting to sRGB.
+/// - "lossless": An optional boolean enabling lossless WebP or JPEG XL encoding.
+/// - "method": An optional WebP compression method (0 to 6).
+/// - "alpha_quality": An optional WebP alpha channel quality (0 to 100).
+/// - "near_lossless": An optional WebP near-lossless level (0 to 100), implies lossless encoding.
+/// - "placeholder": An optional "blurhash" or "thumbhash" of each output, in the `X-Placeholder` header or
+///   next to each variant.
+async fn transform_image_handler(headers: HeaderMap, request: Request) -> Result<Response, AppError> {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    if content_type.starts_with("application/json") {
+        let body = Bytes::from_request(request, &()).await
+            .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+        return transform_json(&headers, &body).await;
+    }
+    if content_type.starts_with("image/") || content_type.starts_with("application/octet-stream") {
+        return transform_raw(&headers, request).await;
+    }
+
+    let mut multipart = Multipart::from_request(request, &()).await
+        .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+    let (image_data, source_url, transform) = async {
+        let mut image_data: Option<Bytes> = None;
+        let mut source_url: Option<String> = None;
+        let mut transform = TransformRequest::new();
+
+        // Process multipart form data
+        while let Some(field) = multipart.next_field().await? {
+            let name = field.name().unwrap_or("").to_string();
+
+            match name.as_str() {
+                "image" => {
+                    image_data = Some(upload::read_file(field).await?);
+                }
+                "watermark" => {
+                    transform.fields.options.watermark = Some(field.bytes().await?);
+                }
+                "url" => {
+                    source_url = Some(field.text().await?);
                 }
+                _ => transform.set(&name, &field.text().await?)?,
             }
-            _ => { /* Ignore other fields */ }
         }
+        Ok::<_, AppError>((image_data, source_url, transform))
     }
+    .instrument(tracing::info_span!("parse_multipart"))
+    .await?;
 
-    let image_bytes = image_data.ok_or_else(|| {
-        AppError::new(StatusCode::BAD_REQUEST, "Image data not provided in 'image' field")
-    })?;
+    let image_bytes = match (image_data, source_url) {
+        (Some(image_bytes), _) => image_bytes,
+        (None, Some(url)) => fetch::fetch(&url).await?,
+        (None, None) => return Err(AppError::invalid("Image data not provided in 'image' field").with_code("missing_image")),
+    };
+    transform.run(image_bytes, &headers).await
+}
 
-    let webp_bytes = tokio::task::spawn_blocking(move || {
-        process_image(image_bytes, size_str, quality)
-    })
-    .await??;
+/// Handler for `GET /transform?url=...`, which downloads the source image and
+/// takes the other fields as query parameters. With `URL_SIGNING_KEY` set, the
+/// query must be signed.
+async fn transform_url_handler(headers: HeaderMap, uri: Uri, Query(fields): Query<Vec<(String, String)>>) -> Result<Response, AppError> {
+    signing::verify_query(&uri)?;
 
-    Ok((
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "image/webp")],
-        webp_bytes,
-    ).into_response())
+    let mut source_url = None;
+    let mut transform = TransformRequest::new();
+    for (name, value) in &fields {
+        match name.as_str() {
+            "url" => source_url = Some(value),
+            "signature" | "expires" => {}
+            _ => transform.set(name, value)?,
+        }
+    }
+
+    let url = source_url.ok_or_else(|| AppError::invalid("Source URL not provided in 'url' parameter").with_code("missing_image"))?;
+    let image_bytes = fetch::fetch(url).await?;
+    transform.run(image_bytes, &headers).await
 }
 
-fn process_image(image_bytes: Bytes, size_str: Option<String>, quality: Option<f32>) -> Result<Vec<u8>, AppError> {
-    let image_format = image::guess_format(&image_bytes)
-        .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, "Could not determine image format"))?;
+/// Handles a `/transform` request sent as an `application/json`
+/// [`spec::TransformSpec`].
+async fn transform_json(headers: &HeaderMap, body: &[u8]) -> Result<Response, AppError> {
+    let spec: spec::TransformSpec = serde_json::from_slice(body)
+        .map_err(|e| AppError::malformed(format!("Invalid JSON request: {}", e)).with_code("invalid_json"))?;
+    let parts = spec.into_parts().await?;
 
-    if ![ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP].contains(&image_format) {
-        return Err(AppError::new(StatusCode::BAD_REQUEST, "Input image must be PNG, JPG, or WebP"));
+    let mut transform = TransformRequest::new();
+    for (name, value) in parts.fields {
+        transform.set(name, &value)?;
     }
+    transform.fields.options.transform.ops = parts.operations;
+    transform.run(parts.image, headers).await
+}
 
-    let mut img = image::load_from_memory(&image_bytes)
-        .map_err(|e| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to decode image: {}", e)))?;
-
-    if let Some(s) = size_str {
-        let (width, height) = parse_size(&s)?;
-        img = img.resize(width, height, image::imageops::FilterType::Lanczos3);
+/// Handles a `/transform` request whose body is the image itself, with the
+/// form fields passed as query parameters.
+async fn transform_raw(headers: &HeaderMap, request: Request) -> Result<Response, AppError> {
+    let Query(fields) = Query::<Vec<(String, String)>>::try_from_uri(request.uri())
+        .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+    let image_bytes = Bytes::from_request(request, &()).await
+        .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+    if image_bytes.is_empty() {
+        return Err(AppError::invalid("Image data not provided in the request body").with_code("missing_image"));
     }
 
-    encode_to_webp(img, quality.unwrap_or(100.0))
+    let mut transform = TransformRequest::new();
+    for (name, value) in &fields {
+        transform.set(name, value)?;
+    }
+    transform.run(image_bytes, headers).await
 }
 
-fn encode_lossy_webp(img: DynamicImage, quality: f32) -> Result<Vec<u8>, AppError> {
-    let img = img.to_rgba8();
-    let (width, height) = img.dimensions();
-    
-    let encoder = webp::Encoder::new(&*img, webp::PixelLayout::Rgba, width, height);
-    let encoded = encoder.encode(quality);
-    
-    if encoded.is_empty() {
-        return Err(AppError::new(
-            StatusCode::INTERNAL_SERVER_ERROR, 
-            "Failed to encode image to WebP format"
-        ));
+/// Handler for the /transform/batch endpoint, which applies the same fields to
+/// many images at once. Accepts an `application/json` [`batch::BatchSpec`], or
+/// multipart/form-data with any number of "image" files and "url" fields plus
+/// the /transform fields, and "bundle" ("zip", the default, or "multipart").
+/// Images are processed concurrently and a failed image does not fail the
+/// batch; every outcome is listed in the bundled `manifest.json`.
+async fn transform_batch_handler(headers: HeaderMap, request: Request) -> Result<Response, AppError> {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    let mut sources = Vec::new();
+    let mut template = TransformRequest::new();
+    let mut bundle = batch::Bundle::default();
+
+    if content_type.starts_with("application/json") {
+        let body = Bytes::from_request(request, &()).await
+            .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+        let spec: batch::BatchSpec = serde_json::from_slice(&body)
+            .map_err(|e| AppError::malformed(format!("Invalid JSON request: {}", e)).with_code("invalid_json"))?;
+        for (name, value) in spec.fields()? {
+            template.set(&name, &value)?;
+        }
+        if let Some(value) = &spec.bundle {
+            bundle = value.parse()?;
+        }
+        sources.extend(spec.urls.into_iter().map(batch::Source::Url));
+    } else {
+        let mut multipart = Multipart::from_request(request, &()).await
+            .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+        while let Some(field) = multipart.next_field().await? {
+            let name = field.name().unwrap_or("").to_string();
+
+            match name.as_str() {
+                "image" => {
+                    let file_name = field.file_name().map(str::to_string);
+                    sources.push(batch::Source::Upload { file_name, data: upload::read_file(field).await? });
+                }
+                "url" => {
+                    sources.push(batch::Source::Url(field.text().await?));
+                }
+                "watermark" => {
+                    template.fields.options.watermark = Some(field.bytes().await?);
+                }
+                "bundle" => {
+                    bundle = field.text().await?.parse()?;
+                }
+                _ => template.set(&name, &field.text().await?)?,
+            }
+        }
+    }
+
+    if sources.is_empty() {
+        return Err(AppError::invalid("No images provided in 'image' or 'url' fields").with_code("missing_image"));
+    }
+    if !template.fields.options.variants.is_empty() {
+        return Err(AppError::invalid("'variants' is not supported in batch requests"));
+    }
+    if template.output.is_some() {
+        return Err(AppError::invalid("'output' is not supported in batch requests"));
+    }
+    if sources.len() > batch::MAX_BATCH_ITEMS {
+        return Err(AppError::invalid(format!("A batch accepts at most {} images", batch::MAX_BATCH_ITEMS)).with_code("too_many_images"));
+    }
+
+    // Downloads overlap freely, but only as many images are processed at a
+    // time as there are cores.
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let permits = Arc::new(Semaphore::new(cores));
+    let mut tasks = JoinSet::new();
+    for (index, source) in sources.into_iter().enumerate() {
+        let (request, headers, permits) = (template.clone(), headers.clone(), permits.clone());
+        tasks.spawn(quota::Meter::bind(async move {
+            let name = source.name(index);
+            let result = async {
+                let image_bytes = source.load().await?;
+                let _permit = permits.acquire().await?;
+                let outputs = request.render(image_bytes, &headers).await?;
+                let output = outputs.into_iter().next().expect("one output without variants");
+                Ok((output.bytes, output.format, output.placeholder))
+            }.await;
+            (index, batch::Item { name, result })
+        }.in_current_span()));
     }
-    
-    Ok(encoded.to_vec())
+    let total = tasks.len();
+    let mut items = Vec::with_capacity(total);
+    while let Some(item) = tasks.join_next().await {
+        items.push(item?);
+        jobs::report_progress(items.len(), total);
+    }
+    items.sort_by_key(|(index, _)| *index);
+    batch::respond(items.into_iter().map(|(_, item)| item).collect(), bundle)
 }
 
-fn encode_to_webp(img: DynamicImage, quality: f32) -> Result<Vec<u8>, AppError> {
-    encode_lossy_webp(img, quality)
+/// Handler for `POST /jobs`, which queues a /transform request and answers
+/// `202 Accepted` with a job ID at once; see [`jobs::status_handler`]. An
+/// optional `callback_url` query parameter is notified when the job finishes.
+async fn transform_job_handler(request: Request) -> Result<Response, AppError> {
+    let callback = job_callback(request.uri())?;
+    let request = buffer_request(request).await?;
+    let headers = request.headers().clone();
+    jobs::submit(Box::pin(async move { transform_image_handler(headers, request).await.into_response() }), callback)
 }
 
-fn parse_size(size_str: &str) -> Result<(u32, u32), AppError> {
-    let parts: Vec<&str> = size_str.split('x').collect();
-    if parts.len() != 2 {
-        return Err(AppError::new(StatusCode::BAD_REQUEST, "Invalid size format. Use 'WIDTHxHEIGHT'"));
-    }
-    let width = parts[0].parse::<u32>()
-        .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, "Invalid width value"))?;
-    let height = parts[1].parse::<u32>()
-        .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, "Invalid height value"))?;
-    Ok((width, height))
+/// Handler for `POST /jobs/batch`, which queues a /transform/batch request.
+async fn batch_job_handler(request: Request) -> Result<Response, AppError> {
+    let callback = job_callback(request.uri())?;
+    let request = buffer_request(request).await?;
+    let headers = request.headers().clone();
+    jobs::submit(Box::pin(async move { transform_batch_handler(headers, request).await.into_response() }), callback)
 }
 
-struct AppError {
-    status_code: StatusCode,
-    message: String,
+/// Reads and validates the `callback_url` query parameter of a job request.
+fn job_callback(uri: &Uri) -> Result<Option<reqwest::Url>, AppError> {
+    let Query(params) = Query::<Vec<(String, String)>>::try_from_uri(uri)
+        .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+    params.iter().find(|(name, _)| name == "callback_url").map(|(_, url)| webhook::check(url)).transpose()
 }
 
-impl AppError {
-    fn new(status_code: StatusCode, message: impl Into<String>) -> Self {
-        Self { status_code, message: message.into() }
+/// Reads the whole body of a request, so that it can be processed after the
+/// response has been sent.
+async fn buffer_request(request: Request) -> Result<Request, AppError> {
+    let mut builder = Request::builder().method(request.method().clone()).uri(request.uri().clone());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = request.headers().clone();
     }
+    // Extensions carry the body limit on to the extractors that run later.
+    if let Some(extensions) = builder.extensions_mut() {
+        *extensions = request.extensions().clone();
+    }
+    let body = Bytes::from_request(request, &()).await
+        .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+    Ok(builder.body(Body::from(body))?)
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        if self.status_code.is_server_error() {
-            tracing::error!(status = %self.status_code, error = %self.message);
-        } else if self.status_code.is_client_error() {
-            tracing::warn!(status = %self.status_code, error = %self.message);
+/// Handler for Thumbor-style `GET /{signature}/{options}/{image URL}` paths,
+/// with `unsafe` in place of the signature for unsigned requests.
+async fn thumbor_handler(headers: HeaderMap, uri: Uri, Path((signature, path)): Path<(String, String)>) -> Result<Response, AppError> {
+    // The signature covers the path as sent; the image URL may carry its own query.
+    let signed = uri.path().trim_start_matches('/').split_once('/').map_or("", |(_, rest)| rest);
+    let query = uri.query().map(|query| format!("?{}", query)).unwrap_or_default();
+    let request = thumbor::parse(&signature, &format!("{}{}", signed, query), &format!("{}{}", path, query))?;
+
+    let mut transform = TransformRequest::new();
+    for (name, value) in &request.fields {
+        transform.set(name, value)?;
+    }
+    let image_bytes = fetch::fetch(&request.source).await?;
+    transform.run(image_bytes, &headers).await
+}
+
+/// The settings of a /transform request, built up one field at a time.
+#[derive(Clone)]
+struct TransformRequest {
+    fields: Fields,
+    bundle: VariantBundle,
+    /// Uploads the result instead of returning it.
+    output: Option<storage::Target>,
+    /// Fields set by the request itself, which a `preset` does not override.
+    given: HashSet<String>,
+}
+
+impl TransformRequest {
+    fn new() -> Self {
+        let mut fields = Fields::default();
+        fields.options.transform.no_enlarge = config::get().no_enlarge;
+        fields.options.transform.size_limit = config::get().size_limit;
+        Self { fields, bundle: VariantBundle::default(), output: None, given: HashSet::new() }
+    }
+
+    /// Applies a text field by name. Unknown fields are ignored.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), AppError> {
+        match name {
+            "preset" => {
+                let preset = presets::find(value)
+                    .ok_or_else(|| AppError::invalid(format!("Unknown preset '{}'", value.trim())).with_code("invalid_preset"))?;
+                let given = |name: &str| self.given.contains(name) || excluded_by(name).is_some_and(|other| self.given.contains(other));
+                for (name, value) in preset.iter().filter(|(name, _)| !given(name)) {
+                    self.fields.set(name, value)?;
+                }
+                return Ok(());
+            }
+            "bundle" => {
+                self.bundle = value.parse()?;
+            }
+            "output" => {
+                self.output = Some(value.parse()?);
+            }
+            _ => {
+                // What a preset set in place of this field gives way to it.
+                match excluded_by(name) {
+                    Some("variants") if !self.given.contains("variants") => self.fields.options.variants.clear(),
+                    Some("size") if !self.given.contains("size") => {
+                        (self.fields.options.transform.width, self.fields.options.transform.height) = (None, None);
+                    }
+                    _ => {}
+                }
+                self.fields.set(name, value)?;
+            }
+        }
+        self.given.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Processes the image and builds the response.
+    async fn run(self, image_bytes: Bytes, headers: &HeaderMap) -> Result<Response, AppError> {
+        let (bundle, has_variants, output) = (self.bundle, !self.fields.options.variants.is_empty(), self.output.clone());
+        let prepared = self.prepare(image_bytes, headers).await?;
+        let vary = prepared.vary.clone();
+        // Stored results are answered with expiring links, which are neither
+        // validated nor cached.
+        let etag = output.is_none().then(|| prepared.etag(has_variants.then_some(bundle)));
+        let mut response = if let Some(etag) = etag.as_deref() && if_none_match(headers, etag) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            let mut outputs = prepared.process().await?;
+            if let Some(target) = output {
+                storage::store(&target, outputs, has_variants).await?
+            } else if has_variants {
+                variants::respond(outputs, bundle)?
+            } else {
+                let Variant { bytes, format, placeholder, .. } = outputs.remove(0);
+                let mut response = (
+                    StatusCode::OK,
+                    [(header::CONTENT_TYPE, format.content_type())],
+                    bytes,
+                ).into_response();
+                if let Some(placeholder) = placeholder && let Ok(value) = header::HeaderValue::from_str(&placeholder) {
+                    response.headers_mut().insert(&X_PLACEHOLDER, value);
+                }
+                response
+            }
+        };
+
+        if !vary.is_empty() && let Ok(value) = header::HeaderValue::from_str(&vary.join(", ")) {
+            response.headers_mut().insert(header::VARY, value);
+        }
+        if let Some(etag) = etag && let Ok(value) = header::HeaderValue::from_str(&etag) {
+            response.headers_mut().insert(header::ETAG, value);
+            if let Some(cache_control) = config::get().cache_control.as_deref()
+                && let Ok(value) = header::HeaderValue::from_str(cache_control)
+            {
+                response.headers_mut().insert(header::CACHE_CONTROL, value);
+            }
         }
 
-        (self.status_code, self.message).into_response()
+        Ok(response)
+    }
+
+    /// Processes the image without building a response.
+    async fn render(self, image_bytes: Bytes, headers: &HeaderMap) -> Result<Vec<Variant>, AppError> {
+        self.prepare(image_bytes, headers).await?.process().await
+    }
+
+    /// Resolves defaults and Client Hints, and identifies the outputs.
+    async fn prepare(self, image_bytes: Bytes, headers: &HeaderMap) -> Result<Prepared, AppError> {
+        // Client Hints stand in for fields the request leaves out, so responses
+        // must vary on whichever hints were consulted.
+        let hints = ClientHints {
+            accept: headers.get(header::ACCEPT).and_then(|value| value.to_str().ok()),
+            dpr: client_hint(headers, "dpr"),
+            width: client_hint(headers, "width"),
+        };
+        let (options, vary) = self.fields.resolve(&hints)?;
+
+        // Large inputs take a while to hash, so it is done off the runtime.
+        let (options, image_bytes, key) = tokio::task::spawn_blocking(move || {
+            let key = cache_key(&options, &image_bytes);
+            (options, image_bytes, key)
+        })
+        .await?;
+        Ok(Prepared { options, image_bytes, key, vary })
     }
 }
 
-impl<E> From<E> for AppError
-where
-    E: std::error::Error,
-{
-    fn from(err: E) -> Self {
-        Self {
-            status_code: StatusCode::INTERNAL_SERVER_ERROR,
-            message: err.to_string(),
+/// The field that cannot be set along with `name`.
+fn excluded_by(name: &str) -> Option<&'static str> {
+    match name {
+        "size" => Some("variants"),
+        "variants" => Some("size"),
+        _ => None,
+    }
+}
+
+/// A /transform request with every setting resolved, ready to be processed.
+struct Prepared {
+    options: ProcessOptions,
+    image_bytes: Bytes,
+    /// Identifies the outputs, for the result cache and the `ETag`.
+    key: cache::Key,
+    /// Request headers the outputs depend on.
+    vary: Vec<&'static str>,
+}
+
+impl Prepared {
+    /// A strong validator for the response: the outputs' key, plus how
+    /// variants are bundled.
+    fn etag(&self, bundle: Option<VariantBundle>) -> String {
+        let suffix = match bundle {
+            None => "",
+            Some(VariantBundle::Zip) => "-zip",
+            Some(VariantBundle::Json) => "-json",
+        };
+        format!("\"{}{}\"", self.key.hex(), suffix)
+    }
+
+    /// Encodes one output per requested variant, or a single one without
+    /// variants, reusing cached results where possible.
+    async fn process(self) -> Result<Vec<Variant>, AppError> {
+        let Prepared { options, image_bytes, key, .. } = self;
+        if cache::enabled() && let Some(outputs) = cache::get(&key).await {
+            return Ok(outputs);
         }
+        // Concurrent identical requests, e.g. for a newly published image,
+        // share one computation.
+        cache::coalesce(key, async move {
+            let outputs = pool::run(move || {
+                process_image(image_bytes, options)
+            })
+            .await??;
+            if cache::enabled() {
+                cache::put(key, &outputs).await;
+            }
+            Ok(outputs)
+        })
+        .await
     }
 }
+
+/// Whether an `If-None-Match` header lists `etag`, using the weak comparison
+/// RFC 9110 prescribes for it.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Identifies the outputs for the result cache. Every setting is hashed in
+/// its parsed form, so requests that spell the same options differently
+/// share an entry.
+fn cache_key(options: &ProcessOptions, image_bytes: &[u8]) -> cache::Key {
+    let ProcessOptions { transform, variants, watermark, page, format, keep_profile, auto_orient, metadata, encode, placeholder } = options;
+    let settings = format!(
+        "{:?}|{:?}|{}|{:?}|{}|{}|{:?}|{:?}|{:?}",
+        transform, variants, page, format, keep_profile, auto_orient, metadata, encode, placeholder
+    );
+    cache::Key::new(&[image_bytes, watermark.as_deref().unwrap_or_default(), settings.as_bytes()])
+}
+
+/// Reads a numeric Client Hints header such as `DPR` or `Width`.
+fn client_hint(headers: &HeaderMap, name: &str) -> Option<f32> {
+    headers.get(name)?.to_str().ok()?.trim().parse::<f32>().ok().filter(|value| value.is_finite())
+}
@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::config;
+use crate::error::AppError;
+use crate::quota::Meter;
+
+/// Limits for image processing, which runs on Tokio's blocking threads.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Images processed at the same time; the number of cores by default.
+    pub workers: usize,
+    /// Requests that may wait for a worker before new ones are refused.
+    pub queue_size: usize,
+    /// How long a request waits for a worker before giving up.
+    pub queue_timeout: Duration,
+}
+
+/// Suggested wait before retrying a refused request.
+const RETRY_AFTER_SECS: u64 = 5;
+
+struct Pool {
+    permits: Semaphore,
+    waiting: AtomicUsize,
+}
+
+static POOL: LazyLock<Pool> = LazyLock::new(|| Pool { permits: Semaphore::new(config::get().pool.workers), waiting: AtomicUsize::new(0) });
+
+/// Requests waiting for a worker.
+pub fn waiting() -> usize {
+    POOL.waiting.load(Ordering::Acquire)
+}
+
+/// Workers processing an image.
+pub fn busy() -> usize {
+    config::get().pool.workers.saturating_sub(POOL.permits.available_permits())
+}
+
+/// Counts a request as waiting until it is dropped, even if the request is
+/// cancelled meanwhile.
+struct Waiting;
+
+impl Waiting {
+    fn enter() -> Option<Self> {
+        let queue_size = config::get().pool.queue_size;
+        POOL.waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| (waiting < queue_size).then_some(waiting + 1))
+            .ok()
+            .map(|_| Waiting)
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        POOL.waiting.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Runs CPU-heavy work on a blocking thread once a worker is free. Answers
+/// `503 Service Unavailable` with `Retry-After` when `TRANSFORM_QUEUE_SIZE`
+/// requests are already waiting, or no worker frees up within
+/// `TRANSFORM_QUEUE_TIMEOUT_SECS`.
+pub async fn run<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Result<T, AppError> {
+    let busy = |message: &str| AppError::unavailable(message).with_code("busy").with_retry_after(RETRY_AFTER_SECS);
+    let permit = match POOL.permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            let _waiting = Waiting::enter().ok_or_else(|| busy("The server is busy, try again later"))?;
+            tokio::time::timeout(config::get().pool.queue_timeout, POOL.permits.acquire())
+                .await
+                .map_err(|_| busy("Timed out waiting for a free worker, try again later"))??
+        }
+    };
+    // The worker stays taken until the work is done, even if the request is
+    // cancelled first. Its spans nest under the request's, and its inputs
+    // count towards the request's quota.
+    let (span, meter) = (tracing::Span::current(), Meter::current());
+    Ok(tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let _span = span.entered();
+        Meter::enter(meter, work)
+    })
+    .await?)
+}
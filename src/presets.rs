@@ -0,0 +1,355 @@
+use axum::{
+    body::Bytes,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use image_transformer_core::Fields;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
+
+use crate::config;
+use crate::error::AppError;
+use crate::storage::UtcTime;
+
+/// A preset created through `/admin/presets`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManagedPreset {
+    /// As returned by `key`.
+    pub name: String,
+    /// The /transform fields as a query string.
+    pub fields: String,
+    /// Unix time of its last change.
+    pub updated: u64,
+}
+
+/// The /transform fields of a preset, in order.
+type Preset = Vec<(String, String)>;
+
+/// The presets of `PRESET_STORE_PATH` by name, parsed, as last read.
+static PRESETS: LazyLock<RwLock<HashMap<String, Preset>>> = LazyLock::new(Default::default);
+
+/// How often presets are read again from `PRESET_STORE_PATH`, so that
+/// changes made through other instances sharing it take effect.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The name presets are kept by, so that `og-image` and `PRESET_OG_IMAGE`
+/// name the same one.
+pub fn key(name: &str) -> String {
+    name.trim().to_ascii_lowercase().replace('-', "_")
+}
+
+/// Parses and checks the fields of a preset, given as a query string such
+/// as `size=400x400&fit=cover&format=webp`.
+pub fn parse(query: &str) -> Result<Preset, String> {
+    let fields = config::parse_fields(query)?;
+    let mut options = Fields::default();
+    for (name, value) in &fields {
+        match options.set(name, value) {
+            Ok(true) => {}
+            Ok(false) => return Err(format!("'{}' cannot be set by a preset", name)),
+            Err(e) => return Err(e.message().to_string()),
+        }
+    }
+    Ok(fields)
+}
+
+/// The fields of a preset, from the configuration or else the store.
+pub fn find(name: &str) -> Option<Preset> {
+    let name = key(name);
+    config::get().presets.get(&name).cloned().or_else(|| PRESETS.read().unwrap().get(&name).cloned())
+}
+
+/// Reads the presets of `PRESET_STORE_PATH`, and reads them again every 30
+/// seconds.
+pub fn install() {
+    let Some(path) = config::get().preset_store_path.clone() else {
+        return;
+    };
+    if let Err(e) = reload(&path) {
+        tracing::error!("Failed to read presets from PRESET_STORE_PATH {}: {}", path.display(), e);
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let path = path.clone();
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || reload(&path)).await {
+                tracing::warn!("Failed to read presets again, keeping the ones read before: {}", e);
+            }
+        }
+    });
+}
+
+fn reload(path: &std::path::Path) -> Result<(), String> {
+    let mut presets = HashMap::new();
+    for preset in store::load(path)? {
+        // Checked when stored, so only a change of the fields themselves
+        // can make one invalid.
+        match parse(&preset.fields) {
+            Ok(fields) => {
+                presets.insert(preset.name, fields);
+            }
+            Err(e) => tracing::error!("Ignoring invalid preset '{}' in PRESET_STORE_PATH: {}", preset.name, e),
+        }
+    }
+    *PRESETS.write().unwrap() = presets;
+    Ok(())
+}
+
+fn store_path() -> Result<PathBuf, AppError> {
+    config::get().preset_store_path.clone()
+        .ok_or_else(|| AppError::forbidden("Managing presets is not configured on this server").with_code("preset_store_not_configured"))
+}
+
+/// Refuses to change presets of the configuration, which would take
+/// precedence over the stored ones anyway.
+fn check_managed(name: &str) -> Result<(), AppError> {
+    if config::get().presets.contains_key(name) {
+        return Err(AppError::conflict(format!("The preset '{}' is set in the configuration, and can only be changed there", name)).with_code("preset_not_managed"));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetBody {
+    fields: String,
+}
+
+/// Handler for `PUT /admin/presets/{name}`, which creates or replaces a
+/// preset.
+pub async fn put_handler(Path(name): Path<String>, body: Bytes) -> Result<Response, AppError> {
+    let path = store_path()?;
+    let body: PresetBody = serde_json::from_slice(&body)
+        .map_err(|e| AppError::malformed(format!("Invalid JSON request: {}", e)).with_code("invalid_json"))?;
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) {
+        return Err(AppError::invalid("Preset names must be 1 to 64 letters, digits, '-' or '_'").with_code("invalid_preset_name"));
+    }
+    let name = key(&name);
+    check_managed(&name)?;
+    parse(&body.fields).map_err(|e| AppError::invalid(e).with_code("invalid_preset"))?;
+    let preset = ManagedPreset { name, fields: body.fields.trim().to_string(), updated: UtcTime::now().unix };
+
+    let stored = preset.clone();
+    let created = tokio::task::spawn_blocking(move || store::put(&path, &stored).and_then(|created| reload(&path).map(|_| created)))
+        .await
+        .map_err(|e| AppError::internal(e.to_string()))?
+        .map_err(|e| AppError::internal(format!("Failed to store the preset: {}", e)))?;
+    tracing::info!("{} preset '{}'", if created { "Created" } else { "Updated" }, preset.name);
+
+    let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+    Ok((status, Json(to_json(&preset.name, &preset.fields, Some(preset.updated)))).into_response())
+}
+
+/// Handler for `GET /admin/presets`, which lists the presets of the
+/// configuration and the store.
+pub async fn list_handler() -> Result<Response, AppError> {
+    let mut presets: Vec<_> = config::get().presets.iter()
+        .map(|(name, fields)| (name.clone(), to_query(fields), None))
+        .collect();
+    if let Some(path) = config::get().preset_store_path.clone() {
+        let stored = tokio::task::spawn_blocking(move || store::load(&path))
+            .await
+            .map_err(|e| AppError::internal(e.to_string()))?
+            .map_err(|e| AppError::internal(format!("Failed to read the presets: {}", e)))?;
+        presets.extend(stored.into_iter()
+            .filter(|preset| !config::get().presets.contains_key(&preset.name))
+            .map(|preset| (preset.name, preset.fields, Some(preset.updated))));
+    }
+    presets.sort_by(|a, b| a.0.cmp(&b.0));
+    let presets: Vec<_> = presets.iter().map(|(name, fields, updated)| to_json(name, fields, *updated)).collect();
+    Ok(Json(json!({ "presets": presets })).into_response())
+}
+
+/// Handler for `DELETE /admin/presets/{name}`, which removes a preset.
+pub async fn delete_handler(Path(name): Path<String>) -> Result<Response, AppError> {
+    let path = store_path()?;
+    let name = key(&name);
+    check_managed(&name)?;
+    let removed = {
+        let name = name.clone();
+        tokio::task::spawn_blocking(move || store::remove(&path, &name).and_then(|removed| reload(&path).map(|_| removed)))
+            .await
+            .map_err(|e| AppError::internal(e.to_string()))?
+            .map_err(|e| AppError::internal(format!("Failed to remove the preset: {}", e)))?
+    };
+    if !removed {
+        return Err(AppError::not_found(format!("No preset named '{}'", name)).with_code("preset_not_found"));
+    }
+    tracing::info!("Removed preset '{}'", name);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Presets of the configuration have no `updated`, and cannot be changed.
+fn to_json(name: &str, fields: &str, updated: Option<u64>) -> serde_json::Value {
+    json!({
+        "name": name,
+        "fields": fields,
+        "source": if updated.is_some() { "store" } else { "config" },
+        "updated": updated.map(|updated| UtcTime::from_unix(updated).iso8601()),
+    })
+}
+
+fn to_query(fields: &[(String, String)]) -> String {
+    fields.iter()
+        .map(|(name, value)| format!("{}={}", name, crate::storage::uri_encode(value, true)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Keeps presets in a SQLite database when `PRESET_STORE_PATH` ends in
+/// `.db`, `.sqlite` or `.sqlite3`, and in a JSON file otherwise.
+mod store {
+    use std::path::Path;
+
+    use super::ManagedPreset;
+
+    fn is_sqlite(path: &Path) -> bool {
+        matches!(path.extension().and_then(|extension| extension.to_str()), Some("db" | "sqlite" | "sqlite3"))
+    }
+
+    pub fn load(path: &Path) -> Result<Vec<ManagedPreset>, String> {
+        if is_sqlite(path) { sqlite::load(path) } else { file::load(path) }
+    }
+
+    /// Adds a preset or replaces the one of its name. Returns whether it
+    /// was added.
+    pub fn put(path: &Path, preset: &ManagedPreset) -> Result<bool, String> {
+        if is_sqlite(path) { sqlite::put(path, preset) } else { file::put(path, preset) }
+    }
+
+    /// Removes the preset of this name, if there is one.
+    pub fn remove(path: &Path, name: &str) -> Result<bool, String> {
+        if is_sqlite(path) { sqlite::remove(path, name) } else { file::remove(path, name) }
+    }
+
+    mod file {
+        use std::path::{Path, PathBuf};
+        use std::sync::Mutex;
+
+        use super::ManagedPreset;
+
+        /// Keeps this instance's changes from overwriting each other.
+        static WRITE: Mutex<()> = Mutex::new(());
+
+        pub fn load(path: &Path) -> Result<Vec<ManagedPreset>, String> {
+            match std::fs::read(path) {
+                Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| e.to_string()),
+                // Created with the first preset.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+
+        fn save(path: &Path, presets: &[ManagedPreset]) -> Result<(), String> {
+            let json = serde_json::to_vec_pretty(presets).map_err(|e| e.to_string())?;
+            // Written under a temporary name first, so a crash never leaves
+            // a partial file.
+            let partial = PathBuf::from(format!("{}.partial", path.display()));
+            std::fs::write(&partial, json).map_err(|e| e.to_string())?;
+            std::fs::rename(&partial, path).map_err(|e| e.to_string())
+        }
+
+        pub fn put(path: &Path, preset: &ManagedPreset) -> Result<bool, String> {
+            let _write = WRITE.lock().unwrap();
+            let mut presets = load(path)?;
+            let count = presets.len();
+            presets.retain(|existing| existing.name != preset.name);
+            let created = presets.len() == count;
+            presets.push(preset.clone());
+            save(path, &presets).map(|_| created)
+        }
+
+        pub fn remove(path: &Path, name: &str) -> Result<bool, String> {
+            let _write = WRITE.lock().unwrap();
+            let mut presets = load(path)?;
+            let count = presets.len();
+            presets.retain(|preset| preset.name != name);
+            if presets.len() == count {
+                return Ok(false);
+            }
+            save(path, &presets).map(|_| true)
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod sqlite {
+        use rusqlite::{params, Connection};
+        use std::path::Path;
+
+        use super::ManagedPreset;
+
+        fn open(path: &Path) -> rusqlite::Result<Connection> {
+            let connection = Connection::open(path)?;
+            connection.busy_timeout(std::time::Duration::from_secs(5))?;
+            connection.execute_batch(
+                "CREATE TABLE IF NOT EXISTS presets (
+                    name TEXT PRIMARY KEY,
+                    fields TEXT NOT NULL,
+                    updated INTEGER NOT NULL
+                )",
+            )?;
+            Ok(connection)
+        }
+
+        pub fn load(path: &Path) -> Result<Vec<ManagedPreset>, String> {
+            let connection = open(path).map_err(|e| e.to_string())?;
+            let mut statement = connection
+                .prepare("SELECT name, fields, updated FROM presets")
+                .map_err(|e| e.to_string())?;
+            let rows = statement.query_map([], |row| {
+                Ok(ManagedPreset { name: row.get(0)?, fields: row.get(1)?, updated: row.get::<_, i64>(2)? as u64 })
+            }).map_err(|e| e.to_string())?;
+            rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+        }
+
+        pub fn put(path: &Path, preset: &ManagedPreset) -> Result<bool, String> {
+            let mut connection = open(path).map_err(|e| e.to_string())?;
+            let transaction = connection.transaction().map_err(|e| e.to_string())?;
+            let exists = transaction
+                .query_row("SELECT EXISTS (SELECT 1 FROM presets WHERE name = ?1)", [&preset.name], |row| row.get::<_, bool>(0))
+                .map_err(|e| e.to_string())?;
+            transaction.execute(
+                "INSERT INTO presets (name, fields, updated) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE SET fields = excluded.fields, updated = excluded.updated",
+                params![preset.name, preset.fields, preset.updated as i64],
+            ).map_err(|e| e.to_string())?;
+            transaction.commit().map_err(|e| e.to_string())?;
+            Ok(!exists)
+        }
+
+        pub fn remove(path: &Path, name: &str) -> Result<bool, String> {
+            let connection = open(path).map_err(|e| e.to_string())?;
+            let removed = connection.execute("DELETE FROM presets WHERE name = ?1", [name]).map_err(|e| e.to_string())?;
+            Ok(removed > 0)
+        }
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    mod sqlite {
+        use std::path::Path;
+
+        use super::ManagedPreset;
+
+        const UNSUPPORTED: &str = "a SQLite PRESET_STORE_PATH needs the `sqlite` feature";
+
+        pub fn load(_path: &Path) -> Result<Vec<ManagedPreset>, String> {
+            Err(UNSUPPORTED.to_string())
+        }
+
+        pub fn put(_path: &Path, _preset: &ManagedPreset) -> Result<bool, String> {
+            Err(UNSUPPORTED.to_string())
+        }
+
+        pub fn remove(_path: &Path, _name: &str) -> Result<bool, String> {
+            Err(UNSUPPORTED.to_string())
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use qrcode::{Color, EcLevel, QrCode};
+
+use crate::config;
+use crate::encode::{self, EncodeOptions, OutputFormat};
+use crate::error::AppError;
+use crate::{pool, transform};
+
+/// Side of the code in pixels without a `size`.
+const DEFAULT_SIZE: u32 = 256;
+
+/// Largest accepted side, in pixels.
+const MAX_SIZE: u32 = 4096;
+
+/// Modules of blank margin around the code without a `margin`, the quiet
+/// zone that the specification asks for.
+const DEFAULT_MARGIN: u32 = 4;
+
+/// Largest accepted margin, in modules.
+const MAX_MARGIN: u32 = 16;
+
+/// The fields of a QR code, with their defaults resolved.
+struct QrRequest {
+    data: String,
+    size: u32,
+    margin: u32,
+    ec_level: EcLevel,
+    color: Rgba<u8>,
+    background: Rgba<u8>,
+    format: OutputFormat,
+    encode_options: EncodeOptions,
+}
+
+/// Handler for `GET /generate/qr`, which draws a QR code.
+/// Takes the following query parameters:
+/// - "data": The text or URL to encode.
+/// - "size": An optional side in pixels, up to 4096 (default 256).
+/// - "margin": An optional quiet zone around the code in modules, up to 16 (default 4).
+/// - "ec": An optional error correction level: "l", "m" (default), "q" or "h".
+/// - "color", "background": Optional hex colors of the modules and the background (default black on white).
+/// - "format": An optional output format ("png" by default, "webp", "jpeg", "avif" or "jxl").
+/// - "quality": An optional float for lossy compression quality (0.0 to 100.0).
+pub async fn qr_handler(Query(fields): Query<Vec<(String, String)>>) -> Result<Response, AppError> {
+    let mut data = None;
+    let mut request = QrRequest {
+        data: String::new(),
+        size: DEFAULT_SIZE,
+        margin: DEFAULT_MARGIN,
+        ec_level: EcLevel::M,
+        color: Rgba([0, 0, 0, 255]),
+        background: Rgba([255, 255, 255, 255]),
+        format: OutputFormat::Png,
+        encode_options: EncodeOptions::default(),
+    };
+    for (name, value) in fields {
+        match name.as_str() {
+            "data" => data = Some(value),
+            "size" => {
+                request.size = value.trim().parse().ok()
+                    .filter(|size| (1..=MAX_SIZE).contains(size))
+                    .ok_or_else(|| AppError::invalid(format!("Size must be between 1 and {} pixels", MAX_SIZE)).with_code("invalid_size"))?;
+            }
+            "margin" => {
+                request.margin = value.trim().parse().ok()
+                    .filter(|margin| *margin <= MAX_MARGIN)
+                    .ok_or_else(|| AppError::invalid(format!("Margin must be between 0 and {} modules", MAX_MARGIN)).with_code("invalid_margin"))?;
+            }
+            "ec" => {
+                request.ec_level = match value.trim().to_ascii_lowercase().as_str() {
+                    "l" => EcLevel::L,
+                    "m" => EcLevel::M,
+                    "q" => EcLevel::Q,
+                    "h" => EcLevel::H,
+                    _ => return Err(AppError::invalid("Invalid error correction level. Use 'l', 'm', 'q' or 'h'").with_code("invalid_ec")),
+                };
+            }
+            "color" => request.color = transform::parse_color(&value)?,
+            "background" => request.background = transform::parse_color(&value)?,
+            "format" => {
+                request.format = value.parse::<OutputFormat>()?;
+                if request.format == OutputFormat::Ico {
+                    return Err(AppError::invalid("Use the /favicon endpoint for ICO output"));
+                }
+            }
+            "quality" => {
+                request.encode_options.quality = value.trim().parse::<f32>()
+                    .ok()
+                    .filter(|q| (0.0..=100.0).contains(q))
+                    .ok_or_else(|| AppError::invalid("Quality must be between 0.0 and 100.0").with_code("invalid_quality"))?;
+            }
+            _ => { /* Ignore other fields */ }
+        }
+    }
+    request.data = data.filter(|data| !data.is_empty())
+        .ok_or_else(|| AppError::invalid("Data not provided in 'data' parameter").with_code("missing_data"))?;
+
+    let format = request.format;
+    let output_bytes = pool::run(move || -> Result<Vec<u8>, AppError> {
+        let img = render(&request)?;
+        Ok(encode::encode_image(img, request.format, &request.encode_options)?)
+    })
+    .await??;
+
+    let mut response = (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, format.content_type())],
+        output_bytes,
+    ).into_response();
+    if let Some(cache_control) = config::get().cache_control.as_deref()
+        && let Ok(value) = header::HeaderValue::from_str(cache_control)
+    {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    Ok(response)
+}
+
+/// Draws the code with whole pixels per module, so that its edges stay
+/// sharp, and centers it on a canvas of the requested size.
+fn render(request: &QrRequest) -> Result<DynamicImage, AppError> {
+    let code = QrCode::with_error_correction_level(request.data.as_bytes(), request.ec_level)
+        .map_err(|e| AppError::invalid(format!("Cannot encode the data as a QR code: {}", e)).with_code("data_too_long"))?;
+    let modules = code.width() as u32;
+    let side = modules + 2 * request.margin;
+    if request.size < side {
+        return Err(AppError::invalid(format!(
+            "Size must be at least {} pixels to fit the {} modules of this code and its margin", side, modules,
+        )).with_code("invalid_size"));
+    }
+
+    let scale = request.size / side;
+    let colors = code.to_colors();
+    let code_image = RgbaImage::from_fn(modules * scale, modules * scale, |x, y| {
+        match colors[(y / scale * modules + x / scale) as usize] {
+            Color::Dark => request.color,
+            Color::Light => request.background,
+        }
+    });
+    let mut canvas = RgbaImage::from_pixel(request.size, request.size, request.background);
+    let offset = i64::from((request.size - modules * scale) / 2);
+    imageops::replace(&mut canvas, &code_image, offset, offset);
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+/// Settings for consuming transform jobs from a NATS JetStream stream, from
+/// the `NATS_*` variables.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "nats"), allow(dead_code))]
+pub struct QueueConfig {
+    /// Server URL, such as `nats://localhost:4222`, or several separated by
+    /// commas.
+    pub url: String,
+    /// Stream holding the jobs, created when missing.
+    pub stream: String,
+    /// Subject that jobs are published to.
+    pub subject: String,
+    /// Durable consumer shared by every replica, so that each job is
+    /// processed once.
+    pub consumer: String,
+    /// Subject that each job's outcome is published to.
+    pub result_subject: Option<String>,
+    /// Deliveries of a job before it is given up on.
+    pub max_deliver: i64,
+    /// How long a job may take before it is delivered again.
+    pub ack_wait: Duration,
+}
+
+/// Consumes jobs until shutdown is requested, each a JSON /transform request
+/// whose results are written to its `output.target`.
+#[cfg(feature = "nats")]
+pub async fn consume(config: &'static QueueConfig) {
+    consumer::consume(config).await;
+}
+
+/// `NATS_URL` is ignored without the `nats` feature, so there is never a
+/// queue to consume.
+#[cfg(not(feature = "nats"))]
+pub async fn consume(_config: &'static QueueConfig) {}
+
+/// Jobs taken from the queue that are not finished yet.
+pub fn in_flight() -> usize {
+    #[cfg(feature = "nats")]
+    return consumer::in_flight();
+    #[cfg(not(feature = "nats"))]
+    0
+}
+
+/// Whether the connection to NATS is up, for `/readyz`.
+pub fn check() -> Result<(), String> {
+    #[cfg(feature = "nats")]
+    return consumer::check();
+    #[cfg(not(feature = "nats"))]
+    Ok(())
+}
+
+#[cfg(feature = "nats")]
+mod consumer {
+    use async_nats::connection::State;
+    use async_nats::jetstream::{self, consumer::pull, message::AckKind, Message};
+    use axum::{body, http::HeaderMap};
+    use futures_util::StreamExt;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, OnceLock};
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+    use tracing::Instrument;
+
+    use super::QueueConfig;
+    use crate::config;
+    use crate::error::AppError;
+    use crate::shutdown;
+    use crate::telemetry;
+
+    static CLIENT: OnceLock<async_nats::Client> = OnceLock::new();
+
+    static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+    /// How long to wait before setting up the consumer again, and before a
+    /// job that failed on the server's side is delivered again.
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    pub fn in_flight() -> usize {
+        IN_FLIGHT.load(Ordering::Relaxed)
+    }
+
+    pub fn check() -> Result<(), String> {
+        match CLIENT.get().map(async_nats::Client::connection_state) {
+            Some(State::Connected) => Ok(()),
+            Some(_) => Err("disconnected from NATS".to_string()),
+            None => Err("not connected to NATS yet".to_string()),
+        }
+    }
+
+    pub async fn consume(config: &'static QueueConfig) {
+        tokio::select! {
+            _ = run(config) => {}
+            _ = shutdown::requested() => {}
+        }
+    }
+
+    /// Pulls jobs as long as workers are free, setting the consumer up again
+    /// whenever the stream of messages breaks off.
+    async fn run(config: &'static QueueConfig) {
+        let client = match async_nats::ConnectOptions::new()
+            .name("image_transformer_api")
+            .retry_on_initial_connect()
+            .connect(config.url.as_str())
+            .await
+        {
+            Ok(client) => CLIENT.get_or_init(|| client).clone(),
+            Err(e) => {
+                tracing::error!("Failed to connect to NATS at {}: {}", config.url, e);
+                return;
+            }
+        };
+        let workers = Arc::new(Semaphore::new(config::get().jobs.workers));
+        loop {
+            let mut messages = match subscribe(&client, config).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    tracing::error!("Failed to consume {} from NATS: {}", config.subject, e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            tracing::info!("consuming jobs from {} on NATS", config.subject);
+            while let Some(message) = messages.next().await {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        tracing::warn!("Failed to receive a job from NATS: {}", e);
+                        continue;
+                    }
+                };
+                let permit = workers.clone().acquire_owned().await.expect("the semaphore is never closed");
+                IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+                let client = client.clone();
+                tokio::spawn(async move {
+                    process(&client, config, message).await;
+                    IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+                    drop(permit);
+                });
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    async fn subscribe(client: &async_nats::Client, config: &QueueConfig) -> Result<pull::Stream, String> {
+        let context = jetstream::new(client.clone());
+        let stream = context
+            .get_or_create_stream(jetstream::stream::Config {
+                name: config.stream.clone(),
+                subjects: vec![config.subject.clone()],
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        let consumer = stream
+            .get_or_create_consumer(&config.consumer, pull::Config {
+                durable_name: Some(config.consumer.clone()),
+                filter_subject: config.subject.clone(),
+                max_deliver: config.max_deliver,
+                ack_wait: config.ack_wait,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        // Only as many jobs as there are workers are held at a time, so
+        // that the others go to replicas with free workers.
+        consumer.stream()
+            .max_messages_per_batch(config::get().jobs.workers)
+            .messages()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Runs a job and acknowledges it, or has it delivered again when it
+    /// failed on the server's side and has attempts left.
+    async fn process(client: &async_nats::Client, config: &QueueConfig, message: Message) {
+        let (id, delivered) = match message.info() {
+            Ok(info) => (format!("{}-{}", info.stream, info.stream_sequence), info.delivered),
+            Err(_) => (String::new(), 1),
+        };
+        // A `Nats-Msg-Id` set by the publisher identifies the job to it.
+        let id = message.headers.as_ref()
+            .and_then(|headers| headers.get("Nats-Msg-Id"))
+            .map_or(id, |value| value.as_str().to_string());
+        let span = tracing::info_span!("job", job = %id, delivered);
+        async {
+            let result = transform(&message.payload).await;
+            let (outcome, ack) = match &result {
+                Ok(_) => ("succeeded", AckKind::Ack),
+                Err(error) if error.status_code().is_server_error() && delivered < config.max_deliver => {
+                    tracing::warn!(code = %error.code(), error = %error.message(), "job failed, retrying");
+                    ("retried", AckKind::Nak(Some(RETRY_DELAY)))
+                }
+                Err(error) => {
+                    error.log();
+                    ("failed", AckKind::Term)
+                }
+            };
+            telemetry::record_queue_message(outcome);
+            if let Some(subject) = &config.result_subject && outcome != "retried" {
+                let payload = match result {
+                    Ok(result) => json!({ "id": id, "status": "succeeded", "result": result }),
+                    Err(error) => json!({
+                        "id": id,
+                        "status": "failed",
+                        "error": { "status": error.status_code().as_u16(), "code": error.code(), "message": error.message() },
+                    }),
+                };
+                if let Err(e) = client.publish(subject.clone(), payload.to_string().into()).await {
+                    tracing::warn!("Failed to publish the outcome of the job to {}: {}", subject, e);
+                }
+            }
+            if let Err(e) = message.ack_with(ack).await {
+                tracing::warn!("Failed to acknowledge the job: {}", e);
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+
+    /// Processes a job like a JSON /transform request, which must store its
+    /// results since there is no one to answer them to.
+    async fn transform(payload: &[u8]) -> Result<Value, AppError> {
+        let request: Value = serde_json::from_slice(payload)
+            .map_err(|e| AppError::malformed(format!("Invalid JSON request: {}", e)).with_code("invalid_json"))?;
+        if !request["output"]["target"].is_string() {
+            return Err(AppError::invalid("Queued jobs must name an 'output.target' to store their results in").with_code("missing_output"));
+        }
+        let response = crate::transform_json(&HeaderMap::new(), payload).await?;
+        let body = body::to_bytes(response.into_body(), usize::MAX).await
+            .map_err(|e| AppError::internal(format!("Failed to read the result of the job: {}", e)))?;
+        Ok(serde_json::from_slice(&body).unwrap_or_default())
+    }
+}
@@ -0,0 +1,274 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, Request},
+    http::{HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+
+use crate::auth::Client;
+use crate::config;
+use crate::error::AppError;
+use crate::storage::UtcTime;
+use crate::usage::{self, Counters};
+
+/// Limits shared by each API key or token subject, or by each client IP for
+/// requests without one. Keys created through `/admin/keys` may have their
+/// own.
+#[derive(Debug, Clone, Default)]
+pub struct QuotaConfig {
+    /// Requests per minute; unlimited when unset.
+    pub requests_per_minute: Option<u64>,
+    /// Input megapixels decoded per UTC day; unlimited when unset.
+    pub megapixels_per_day: Option<u64>,
+}
+
+impl QuotaConfig {
+    fn enabled(&self) -> bool {
+        self.requests_per_minute.is_some() || self.megapixels_per_day.is_some()
+    }
+}
+
+/// Counters of one client in the current fixed windows.
+#[derive(Default)]
+struct Windows {
+    minute: u64,
+    requests: u64,
+    day: u64,
+    pixels: u64,
+}
+
+impl Windows {
+    /// Starts new windows once the minute or day is over.
+    fn roll(&mut self, now: u64) {
+        if self.minute != now / 60 {
+            self.minute = now / 60;
+            self.requests = 0;
+        }
+        if self.day != now / 86400 {
+            self.day = now / 86400;
+            self.pixels = 0;
+        }
+    }
+}
+
+/// The client whose request is being processed, which decoding charges its
+/// pixels to.
+#[derive(Clone)]
+pub struct Meter {
+    /// Who usage is recorded for: the API key name or token subject, or
+    /// `anonymous`.
+    client: Arc<str>,
+    /// The windows of the client's limits, when there are any.
+    windows: Option<Arc<Mutex<Windows>>>,
+}
+
+tokio::task_local! {
+    static METER: Meter;
+}
+
+/// Limit windows by API key name, token subject or client IP.
+static CLIENTS: LazyLock<Mutex<HashMap<String, Arc<Mutex<Windows>>>>> = LazyLock::new(Default::default);
+
+/// Clients without requests in the current minute or pixels today are
+/// forgotten once there are this many, so that passing IPs do not pile up.
+const MAX_CLIENTS: usize = 10_000;
+
+static RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+static RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+static RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+static QUOTA_LIMIT: HeaderName = HeaderName::from_static("x-quota-megapixels-limit");
+static QUOTA_REMAINING: HeaderName = HeaderName::from_static("x-quota-megapixels-remaining");
+static QUOTA_RESET: HeaderName = HeaderName::from_static("x-quota-reset");
+
+/// Middleware metering requests for the usage of their client, and enforcing
+/// `RATE_LIMIT_PER_MINUTE` and `QUOTA_MEGAPIXELS_PER_DAY`. Answers `429 Too
+/// Many Requests` with `Retry-After` once either is used up, and reports what
+/// is left of both in the headers of every response.
+pub async fn meter(request: Request, next: Next) -> Result<Response, AppError> {
+    let quota = &request.extensions().get::<QuotaConfig>().cloned().unwrap_or_else(|| config::get().quota.clone());
+    let client = request.extensions().get::<Client>().map(|Client(name)| name.clone());
+    let address = || request.extensions().get::<ConnectInfo<SocketAddr>>().map_or("unknown".to_string(), |info| info.0.ip().to_string());
+    // Bodies are counted as they are read and sent, since streamed ones do
+    // not know their size up front.
+    let meter = admit(client.as_deref(), address, quota, processes_images(&request), 0)?;
+    let request = request.map(|body| Counted::body(body, &meter, |usage, bytes| usage.input_bytes += bytes));
+
+    let response = METER.scope(meter.clone(), next.run(request)).await;
+
+    let mut response = response.map(|body| Counted::body(body, &meter, |usage, bytes| usage.output_bytes += bytes));
+    let Some(windows) = &meter.windows else {
+        return Ok(response);
+    };
+    let now = UtcTime::now().unix;
+    let (minute_reset, day_reset) = (60 - now % 60, 86400 - now % 86400);
+    let windows = windows.lock().unwrap();
+    let headers = response.headers_mut();
+    if let Some(limit) = quota.requests_per_minute {
+        headers.insert(RATELIMIT_LIMIT.clone(), HeaderValue::from(limit));
+        headers.insert(RATELIMIT_REMAINING.clone(), HeaderValue::from(limit.saturating_sub(windows.requests)));
+        headers.insert(RATELIMIT_RESET.clone(), HeaderValue::from(minute_reset));
+    }
+    if let Some(limit) = quota.megapixels_per_day {
+        headers.insert(QUOTA_LIMIT.clone(), HeaderValue::from(limit));
+        // In tenths of a megapixel, rounded down.
+        let remaining = (limit * 1_000_000).saturating_sub(windows.pixels) / 100_000;
+        headers.insert(QUOTA_REMAINING.clone(), HeaderValue::from_str(&format!("{}.{}", remaining / 10, remaining % 10)).expect("a number"));
+        headers.insert(QUOTA_RESET.clone(), HeaderValue::from(day_reset));
+    }
+    Ok(response)
+}
+
+/// Counts a request of `received` bytes against the limits of its client,
+/// or of `address` for requests without credentials, and records it for
+/// `/usage`. Requests that do not process images are let through once the
+/// daily quota is used up.
+pub fn admit(client: Option<&str>, address: impl FnOnce() -> String, quota: &QuotaConfig, processes_images: bool, received: u64) -> Result<Meter, AppError> {
+    let windows = quota.enabled().then(|| windows(client.map_or_else(address, str::to_string)));
+    let meter = Meter { client: client.unwrap_or("anonymous").into(), windows };
+
+    let now = UtcTime::now().unix;
+    let (minute_reset, day_reset) = (60 - now % 60, 86400 - now % 86400);
+    if let Some(windows) = &meter.windows {
+        let mut windows = windows.lock().unwrap();
+        windows.roll(now);
+        if let Some(limit) = quota.requests_per_minute && windows.requests >= limit {
+            return Err(AppError::too_many_requests(format!("Rate limit of {} requests per minute exceeded", limit))
+                .with_code("rate_limited")
+                .with_retry_after(minute_reset));
+        }
+        if let Some(limit) = quota.megapixels_per_day && windows.pixels >= limit * 1_000_000 && processes_images {
+            return Err(AppError::too_many_requests(format!("Daily quota of {} megapixels exceeded", limit))
+                .with_code("quota_exceeded")
+                .with_retry_after(day_reset));
+        }
+        windows.requests += 1;
+    }
+    usage::record(&meter.client, |usage| {
+        usage.requests += 1;
+        usage.input_bytes += received;
+    });
+    Ok(meter)
+}
+
+/// A body that records the bytes that passed through it for `/usage` once
+/// it is dropped, whether read to the end or not.
+struct Counted {
+    inner: Body,
+    client: Arc<str>,
+    bytes: u64,
+    record: fn(&mut Counters, u64),
+}
+
+impl Counted {
+    fn body(inner: Body, meter: &Meter, record: fn(&mut Counters, u64)) -> Body {
+        Body::new(Counted { inner, client: meter.client.clone(), bytes: 0, record })
+    }
+}
+
+impl HttpBody for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.bytes += data.len() as u64;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            usage::record(&self.client, |usage| (self.record)(usage, self.bytes));
+        }
+    }
+}
+
+/// Results of jobs and file output stay available once the quota is used
+/// up, since their pixels were charged already.
+fn processes_images(request: &Request) -> bool {
+    let path = request.uri().path();
+    request.method() != Method::GET || !(path.starts_with("/jobs/") || path.starts_with("/files/"))
+}
+
+fn windows(client: String) -> Arc<Mutex<Windows>> {
+    let mut clients = CLIENTS.lock().unwrap();
+    if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+        let now = UtcTime::now().unix;
+        clients.retain(|_, windows| {
+            let windows = windows.lock().unwrap();
+            windows.minute == now / 60 || (windows.day == now / 86400 && windows.pixels > 0)
+        });
+    }
+    clients.entry(client).or_default().clone()
+}
+
+impl Meter {
+    /// Who usage is recorded for.
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    pub fn client(&self) -> &str {
+        &self.client
+    }
+
+    /// Runs the work of a request under this meter.
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    pub async fn scope<T>(self, work: impl Future<Output = T>) -> T {
+        METER.scope(self, work).await
+    }
+
+    /// The meter of the request being processed, if it is metered.
+    pub fn current() -> Option<Self> {
+        METER.try_with(Meter::clone).ok()
+    }
+
+    /// Runs blocking work, such as that of `pool::run`, under this meter.
+    pub fn enter<T>(meter: Option<Self>, work: impl FnOnce() -> T) -> T {
+        match meter {
+            Some(meter) => METER.sync_scope(meter, work),
+            None => work(),
+        }
+    }
+
+    /// Keeps charging the request's client for work that outlives the
+    /// request, such as a job.
+    pub fn bind<T: 'static>(work: impl Future<Output = T> + Send + 'static) -> Pin<Box<dyn Future<Output = T> + Send>> {
+        match Self::current() {
+            Some(meter) => Box::pin(METER.scope(meter, work)),
+            None => Box::pin(work),
+        }
+    }
+}
+
+/// Charges a decoded input to the client of the current request.
+pub fn charge_pixels((width, height): (u32, u32)) {
+    let Some(meter) = Meter::current() else {
+        return;
+    };
+    let pixels = u64::from(width) * u64::from(height);
+    usage::record(&meter.client, |usage| usage.pixels += pixels);
+    if let Some(windows) = &meter.windows {
+        let mut windows = windows.lock().unwrap();
+        windows.roll(UtcTime::now().unix);
+        windows.pixels += pixels;
+    }
+}
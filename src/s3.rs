@@ -0,0 +1,183 @@
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::{Client, Url};
+use sha2::{Digest, Sha256};
+use std::{sync::LazyLock, time::Duration};
+
+use crate::config;
+use crate::error::AppError;
+use crate::signing;
+use crate::storage::{self, uri_encode, Location, StorageBackend, UtcTime};
+
+/// Settings for S3-compatible object storage.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Base URL of the service; AWS's regional endpoint when unset.
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// For temporary credentials.
+    pub session_token: Option<String>,
+    /// Buckets that may be written to; the first is the default. Empty
+    /// disables S3 output.
+    pub buckets: Vec<String>,
+    /// Address buckets as `endpoint/bucket` instead of `bucket.endpoint`,
+    /// as MinIO and most other S3-compatible services expect.
+    pub path_style: bool,
+    /// Lifetime of presigned download URLs.
+    pub url_expiry: Duration,
+}
+
+/// Signs requests with AWS Signature Version 4.
+pub struct S3;
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()
+        .expect("failed to build the HTTP client")
+});
+
+impl S3 {
+    /// Resolves the bucket of an `output` target, defaulting to the first
+    /// configured one.
+    pub fn bucket(bucket: Option<&str>) -> Result<String, AppError> {
+        let s3 = &config::get().s3;
+        if s3.buckets.is_empty() || s3.access_key_id.is_none() || s3.secret_access_key.is_none() {
+            return Err(AppError::forbidden("S3 output is not configured on this server").with_code("storage_not_configured"));
+        }
+        match bucket {
+            None => Ok(s3.buckets[0].clone()),
+            Some(bucket) if s3.buckets.iter().any(|allowed| allowed == bucket) => Ok(bucket.to_string()),
+            Some(bucket) => Err(AppError::forbidden(format!("Bucket '{}' is not allowed", bucket)).with_code("bucket_not_allowed")),
+        }
+    }
+}
+
+impl StorageBackend for S3 {
+    async fn put(&self, bucket: &str, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<Location, AppError> {
+        let s3 = &config::get().s3;
+        let object = Object::new(s3, bucket, key)?;
+        let (access_key_id, secret_access_key) = s3.access_key_id.as_deref().zip(s3.secret_access_key.as_deref())
+            .ok_or_else(|| AppError::forbidden("S3 output is not configured on this server").with_code("storage_not_configured"))?;
+        let now = UtcTime::now();
+        let (date, timestamp) = (now.basic_date(), now.basic());
+        let scope = format!("{}/{}/s3/aws4_request", date, s3.region);
+        let key = signing_key(secret_access_key, &date, &s3.region);
+
+        let payload_hash = signing::hex(&Sha256::digest(&bytes));
+        let mut headers = vec![
+            ("content-type", content_type.to_string()),
+            ("host", object.host.clone()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &s3.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let canonical_request = format!("PUT\n{}\n\n{}\n{}\n{}", object.path, canonical_headers, signed_headers, payload_hash);
+        let signature = sign(&key, &timestamp, &scope, &canonical_request);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            access_key_id, scope, signed_headers, signature
+        );
+
+        let mut request = CLIENT.put(object.url(""));
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(*name, value);
+        }
+        let response = request
+            .header("authorization", authorization)
+            .body(bytes)
+            .send()
+            .await
+            .map_err(|e| AppError::upstream(format!("Failed to upload to S3: {}", e)).with_code("storage_error"))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(AppError::upstream(format!("S3 returned {}: {}", status, body.trim())).with_code("storage_error"));
+        }
+
+        // Presigned GET, valid for `url_expiry` (SigV4 allows up to 7 days).
+        let expires = s3.url_expiry.as_secs().clamp(1, 7 * 24 * 3600);
+        let mut query = vec![
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+            ("X-Amz-Credential", uri_encode(&format!("{}/{}", access_key_id, scope), true)),
+            ("X-Amz-Date", timestamp.clone()),
+            ("X-Amz-Expires", expires.to_string()),
+            ("X-Amz-SignedHeaders", "host".to_string()),
+        ];
+        if let Some(token) = &s3.session_token {
+            query.push(("X-Amz-Security-Token", uri_encode(token, true)));
+        }
+        query.sort();
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+        let canonical_request = format!("GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", object.path, query, object.host);
+        let signature = sign(&key, &timestamp, &scope, &canonical_request);
+
+        Ok(Location { url: object.url(&format!("?{}&X-Amz-Signature={}", query, signature)), expires_in: Some(expires) })
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        storage::reachable(&endpoint(&config::get().s3)).await
+    }
+}
+
+/// The address of an object.
+struct Object {
+    scheme: String,
+    /// Host and port, as sent in the `Host` header.
+    host: String,
+    /// The URI-encoded path.
+    path: String,
+}
+
+impl Object {
+    fn new(s3: &S3Config, bucket: &str, key: &str) -> Result<Self, AppError> {
+        let endpoint = Url::parse(&endpoint(s3))
+            .map_err(|e| AppError::internal(format!("Invalid S3_ENDPOINT: {}", e)))?;
+        let mut host = endpoint.host_str().unwrap_or_default().to_string();
+        if let Some(port) = endpoint.port() {
+            host = format!("{}:{}", host, port);
+        }
+        let key = uri_encode(key, false);
+        let (host, path) = if s3.path_style {
+            (host, format!("/{}/{}", uri_encode(bucket, true), key))
+        } else {
+            (format!("{}.{}", bucket, host), format!("/{}", key))
+        };
+        Ok(Object { scheme: endpoint.scheme().to_string(), host, path })
+    }
+
+    fn url(&self, query: &str) -> String {
+        format!("{}://{}{}{}", self.scheme, self.host, self.path, query)
+    }
+}
+
+/// `S3_ENDPOINT`, or AWS's endpoint for the region.
+fn endpoint(s3: &S3Config) -> String {
+    s3.endpoint.clone().unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", s3.region))
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_access_key).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, "s3");
+    hmac(&key, "aws4_request")
+}
+
+fn sign(key: &[u8], timestamp: &str, scope: &str, canonical_request: &str) -> String {
+    let hash = signing::hex(&Sha256::digest(canonical_request.as_bytes()));
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, hash);
+    signing::hex(&hmac(key, &string_to_sign))
+}
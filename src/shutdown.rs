@@ -0,0 +1,85 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::watch::Sender;
+use tokio::time::Instant;
+
+use crate::config;
+use crate::jobs;
+use crate::pool;
+use crate::queue;
+use crate::watch;
+
+/// When draining has to end, once a shutdown signal was received.
+static DEADLINE: LazyLock<Sender<Option<Instant>>> = LazyLock::new(|| Sender::new(None));
+
+/// Whether the server is shutting down, and only finishing what it started.
+pub fn is_draining() -> bool {
+    DEADLINE.borrow().is_some()
+}
+
+/// Waits for SIGTERM or SIGINT, and starts `SHUTDOWN_TIMEOUT_SECS` of
+/// draining.
+pub async fn signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    let name = tokio::select! {
+        _ = interrupt => "SIGINT",
+        _ = terminate => "SIGTERM",
+    };
+    let timeout = config::get().shutdown_timeout;
+    tracing::info!("Received {}, finishing requests and jobs for up to {}s", name, timeout.as_secs());
+    DEADLINE.send_replace(Some(Instant::now() + timeout));
+}
+
+/// Completes once a shutdown signal was received. Passed to the listeners,
+/// which stop accepting connections then.
+pub async fn requested() {
+    let _ = DEADLINE.subscribe().wait_for(Option::is_some).await;
+}
+
+/// Completes once draining has taken `SHUTDOWN_TIMEOUT_SECS`, so that
+/// connections still open then can be dropped.
+pub async fn deadline() {
+    let deadline = *DEADLINE.subscribe().wait_for(Option::is_some).await.expect("the sender is never dropped");
+    tokio::time::sleep_until(deadline.expect("a deadline")).await;
+}
+
+/// Waits for queued and running jobs, including those taken from NATS and
+/// files being transformed from `WATCH_DIR`, and
+/// for image processing of requests that were cancelled, until the
+/// deadline. Returns whether all of it finished.
+pub async fn drain() -> bool {
+    let Some(deadline) = *DEADLINE.borrow() else {
+        return true;
+    };
+    loop {
+        let (jobs, busy) = (jobs::unfinished() + queue::in_flight() + watch::in_flight(), pool::busy());
+        if jobs == 0 && busy == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            tracing::warn!("Shutting down with unfinished work: {} jobs, {} images being processed", jobs, busy);
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
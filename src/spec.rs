@@ -0,0 +1,189 @@
+use axum::body::Bytes;
+use base64::Engine;
+use image::Rgba;
+use serde::Deserialize;
+
+use crate::error::AppError;
+use crate::fetch;
+use crate::filter::{self, Adjustments, Filter};
+use crate::ops::{self, Operation};
+use crate::transform::{Frame, Radius, Region, TransformOptions};
+
+/// A `/transform` request sent as JSON instead of a multipart form, e.g.
+/// `{"source": {"base64": "..."}, "operations": [{"op": "resize", "width": 400}], "output": {"format": "avif"}}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformSpec {
+    pub source: Source,
+    /// Steps run in order, like the `ops` form field.
+    #[serde(default)]
+    pub operations: Vec<OperationSpec>,
+    #[serde(default)]
+    pub output: OutputSpec,
+}
+
+/// Where the source image comes from.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Source {
+    /// Standard base64, with or without padding.
+    Base64(String),
+    /// An http(s) URL, fetched within the server's `FETCH_*` limits.
+    Url(String),
+}
+
+/// One pipeline step, tagged by `op`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum OperationSpec {
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    Resize { width: Option<u32>, height: Option<u32>, fit: Option<String>, gravity: Option<String> },
+    Rotate { degrees: f64 },
+    Flip { direction: String },
+    Trim { tolerance: Option<u8> },
+    Blur { sigma: f32 },
+    Sharpen { amount: f32 },
+    Brightness { factor: f32 },
+    Contrast { factor: f32 },
+    Saturation { factor: f32 },
+    Gamma { factor: f32 },
+    Grayscale,
+    Sepia,
+    Invert,
+    Pad { width: u32, color: Option<String> },
+    Border { width: u32, color: Option<String> },
+    Radius { radius: RadiusSpec },
+}
+
+/// A corner radius in pixels, or `"max"`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RadiusSpec {
+    Pixels(u32),
+    Named(String),
+}
+
+/// Output and decoding options, named and validated like the form fields of
+/// the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSpec {
+    /// A preset of the server, which the other options override.
+    pub preset: Option<String>,
+    pub format: Option<String>,
+    pub quality: Option<f32>,
+    pub max_bytes: Option<usize>,
+    pub lossless: Option<bool>,
+    pub speed: Option<u8>,
+    pub effort: Option<u8>,
+    pub progressive: Option<bool>,
+    pub subsampling: Option<String>,
+    pub colors: Option<u16>,
+    pub optimize: Option<bool>,
+    pub method: Option<u8>,
+    pub alpha_quality: Option<u8>,
+    pub near_lossless: Option<u8>,
+    pub metadata: Option<String>,
+    pub keep_profile: Option<bool>,
+    pub auto_orient: Option<bool>,
+    pub page: Option<u32>,
+    pub no_enlarge: Option<bool>,
+    pub resize_filter: Option<String>,
+    pub background: Option<String>,
+    pub placeholder: Option<String>,
+    pub variants: Option<String>,
+    pub bundle: Option<String>,
+    /// The `output` form field, e.g. `s3://bucket/key`.
+    pub target: Option<String>,
+}
+
+/// A validated JSON request.
+pub struct Parts {
+    pub image: Bytes,
+    pub operations: Vec<Operation>,
+    /// Output options as form fields, for the caller to apply.
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl TransformSpec {
+    /// Validates the operations, then decodes or downloads the source.
+    pub async fn into_parts(self) -> Result<Parts, AppError> {
+        ops::check_count(self.operations.len())?;
+        let operations = self.operations.into_iter().map(OperationSpec::into_operation).collect::<Result<_, _>>()?;
+        let image = match self.source {
+            Source::Base64(data) => base64::engine::general_purpose::STANDARD_NO_PAD
+                .decode(data.trim().trim_end_matches('='))
+                .map(Bytes::from)
+                .map_err(|e| AppError::invalid(format!("Invalid base64 source: {}", e)).with_code("invalid_source"))?,
+            Source::Url(url) => fetch::fetch(&url).await?,
+        };
+        Ok(Parts { image, operations, fields: self.output.into_fields() })
+    }
+}
+
+impl OperationSpec {
+    fn into_operation(self) -> Result<Operation, AppError> {
+        let adjust = |adjustments: Adjustments| Ok(Operation::Adjust(adjustments));
+        match self {
+            OperationSpec::Crop { x, y, width, height } => Ok(Operation::Crop(Region { x, y, width, height })),
+            OperationSpec::Resize { width: None, height: None, .. } => {
+                Err(AppError::invalid("A resize operation needs a width, a height or both").with_code("invalid_ops"))
+            }
+            OperationSpec::Resize { width, height, fit, gravity } => Ok(Operation::Resize {
+                width,
+                height,
+                fit: fit.map(|fit| fit.parse()).transpose()?,
+                gravity: gravity.map(|gravity| gravity.parse()).transpose()?,
+            }),
+            OperationSpec::Rotate { degrees } => Ok(Operation::Rotate(degrees.rem_euclid(360.0))),
+            OperationSpec::Flip { direction } => Ok(Operation::Flip(direction.parse()?)),
+            OperationSpec::Trim { tolerance } => Ok(Operation::Trim(tolerance.unwrap_or(TransformOptions::default().trim_tolerance))),
+            OperationSpec::Blur { sigma } => Ok(Operation::Blur(filter::check_blur(sigma)?)),
+            OperationSpec::Sharpen { amount } => Ok(Operation::Sharpen(filter::check_sharpen(amount)?)),
+            OperationSpec::Brightness { factor } => adjust(Adjustments { brightness: filter::check_factor(factor, "brightness", 0.0)?, ..Default::default() }),
+            OperationSpec::Contrast { factor } => adjust(Adjustments { contrast: filter::check_factor(factor, "contrast", 0.0)?, ..Default::default() }),
+            OperationSpec::Saturation { factor } => adjust(Adjustments { saturation: filter::check_factor(factor, "saturation", 0.0)?, ..Default::default() }),
+            OperationSpec::Gamma { factor } => adjust(Adjustments { gamma: filter::check_factor(factor, "gamma", 0.1)?, ..Default::default() }),
+            OperationSpec::Grayscale => Ok(Operation::Filter(Filter::Grayscale)),
+            OperationSpec::Sepia => Ok(Operation::Filter(Filter::Sepia)),
+            OperationSpec::Invert => Ok(Operation::Filter(Filter::Invert)),
+            OperationSpec::Pad { width, color } => Ok(Operation::Pad(Frame::new(width, color.as_deref(), "pad", Rgba([0, 0, 0, 0]))?)),
+            OperationSpec::Border { width, color } => Ok(Operation::Border(Frame::new(width, color.as_deref(), "border", Rgba([0, 0, 0, 255]))?)),
+            OperationSpec::Radius { radius: RadiusSpec::Pixels(pixels) } => Ok(Operation::Radius(Radius::Pixels(pixels))),
+            OperationSpec::Radius { radius: RadiusSpec::Named(name) } => Ok(Operation::Radius(name.parse()?)),
+        }
+    }
+}
+
+impl OutputSpec {
+    fn into_fields(self) -> Vec<(&'static str, String)> {
+        let fields = [
+            ("preset", self.preset),
+            ("format", self.format),
+            ("quality", self.quality.map(|v| v.to_string())),
+            ("max_bytes", self.max_bytes.map(|v| v.to_string())),
+            ("lossless", self.lossless.map(|v| v.to_string())),
+            ("speed", self.speed.map(|v| v.to_string())),
+            ("effort", self.effort.map(|v| v.to_string())),
+            ("progressive", self.progressive.map(|v| v.to_string())),
+            ("subsampling", self.subsampling),
+            ("colors", self.colors.map(|v| v.to_string())),
+            ("optimize", self.optimize.map(|v| v.to_string())),
+            ("method", self.method.map(|v| v.to_string())),
+            ("alpha_quality", self.alpha_quality.map(|v| v.to_string())),
+            ("near_lossless", self.near_lossless.map(|v| v.to_string())),
+            ("metadata", self.metadata),
+            ("keep_profile", self.keep_profile.map(|v| v.to_string())),
+            ("auto_orient", self.auto_orient.map(|v| v.to_string())),
+            ("page", self.page.map(|v| v.to_string())),
+            ("no_enlarge", self.no_enlarge.map(|v| v.to_string())),
+            ("resize_filter", self.resize_filter),
+            ("background", self.background),
+            ("placeholder", self.placeholder),
+            ("variants", self.variants),
+            ("bundle", self.bundle),
+            ("output", self.target),
+        ];
+        fields.into_iter().filter_map(|(name, value)| Some((name, value?))).collect()
+    }
+}
@@ -0,0 +1,301 @@
+use axum::{
+    body::Bytes,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::azure::Azure;
+use crate::error::AppError;
+use crate::files::FileSystem;
+use crate::gcs::Gcs;
+use crate::s3::S3;
+use crate::variants::Variant;
+
+/// A storage service that results can be written to instead of being
+/// returned in the response, and that may serve source images.
+pub trait StorageBackend {
+    /// Writes an object and returns where it can be downloaded from.
+    fn put(&self, bucket: &str, key: &str, bytes: Vec<u8>, content_type: &str) -> impl Future<Output = Result<Location, AppError>> + Send;
+
+    /// Reads a source image, up to `FETCH_MAX_BYTES`.
+    fn get(&self, _bucket: &str, _key: &str) -> impl Future<Output = Result<Bytes, AppError>> + Send {
+        async { Err(AppError::invalid("Only gs:// storage URLs can be used as sources").with_code("invalid_source")) }
+    }
+
+    /// Checks that the service can be reached, for `/readyz`.
+    fn check(&self) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+static PROBE_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(2))
+        .build()
+        .expect("failed to build the HTTP client")
+});
+
+/// Checks that a service answers at `url`. Any status will do, since probes
+/// are not authorized.
+pub async fn reachable(url: &str) -> Result<(), String> {
+    PROBE_CLIENT.head(url).send().await.map(|_| ()).map_err(|e| match e {
+        e if e.is_timeout() => "timed out".to_string(),
+        e if e.is_connect() => "failed to connect".to_string(),
+        e => e.without_url().to_string(),
+    })
+}
+
+/// Checks that a directory such as `FILE_STORAGE_DIR` exists.
+pub async fn check_dir(path: &std::path::Path) -> Result<(), String> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(format!("{} is not a directory", path.display())),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// A download link for a stored object.
+pub struct Location {
+    pub url: String,
+    /// Seconds until a presigned `url` stops working.
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    S3,
+    Azure,
+    Gcs,
+    /// Local files below `FILE_STORAGE_DIR`, which have no buckets.
+    File,
+}
+
+/// Where the `output` field sends a result: `s3` for the default bucket,
+/// `s3://bucket` or `s3://bucket/prefix/` for a generated name, or
+/// `s3://bucket/path/name.webp` for an exact key. `azure://container/...`
+/// and `gs://bucket/...` work the same way for Azure Blob Storage and
+/// Google Cloud Storage, and `file` or `file://path/...` for local files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub backend: Backend,
+    /// The bucket, or container on Azure.
+    pub bucket: String,
+    /// An exact key, or a prefix ending in `/` (possibly empty) that a
+    /// generated name is appended to.
+    pub key: String,
+}
+
+impl FromStr for Target {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (scheme, rest) = s.split_once("://").unwrap_or((s, ""));
+        let backend = match scheme.to_ascii_lowercase().as_str() {
+            "s3" => Backend::S3,
+            "azure" => Backend::Azure,
+            "gs" => Backend::Gcs,
+            "file" => Backend::File,
+            _ => return Err(AppError::invalid("Invalid output. Use 's3://bucket/key', 'azure://container/key', 'gs://bucket/key' or 'file://key'",
+            ).with_code("invalid_output")),
+        };
+        let (bucket, key) = match backend {
+            Backend::File => ("", rest),
+            _ => rest.split_once('/').unwrap_or((rest, "")),
+        };
+        let bucket = match backend {
+            Backend::S3 => S3::bucket((!bucket.is_empty()).then_some(bucket))?,
+            Backend::Azure => Azure::container((!bucket.is_empty()).then_some(bucket))?,
+            Backend::Gcs => Gcs::bucket((!bucket.is_empty()).then_some(bucket))?,
+            Backend::File => {
+                FileSystem::check()?;
+                String::new()
+            }
+        };
+        if !is_valid_key(key) {
+            return Err(AppError::invalid(format!("Invalid output key '{}'", key)).with_code("invalid_output"));
+        }
+        Ok(Target { backend, bucket, key: key.to_string() })
+    }
+}
+
+impl Target {
+    /// The key for one output. Generated names share `id`, and variants are
+    /// told apart by their size, e.g. `photos/hero-800x600.webp`.
+    fn key_for(&self, output: &Variant, variants: bool, id: Uuid) -> String {
+        let size = if variants { format!("-{}x{}", output.width, output.height) } else { String::new() };
+        let extension = output.format.extension();
+        if self.key.is_empty() || self.key.ends_with('/') {
+            return format!("{}{}{}.{}", self.key, id, size, extension);
+        }
+        if !variants {
+            return self.key.clone();
+        }
+        match self.key.rsplit_once('.').filter(|(_, ext)| !ext.contains('/')) {
+            Some((stem, ext)) => format!("{}{}.{}", stem, size, ext),
+            None => format!("{}{}.{}", self.key, size, extension),
+        }
+    }
+}
+
+/// Uploads the outputs and answers with JSON describing each object, or a
+/// list of them under `objects` for variants.
+pub async fn store(target: &Target, outputs: Vec<Variant>, variants: bool) -> Result<Response, AppError> {
+    let id = Uuid::new_v4();
+    let mut objects = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let key = target.key_for(&output, variants, id);
+        let (content_type, bytes, width, height) = (output.format.content_type(), output.bytes.len(), output.width, output.height);
+        let placeholder = output.placeholder;
+        let location = match target.backend {
+            Backend::S3 => S3.put(&target.bucket, &key, output.bytes, content_type).await?,
+            Backend::Azure => Azure.put(&target.bucket, &key, output.bytes, content_type).await?,
+            Backend::Gcs => Gcs.put(&target.bucket, &key, output.bytes, content_type).await?,
+            Backend::File => FileSystem.put(&target.bucket, &key, output.bytes, content_type).await?,
+        };
+        let mut object = json!({
+            "bucket": (!target.bucket.is_empty()).then_some(&target.bucket),
+            "key": key,
+            "url": location.url,
+            "expires_in": location.expires_in,
+            "content_type": content_type,
+            "bytes": bytes,
+            "width": width,
+            "height": height,
+        });
+        if let Some(placeholder) = placeholder {
+            object["placeholder"] = json!(placeholder);
+        }
+        objects.push(object);
+    }
+    let body = if variants { json!({ "objects": objects }) } else { objects.remove(0) };
+    Ok(Json(body).into_response())
+}
+
+/// Whether a key or prefix is safe to use as a relative path: no empty,
+/// `.` or `..` segments and no backslashes.
+pub fn is_valid_key(key: &str) -> bool {
+    let invalid_segment = |segment: &str| segment.is_empty() || segment == "." || segment == ".." || segment.contains('\\');
+    key.is_empty() || !key.trim_end_matches('/').split('/').any(invalid_segment)
+}
+
+/// Reads a source image from a storage URL like `gs://bucket/key`.
+pub async fn load(url: &str) -> Result<Bytes, AppError> {
+    let source: Target = url.parse()?;
+    if source.key.is_empty() || source.key.ends_with('/') {
+        return Err(AppError::invalid(format!("Source '{}' does not name an object", url.trim())).with_code("invalid_source"));
+    }
+    match source.backend {
+        Backend::S3 => S3.get(&source.bucket, &source.key).await,
+        Backend::Azure => Azure.get(&source.bucket, &source.key).await,
+        Backend::Gcs => Gcs.get(&source.bucket, &source.key).await,
+        Backend::File => FileSystem.get(&source.bucket, &source.key).await,
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` unless
+/// `encode_slash` is set.
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value.bytes().fold(String::with_capacity(value.len()), |mut encoded, byte| {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+        encoded
+    })
+}
+
+/// A UTC time, broken down for the timestamp formats storage APIs sign.
+#[derive(Debug, Clone, Copy)]
+pub struct UtcTime {
+    pub unix: u64,
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+}
+
+impl UtcTime {
+    pub fn now() -> Self {
+        Self::from_unix(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()))
+    }
+
+    pub fn from_unix(unix: u64) -> Self {
+        let (days, seconds) = ((unix / 86400) as i64, unix % 86400);
+        // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        Self { unix, year, month, day, hour: seconds / 3600, minute: seconds % 3600 / 60, second: seconds % 60 }
+    }
+
+    /// Midnight of a `YYYY-MM-DD` date.
+    pub fn parse_date(date: &str) -> Option<Self> {
+        let mut parts = date.splitn(3, '-');
+        let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+        if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return None;
+        }
+        let (year, month, day) = (year.parse::<i64>().ok()?, month.parse::<i64>().ok()?, day.parse::<i64>().ok()?);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+            return None;
+        }
+        // Days since 1970-01-01 from a civil date, the inverse of `from_unix`.
+        let year = year - i64::from(month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let time = Self::from_unix((era * 146097 + doe - 719468) as u64 * 86400);
+        // Rejects days past the end of the month, such as 2026-02-30.
+        (time.day as i64 == day).then_some(time)
+    }
+
+    /// Adds seconds.
+    pub fn plus(self, seconds: u64) -> Self {
+        Self::from_unix(self.unix + seconds)
+    }
+
+    /// `YYYYMMDD`.
+    pub fn basic_date(&self) -> String {
+        format!("{:04}{:02}{:02}", self.year, self.month, self.day)
+    }
+
+    /// `YYYY-MM-DD`.
+    pub fn date(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    /// `YYYYMMDDTHHMMSSZ`.
+    pub fn basic(&self) -> String {
+        format!("{}T{:02}{:02}{:02}Z", self.basic_date(), self.hour, self.minute, self.second)
+    }
+
+    /// `YYYY-MM-DDTHH:MM:SSZ`.
+    pub fn iso8601(&self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+
+    /// `Wed, 14 Oct 2026 06:21:26 GMT`, as in HTTP `Date` headers.
+    pub fn rfc1123(&self) -> String {
+        const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+        const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        format!(
+            "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[(self.unix / 86400 % 7) as usize], self.day, MONTHS[self.month as usize - 1], self.year, self.hour, self.minute, self.second
+        )
+    }
+}
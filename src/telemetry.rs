@@ -0,0 +1,253 @@
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{field::Empty, Span};
+
+use crate::{jobs, pool};
+
+/// Upper bounds in seconds of the latency and encode time histograms.
+const DURATION_BUCKETS: [f64; 14] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Installs the Prometheus recorder that `/metrics` renders.
+pub fn install() {
+    let recorder = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_duration_seconds".to_string()), &DURATION_BUCKETS)
+        .and_then(PrometheusBuilder::install_recorder);
+    match recorder {
+        Ok(handle) => {
+            let _ = HANDLE.set(handle);
+        }
+        Err(e) => tracing::error!(error = %e, "failed to install the metrics recorder"),
+    }
+    describe_counter!("http_requests_total", "Requests answered, by route and status");
+    describe_histogram!("http_request_duration_seconds", Unit::Seconds, "Time to answer a request");
+    describe_counter!("http_request_bytes_total", Unit::Bytes, "Request bodies received");
+    describe_counter!("http_response_bytes_total", Unit::Bytes, "Response bodies sent, where their size is known up front");
+    describe_counter!("result_cache_lookups_total", "Result cache lookups, by outcome and the layer that hit");
+    describe_histogram!("image_encode_duration_seconds", Unit::Seconds, "Time to encode an output, by format");
+    describe_gauge!("transform_queue_depth", "Requests waiting for a free worker");
+    describe_gauge!("transform_workers_busy", "Workers processing an image");
+    describe_gauge!("jobs_queued", "Jobs waiting for a job worker");
+    describe_counter!("queue_messages_total", "Jobs taken from NATS, by outcome");
+    describe_counter!("watch_files_total", "Files transformed from WATCH_DIR, by outcome");
+}
+
+/// Handler for `GET /metrics`, in the Prometheus text format.
+pub async fn metrics_handler() -> Response {
+    let Some(handle) = HANDLE.get() else {
+        return axum::http::StatusCode::NOT_FOUND.into_response();
+    };
+    // Gauges are sampled when scraped.
+    gauge!("transform_queue_depth").set(pool::waiting() as f64);
+    gauge!("transform_workers_busy").set(pool::busy() as f64);
+    gauge!("jobs_queued").set(jobs::queued() as f64);
+    handle.run_upkeep();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], handle.render()).into_response()
+}
+
+/// Middleware counting requests and their latency and size, labelled with
+/// the route rather than the path so that label values stay bounded.
+pub async fn track(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let route = route(&request);
+    let received = request.body().size_hint().exact().unwrap_or(0);
+
+    let response = next.run(request).await;
+
+    let labels = [("method", method), ("route", route), ("status", response.status().as_u16().to_string())];
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!("http_request_duration_seconds", &labels).record(started.elapsed().as_secs_f64());
+    counter!("http_request_bytes_total", &labels).increment(received);
+    if let Some(sent) = response.body().size_hint().exact() {
+        counter!("http_response_bytes_total", &labels).increment(sent);
+    }
+    response
+}
+
+fn route(request: &Request) -> String {
+    request.extensions().get::<MatchedPath>().map_or("unmatched".to_string(), |path| path.as_str().to_string())
+}
+
+/// Middleware giving every request an ID, sent back in `x-request-id`. A
+/// client's own `x-request-id` is kept if it is at most 128 visible ASCII
+/// characters, and a UUID is generated otherwise.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request.headers().get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| (1..=128).contains(&id.len()) && id.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
+    let value = HeaderValue::from_str(&id).expect("request IDs are visible ASCII");
+    request.headers_mut().insert(X_REQUEST_ID.clone(), value.clone());
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+    response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    response
+}
+
+/// The ID of the request being answered, for error responses.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/// Makes the span of a request, which continues the caller's trace when
+/// it sent a W3C `traceparent` header.
+pub fn request_span(request: &Request) -> Span {
+    let request_id = request.headers().get(&X_REQUEST_ID).and_then(|value| value.to_str().ok());
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id,
+        client = Empty,
+        otel.name = Empty,
+        otel.kind = Empty,
+    );
+    #[cfg(feature = "otel")]
+    if otel::enabled() {
+        otel::continue_trace(&span, request, &format!("{} {}", request.method(), route(request)));
+    }
+    span
+}
+
+/// Logs the status and latency of an answered request.
+pub fn log_response<B>(response: &axum::http::Response<B>, latency: Duration, _span: &Span) {
+    tracing::info!(status = response.status().as_u16(), latency_ms = latency.as_secs_f64() * 1000.0, "finished processing request");
+}
+
+/// Counts a result cache lookup; `layer` is where it was found, `None` for
+/// a miss.
+pub fn record_cache_lookup(layer: Option<&'static str>) {
+    match layer {
+        Some(layer) => counter!("result_cache_lookups_total", "result" => "hit", "layer" => layer).increment(1),
+        None => counter!("result_cache_lookups_total", "result" => "miss").increment(1),
+    }
+}
+
+/// Counts a job taken from NATS as `succeeded`, `retried` or `failed`.
+#[cfg_attr(not(feature = "nats"), allow(dead_code))]
+pub fn record_queue_message(outcome: &'static str) {
+    counter!("queue_messages_total", "outcome" => outcome).increment(1);
+}
+
+/// Counts a file transformed from `WATCH_DIR` as `succeeded`, `retried` or
+/// `failed`.
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+pub fn record_watch_file(outcome: &'static str) {
+    counter!("watch_files_total", "outcome" => outcome).increment(1);
+}
+
+/// Returns the layer exporting spans over OTLP, when
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is
+/// set. The other standard `OTEL_*` variables are read by the SDK.
+#[cfg(feature = "otel")]
+pub fn otel_layer<S>() -> Option<impl tracing_subscriber::Layer<S>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    otel::layer()
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn otel_layer() -> Option<tracing_subscriber::layer::Identity> {
+    None
+}
+
+/// Flushes spans that have not been exported yet.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use axum::{extract::Request, http::HeaderMap};
+    use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _};
+    use opentelemetry_otlp::SpanExporter;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+    use std::sync::OnceLock;
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    pub fn layer<S>() -> Option<impl tracing_subscriber::Layer<S>>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+            .iter()
+            .any(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()));
+        if !configured {
+            return None;
+        }
+        let exporter = match SpanExporter::builder().with_http().build() {
+            Ok(exporter) => exporter,
+            Err(e) => {
+                // The subscriber is not installed yet.
+                eprintln!("failed to set up OTLP trace export: {}", e);
+                return None;
+            }
+        };
+        let mut resource = Resource::builder();
+        if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+            resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        global::set_tracer_provider(provider.clone());
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        let _ = PROVIDER.set(provider);
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
+    pub fn enabled() -> bool {
+        PROVIDER.get().is_some()
+    }
+
+    /// Parents a request's span on the trace context in its headers.
+    pub fn continue_trace(span: &Span, request: &Request, name: &str) {
+        span.record("otel.name", name);
+        span.record("otel.kind", "server");
+        let context = global::get_text_map_propagator(|propagator| propagator.extract(&Headers(request.headers())));
+        let _ = span.set_parent(context);
+    }
+
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get()
+            && let Err(e) = provider.shutdown()
+        {
+            tracing::warn!(error = %e, "failed to flush traces");
+        }
+    }
+
+    struct Headers<'a>(&'a HeaderMap);
+
+    impl Extractor for Headers<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|name| name.as_str()).collect()
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use base64::Engine;
+use hmac::{Hmac, KeyInit, Mac};
+use sha1::Sha1;
+
+use crate::config;
+use crate::error::AppError;
+
+/// A Thumbor-style request path, translated into `/transform` form fields.
+#[derive(Debug)]
+pub struct ThumborRequest {
+    pub source: String,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+/// Checks the signature of a path
+/// `/{signature}/[trim/][AxB:CxD/][fit-in/][-]WxH/[halign/][valign/][smart/][filters:...]/image`,
+/// then parses it. `unsafe` in place of the signature skips the check, unless
+/// unsafe URLs are disabled.
+///
+/// The signature is the URL-safe base64 HMAC-SHA1 of everything after it as
+/// sent, before percent-decoding, keyed with `URL_SIGNING_KEY` as in Thumbor.
+/// `path` is the decoded remainder that is parsed.
+pub fn parse(signature: &str, signed: &str, path: &str) -> Result<ThumborRequest, AppError> {
+    if !is_unsigned(signature) {
+        verify(signature, signed)?;
+    } else if !config::get().allow_unsafe_urls {
+        return Err(AppError::forbidden("Unsigned URLs are disabled on this server").with_code("unsigned_urls_disabled"));
+    }
+    parse_options(path)
+}
+
+/// Whether the percent-decoded signature segment asks to skip signing.
+pub fn is_unsigned(signature: &str) -> bool {
+    signature == "unsafe"
+}
+
+fn verify(signature: &str, signed: &str) -> Result<(), AppError> {
+    let key = config::get().signing_key.as_deref()
+        .ok_or_else(|| AppError::forbidden("Signed URLs are not enabled on this server").with_code("signed_urls_disabled"))?;
+    let signature = base64::engine::general_purpose::URL_SAFE
+        .decode(signature)
+        .map_err(|_| AppError::forbidden("Invalid URL signature").with_code("invalid_signature"))?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(signed.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| AppError::forbidden("Invalid URL signature").with_code("invalid_signature"))
+}
+
+fn parse_options(path: &str) -> Result<ThumborRequest, AppError> {
+    let invalid = |segment: &str, reason: &str| AppError::invalid(format!("Invalid path segment '{}': {}", segment, reason)).with_code("invalid_path");
+    let mut fields = Vec::new();
+    let mut segments = path.split('/').peekable();
+    let mut fit_in = false;
+    let mut size = None;
+    let (mut horizontal, mut vertical) = ("", "");
+
+    while let Some(&segment) = segments.peek() {
+        match segment {
+            "trim" => fields.push(("trim", "true".to_string())),
+            "fit-in" => fit_in = true,
+            "smart" => fields.push(("gravity", "smart".to_string())),
+            "left" | "center" | "right" => horizontal = segment,
+            "top" | "middle" | "bottom" => vertical = segment,
+            _ if segment.starts_with("filters:") => {
+                for filter in split_filters(&segment["filters:".len()..]) {
+                    apply_filter(filter, &mut fields).map_err(|reason| invalid(filter, &reason))?;
+                }
+            }
+            _ if size.is_none() && is_crop(segment) => {
+                let (a, b) = segment.split_once(':').unwrap_or_default();
+                let corner = |point: &str| -> Option<(u32, u32)> {
+                    let (x, y) = point.split_once('x')?;
+                    Some((x.parse().ok()?, y.parse().ok()?))
+                };
+                let ((left, top), (right, bottom)) = corner(a).zip(corner(b))
+                    .filter(|((left, top), (right, bottom))| right > left && bottom > top)
+                    .ok_or_else(|| invalid(segment, "expected 'LEFTxTOP:RIGHTxBOTTOM'"))?;
+                fields.push(("crop", format!("{},{},{},{}", left, top, right - left, bottom - top)));
+            }
+            _ if size.is_none() && is_size(segment) => size = Some(segment),
+            _ => break,
+        }
+        segments.next();
+    }
+
+    if let Some(segment) = size {
+        let (width, height) = segment.split_once('x').unwrap_or_default();
+        let (flip_h, width) = (width.starts_with('-'), width.trim_start_matches('-'));
+        let (flip_v, height) = (height.starts_with('-'), height.trim_start_matches('-'));
+        // 0 or an empty side follows the aspect ratio.
+        let side = |value: &str| value.trim_start_matches('0').to_string();
+        let (width, height) = (side(width), side(height));
+        if !width.is_empty() || !height.is_empty() {
+            fields.push(("size", format!("{}x{}", width, height)));
+        }
+        match (flip_h, flip_v) {
+            (true, true) => fields.push(("rotate", "180".to_string())),
+            (true, false) => fields.push(("flip", "h".to_string())),
+            (false, true) => fields.push(("flip", "v".to_string())),
+            (false, false) => {}
+        }
+    }
+    // `fill` pads fit-in results to the full size.
+    let fit = match (fit_in, fields.iter().any(|(name, _)| *name == "background")) {
+        (true, true) => "contain",
+        (true, false) => "inside",
+        (false, _) => "cover",
+    };
+    fields.push(("fit", fit.to_string()));
+    let gravity = match (vertical, horizontal) {
+        ("top", "left") => Some("northwest"),
+        ("top", "right") => Some("northeast"),
+        ("top", _) => Some("north"),
+        ("bottom", "left") => Some("southwest"),
+        ("bottom", "right") => Some("southeast"),
+        ("bottom", _) => Some("south"),
+        (_, "left") => Some("west"),
+        (_, "right") => Some("east"),
+        _ => None,
+    };
+    if let Some(gravity) = gravity && !fields.iter().any(|(name, _)| *name == "gravity") {
+        fields.push(("gravity", gravity.to_string()));
+    }
+
+    let source: Vec<&str> = segments.collect();
+    let source = source.join("/");
+    if source.is_empty() {
+        return Err(AppError::invalid("Path does not end with an image URL").with_code("invalid_path"));
+    }
+    let source = match source.split_once("://") {
+        Some(_) => source,
+        None => match source.split_once(":/") {
+            // Proxies and path normalization may collapse `//` after the scheme.
+            Some((scheme, rest)) if matches!(scheme, "http" | "https") => format!("{}://{}", scheme, rest),
+            _ => format!("https://{}", source),
+        },
+    };
+    Ok(ThumborRequest { source, fields })
+}
+
+fn is_crop(segment: &str) -> bool {
+    segment.contains(':') && segment.chars().all(|c| c.is_ascii_digit() || c == 'x' || c == ':')
+}
+
+fn is_size(segment: &str) -> bool {
+    segment.contains('x') && segment.chars().all(|c| c.is_ascii_digit() || c == 'x' || c == '-')
+}
+
+/// Splits `quality(80):blur(2,1)` on the colons between filters.
+fn split_filters(filters: &str) -> impl Iterator<Item = &str> {
+    let mut depth = 0;
+    filters
+        .split(move |c| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            c == ':' && depth == 0
+        })
+        .filter(|filter| !filter.is_empty())
+}
+
+fn apply_filter(filter: &str, fields: &mut Vec<(&'static str, String)>) -> Result<(), String> {
+    let (name, args) = filter
+        .strip_suffix(')')
+        .and_then(|filter| filter.split_once('('))
+        .ok_or_else(|| "expected 'name(arguments)'".to_string())?;
+    let args: Vec<&str> = args.split(',').map(str::trim).collect();
+    let number = |index: usize| -> Result<f32, String> {
+        args.get(index).and_then(|arg| arg.parse::<f32>().ok()).ok_or_else(|| format!("argument {} must be a number", index + 1))
+    };
+    // Thumbor adjusts by a percentage from -100 to 100.
+    let percentage = |index: usize| number(index).map(|value| (1.0 + value.clamp(-100.0, 100.0) / 100.0).to_string());
+
+    match name {
+        "quality" => fields.push(("quality", number(0)?.to_string())),
+        "format" => fields.push(("format", args[0].to_string())),
+        "blur" => fields.push(("blur", number(1).or_else(|_| number(0))?.to_string())),
+        "sharpen" => fields.push(("sharpen", number(0)?.to_string())),
+        "grayscale" => fields.push(("filter", "grayscale".to_string())),
+        "brightness" => fields.push(("brightness", percentage(0)?)),
+        "contrast" => fields.push(("contrast", percentage(0)?)),
+        "saturation" => fields.push(("saturation", number(0)?.to_string())),
+        // Thumbor rotates counterclockwise.
+        "rotate" => fields.push(("rotate", (-number(0)?).to_string())),
+        "round_corner" => fields.push(("radius", number(0)?.to_string())),
+        "no_upscale" => fields.push(("no_enlarge", "true".to_string())),
+        "fill" => {
+            let color = match args[0] {
+                "transparent" => "00000000".to_string(),
+                color => color.trim_start_matches('#').to_string(),
+            };
+            fields.push(("background", color));
+        }
+        "strip_exif" | "strip_icc" => {}
+        _ => return Err(format!("unknown filter '{}'", name)),
+    }
+    Ok(())
+}
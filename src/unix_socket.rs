@@ -0,0 +1,74 @@
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::UnixListener;
+
+use crate::shutdown;
+
+/// Settings for listening on a Unix domain socket, such as for a reverse
+/// proxy on the same host.
+#[derive(Debug, Clone)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file, from `UNIX_SOCKET_MODE`; the umask's
+    /// when unset.
+    pub mode: Option<u32>,
+}
+
+/// Listens on the socket, replacing one left behind by an earlier run.
+pub fn bind(config: &UnixSocketConfig) -> Result<UnixListener, String> {
+    let path = &config.path;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(|e| format!("Failed to remove the old socket {}: {}", path.display(), e))?;
+        }
+        Ok(_) => return Err(format!("Refusing to replace {}, which is not a socket", path.display())),
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path).map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+    if let Some(mode) = config.mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Failed to set the permissions of {}: {}", path.display(), e))?;
+    }
+    Ok(listener)
+}
+
+/// Serves the app on the socket until shutdown is requested, then waits for
+/// open connections to finish and removes the socket file.
+pub async fn serve(listener: UnixListener, app: Router, config: &UnixSocketConfig) {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Such as running out of file descriptors, which takes
+                    // a moment to pass.
+                    tracing::error!("Failed to accept a connection on {}: {}", config.path.display(), e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown::requested() => break,
+        };
+        let connection = builder.serve_connection(TokioIo::new(stream), TowerToHyperService::new(app.clone())).into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("Connection on the Unix socket failed: {}", e);
+            }
+        });
+    }
+    drop(listener);
+    if let Err(e) = std::fs::remove_file(&config.path) {
+        tracing::warn!("Failed to remove the socket {}: {}", config.path.display(), e);
+    }
+    graceful.shutdown().await;
+}
@@ -0,0 +1,78 @@
+use axum::extract::{multipart::Field, FromRequest, Multipart, Request};
+use axum::http::header;
+use bytes::{Bytes, BytesMut};
+use tokio::io::AsyncWriteExt;
+
+use crate::error::AppError;
+use crate::{config, fetch};
+
+/// Reads the image of a request that only takes an image: an `image/*` or
+/// `application/octet-stream` body, or multipart/form-data with an "image"
+/// file or a "url" to download it from. Other fields are ignored.
+pub async fn read_image(request: Request) -> Result<Bytes, AppError> {
+    let content_type = request.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    let image_bytes = if content_type.starts_with("image/") || content_type.starts_with("application/octet-stream") {
+        Bytes::from_request(request, &()).await
+            .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?
+    } else {
+        let mut multipart = Multipart::from_request(request, &()).await
+            .map_err(|rejection| AppError::body(rejection.status(), rejection.body_text()))?;
+        let (mut image_data, mut source_url) = (None, None);
+        while let Some(field) = multipart.next_field().await? {
+            match field.name().unwrap_or("") {
+                "image" => image_data = Some(read_file(field).await?),
+                "url" => source_url = Some(field.text().await?),
+                _ => { /* Ignore other fields */ }
+            }
+        }
+        match (image_data, source_url) {
+            (Some(image_bytes), _) => image_bytes,
+            (None, Some(url)) => fetch::fetch(&url).await?,
+            (None, None) => Bytes::new(),
+        }
+    };
+    if image_bytes.is_empty() {
+        return Err(AppError::invalid("Image data not provided in 'image' field or the request body").with_code("missing_image"));
+    }
+    Ok(image_bytes)
+}
+
+/// Reads an uploaded file. Files over `UPLOAD_MEMORY_BYTES` are written to a
+/// temporary file as they arrive and memory-mapped from there, so that large
+/// uploads are not held in memory next to the decoded image.
+pub async fn read_file(mut field: Field<'_>) -> Result<Bytes, AppError> {
+    let threshold = config::get().upload_memory_bytes;
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = field.chunk().await? {
+        if buffer.len() + chunk.len() > threshold {
+            return spool(buffer, chunk, field).await;
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer.freeze())
+}
+
+/// Moves what was read so far into a temporary file, and appends the rest of
+/// the field to it.
+async fn spool(buffer: BytesMut, chunk: Bytes, mut field: Field<'_>) -> Result<Bytes, AppError> {
+    let error = |e: std::io::Error| AppError::internal(format!("Failed to store the upload: {}", e));
+    // Deleted on creation, so it goes away with the last handle.
+    let dir = config::get().upload_temp_dir.clone();
+    let file = tokio::task::spawn_blocking(move || dir.map_or_else(tempfile::tempfile, tempfile::tempfile_in))
+        .await?
+        .map_err(error)?;
+    let mut file = tokio::fs::File::from_std(file);
+    file.write_all(&buffer).await.map_err(error)?;
+    drop(buffer);
+    file.write_all(&chunk).await.map_err(error)?;
+    while let Some(chunk) = field.chunk().await? {
+        file.write_all(&chunk).await.map_err(error)?;
+    }
+    file.flush().await.map_err(error)?;
+    let file = file.into_std().await;
+
+    // SAFETY: the file has no name, so nothing else can modify or truncate
+    // it while it is mapped.
+    let map = unsafe { memmap2::Mmap::map(&file) }.map_err(error)?;
+    Ok(Bytes::from_owner(map))
+}
@@ -0,0 +1,231 @@
+use axum::{
+    extract::Query,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use crate::config;
+use crate::error::AppError;
+use crate::storage::UtcTime;
+
+/// What a client used on one UTC day.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    pub requests: u64,
+    /// Request bodies, such as uploads.
+    pub input_bytes: u64,
+    /// Response bodies, such as transformed images.
+    pub output_bytes: u64,
+    /// Decoded input pixels.
+    pub pixels: u64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.requests += other.requests;
+        self.input_bytes += other.input_bytes;
+        self.output_bytes += other.output_bytes;
+        self.pixels += other.pixels;
+    }
+}
+
+/// Counters by client and day since 1970-01-01.
+type Days = HashMap<(String, u64), Counters>;
+
+#[derive(Default)]
+struct Usage {
+    days: Days,
+    /// What was counted since the last write to `USAGE_DB_PATH`.
+    pending: Days,
+}
+
+static USAGE: LazyLock<Mutex<Usage>> = LazyLock::new(Default::default);
+
+/// How often counters are written to `USAGE_DB_PATH`.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Loads the counters kept in `USAGE_DB_PATH`, and writes new counts there
+/// every 30 seconds.
+pub fn install() {
+    let Some(path) = config::get().usage_db_path.as_deref() else {
+        return;
+    };
+    match store::load(path) {
+        Ok(days) => USAGE.lock().unwrap().days = days,
+        // New counts are still added to what the database holds.
+        Err(e) => tracing::error!("Failed to load usage from {}: {}", path.display(), e),
+    }
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush().await;
+        }
+    });
+}
+
+/// Writes new counts to `USAGE_DB_PATH`, keeping them for the next attempt
+/// if that fails.
+pub async fn flush() {
+    let Some(path) = config::get().usage_db_path.clone() else {
+        return;
+    };
+    let pending = std::mem::take(&mut USAGE.lock().unwrap().pending);
+    if pending.is_empty() {
+        return;
+    }
+    let result = tokio::task::spawn_blocking(move || store::add(&path, &pending).map_err(|e| (e, pending))).await;
+    if let Ok(Err((e, pending))) = result {
+        tracing::warn!("Failed to write usage: {}", e);
+        let mut usage = USAGE.lock().unwrap();
+        for (key, counters) in pending {
+            usage.pending.entry(key).or_default().add(&counters);
+        }
+    }
+}
+
+/// Adds to today's counters of a client.
+pub fn record(client: &str, update: impl FnOnce(&mut Counters)) {
+    let mut counters = Counters::default();
+    update(&mut counters);
+    let key = (client.to_string(), UtcTime::now().unix / 86400);
+    let mut usage = USAGE.lock().unwrap();
+    usage.days.entry(key.clone()).or_default().add(&counters);
+    if config::get().usage_db_path.is_some() {
+        usage.pending.entry(key).or_default().add(&counters);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    /// First day counted, as `YYYY-MM-DD`.
+    from: Option<String>,
+    /// Last day counted.
+    to: Option<String>,
+    client: Option<String>,
+}
+
+/// Handler for `GET /usage`, which totals what each client used between
+/// `from` and `to`, for admins.
+pub async fn usage_handler(Query(query): Query<UsageQuery>) -> Result<Response, AppError> {
+    let day = |date: &Option<String>, name: &str| -> Result<Option<u64>, AppError> {
+        date.as_deref()
+            .map(|date| UtcTime::parse_date(date).map(|time| time.unix / 86400).ok_or_else(|| {
+                AppError::malformed(format!("Invalid '{}' date '{}', expected YYYY-MM-DD", name, date)).with_code("invalid_date")
+            }))
+            .transpose()
+    };
+    let (from, to) = (day(&query.from, "from")?, day(&query.to, "to")?);
+    let range = from.unwrap_or(0)..=to.unwrap_or(u64::MAX);
+
+    let mut clients: BTreeMap<String, Counters> = BTreeMap::new();
+    for ((client, day), counters) in &USAGE.lock().unwrap().days {
+        if range.contains(day) && query.client.as_ref().is_none_or(|name| name == client) {
+            clients.entry(client.clone()).or_default().add(counters);
+        }
+    }
+    let clients: Vec<_> = clients.into_iter().map(|(client, counters)| json!({
+        "client": client,
+        "requests": counters.requests,
+        "input_bytes": counters.input_bytes,
+        "output_bytes": counters.output_bytes,
+        "megapixels": (counters.pixels as f64 / 10_000.0).round() / 100.0,
+    })).collect();
+    let date = |day: Option<u64>| day.map(|day| UtcTime::from_unix(day * 86400).date());
+    Ok(Json(json!({ "from": date(from), "to": date(to), "clients": clients })).into_response())
+}
+
+/// Counters in SQLite, one row per client and day.
+#[cfg(feature = "sqlite")]
+mod store {
+    use rusqlite::{params, Connection};
+    use std::path::Path;
+
+    use super::{Counters, Days};
+    use crate::storage::UtcTime;
+
+    fn open(path: &Path) -> rusqlite::Result<Connection> {
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                client TEXT NOT NULL,
+                day TEXT NOT NULL,
+                requests INTEGER NOT NULL,
+                input_bytes INTEGER NOT NULL,
+                output_bytes INTEGER NOT NULL,
+                pixels INTEGER NOT NULL,
+                PRIMARY KEY (client, day)
+            )",
+        )?;
+        Ok(connection)
+    }
+
+    pub fn load(path: &Path) -> Result<Days, String> {
+        let connection = open(path).map_err(|e| e.to_string())?;
+        let mut statement = connection
+            .prepare("SELECT client, day, requests, input_bytes, output_bytes, pixels FROM usage")
+            .map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| {
+            let counters = Counters {
+                requests: row.get::<_, i64>(2)? as u64,
+                input_bytes: row.get::<_, i64>(3)? as u64,
+                output_bytes: row.get::<_, i64>(4)? as u64,
+                pixels: row.get::<_, i64>(5)? as u64,
+            };
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, counters))
+        }).map_err(|e| e.to_string())?;
+        let mut days = Days::new();
+        for row in rows {
+            let (client, date, counters) = row.map_err(|e| e.to_string())?;
+            let day = UtcTime::parse_date(&date).ok_or_else(|| format!("invalid day '{}'", date))?.unix / 86400;
+            days.insert((client, day), counters);
+        }
+        Ok(days)
+    }
+
+    /// Adds counts to the stored ones.
+    pub fn add(path: &Path, days: &Days) -> Result<(), String> {
+        let mut connection = open(path).map_err(|e| e.to_string())?;
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        for ((client, day), counters) in days {
+            transaction.execute(
+                "INSERT INTO usage (client, day, requests, input_bytes, output_bytes, pixels) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (client, day) DO UPDATE SET
+                     requests = requests + excluded.requests,
+                     input_bytes = input_bytes + excluded.input_bytes,
+                     output_bytes = output_bytes + excluded.output_bytes,
+                     pixels = pixels + excluded.pixels",
+                params![
+                    client,
+                    UtcTime::from_unix(day * 86400).date(),
+                    counters.requests as i64,
+                    counters.input_bytes as i64,
+                    counters.output_bytes as i64,
+                    counters.pixels as i64,
+                ],
+            ).map_err(|e| e.to_string())?;
+        }
+        transaction.commit().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod store {
+    use std::path::Path;
+
+    use super::Days;
+
+    pub fn load(_path: &Path) -> Result<Days, String> {
+        Err("USAGE_DB_PATH needs the `sqlite` feature, usage is only kept in memory".to_string())
+    }
+
+    pub fn add(_path: &Path, _days: &Days) -> Result<(), String> {
+        Ok(())
+    }
+}
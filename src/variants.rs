@@ -0,0 +1,86 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use serde_json::json;
+use std::io::{Cursor, Write};
+use std::str::FromStr;
+
+use crate::error::AppError;
+
+pub use image_transformer_core::Variant;
+
+/// How the outputs of a `variants` request are returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VariantBundle {
+    /// A ZIP archive with one file per size.
+    #[default]
+    Zip,
+    /// A JSON list with each output inlined as a `data:` URL.
+    Json,
+}
+
+impl FromStr for VariantBundle {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "zip" => Ok(VariantBundle::Zip),
+            "json" => Ok(VariantBundle::Json),
+            _ => Err(AppError::invalid("Invalid bundle. Use 'zip' or 'json'").with_code("invalid_bundle")),
+        }
+    }
+}
+
+/// Packages the outputs, in the order the sizes were requested.
+pub fn respond(variants: Vec<Variant>, bundle: VariantBundle) -> Result<Response, AppError> {
+    match bundle {
+        VariantBundle::Zip => {
+            // Images are already compressed, so store them without deflating again.
+            let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            let mut names = Vec::with_capacity(variants.len());
+            for variant in &variants {
+                let name = variant.file_name();
+                // Sizes that come out the same, e.g. with `no_enlarge`, are only stored once.
+                if names.contains(&name) {
+                    continue;
+                }
+                archive.start_file(name.as_str(), options)?;
+                archive.write_all(&variant.bytes)?;
+                names.push(name);
+            }
+            let archive = archive.finish()?.into_inner();
+
+            Ok((
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, "application/zip".to_string()),
+                    (header::CONTENT_DISPOSITION, "attachment; filename=\"variants.zip\"".to_string()),
+                ],
+                archive,
+            ).into_response())
+        }
+        VariantBundle::Json => {
+            let variants: Vec<_> = variants
+                .iter()
+                .map(|variant| {
+                    let data = base64::engine::general_purpose::STANDARD.encode(&variant.bytes);
+                    let mut object = json!({
+                        "width": variant.width,
+                        "height": variant.height,
+                        "content_type": variant.format.content_type(),
+                        "bytes": variant.bytes.len(),
+                        "url": format!("data:{};base64,{}", variant.format.content_type(), data),
+                    });
+                    if let Some(placeholder) = &variant.placeholder {
+                        object["placeholder"] = json!(placeholder);
+                    }
+                    object
+                })
+                .collect();
+            Ok(axum::Json(json!({ "variants": variants })).into_response())
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::decode;
+use crate::encode::OutputFormat;
+use crate::storage::UtcTime;
+
+/// Cargo features that change what the server can do, and whether this
+/// build has them.
+const FEATURES: [(&str, bool); 12] = [
+    ("asm", cfg!(feature = "asm")),
+    ("heif", cfg!(feature = "heif")),
+    ("jxl", cfg!(feature = "jxl")),
+    ("mozjpeg", cfg!(feature = "mozjpeg")),
+    ("pdf", cfg!(feature = "pdf")),
+    ("face", cfg!(feature = "face")),
+    ("redis", cfg!(feature = "redis")),
+    ("otel", cfg!(feature = "otel")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("nats", cfg!(feature = "nats")),
+    ("lambda", cfg!(feature = "lambda")),
+];
+
+/// Handler for `GET /version`, which describes the build: its version,
+/// commit and time, its cargo features, and the formats it reads and writes.
+pub async fn version_handler() -> Response {
+    let sha = env!("BUILD_GIT_SHA");
+    let built = env!("BUILD_UNIX_TIME").parse().map(|time| UtcTime::from_unix(time).iso8601()).ok();
+    let features: Vec<_> = FEATURES.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect();
+    let output: Vec<_> = OutputFormat::available().into_iter().map(OutputFormat::content_type).collect();
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": (!sha.is_empty()).then_some(sha),
+        "built_at": built,
+        "features": features,
+        "formats": { "input": decode::input_formats(), "output": output },
+    })).into_response()
+}
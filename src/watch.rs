@@ -0,0 +1,280 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Settings for transforming the images dropped into a directory, from the
+/// `WATCH_*` variables.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+pub struct WatchConfig {
+    /// Directory watched for new images, including its subdirectories.
+    pub dir: PathBuf,
+    /// Directory the results are written to, keeping the layout of `dir`.
+    pub output_dir: PathBuf,
+    /// The /transform fields applied to every image, as a query string such
+    /// as `size=800x600&format=avif`.
+    pub preset: String,
+    /// Directory that inputs are moved to once transformed; without one they
+    /// are left in place.
+    pub done_dir: Option<PathBuf>,
+    /// Checks `dir` for changes this often instead of being notified of
+    /// them, for network shares that report no events.
+    pub poll_interval: Option<Duration>,
+    /// How long a file must go unchanged before it is taken to be complete.
+    pub settle: Duration,
+}
+
+/// Transforms the images dropped into the directory until shutdown is
+/// requested.
+#[cfg(feature = "watch")]
+pub async fn watch(config: &'static WatchConfig) {
+    watcher::watch(config).await;
+}
+
+/// `WATCH_DIR` is ignored without the `watch` feature, so there is never a
+/// directory to watch.
+#[cfg(not(feature = "watch"))]
+pub async fn watch(_config: &'static WatchConfig) {}
+
+/// Files being transformed that are not finished yet.
+pub fn in_flight() -> usize {
+    #[cfg(feature = "watch")]
+    return watcher::in_flight();
+    #[cfg(not(feature = "watch"))]
+    0
+}
+
+#[cfg(feature = "watch")]
+mod watcher {
+    use axum::http::HeaderMap;
+    use notify::event::{AccessKind, AccessMode, EventKind};
+    use notify::{PollWatcher, RecursiveMode, Watcher};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, Semaphore};
+    use tokio::time::Instant;
+    use tracing::Instrument;
+
+    use super::WatchConfig;
+    use crate::config;
+    use crate::error::AppError;
+    use crate::shutdown;
+    use crate::telemetry;
+    use crate::variants::Variant;
+
+    static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Attempts per file, for failures on the server's side such as every
+    /// worker being busy.
+    const ATTEMPTS: u32 = 3;
+
+    /// How long to wait before attempting a file again.
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    pub fn in_flight() -> usize {
+        IN_FLIGHT.load(Ordering::Relaxed)
+    }
+
+    pub async fn watch(config: &'static WatchConfig) {
+        tokio::select! {
+            _ = run(config) => {}
+            _ = shutdown::requested() => {}
+        }
+    }
+
+    /// Collects the files that changed, and transforms each once it has
+    /// settled.
+    async fn run(config: &'static WatchConfig) {
+        let fields = match preset(&config.preset) {
+            Ok(fields) => fields,
+            Err(e) => {
+                tracing::error!("Not watching {}, WATCH_PRESET is invalid: {}", config.dir.display(), e.message());
+                return;
+            }
+        };
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let handler = move |event: notify::Result<notify::Event>| {
+            let _ = sender.send(event);
+        };
+        let watcher: notify::Result<Box<dyn Watcher + Send>> = match config.poll_interval {
+            Some(interval) => PollWatcher::new(handler, notify::Config::default().with_poll_interval(interval)).map(|w| Box::new(w) as _),
+            None => notify::recommended_watcher(handler).map(|w| Box::new(w) as _),
+        };
+        // Dropping the watcher stops the events, so it is kept until the end.
+        let _watcher = match watcher.and_then(|mut watcher| watcher.watch(&config.dir, RecursiveMode::Recursive).map(|_| watcher)) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                tracing::error!("Failed to watch {}: {}", config.dir.display(), e);
+                return;
+            }
+        };
+        tracing::info!("watching {} for images", config.dir.display());
+
+        // A file seen last at a given time, which is complete once `settle`
+        // passed without another change. Polling only sees changes once per
+        // interval, so it waits for one more.
+        let settle = config.settle + config.poll_interval.unwrap_or_default();
+        let mut pending = HashMap::new();
+        // Without a `done_dir`, files left from before would be transformed
+        // again on every start.
+        if config.done_dir.is_some() {
+            for path in existing(&config.dir).into_iter().filter(|path| is_input(config, path)) {
+                pending.insert(path, Instant::now());
+            }
+        }
+        let workers = Arc::new(Semaphore::new(config::get().jobs.workers));
+        let mut tick = tokio::time::interval(Duration::from_millis(250));
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(Ok(event)) if is_write(event.kind) => {
+                        for path in event.paths.into_iter().filter(|path| is_input(config, path)) {
+                            pending.insert(path, Instant::now());
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => tracing::warn!("Failed to watch {}: {}", config.dir.display(), e),
+                    None => return,
+                },
+                _ = tick.tick() => {
+                    let settled: Vec<PathBuf> = pending.iter()
+                        .filter(|(_, seen)| seen.elapsed() >= settle)
+                        .map(|(path, _)| path.clone())
+                        .collect();
+                    for path in settled {
+                        pending.remove(&path);
+                        // Removed or renamed files also report a change.
+                        if !path.is_file() {
+                            continue;
+                        }
+                        let permit = workers.clone().acquire_owned().await.expect("the semaphore is never closed");
+                        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+                        let fields = fields.clone();
+                        tokio::spawn(async move {
+                            process(config, &fields, &path).await;
+                            IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+                            drop(permit);
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads `WATCH_PRESET` like the query of a `GET /transform`, rejecting
+    /// it early rather than with every file.
+    fn preset(preset: &str) -> Result<Vec<(String, String)>, AppError> {
+        let fields = config::parse_fields(preset).map_err(AppError::invalid)?;
+        let mut transform = crate::TransformRequest::new();
+        for (name, value) in &fields {
+            if matches!(name.as_str(), "output" | "bundle") {
+                return Err(AppError::invalid(format!("'{}' does not apply, results are written to WATCH_OUTPUT_DIR", name)));
+            }
+            transform.set(name, value)?;
+        }
+        Ok(fields)
+    }
+
+    /// Writes, creates as well as renames into the directory, which are all
+    /// that a new file can show up with.
+    fn is_write(kind: EventKind) -> bool {
+        matches!(kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Access(AccessKind::Close(AccessMode::Write)))
+    }
+
+    /// Leaves out hidden files, such as those of partial uploads, and the
+    /// results when they are written inside the watched directory.
+    fn is_input(config: &WatchConfig, path: &Path) -> bool {
+        let hidden = path.file_name().is_none_or(|name| name.to_string_lossy().starts_with('.'));
+        let result = path.starts_with(&config.output_dir) || config.done_dir.as_ref().is_some_and(|dir| path.starts_with(dir));
+        !hidden && !result && path.extension().is_none_or(|extension| extension != "partial")
+    }
+
+    /// The files already in the directory, which arrived while the server
+    /// was down.
+    fn existing(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+            for entry in entries.flatten() {
+                match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+                    Ok(_) => files.push(entry.path()),
+                    Err(_) => {}
+                }
+            }
+        }
+        files
+    }
+
+    /// Transforms a file, attempting it again while it fails on the
+    /// server's side.
+    async fn process(config: &WatchConfig, fields: &[(String, String)], path: &Path) {
+        let relative = path.strip_prefix(&config.dir).unwrap_or(path).to_path_buf();
+        let span = tracing::info_span!("watch", file = %relative.display());
+        async {
+            for attempt in 1..=ATTEMPTS {
+                let (outcome, result) = match transform(config, fields, path, &relative).await {
+                    Ok(written) => ("succeeded", Ok(written)),
+                    Err(error) if error.status_code().is_server_error() && attempt < ATTEMPTS => {
+                        tracing::warn!(code = %error.code(), error = %error.message(), "transforming the file failed, retrying");
+                        telemetry::record_watch_file("retried");
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        continue;
+                    }
+                    Err(error) => ("failed", Err(error)),
+                };
+                telemetry::record_watch_file(outcome);
+                match result {
+                    Ok(written) => tracing::info!(outputs = written, "transformed the file"),
+                    Err(error) => error.log(),
+                }
+                return;
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+
+    /// Writes the outputs below `output_dir`, and moves the input to
+    /// `done_dir`. Returns the number of outputs.
+    async fn transform(config: &WatchConfig, fields: &[(String, String)], path: &Path, relative: &Path) -> Result<usize, AppError> {
+        let mut transform = crate::TransformRequest::new();
+        for (name, value) in fields {
+            transform.set(name, value)?;
+        }
+        let has_variants = !transform.fields.options.variants.is_empty();
+        let image_bytes = tokio::fs::read(path).await?;
+        let outputs = transform.render(image_bytes.into(), &HeaderMap::new()).await?;
+
+        let output = config.output_dir.join(relative).with_extension("");
+        if let Some(parent) = output.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let count = outputs.len();
+        for Variant { bytes, format, width, height, .. } in outputs {
+            let mut name = output.file_name().unwrap_or_default().to_os_string();
+            if has_variants {
+                name.push(format!("-{}x{}", width, height));
+            }
+            name.push(format!(".{}", format.extension()));
+            let path = output.with_file_name(name);
+            // Written under a temporary name first, so that whatever picks
+            // the results up never reads a partial file.
+            let partial = PathBuf::from(format!("{}.partial", path.display()));
+            tokio::fs::write(&partial, bytes).await?;
+            tokio::fs::rename(&partial, &path).await?;
+        }
+
+        if let Some(done_dir) = &config.done_dir {
+            let done = done_dir.join(relative);
+            if let Some(parent) = done.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::rename(path, &done).await?;
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,105 @@
+use axum::http::StatusCode;
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::{header, redirect, Client, Url};
+use sha2::Sha256;
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config;
+use crate::error::AppError;
+use crate::fetch::{self, PublicResolver};
+use crate::signing;
+
+/// Settings for job completion callbacks.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Key for the `X-Signature` header; callbacks are disabled without one.
+    pub secret: Option<String>,
+    /// Deliveries tried before a callback is given up on.
+    pub attempts: u32,
+}
+
+/// Wait before the first retry, doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(10))
+        // A redirect or proxy could lead the callback to an internal address.
+        .redirect(redirect::Policy::none())
+        .no_proxy();
+    if !config::get().fetch.allow_private {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    builder.build().expect("failed to build the HTTP client")
+});
+
+/// Validates a `callback_url` when the job is submitted.
+pub fn check(url: &str) -> Result<Url, AppError> {
+    if config::get().webhook.secret.is_none() {
+        return Err(AppError::forbidden("Job callbacks are disabled on this server").with_code("callbacks_disabled"));
+    }
+    let url = Url::parse(url.trim())
+        .map_err(|e| AppError::invalid(format!("Invalid callback URL: {}", e)).with_code("invalid_callback"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::invalid(format!("Callback scheme '{}' is not allowed", url.scheme())).with_code("invalid_callback"));
+    }
+    // IP literals never reach the resolver, so they are checked here.
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() && !config::get().fetch.allow_private && !fetch::is_public(ip) {
+        return Err(AppError::forbidden(format!("Callback address {} is not public", ip)).with_code("invalid_callback"));
+    }
+    Ok(url)
+}
+
+/// POSTs a JSON payload to a callback URL, retrying network errors, `429`
+/// and `5xx` responses with exponential backoff.
+///
+/// The `X-Signature` header is `t=TIMESTAMP,v1=HEX`, where `HEX` is the
+/// HMAC-SHA256 of `TIMESTAMP.BODY` keyed with the callback secret.
+pub async fn deliver(url: Url, payload: &serde_json::Value) {
+    let Some(secret) = config::get().webhook.secret.as_deref() else { return };
+    let attempts = config::get().webhook.attempts;
+    let body = payload.to_string();
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=attempts {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        let signature = sign(secret, timestamp, &body);
+        let result = CLIENT
+            .post(url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Signature", format!("t={},v1={}", timestamp, signature))
+            .body(body.clone())
+            .send()
+            .await;
+        let retry = match result {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => {
+                let status = response.status();
+                tracing::warn!(url = %url, status = %status, attempt, "callback rejected");
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                tracing::warn!(url = %url, error = %e, attempt, "callback failed");
+                true
+            }
+        };
+        if !retry || attempt == attempts {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    tracing::error!(url = %url, "giving up on callback");
+}
+
+fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    signing::hex(&mac.finalize().into_bytes())
+}